@@ -0,0 +1,134 @@
+//! Benchmarks of the core filesystem operations against an in-memory and a SQLite store.
+//!
+//! Run with `cargo bench`, or `cargo bench -- sqlite/flush` for a subset. Mutations are not
+//! flushed unless noted, so the flush benchmark shows the cost that the others leave out.
+
+use std::time::{Duration, Instant};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use futures::executor::block_on;
+use rand::RngCore;
+use wnfs_experiments::fs::Wnfs;
+use wnfs_experiments::store::{MemoryStore, Store};
+use wnfs_experiments::SqliteBlockStore;
+
+const SMALL_FILE_SIZE: usize = 4 * 1024;
+const LARGE_FILE_SIZE: usize = 16 * 1024 * 1024;
+const READ_CHUNK_SIZE: usize = 1024 * 1024;
+const DIR_SIZES: [usize; 3] = [10, 100, 1000];
+
+fn random_content(size: usize) -> Vec<u8> {
+    let mut content = vec![0u8; size];
+    // Random content, so that nothing benefits from deduplication.
+    rand::rngs::OsRng.fill_bytes(&mut content);
+    content
+}
+
+fn segments(path: &str) -> Vec<String> {
+    path.split('/').map(str::to_string).collect()
+}
+
+fn init<S: Store>(store: S) -> Wnfs<S> {
+    block_on(Wnfs::init_in_store(store, "bench".to_string(), None)).unwrap()
+}
+
+fn bench_store<S: Store>(c: &mut Criterion, name: &str, mut new_store: impl FnMut() -> S) {
+    let mut group = c.benchmark_group(name);
+
+    let mut fs = init(new_store());
+    let mut i = 0;
+    group.bench_function("mkdir", |b| {
+        b.iter(|| {
+            i += 1;
+            block_on(fs.mkdir(&segments(&format!("dirs/{i}")))).unwrap();
+        })
+    });
+
+    let mut fs = init(new_store());
+    let content = random_content(SMALL_FILE_SIZE);
+    let mut i = 0;
+    group.throughput(Throughput::Bytes(SMALL_FILE_SIZE as u64));
+    group.bench_function("small write", |b| {
+        b.iter(|| {
+            i += 1;
+            let path = segments(&format!("small/{i}"));
+            block_on(fs.write_file(&path, content.clone())).unwrap();
+        })
+    });
+    let path = segments("small/1");
+    group.bench_function("small read", |b| {
+        b.iter(|| block_on(fs.read_file(&path)).unwrap())
+    });
+
+    let mut fs = init(new_store());
+    let content = random_content(LARGE_FILE_SIZE);
+    let path = segments("large");
+    group.throughput(Throughput::Bytes(LARGE_FILE_SIZE as u64));
+    group.sample_size(10);
+    group.bench_function("large write", |b| {
+        b.iter(|| block_on(fs.write_file(&path, content.clone())).unwrap())
+    });
+    group.bench_function("large read", |b| {
+        b.iter(|| {
+            for offset in (0..LARGE_FILE_SIZE).step_by(READ_CHUNK_SIZE) {
+                block_on(fs.read_file_at(&path, offset, READ_CHUNK_SIZE)).unwrap();
+            }
+        })
+    });
+    group.sample_size(100);
+
+    let mut fs = init(new_store());
+    for entries in DIR_SIZES {
+        let dir = format!("dir-{entries}");
+        for i in 0..entries {
+            block_on(fs.write_file(&segments(&format!("{dir}/{i}")), vec![])).unwrap();
+        }
+        let path = segments(&dir);
+        group.throughput(Throughput::Elements(entries as u64));
+        group.bench_with_input(BenchmarkId::new("readdir", entries), &path, |b, path| {
+            b.iter(|| block_on(fs.ls_entries(path)).unwrap())
+        });
+    }
+
+    // Only the flush is measured, after a small write that makes the filesystem dirty.
+    let mut fs = init(new_store());
+    let content = random_content(SMALL_FILE_SIZE);
+    let mut i = 0;
+    group.throughput(Throughput::Elements(1));
+    group.bench_function("flush", |b| {
+        b.iter_custom(|iters| {
+            let mut total = Duration::ZERO;
+            for _ in 0..iters {
+                i += 1;
+                let path = segments(&format!("flushed/{i}"));
+                block_on(fs.write_file(&path, content.clone())).unwrap();
+                let start = Instant::now();
+                block_on(fs.flush()).unwrap();
+                total += start.elapsed();
+            }
+            total
+        })
+    });
+
+    group.finish();
+}
+
+fn memory(c: &mut Criterion) {
+    bench_store(c, "memory", MemoryStore::new);
+}
+
+fn sqlite(c: &mut Criterion) {
+    let dir = std::env::temp_dir().join(format!("wnfs-bench-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let mut count = 0;
+    let new_store = || {
+        count += 1;
+        let path = dir.join(format!("blocks-{count}.db"));
+        SqliteBlockStore::new(path).unwrap()
+    };
+    bench_store(c, "sqlite", new_store);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+criterion_group!(benches, memory, sqlite);
+criterion_main!(benches);
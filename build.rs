@@ -0,0 +1,7 @@
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    #[cfg(feature = "grpc")]
+    tonic_build::compile_protos("proto/wnfs.proto")?;
+    #[cfg(feature = "csi")]
+    tonic_build::compile_protos("proto/csi.proto")?;
+    Ok(())
+}
@@ -0,0 +1,52 @@
+/* C bindings for wnfs-experiments, built with `cargo build --release --features ffi`.
+ * See src/ffi.rs for the documentation of each function. */
+
+#ifndef WNFS_H
+#define WNFS_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define WNFS_OK 0
+#define WNFS_ERR_INVALID_ARGUMENT -1
+#define WNFS_ERR_NOT_FOUND -2
+#define WNFS_ERR_FAILED -3
+#define WNFS_ERR_PANIC -4
+
+#define WNFS_ENTRY_FILE 0
+#define WNFS_ENTRY_DIR 1
+
+typedef struct WnfsFs WnfsFs;
+
+typedef struct WnfsDirEntry {
+    char *name;
+    int kind;
+    uint64_t size;
+    int64_t modified;
+} WnfsDirEntry;
+
+int wnfs_open(const char *db_path, const char *name, const char *passphrase, WnfsFs **out);
+int wnfs_close(WnfsFs *fs);
+int wnfs_flush(WnfsFs *fs);
+
+int wnfs_read(WnfsFs *fs, const char *path, uint64_t offset, size_t size, uint8_t **out,
+              size_t *out_len);
+void wnfs_free_buffer(uint8_t *buf, size_t len);
+int wnfs_write(WnfsFs *fs, const char *path, const uint8_t *data, size_t len);
+
+int wnfs_ls(WnfsFs *fs, const char *path, WnfsDirEntry **out, size_t *out_len);
+void wnfs_free_entries(WnfsDirEntry *entries, size_t len);
+int wnfs_mkdir(WnfsFs *fs, const char *path);
+int wnfs_rm(WnfsFs *fs, const char *path);
+
+const char *wnfs_last_error(void);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* WNFS_H */
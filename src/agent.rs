@@ -0,0 +1,190 @@
+//! Agent that keeps the passphrases of protected filesystems in memory, like `ssh-agent`.
+//!
+//! [`run`] listens on a unix socket that only the current user can access. Clients send one
+//! JSON [`Request`] per connection and read one JSON [`Response`]. Passphrases are stored per
+//! block store and filesystem name and are never written to disk.
+//!
+//! The socket is `$WNFS_AGENT_SOCK`, or `agent.sock` in `$XDG_RUNTIME_DIR/wnfs-fuse`.
+
+use std::collections::HashMap;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::{debug, info, warn};
+
+/// A request to the agent.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", tag = "op")]
+pub enum Request {
+    Get {
+        db_path: String,
+        name: String,
+    },
+    Add {
+        db_path: String,
+        name: String,
+        passphrase: String,
+    },
+    Remove {
+        db_path: String,
+        name: String,
+    },
+    /// Forget all passphrases.
+    Clear,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Response {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub passphrase: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+type Secrets = Arc<Mutex<HashMap<(String, String), String>>>;
+
+/// Path of the agent socket.
+pub fn socket_path() -> PathBuf {
+    if let Some(path) = std::env::var_os("WNFS_AGENT_SOCK") {
+        return PathBuf::from(path);
+    }
+    std::env::var_os("XDG_RUNTIME_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(std::env::temp_dir)
+        .join("wnfs-fuse")
+        .join("agent.sock")
+}
+
+/// Serve requests on a socket until the process is terminated.
+pub async fn run(socket: &Path) -> anyhow::Result<()> {
+    if let Some(dir) = socket.parent() {
+        std::fs::create_dir_all(dir)?;
+        std::fs::set_permissions(dir, std::fs::Permissions::from_mode(0o700))?;
+    }
+    if UnixStream::connect(socket).await.is_ok() {
+        anyhow::bail!("An agent is already listening on {socket:?}");
+    }
+    // Left behind by an agent that did not exit cleanly.
+    let _ = std::fs::remove_file(socket);
+    let listener = UnixListener::bind(socket)?;
+    std::fs::set_permissions(socket, std::fs::Permissions::from_mode(0o600))?;
+    info!("agent listening on {socket:?}");
+
+    let secrets = Secrets::default();
+    let mut terminate = signal(SignalKind::terminate())?;
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (stream, _addr) = accepted?;
+                let secrets = secrets.clone();
+                tokio::spawn(async move {
+                    if let Err(err) = handle(stream, &secrets).await {
+                        warn!("agent connection failed: {err}");
+                    }
+                });
+            }
+            _ = terminate.recv() => break,
+            _ = tokio::signal::ctrl_c() => break,
+        }
+    }
+    std::fs::remove_file(socket)?;
+    Ok(())
+}
+
+async fn handle(stream: UnixStream, secrets: &Secrets) -> anyhow::Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut line = String::new();
+    BufReader::new(reader).read_line(&mut line).await?;
+    let response = match serde_json::from_str::<Request>(&line) {
+        Ok(request) => respond(request, secrets),
+        Err(err) => Response {
+            error: Some(format!("Invalid request: {err}")),
+            ..Default::default()
+        },
+    };
+    let mut response = serde_json::to_vec(&response)?;
+    response.push(b'\n');
+    writer.write_all(&response).await?;
+    Ok(())
+}
+
+fn respond(request: Request, secrets: &Secrets) -> Response {
+    let mut secrets = secrets.lock().unwrap();
+    let passphrase = match request {
+        Request::Get { db_path, name } => secrets.get(&(db_path, name)).cloned(),
+        Request::Add {
+            db_path,
+            name,
+            passphrase,
+        } => {
+            debug!("add passphrase of {name}");
+            secrets.insert((db_path, name), passphrase);
+            None
+        }
+        Request::Remove { db_path, name } => secrets.remove(&(db_path, name)),
+        Request::Clear => {
+            secrets.clear();
+            None
+        }
+    };
+    Response {
+        passphrase,
+        error: None,
+    }
+}
+
+/// Send a request to the agent. Returns `None` if no agent is running.
+pub async fn request(request: &Request) -> anyhow::Result<Option<Response>> {
+    let socket = socket_path();
+    let stream = match UnixStream::connect(&socket).await {
+        Ok(stream) => stream,
+        Err(err) => {
+            debug!("no agent at {socket:?}: {err}");
+            return Ok(None);
+        }
+    };
+    let (reader, mut writer) = stream.into_split();
+    let mut request = serde_json::to_vec(request)?;
+    request.push(b'\n');
+    writer.write_all(&request).await?;
+    let mut line = String::new();
+    BufReader::new(reader).read_line(&mut line).await?;
+    let response: Response = serde_json::from_str(&line)?;
+    if let Some(error) = response.error {
+        anyhow::bail!("Agent error: {error}");
+    }
+    Ok(Some(response))
+}
+
+/// Identifies a block store across working directories.
+pub fn store_id(db_path: &str) -> String {
+    std::fs::canonicalize(db_path)
+        .map(|path| path.to_string_lossy().into_owned())
+        .unwrap_or_else(|_| db_path.to_string())
+}
+
+/// The passphrase of a filesystem from the agent, if one is running and has it.
+pub async fn get_passphrase(db_path: &str, name: &str) -> anyhow::Result<Option<String>> {
+    let request = Request::Get {
+        db_path: store_id(db_path),
+        name: name.to_string(),
+    };
+    Ok(self::request(&request)
+        .await?
+        .and_then(|response| response.passphrase))
+}
+
+/// Hand the passphrase of a filesystem to the agent. Returns whether an agent is running.
+pub async fn add_passphrase(db_path: &str, name: &str, passphrase: &str) -> anyhow::Result<bool> {
+    let request = Request::Add {
+        db_path: store_id(db_path),
+        name: name.to_string(),
+        passphrase: passphrase.to_string(),
+    };
+    Ok(self::request(&request).await?.is_some())
+}
@@ -0,0 +1,371 @@
+//! JSON API for web UIs and remote automation.
+//!
+//! All endpoints are below `/api/v1` and require an `Authorization: Bearer <token>` header.
+//! Errors are returned as `{"error": "..."}` with a matching status code.
+//!
+//! * `GET /status` returns the name, root CID and heads of the filesystem
+//! * `POST /heads` with `{"choose": <index>}` or `{"merge": true}` resolves divergent heads
+//! * `GET /ls/<path>` lists a directory, `GET /stat/<path>` returns a single entry
+//! * `GET /read/<path>?offset=&length=` returns (a range of) the content of a file
+//! * `PUT /write/<path>?append=` writes the request body to a file
+//! * `POST /mkdir/<path>` creates a directory, `DELETE /rm/<path>` removes a file or directory
+//! * `POST /mv` with `{"from": "...", "to": "..."}` moves a file or directory
+//! * `GET /snapshots` lists snapshots, `POST /snapshots` with `{"name": "..."}` creates one
+
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+use axum::body::Bytes;
+use axum::extract::{Path, Query, State};
+use axum::http::header::AUTHORIZATION;
+use axum::http::{Request, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{delete, get, post, put};
+use axum::{Json, Router};
+use axum_server::tls_rustls::RustlsConfig;
+use futures::FutureExt;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tracing::debug;
+
+use crate::fs::{DirEntry, HeadInfo, SnapshotInfo};
+use crate::handle::WnfsHandle;
+
+/// Options for the API server.
+#[derive(Debug, Clone)]
+pub struct ApiConfig {
+    /// Token that clients have to send as bearer token.
+    pub token: String,
+    /// Certificate and private key files (PEM) to serve HTTPS instead of HTTP.
+    pub tls: Option<(PathBuf, PathBuf)>,
+}
+
+#[derive(Clone)]
+struct ApiState {
+    fs: WnfsHandle,
+    /// Hash of the token, compared in constant time.
+    token_hash: blake3::Hash,
+}
+
+/// Serve the API until the server fails.
+pub async fn serve(fs: WnfsHandle, addr: SocketAddr, config: ApiConfig) -> anyhow::Result<()> {
+    let app = router(fs, &config.token);
+    debug!("serve API on {addr}");
+    match config.tls {
+        Some((cert, key)) => {
+            let tls = RustlsConfig::from_pem_file(cert, key).await?;
+            axum_server::bind_rustls(addr, tls)
+                .serve(app.into_make_service())
+                .await?;
+        }
+        None => {
+            axum::Server::bind(&addr)
+                .serve(app.into_make_service())
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+/// Create the API routes, to be served by an application's own server.
+pub fn router(fs: WnfsHandle, token: &str) -> Router {
+    let state = ApiState {
+        fs,
+        token_hash: blake3::hash(token.as_bytes()),
+    };
+    let api = Router::new()
+        .route("/status", get(status))
+        .route("/heads", post(resolve_heads))
+        .route("/ls", get(ls_root))
+        .route("/ls/*path", get(ls))
+        .route("/stat/*path", get(stat))
+        .route("/read/*path", get(read))
+        .route("/write/*path", put(write))
+        .route("/mkdir/*path", post(mkdir))
+        .route("/rm/*path", delete(rm))
+        .route("/mv", post(mv))
+        .route("/snapshots", get(list_snapshots).post(create_snapshot))
+        .route_layer(middleware::from_fn_with_state(state.clone(), authenticate))
+        .with_state(state);
+    Router::new().nest("/api/v1", api)
+}
+
+async fn authenticate<B>(
+    State(state): State<ApiState>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Result<Response, Error> {
+    let token = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    match token {
+        // blake3::Hash compares in constant time.
+        Some(token) if blake3::hash(token.as_bytes()) == state.token_hash => {
+            Ok(next.run(request).await)
+        }
+        _ => Err(Error(StatusCode::UNAUTHORIZED, "Invalid token".into())),
+    }
+}
+
+#[derive(Serialize)]
+struct Status {
+    name: String,
+    root_cid: Option<String>,
+    heads: Vec<HeadInfo>,
+}
+
+async fn status(State(state): State<ApiState>) -> Result<Json<Status>, Error> {
+    let status = state
+        .fs
+        .call(|fs| {
+            async move {
+                let root_cid = fs.root_cid().await?;
+                Ok(Status {
+                    name: fs.name().to_string(),
+                    root_cid: root_cid.map(|cid| cid.to_string()),
+                    heads: fs.heads().await?,
+                })
+            }
+            .boxed_local()
+        })
+        .await?;
+    Ok(Json(status))
+}
+
+#[derive(Deserialize)]
+struct ResolveRequest {
+    choose: Option<usize>,
+    #[serde(default)]
+    merge: bool,
+}
+
+/// Returns the paths of conflict copies created by a merge.
+async fn resolve_heads(
+    State(state): State<ApiState>,
+    Json(request): Json<ResolveRequest>,
+) -> Result<Json<Vec<String>>, Error> {
+    let copies = match (request.choose, request.merge) {
+        (Some(index), false) => {
+            state
+                .fs
+                .call(move |fs| {
+                    async move {
+                        fs.flush().await?;
+                        fs.choose_head(index).await?;
+                        fs.flush().await
+                    }
+                    .boxed_local()
+                })
+                .await?;
+            vec![]
+        }
+        (None, true) => {
+            state
+                .fs
+                .call(|fs| {
+                    async move {
+                        let copies = fs.merge_heads().await?;
+                        fs.flush().await?;
+                        Ok(copies)
+                    }
+                    .boxed_local()
+                })
+                .await?
+        }
+        _ => {
+            let message = "Expected either choose or merge".to_string();
+            return Err(Error(StatusCode::BAD_REQUEST, message));
+        }
+    };
+    let copies = copies.iter().map(|copy| copy.segments().join("/"));
+    Ok(Json(copies.collect()))
+}
+
+async fn ls_root(state: State<ApiState>) -> Result<Json<Vec<DirEntry>>, Error> {
+    ls(state, Path(String::new())).await
+}
+
+async fn ls(
+    State(state): State<ApiState>,
+    Path(path): Path<String>,
+) -> Result<Json<Vec<DirEntry>>, Error> {
+    let path_segments = into_segments(&path);
+    let entries = state
+        .fs
+        .call(move |fs| async move { fs.ls_entries(&path_segments).await }.boxed_local())
+        .await?;
+    Ok(Json(entries))
+}
+
+async fn stat(
+    State(state): State<ApiState>,
+    Path(path): Path<String>,
+) -> Result<Json<DirEntry>, Error> {
+    let path_segments = into_segments(&path);
+    let entry = state
+        .fs
+        .call(move |fs| async move { fs.stat(&path_segments).await }.boxed_local())
+        .await?
+        .ok_or_else(|| Error(StatusCode::NOT_FOUND, "Not found".into()))?;
+    Ok(Json(entry))
+}
+
+#[derive(Deserialize)]
+struct ReadQuery {
+    #[serde(default)]
+    offset: usize,
+    length: Option<usize>,
+}
+
+async fn read(
+    State(state): State<ApiState>,
+    Path(path): Path<String>,
+    Query(query): Query<ReadQuery>,
+) -> Result<Vec<u8>, Error> {
+    let path_segments = into_segments(&path);
+    let data = state
+        .fs
+        .call(move |fs| {
+            async move {
+                match query.length {
+                    Some(length) => fs.read_file_at(&path_segments, query.offset, length).await,
+                    None => {
+                        let content = fs.read_file(&path_segments).await?;
+                        Ok(content.get(query.offset..).unwrap_or_default().to_vec())
+                    }
+                }
+            }
+            .boxed_local()
+        })
+        .await?;
+    Ok(data)
+}
+
+#[derive(Deserialize)]
+struct WriteQuery {
+    #[serde(default)]
+    append: bool,
+}
+
+async fn write(
+    State(state): State<ApiState>,
+    Path(path): Path<String>,
+    Query(query): Query<WriteQuery>,
+    body: Bytes,
+) -> Result<StatusCode, Error> {
+    let path_segments = into_segments(&path);
+    state
+        .fs
+        .call(move |fs| {
+            async move {
+                if query.append {
+                    fs.append_file(&path_segments, body.to_vec()).await
+                } else {
+                    fs.write_file(&path_segments, body.to_vec()).await
+                }
+            }
+            .boxed_local()
+        })
+        .await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn mkdir(
+    State(state): State<ApiState>,
+    Path(path): Path<String>,
+) -> Result<StatusCode, Error> {
+    let path_segments = into_segments(&path);
+    state
+        .fs
+        .call(move |fs| async move { fs.mkdir(&path_segments).await }.boxed_local())
+        .await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn rm(State(state): State<ApiState>, Path(path): Path<String>) -> Result<StatusCode, Error> {
+    let path_segments = into_segments(&path);
+    let found = state
+        .fs
+        .call(move |fs| {
+            async move {
+                if fs.get_node(&path_segments).await?.is_none() {
+                    return Ok(false);
+                }
+                fs.rm(&path_segments).await?;
+                Ok(true)
+            }
+            .boxed_local()
+        })
+        .await?;
+    if !found {
+        return Err(Error(StatusCode::NOT_FOUND, "Not found".into()));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Deserialize)]
+struct MvRequest {
+    from: String,
+    to: String,
+}
+
+async fn mv(
+    State(state): State<ApiState>,
+    Json(request): Json<MvRequest>,
+) -> Result<StatusCode, Error> {
+    let from = into_segments(&request.from);
+    let to = into_segments(&request.to);
+    state
+        .fs
+        .call(move |fs| async move { fs.mv(&from, &to).await }.boxed_local())
+        .await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn list_snapshots(State(state): State<ApiState>) -> Result<Json<Vec<SnapshotInfo>>, Error> {
+    let snapshots = state
+        .fs
+        .call(|fs| async move { fs.list_snapshots().await }.boxed_local())
+        .await?;
+    Ok(Json(snapshots))
+}
+
+#[derive(Deserialize)]
+struct SnapshotRequest {
+    name: String,
+}
+
+async fn create_snapshot(
+    State(state): State<ApiState>,
+    Json(request): Json<SnapshotRequest>,
+) -> Result<StatusCode, Error> {
+    state
+        .fs
+        .call(move |fs| async move { fs.create_snapshot(&request.name).await }.boxed_local())
+        .await?;
+    Ok(StatusCode::CREATED)
+}
+
+fn into_segments(path: &str) -> Vec<String> {
+    path.split('/')
+        .filter(|segment| !segment.is_empty())
+        .map(|segment| segment.to_owned())
+        .collect()
+}
+
+struct Error(StatusCode, String);
+
+impl<E: Into<anyhow::Error>> From<E> for Error {
+    fn from(err: E) -> Self {
+        Self(StatusCode::INTERNAL_SERVER_ERROR, err.into().to_string())
+    }
+}
+
+impl IntoResponse for Error {
+    fn into_response(self) -> Response {
+        (self.0, Json(json!({ "error": self.1 }))).into_response()
+    }
+}
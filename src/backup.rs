@@ -0,0 +1,146 @@
+//! Incremental offline backups as a directory of CAR files.
+//!
+//! The first [`backup`] of a filesystem writes all of its blocks to a CAR file. Later backups
+//! only write the blocks that are not reachable from the last backed-up root (see
+//! [`crate::car`]). `catalog.json` lists the files in order with their roots, and [`restore`]
+//! imports them in that order.
+
+use std::path::Path;
+
+use chrono::{DateTime, Utc};
+use libipld::Cid;
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+use crate::car::{self, CarStats};
+use crate::fs::private_root_alias;
+use crate::SqliteBlockStore;
+
+const CATALOG_FILE: &str = "catalog.json";
+
+/// Index of the CAR files in a backup directory.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Catalog {
+    /// Name of the backed-up filesystem.
+    pub name: String,
+    /// Backups, oldest first.
+    pub entries: Vec<CatalogEntry>,
+}
+
+/// A CAR file in a backup directory.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CatalogEntry {
+    pub file: String,
+    /// CID of the root record.
+    pub root: String,
+    /// Root of the previous backup, which this file contains the new blocks of. `None` for
+    /// the full backup.
+    pub base: Option<String>,
+    pub created: DateTime<Utc>,
+    pub blocks: u64,
+    pub bytes: u64,
+}
+
+impl Catalog {
+    /// Read the catalog of a backup directory.
+    pub async fn load(dir: &Path) -> anyhow::Result<Self> {
+        let path = dir.join(CATALOG_FILE);
+        let content = tokio::fs::read(&path)
+            .await
+            .map_err(|err| anyhow::anyhow!("Failed to read {path:?}: {err}"))?;
+        Ok(serde_json::from_slice(&content)?)
+    }
+
+    async fn save(&self, dir: &Path) -> anyhow::Result<()> {
+        // Written to a temporary file first, so that an interrupted backup keeps the old one.
+        let tmp = dir.join(format!("{CATALOG_FILE}.tmp"));
+        tokio::fs::write(&tmp, serde_json::to_vec_pretty(self)?).await?;
+        tokio::fs::rename(&tmp, dir.join(CATALOG_FILE)).await?;
+        Ok(())
+    }
+}
+
+/// Back up a named filesystem to a directory, creating it if needed.
+///
+/// Returns the new catalog entry, or `None` if the filesystem did not change since the last
+/// backup.
+pub async fn backup(
+    store: &SqliteBlockStore,
+    name: &str,
+    dir: &Path,
+) -> anyhow::Result<Option<CatalogEntry>> {
+    let root = store
+        .resolve_alias(&private_root_alias(name))
+        .await?
+        .ok_or_else(|| anyhow::anyhow!("Filesystem {name} does not exist"))?;
+    tokio::fs::create_dir_all(dir).await?;
+    let mut catalog = if tokio::fs::try_exists(dir.join(CATALOG_FILE)).await? {
+        Catalog::load(dir).await?
+    } else {
+        Catalog {
+            name: name.to_string(),
+            entries: vec![],
+        }
+    };
+    if catalog.name != name {
+        anyhow::bail!("{dir:?} is a backup of {}", catalog.name);
+    }
+    let base = match catalog.entries.last() {
+        Some(entry) => Some(Cid::try_from(entry.root.as_str())?),
+        None => None,
+    };
+    if base == Some(root) {
+        return Ok(None);
+    }
+
+    let kind = if base.is_some() { "delta" } else { "full" };
+    let file = format!("{:04}-{kind}.car", catalog.entries.len());
+    let tmp = dir.join(format!("{file}.tmp"));
+    let writer = tokio::io::BufWriter::new(tokio::fs::File::create(&tmp).await?);
+    let stats = car::export(store, name, base.as_ref(), writer).await?;
+    tokio::fs::rename(&tmp, dir.join(&file)).await?;
+    debug!("wrote {file} with {} blocks", stats.blocks);
+
+    let entry = CatalogEntry {
+        file,
+        root: root.to_string(),
+        base: base.map(|base| base.to_string()),
+        created: Utc::now(),
+        blocks: stats.blocks,
+        bytes: stats.bytes,
+    };
+    catalog.entries.push(entry.clone());
+    catalog.save(dir).await?;
+    Ok(Some(entry))
+}
+
+/// Import all CAR files of a backup directory into the store as a named filesystem.
+///
+/// An existing filesystem is only replaced if `force` is set or if it is the state of one of
+/// the backups.
+pub async fn restore(
+    store: &SqliteBlockStore,
+    dir: &Path,
+    name: &str,
+    force: bool,
+) -> anyhow::Result<CarStats> {
+    let catalog = Catalog::load(dir).await?;
+    let local = store.resolve_alias(&private_root_alias(name)).await?;
+    if let Some(local) = local.map(|local| local.to_string()) {
+        if !force && !catalog.entries.iter().any(|entry| entry.root == local) {
+            anyhow::bail!("Filesystem {name} exists and differs, use --force to replace it");
+        }
+    }
+    let mut stats = CarStats::default();
+    for entry in &catalog.entries {
+        let file = tokio::fs::File::open(dir.join(&entry.file)).await?;
+        debug!("import {}", entry.file);
+        // Each file applies on top of the previous one, so only the first may replace the
+        // local filesystem.
+        let force = entry.base.is_none();
+        let imported = car::import(store, name, tokio::io::BufReader::new(file), force).await?;
+        stats.blocks += imported.blocks;
+        stats.bytes += imported.bytes;
+    }
+    Ok(stats)
+}
@@ -0,0 +1,187 @@
+//! Standard workloads for comparing performance between stores and versions.
+//!
+//! The workloads run against a temporary filesystem in the given store, which is deleted
+//! afterwards. Its blocks stay in the store until the next garbage collection.
+
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use rand::RngCore;
+use serde::Serialize;
+
+use crate::fs::Wnfs;
+use crate::SqliteBlockStore;
+
+const READ_CHUNK_SIZE: usize = 1024 * 1024;
+const SMALL_FILE_SIZE: usize = 4 * 1024;
+const DIR_ENTRIES: usize = 16;
+
+/// Parameters of the workloads.
+#[derive(Debug, Clone)]
+pub struct BenchOptions {
+    /// Size of the file for sequential writes and reads, in MiB.
+    pub size_mb: usize,
+    /// Number of files for the small-file workload.
+    pub files: usize,
+    /// Nesting depth for the readdir workload.
+    pub depth: usize,
+}
+
+impl Default for BenchOptions {
+    fn default() -> Self {
+        Self {
+            size_mb: 64,
+            files: 200,
+            depth: 16,
+        }
+    }
+}
+
+/// Result of a single workload.
+#[derive(Debug, Clone, Serialize)]
+pub struct BenchResult {
+    pub name: &'static str,
+    pub ops: usize,
+    pub bytes: u64,
+    #[serde(serialize_with = "serialize_secs")]
+    pub total: Duration,
+    #[serde(serialize_with = "serialize_secs")]
+    pub mean_latency: Duration,
+    #[serde(serialize_with = "serialize_secs")]
+    pub max_latency: Duration,
+}
+
+impl BenchResult {
+    fn new(name: &'static str, bytes: u64, latencies: &[Duration]) -> Self {
+        let total = latencies.iter().sum::<Duration>();
+        Self {
+            name,
+            ops: latencies.len(),
+            bytes,
+            total,
+            mean_latency: total / latencies.len().max(1) as u32,
+            max_latency: latencies.iter().max().copied().unwrap_or_default(),
+        }
+    }
+
+    /// Throughput in bytes per second, if the workload transfers content.
+    pub fn throughput(&self) -> Option<f64> {
+        (self.bytes > 0).then(|| self.bytes as f64 / self.total.as_secs_f64())
+    }
+
+    pub fn ops_per_sec(&self) -> f64 {
+        self.ops as f64 / self.total.as_secs_f64()
+    }
+}
+
+fn serialize_secs<S: serde::Serializer>(
+    duration: &Duration,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_f64(duration.as_secs_f64())
+}
+
+/// Run all workloads against a temporary filesystem in the store at `db_path`.
+pub async fn run(
+    db_path: impl AsRef<Path>,
+    options: &BenchOptions,
+) -> anyhow::Result<Vec<BenchResult>> {
+    let name = format!("bench-{:016x}", rand::rngs::OsRng.next_u64());
+    let mut fs = Wnfs::init(&db_path, name.clone(), None).await?;
+    // Measure the latency of persisted operations, as before lazy flushing.
+    fs.set_autoflush(true);
+    let results = run_workloads(&mut fs, options).await;
+    drop(fs);
+    let store = SqliteBlockStore::new(&db_path)?;
+    Wnfs::delete(&store, &name).await?;
+    results
+}
+
+async fn run_workloads(fs: &mut Wnfs, options: &BenchOptions) -> anyhow::Result<Vec<BenchResult>> {
+    Ok(vec![
+        sequential_write(fs, options).await?,
+        sequential_read(fs, options).await?,
+        small_files(fs, options).await?,
+        deep_readdir(fs, options).await?,
+    ])
+}
+
+async fn sequential_write(fs: &mut Wnfs, options: &BenchOptions) -> anyhow::Result<BenchResult> {
+    let mut content = vec![0u8; options.size_mb * 1024 * 1024];
+    // Random content, so that nothing benefits from compression or deduplication.
+    rand::rngs::OsRng.fill_bytes(&mut content);
+    let bytes = content.len() as u64;
+    let start = Instant::now();
+    fs.write_file(&["seq".to_string()], content).await?;
+    Ok(BenchResult::new(
+        "sequential write",
+        bytes,
+        &[start.elapsed()],
+    ))
+}
+
+async fn sequential_read(fs: &mut Wnfs, options: &BenchOptions) -> anyhow::Result<BenchResult> {
+    let path = ["seq".to_string()];
+    let mut latencies = vec![];
+    let mut bytes = 0;
+    for i in 0..options.size_mb * 1024 * 1024 / READ_CHUNK_SIZE {
+        let start = Instant::now();
+        let data = fs
+            .read_file_at(&path, i * READ_CHUNK_SIZE, READ_CHUNK_SIZE)
+            .await?;
+        latencies.push(start.elapsed());
+        bytes += data.len() as u64;
+    }
+    Ok(BenchResult::new("sequential read", bytes, &latencies))
+}
+
+/// Create, overwrite and remove many small files, each operation flushed.
+async fn small_files(fs: &mut Wnfs, options: &BenchOptions) -> anyhow::Result<BenchResult> {
+    fs.mkdir(&["small".to_string()]).await?;
+    let mut content = vec![0u8; SMALL_FILE_SIZE];
+    let mut latencies = vec![];
+    let mut bytes = 0;
+    for round in 0..2 {
+        for i in 0..options.files {
+            rand::rngs::OsRng.fill_bytes(&mut content);
+            let path = ["small".to_string(), format!("{i}-{round}")];
+            let start = Instant::now();
+            fs.write_file(&path, content.clone()).await?;
+            latencies.push(start.elapsed());
+            bytes += content.len() as u64;
+        }
+    }
+    for i in 0..options.files {
+        let path = ["small".to_string(), format!("{i}-0")];
+        let start = Instant::now();
+        fs.rm(&path).await?;
+        latencies.push(start.elapsed());
+    }
+    Ok(BenchResult::new("small file churn", bytes, &latencies))
+}
+
+/// List every level of a deeply nested tree.
+async fn deep_readdir(fs: &mut Wnfs, options: &BenchOptions) -> anyhow::Result<BenchResult> {
+    fs.set_autoflush(false);
+    let mut path = vec!["deep".to_string()];
+    for level in 0..options.depth {
+        for i in 0..DIR_ENTRIES {
+            let mut entry_path = path.clone();
+            entry_path.push(format!("file-{i}"));
+            fs.write_file(&entry_path, vec![]).await?;
+        }
+        path.push(format!("level-{level}"));
+        fs.mkdir(&path).await?;
+    }
+    fs.flush().await?;
+    fs.set_autoflush(true);
+    let mut latencies = vec![];
+    let mut path = vec!["deep".to_string()];
+    for level in 0..options.depth {
+        let start = Instant::now();
+        fs.ls_entries(&path).await?;
+        latencies.push(start.elapsed());
+        path.push(format!("level-{level}"));
+    }
+    Ok(BenchResult::new("deep readdir", 0, &latencies))
+}
@@ -0,0 +1,177 @@
+//! Block exchange with IPFS nodes and other stores over libp2p bitswap.
+//!
+//! A [`BitswapNode`] serves all blocks of a store to anyone who asks. With peers configured,
+//! it is also a [`BlockResolver`]: blocks that are missing locally are requested from the
+//! peers while reading, so a filesystem can be opened before all of its blocks are synced.
+//!
+//! The node identity is kept in the store, so its peer ID stays the same across restarts.
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Mutex;
+
+use async_trait::async_trait;
+use futures::StreamExt;
+use ipfs_sqlite_block_store::{BlockStore as DbBlockStore, Config};
+use libipld::{Block, Cid, IpldCodec};
+use libp2p::core::upgrade;
+use libp2p::identity::Keypair;
+use libp2p::multiaddr::Protocol;
+use libp2p::swarm::{SwarmBuilder, SwarmEvent};
+use libp2p::{noise, tcp, yamux, Multiaddr, PeerId, Transport};
+use libp2p_bitswap::{Bitswap, BitswapConfig, BitswapEvent, BitswapStore, QueryId};
+use tokio::sync::{mpsc, oneshot};
+use tracing::{debug, info, warn};
+
+use crate::store::DefaultParams;
+use crate::{BlockResolver, SqliteBlockStore};
+
+const KEYPAIR_ALIAS: &str = "bitswap-key";
+
+/// Options for [`BitswapNode::spawn`].
+#[derive(Debug, Clone, Default)]
+pub struct BitswapOptions {
+    /// Addresses to listen on, e.g. `/ip4/0.0.0.0/tcp/4001`. Without any, the node only
+    /// fetches blocks and does not accept connections.
+    pub listen: Vec<Multiaddr>,
+    /// Addresses of the peers to fetch blocks from. They have to end in `/p2p/<peer id>`.
+    pub peers: Vec<Multiaddr>,
+}
+
+/// A running bitswap node.
+///
+/// The node stops when all clones of it are dropped.
+#[derive(Clone, Debug)]
+pub struct BitswapNode {
+    peer_id: PeerId,
+    tx: mpsc::UnboundedSender<Fetch>,
+}
+
+struct Fetch {
+    cid: Cid,
+    reply: oneshot::Sender<anyhow::Result<()>>,
+}
+
+impl BitswapNode {
+    /// Start a node on the store at `db_path`.
+    pub async fn spawn(db_path: impl AsRef<Path>, options: BitswapOptions) -> anyhow::Result<Self> {
+        let mut store = SqliteBlockStore::new(&db_path)?;
+        let keypair = load_or_create_keypair(&mut store).await?;
+        let peer_id = keypair.public().to_peer_id();
+        // Bitswap accesses the store synchronously, so it gets its own connection.
+        let db = DbBlockStore::<DefaultParams>::open(&db_path, Config::default())?;
+        let bitswap = Bitswap::new(BitswapConfig::new(), BitswapStorage(Mutex::new(db)));
+        let transport = tcp::tokio::Transport::new(tcp::Config::default().nodelay(true))
+            .upgrade(upgrade::Version::V1)
+            .authenticate(noise::Config::new(&keypair)?)
+            .multiplex(yamux::Config::default())
+            .boxed();
+        let mut swarm = SwarmBuilder::with_tokio_executor(transport, bitswap, peer_id).build();
+        for addr in options.listen {
+            swarm.listen_on(addr)?;
+        }
+        let mut peers = vec![];
+        for addr in options.peers {
+            let Some(Protocol::P2p(hash)) = addr.iter().last() else {
+                anyhow::bail!("Peer address {addr} does not end in /p2p/<peer id>");
+            };
+            let peer = PeerId::from_multihash(hash)
+                .map_err(|_| anyhow::anyhow!("Invalid peer ID in {addr}"))?;
+            swarm.behaviour_mut().add_address(&peer, addr.clone());
+            if let Err(err) = swarm.dial(addr.clone()) {
+                warn!("failed to dial {addr}: {err}");
+            }
+            peers.push(peer);
+        }
+
+        let (tx, mut rx) = mpsc::unbounded_channel::<Fetch>();
+        tokio::spawn(async move {
+            let mut pending: HashMap<QueryId, oneshot::Sender<anyhow::Result<()>>> = HashMap::new();
+            loop {
+                tokio::select! {
+                    fetch = rx.recv() => {
+                        let Some(Fetch { cid, reply }) = fetch else {
+                            break;
+                        };
+                        if peers.is_empty() {
+                            let _ = reply.send(Err(anyhow::anyhow!("No peers configured")));
+                            continue;
+                        }
+                        let id = swarm.behaviour_mut().get(cid, peers.clone().into_iter());
+                        pending.insert(id, reply);
+                    }
+                    event = swarm.select_next_some() => match event {
+                        SwarmEvent::NewListenAddr { address, .. } => {
+                            info!("listening on {address}/p2p/{peer_id}");
+                        }
+                        SwarmEvent::Behaviour(BitswapEvent::Complete(id, result)) => {
+                            if let Some(reply) = pending.remove(&id) {
+                                let result = result.map_err(|err| anyhow::anyhow!("{err}"));
+                                let _ = reply.send(result);
+                            }
+                        }
+                        SwarmEvent::ConnectionEstablished { peer_id, .. } => {
+                            debug!("connected to {peer_id}");
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        });
+        Ok(Self { peer_id, tx })
+    }
+
+    pub fn peer_id(&self) -> PeerId {
+        self.peer_id
+    }
+}
+
+#[async_trait]
+impl BlockResolver for BitswapNode {
+    async fn fetch(&self, cid: &Cid) -> anyhow::Result<()> {
+        debug!("fetch {cid} over bitswap");
+        let (reply, rx) = oneshot::channel();
+        self.tx
+            .send(Fetch { cid: *cid, reply })
+            .map_err(|_| anyhow::anyhow!("Bitswap node stopped"))?;
+        rx.await
+            .map_err(|_| anyhow::anyhow!("Bitswap node stopped"))?
+            .map_err(|err| anyhow::anyhow!("Failed to fetch block {cid}: {err}"))
+    }
+}
+
+async fn load_or_create_keypair(store: &mut SqliteBlockStore) -> anyhow::Result<Keypair> {
+    if let Some(bytes) = store.get_from_alias(KEYPAIR_ALIAS).await? {
+        return Ok(Keypair::from_protobuf_encoding(&bytes)?);
+    }
+    let keypair = Keypair::generate_ed25519();
+    let bytes = keypair.to_protobuf_encoding()?;
+    store
+        .put_with_alias(KEYPAIR_ALIAS, bytes, IpldCodec::Raw)
+        .await?;
+    debug!("created bitswap key");
+    Ok(keypair)
+}
+
+struct BitswapStorage(Mutex<DbBlockStore<DefaultParams>>);
+
+impl BitswapStore for BitswapStorage {
+    type Params = DefaultParams;
+
+    fn contains(&mut self, cid: &Cid) -> anyhow::Result<bool> {
+        Ok(self.0.get_mut().unwrap().has_block(cid)?)
+    }
+
+    fn get(&mut self, cid: &Cid) -> anyhow::Result<Option<Vec<u8>>> {
+        Ok(self.0.get_mut().unwrap().get_block(cid)?)
+    }
+
+    fn insert(&mut self, block: &Block<Self::Params>) -> anyhow::Result<()> {
+        self.0.get_mut().unwrap().put_block(block.clone(), None)?;
+        Ok(())
+    }
+
+    fn missing_blocks(&mut self, cid: &Cid) -> anyhow::Result<Vec<Cid>> {
+        Ok(self.0.get_mut().unwrap().get_missing_blocks(cid)?)
+    }
+}
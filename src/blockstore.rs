@@ -1,12 +1,13 @@
 use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::path::Path;
 use std::sync::Arc;
 
 use async_trait::async_trait;
 use ipfs_sqlite_block_store::{BlockStore as DbBlockStore, Config};
 use libipld::cid::Version;
-use libipld::store::StoreParams;
 use libipld::{Block, Cid, IpldCodec};
+use metrics::{counter, gauge};
 use multihash::Code;
 use multihash::MultihashDigest;
 use serde::de::DeserializeOwned;
@@ -14,23 +15,122 @@ use serde::Serialize;
 use tokio::sync::Mutex;
 use wnfs_common::BlockStore;
 
-/// Default store parameters.
-#[derive(Clone, Debug, Default)]
-pub struct DefaultParams;
+use crate::store::{DefaultParams, Store};
 
-impl StoreParams for DefaultParams {
-    const MAX_BLOCK_SIZE: usize = usize::MAX;
-    type Codecs = libipld::IpldCodec;
-    type Hashes = libipld::multihash::Code;
+/// Blocks and bytes affected by a garbage collection run.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct GcStats {
+    pub blocks: u64,
+    pub bytes: u64,
+}
+
+/// Source for blocks that are not in the local store, e.g. other nodes.
+#[async_trait]
+pub trait BlockResolver: Send + Sync {
+    /// Fetch a block and put it into the local store.
+    async fn fetch(&self, cid: &Cid) -> anyhow::Result<()>;
+}
+
+/// Default memory budget of the cache of recently read blocks, in bytes.
+pub const DEFAULT_BLOCK_CACHE_SIZE: u64 = 32 * 1024 * 1024;
+
+/// Recently read blocks by CID, evicting the least recently used beyond a budget in bytes.
+///
+/// Blocks never change for a CID, so entries only have to be dropped when blocks are deleted.
+#[derive(Debug, Default)]
+struct BlockCache {
+    budget: u64,
+    bytes: u64,
+    blocks: HashMap<Cid, (Vec<u8>, u64)>,
+    lru: BTreeMap<u64, Cid>,
+    clock: u64,
+}
+
+impl BlockCache {
+    fn new(budget: u64) -> Self {
+        Self {
+            budget,
+            ..Default::default()
+        }
+    }
+
+    fn get(&mut self, cid: &Cid) -> Option<Vec<u8>> {
+        let (block, used) = self.blocks.get_mut(cid)?;
+        self.lru.remove(used);
+        self.clock += 1;
+        *used = self.clock;
+        self.lru.insert(self.clock, *cid);
+        Some(block.clone())
+    }
+
+    fn insert(&mut self, cid: Cid, block: Vec<u8>) {
+        let len = block.len() as u64;
+        if len > self.budget || self.blocks.contains_key(&cid) {
+            return;
+        }
+        while self.bytes + len > self.budget {
+            let Some((_, evicted)) = self.lru.pop_first() else {
+                break;
+            };
+            if let Some((block, _)) = self.blocks.remove(&evicted) {
+                self.bytes -= block.len() as u64;
+            }
+        }
+        self.clock += 1;
+        self.lru.insert(self.clock, cid);
+        self.blocks.insert(cid, (block, self.clock));
+        self.bytes += len;
+        gauge!("wnfs_store_cache_bytes", self.bytes as f64);
+    }
+
+    fn clear(&mut self) {
+        self.blocks.clear();
+        self.lru.clear();
+        self.bytes = 0;
+        gauge!("wnfs_store_cache_bytes", 0.0);
+    }
 }
 
 #[derive(Clone)]
-pub struct SqliteBlockStore(pub Arc<Mutex<DbBlockStore<DefaultParams>>>);
+pub struct SqliteBlockStore(
+    pub Arc<Mutex<DbBlockStore<DefaultParams>>>,
+    Option<Arc<dyn BlockResolver>>,
+    Arc<std::sync::Mutex<BlockCache>>,
+);
 
 impl SqliteBlockStore {
     pub fn new(path: impl AsRef<Path>) -> anyhow::Result<Self> {
         let store = DbBlockStore::<DefaultParams>::open(path, Config::default())?;
-        Ok(Self(Arc::new(Mutex::new(store))))
+        Ok(Self::from_db(store))
+    }
+
+    /// A store that only lives in memory, e.g. for tests.
+    pub fn memory() -> anyhow::Result<Self> {
+        let store = DbBlockStore::<DefaultParams>::memory(Config::default())?;
+        Ok(Self::from_db(store))
+    }
+
+    fn from_db(store: DbBlockStore<DefaultParams>) -> Self {
+        let cache = BlockCache::new(DEFAULT_BLOCK_CACHE_SIZE);
+        Self(
+            Arc::new(Mutex::new(store)),
+            None,
+            Arc::new(std::sync::Mutex::new(cache)),
+        )
+    }
+
+    /// Fetch blocks that are missing locally from a resolver when they are read.
+    pub fn with_resolver(mut self, resolver: Arc<dyn BlockResolver>) -> Self {
+        self.1 = Some(resolver);
+        self
+    }
+
+    /// Keep up to `size` bytes of recently read blocks in memory, 0 to disable the cache.
+    ///
+    /// The default is [`DEFAULT_BLOCK_CACHE_SIZE`].
+    pub fn with_cache_size(mut self, size: u64) -> Self {
+        self.2 = Arc::new(std::sync::Mutex::new(BlockCache::new(size)));
+        self
     }
 
     pub async fn put_with_alias(
@@ -56,6 +156,12 @@ impl SqliteBlockStore {
         Ok(cid)
     }
 
+    /// Point an alias to a CID, or remove it if `cid` is `None`.
+    pub async fn alias(&self, name: &str, cid: Option<&Cid>) -> anyhow::Result<()> {
+        self.0.lock().await.alias(name.as_bytes(), cid)?;
+        Ok(())
+    }
+
     pub async fn get_from_alias<'b>(&self, name: &str) -> anyhow::Result<Option<Vec<u8>>> {
         let mut store = self.0.lock().await;
         match store.resolve(name.as_bytes())? {
@@ -80,19 +186,143 @@ impl SqliteBlockStore {
         let maybe_cid = store.resolve(name.as_bytes())?;
         Ok(maybe_cid)
     }
+
+    /// List all aliases starting with `prefix`, with the prefix stripped.
+    pub async fn aliases_with_prefix(&self, prefix: &str) -> anyhow::Result<Vec<(String, Cid)>> {
+        let mut store = self.0.lock().await;
+        let aliases: Vec<(Vec<u8>, Cid)> = store.aliases()?;
+        let aliases = aliases
+            .into_iter()
+            .filter_map(|(name, cid)| {
+                let name = String::from_utf8(name).ok()?;
+                let name = name.strip_prefix(prefix)?.to_string();
+                Some((name, cid))
+            })
+            .collect();
+        Ok(aliases)
+    }
+
+    /// List the CIDs of all blocks in the store.
+    pub async fn block_cids(&self) -> anyhow::Result<Vec<Cid>> {
+        let mut store = self.0.lock().await;
+        Ok(store.get_block_cids()?)
+    }
+
+    /// List the CIDs of `root` and of all blocks reachable from it.
+    pub async fn dag_cids(&self, root: &Cid) -> anyhow::Result<Vec<Cid>> {
+        let mut store = self.0.lock().await;
+        let mut cids: HashSet<Cid> = store.get_descendants(root)?;
+        cids.insert(*root);
+        Ok(cids.into_iter().collect())
+    }
+
+    /// Sum of the sizes of all blocks in the store that are reachable from `root`.
+    pub async fn dag_size(&self, root: &Cid) -> anyhow::Result<u64> {
+        let cids = self.dag_cids(root).await?;
+        let mut store = self.0.lock().await;
+        let mut size = 0;
+        for cid in cids {
+            if let Some(block) = store.get_block(&cid)? {
+                size += block.len() as u64;
+            }
+        }
+        Ok(size)
+    }
+
+    /// Get a block if it is in the store.
+    ///
+    /// Unlike [`BlockStore::get_block`], this can be called from `Send` futures.
+    pub async fn get_block_if_exists(&self, cid: &Cid) -> anyhow::Result<Option<Vec<u8>>> {
+        let mut store = self.0.lock().await;
+        Ok(store.get_block(cid)?)
+    }
+
+    /// Put a block that was received from elsewhere, verifying that it matches its CID.
+    pub async fn put_block_with_cid(&self, cid: &Cid, bytes: Vec<u8>) -> anyhow::Result<()> {
+        let block = Block::<DefaultParams>::new(*cid, bytes)?;
+        let mut store = self.0.lock().await;
+        store.put_block(block, None)?;
+        Ok(())
+    }
+
+    /// List blocks that are referenced from the DAG below `root` but not present in the store.
+    pub async fn missing_blocks(&self, root: &Cid) -> anyhow::Result<Vec<Cid>> {
+        let mut store = self.0.lock().await;
+        let missing = store.get_missing_blocks(root)?;
+        Ok(missing)
+    }
+
+    /// Remove all blocks that are not reachable from any alias.
+    ///
+    /// With `dry_run` set, nothing is deleted and the returned stats are the blocks that
+    /// would be removed.
+    pub async fn gc(&self, dry_run: bool) -> anyhow::Result<GcStats> {
+        let mut store = self.0.lock().await;
+        if dry_run {
+            let aliases: Vec<(Vec<u8>, Cid)> = store.aliases()?;
+            let mut live = HashSet::new();
+            for (_name, cid) in aliases {
+                let descendants: HashSet<Cid> = store.get_descendants(&cid)?;
+                live.insert(cid);
+                live.extend(descendants);
+            }
+            let cids: Vec<Cid> = store.get_block_cids()?;
+            let mut stats = GcStats::default();
+            for cid in cids.iter().filter(|cid| !live.contains(cid)) {
+                if let Some(block) = store.get_block(cid)? {
+                    stats.blocks += 1;
+                    stats.bytes += block.len() as u64;
+                }
+            }
+            Ok(stats)
+        } else {
+            let before = store.get_store_stats()?;
+            self.2.lock().unwrap().clear();
+            store.gc()?;
+            let after = store.get_store_stats()?;
+            Ok(GcStats {
+                blocks: before.count().saturating_sub(after.count()),
+                bytes: before.size().saturating_sub(after.size()),
+            })
+        }
+    }
 }
 
 #[async_trait(?Send)]
 impl wnfs_common::BlockStore for SqliteBlockStore {
+    #[tracing::instrument(level = "trace", skip(self))]
     async fn get_block<'a>(&'a self, cid: &Cid) -> anyhow::Result<Cow<'a, Vec<u8>>> {
-        let mut store = self.0.lock().await;
-        let block = store
-            .get_block(&cid)?
+        if let Some(block) = self.2.lock().unwrap().get(cid) {
+            counter!("wnfs_store_cache_lookups_total", 1, "cached" => "true");
+            return Ok(Cow::Owned(block));
+        }
+        counter!("wnfs_store_cache_lookups_total", 1, "cached" => "false");
+        if let Some(block) = self.0.lock().await.get_block(cid)? {
+            counter!("wnfs_store_reads_total", 1);
+            counter!("wnfs_store_read_bytes_total", block.len() as u64);
+            self.2.lock().unwrap().insert(*cid, block.clone());
+            return Ok(Cow::Owned(block));
+        }
+        let Some(resolver) = &self.1 else {
+            counter!("wnfs_store_misses_total", 1, "resolved" => "false");
+            anyhow::bail!("Block not found");
+        };
+        resolver.fetch(cid).await?;
+        counter!("wnfs_store_misses_total", 1, "resolved" => "true");
+        let block = self
+            .0
+            .lock()
+            .await
+            .get_block(cid)?
             .ok_or_else(|| anyhow::anyhow!("Block not found"))?;
+        self.2.lock().unwrap().insert(*cid, block.clone());
         Ok(Cow::Owned(block))
     }
 
+    #[tracing::instrument(level = "trace", skip(self, bytes), fields(len = bytes.len()))]
     async fn put_block(&mut self, bytes: Vec<u8>, codec: IpldCodec) -> anyhow::Result<Cid> {
+        counter!("wnfs_store_writes_total", 1);
+        counter!("wnfs_store_written_bytes_total", bytes.len() as u64);
         let hash = Code::Blake3_256.digest(&bytes);
         let cid = Cid::new(Version::V1, codec.into(), hash)?;
         let block = Block::new(cid, bytes)?;
@@ -102,3 +332,26 @@ impl wnfs_common::BlockStore for SqliteBlockStore {
         Ok(cid)
     }
 }
+
+#[async_trait(?Send)]
+impl Store for SqliteBlockStore {
+    async fn resolve_alias(&self, name: &str) -> anyhow::Result<Option<Cid>> {
+        SqliteBlockStore::resolve_alias(self, name).await
+    }
+
+    async fn alias(&self, name: &str, cid: Option<&Cid>) -> anyhow::Result<()> {
+        SqliteBlockStore::alias(self, name, cid).await
+    }
+
+    async fn aliases_with_prefix(&self, prefix: &str) -> anyhow::Result<Vec<(String, Cid)>> {
+        SqliteBlockStore::aliases_with_prefix(self, prefix).await
+    }
+
+    async fn dag_size(&self, root: &Cid) -> anyhow::Result<u64> {
+        SqliteBlockStore::dag_size(self, root).await
+    }
+
+    async fn missing_blocks(&self, root: &Cid) -> anyhow::Result<Vec<Cid>> {
+        SqliteBlockStore::missing_blocks(self, root).await
+    }
+}
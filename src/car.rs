@@ -0,0 +1,182 @@
+//! Export and import of filesystems as CAR (content addressable archive) files.
+//!
+//! Exports are CARv1 files whose first root is the root record of the filesystem. An
+//! incremental export only contains the blocks that are not reachable from an earlier root
+//! record, which is recorded as the second root so that imports can check that the delta
+//! applies on top of the local state.
+
+use std::collections::HashSet;
+use std::io::Cursor;
+
+use libipld::Cid;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use wnfs_common::BlockStore;
+
+use crate::fs::{private_root_alias, snapshot_prefix};
+use crate::SqliteBlockStore;
+
+/// Blocks and bytes written by [`export`] or read by [`import`].
+#[derive(Debug, Default, Clone, Copy)]
+pub struct CarStats {
+    pub blocks: u64,
+    pub bytes: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct CarHeader {
+    roots: Vec<Cid>,
+    version: u64,
+}
+
+/// Resolve the base of an incremental export: a root record CID or a snapshot name.
+pub async fn resolve_since(
+    store: &SqliteBlockStore,
+    name: &str,
+    since: &str,
+) -> anyhow::Result<Cid> {
+    let alias = format!("{}{since}", snapshot_prefix(name));
+    match store.resolve_alias(&alias).await? {
+        Some(cid) => Ok(cid),
+        None => Cid::try_from(since)
+            .map_err(|_| anyhow::anyhow!("{since} is neither a snapshot nor a root CID")),
+    }
+}
+
+/// Write the blocks of a named filesystem to a CAR file.
+///
+/// With `since`, only blocks that are not reachable from that root record are written.
+pub async fn export(
+    store: &SqliteBlockStore,
+    name: &str,
+    since: Option<&Cid>,
+    mut writer: impl AsyncWrite + Unpin,
+) -> anyhow::Result<CarStats> {
+    let root = store
+        .resolve_alias(&private_root_alias(name))
+        .await?
+        .ok_or_else(|| anyhow::anyhow!("Filesystem {name} does not exist"))?;
+    let mut roots = vec![root];
+    let mut skip = HashSet::new();
+    if let Some(since) = since {
+        roots.push(*since);
+        skip.extend(store.dag_cids(since).await?);
+    }
+    let header = serde_ipld_dagcbor::to_vec(&CarHeader { roots, version: 1 })?;
+    write_section(&mut writer, &[&header]).await?;
+
+    let mut stats = CarStats::default();
+    for cid in store.dag_cids(&root).await? {
+        if skip.contains(&cid) {
+            continue;
+        }
+        let bytes = store.get_block(&cid).await?;
+        write_section(&mut writer, &[&cid.to_bytes(), &bytes]).await?;
+        stats.blocks += 1;
+        stats.bytes += bytes.len() as u64;
+    }
+    writer.flush().await?;
+    Ok(stats)
+}
+
+/// Read the blocks of a CAR file into the store and set the root of a named filesystem.
+///
+/// An existing filesystem is only replaced if the CAR file is an incremental export on top of
+/// its current root, or if `force` is set.
+pub async fn import(
+    store: &SqliteBlockStore,
+    name: &str,
+    mut reader: impl AsyncRead + Unpin,
+    force: bool,
+) -> anyhow::Result<CarStats> {
+    let header = read_section(&mut reader)
+        .await?
+        .ok_or_else(|| anyhow::anyhow!("Empty CAR file"))?;
+    let header: CarHeader = serde_ipld_dagcbor::from_slice(&header)?;
+    if header.version != 1 {
+        anyhow::bail!("Unsupported CAR version {}", header.version);
+    }
+    let (root, base) = match header.roots.as_slice() {
+        [root] => (*root, None),
+        [root, base] => (*root, Some(*base)),
+        _ => anyhow::bail!("CAR file is not a filesystem export"),
+    };
+    let alias = private_root_alias(name);
+    match store.resolve_alias(&alias).await? {
+        Some(local) if Some(local) == base || local == root || force => {}
+        Some(_) => anyhow::bail!(
+            "Local filesystem {name} differs from the base of the CAR file, use --force to \
+            replace it"
+        ),
+        None => {}
+    }
+
+    let mut stats = CarStats::default();
+    while let Some(section) = read_section(&mut reader).await? {
+        let mut cursor = Cursor::new(section.as_slice());
+        let cid = Cid::read_bytes(&mut cursor)?;
+        let bytes = section[cursor.position() as usize..].to_vec();
+        stats.blocks += 1;
+        stats.bytes += bytes.len() as u64;
+        store.put_block_with_cid(&cid, bytes).await?;
+    }
+    let missing = store.missing_blocks(&root).await?;
+    if !missing.is_empty() {
+        anyhow::bail!(
+            "CAR file is incomplete, {} blocks are missing (import the base first)",
+            missing.len()
+        );
+    }
+    store.alias(&alias, Some(&root)).await?;
+    Ok(stats)
+}
+
+async fn write_section(
+    writer: &mut (impl AsyncWrite + Unpin),
+    parts: &[&[u8]],
+) -> anyhow::Result<()> {
+    let len = parts.iter().map(|part| part.len()).sum::<usize>();
+    let mut len = len as u64;
+    // Unsigned LEB128 varint.
+    let mut prefix = vec![];
+    loop {
+        let byte = (len & 0x7f) as u8;
+        len >>= 7;
+        if len == 0 {
+            prefix.push(byte);
+            break;
+        }
+        prefix.push(byte | 0x80);
+    }
+    writer.write_all(&prefix).await?;
+    for part in parts {
+        writer.write_all(part).await?;
+    }
+    Ok(())
+}
+
+/// Read a length-prefixed section, or `None` at the end of the file.
+async fn read_section(reader: &mut (impl AsyncRead + Unpin)) -> anyhow::Result<Option<Vec<u8>>> {
+    let mut len = 0u64;
+    let mut shift = 0;
+    loop {
+        let byte = match reader.read_u8().await {
+            Ok(byte) => byte,
+            Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof && shift == 0 => {
+                return Ok(None)
+            }
+            Err(err) => return Err(err.into()),
+        };
+        len |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            break;
+        }
+        shift += 7;
+        if shift > 63 {
+            anyhow::bail!("Invalid section length in CAR file");
+        }
+    }
+    let mut section = vec![0; len as usize];
+    reader.read_exact(&mut section).await?;
+    Ok(Some(section))
+}
@@ -0,0 +1,180 @@
+//! User configuration file.
+//!
+//! The configuration is read from `$XDG_CONFIG_HOME/wnfs-fuse/config.toml` (usually
+//! `~/.config/wnfs-fuse/config.toml`). All settings are optional and command line arguments
+//! take precedence.
+//!
+//! ```toml
+//! db_path = "/home/me/.local/share/wnfs/blocks.db"
+//! fs_name = "home"
+//! peers = ["/ip4/192.168.1.10/tcp/4001/p2p/12D3KooW..."]
+//!
+//! [mounts."/home/me/private"]
+//! fs_name = "private"
+//! allow_other = true
+//!
+//! [mounts."/home/me/private".snapshots]
+//! hourly = 24
+//! daily = 7
+//!
+//! [remotes]
+//! backup = "s3://my-bucket/wnfs"
+//!
+//! [pinning.web3]
+//! service = "web3.storage"
+//! token = "eyJhbGciOi..."
+//!
+//! [owners]
+//! shared = ["did:key:z6Mk..."]
+//! ```
+//!
+//! `authorized_nodes` lists the node ids that may sync with `daemon --quic`:
+//!
+//! ```toml
+//! authorized_nodes = ["k5s2ovr3..."]
+//! ```
+//!
+//! `hooks` run commands on written or read files, see [`crate::hooks`].
+//!
+//! `max_read_size` limits the size of files that are read into memory as a whole, e.g. by
+//! servers without range requests, in bytes (default: 1 GiB).
+//!
+//! `block_cache` is the memory budget for recently read blocks of the store in bytes, shared by
+//! all filesystems of a process (default: 32 MiB, 0 disables it).
+//!
+//! `cache` sets the memory budgets of the caches of mounts in bytes, globally or per mountpoint
+//! (see [`crate::fuse::CacheBudget`]), e.g. for a small device:
+//!
+//! ```toml
+//! [cache]
+//! total = 33554432
+//! nodes = 16777216
+//! ```
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use libp2p::Multiaddr;
+use serde::Deserialize;
+
+use crate::fuse::CacheBudget;
+use crate::hooks::HookConfig;
+use crate::pin::PinConfig;
+use crate::schedule::SnapshotSchedule;
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Default path to the SQLite block store.
+    pub db_path: Option<String>,
+    /// Default filesystem name.
+    pub fs_name: Option<String>,
+    /// Settings per mountpoint.
+    #[serde(default)]
+    pub mounts: BTreeMap<PathBuf, MountConfig>,
+    /// Named remotes for `sync`, mapping a name to a remote URL.
+    #[serde(default)]
+    pub remotes: BTreeMap<String, String>,
+    /// Pinning services for `remote pin`, by name.
+    #[serde(default)]
+    pub pinning: BTreeMap<String, PinConfig>,
+    /// Bitswap peers to fetch missing blocks from, as multiaddrs ending in `/p2p/<peer id>`.
+    #[serde(default)]
+    pub peers: Vec<Multiaddr>,
+    /// Automatic snapshots taken by `mount`, unless set for the mountpoint.
+    pub snapshots: Option<SnapshotSchedule>,
+    /// DIDs whose UCANs authorize writes to a filesystem, by filesystem name. Revisions of
+    /// these filesystems are checked by `sync with`.
+    #[serde(default)]
+    pub owners: BTreeMap<String, Vec<String>>,
+    /// Node ids of the devices that may connect to `daemon --quic`, as printed by
+    /// `sync node-id`.
+    #[serde(default)]
+    pub authorized_nodes: Vec<String>,
+    /// Commands that get the content of written or read files.
+    #[serde(default)]
+    pub hooks: Vec<HookConfig>,
+    /// Largest file that is read into memory as a whole, in bytes.
+    pub max_read_size: Option<u64>,
+    /// Bytes of recently read blocks that are kept in memory.
+    pub block_cache: Option<u64>,
+    /// Memory budgets of the caches of mounts, unless set for the mountpoint.
+    pub cache: Option<CacheBudget>,
+}
+
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MountConfig {
+    pub db_path: Option<String>,
+    pub fs_name: Option<String>,
+    #[serde(default)]
+    pub read_only: bool,
+    /// Allow other users to access the mount (requires `user_allow_other` in
+    /// `/etc/fuse.conf`).
+    #[serde(default)]
+    pub allow_other: bool,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    /// Permission bits to clear, e.g. `0o027`.
+    pub umask: Option<u16>,
+    /// Seconds after which an operation fails with an I/O error (default 30).
+    pub timeout: Option<u64>,
+    /// Memory budgets of the caches.
+    pub cache: Option<CacheBudget>,
+    /// Automatic snapshots of the mounted filesystem.
+    pub snapshots: Option<SnapshotSchedule>,
+}
+
+impl Config {
+    /// Default location of the configuration file.
+    pub fn path() -> Option<PathBuf> {
+        let dir = match std::env::var_os("XDG_CONFIG_HOME") {
+            Some(dir) => PathBuf::from(dir),
+            None => PathBuf::from(std::env::var_os("HOME")?).join(".config"),
+        };
+        Some(dir.join("wnfs-fuse").join("config.toml"))
+    }
+
+    /// Load the configuration file, or an empty configuration if there is none.
+    pub fn load() -> anyhow::Result<Self> {
+        match Self::path() {
+            Some(path) => Self::load_from(&path),
+            None => Ok(Self::default()),
+        }
+    }
+
+    pub fn load_from(path: &Path) -> anyhow::Result<Self> {
+        match std::fs::read_to_string(path) {
+            Ok(content) => toml::from_str(&content)
+                .map_err(|err| anyhow::anyhow!("Invalid config file {path:?}: {err}")),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Settings for a mountpoint, matched after resolving symlinks and relative paths.
+    pub fn mount(&self, mountpoint: impl AsRef<Path>) -> MountConfig {
+        let mountpoint = canonicalize(mountpoint.as_ref());
+        self.mounts
+            .iter()
+            .find(|(path, _)| canonicalize(path) == mountpoint)
+            .map(|(_, config)| config.clone())
+            .unwrap_or_default()
+    }
+
+    /// Settings of a pinning service by name.
+    pub fn pinning(&self, service: &str) -> anyhow::Result<&PinConfig> {
+        self.pinning
+            .get(service)
+            .ok_or_else(|| anyhow::anyhow!("Pinning service {service} is not configured"))
+    }
+
+    /// Resolve a remote name to its URL. Anything else is returned as is.
+    pub fn remote<'a>(&'a self, remote: &'a str) -> &'a str {
+        self.remotes.get(remote).map(String::as_str).unwrap_or(remote)
+    }
+}
+
+fn canonicalize(path: &Path) -> PathBuf {
+    std::fs::canonicalize(path).unwrap_or_else(|_| path.to_owned())
+}
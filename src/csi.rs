@@ -0,0 +1,245 @@
+//! Kubernetes CSI node plugin, so that pods can use filesystems as volumes.
+//!
+//! `csi` serves the CSI identity and node services (see `proto/csi.proto`) on a unix socket,
+//! which the kubelet finds through the node-driver-registrar sidecar. There is no controller
+//! service: volumes are provisioned statically, as persistent volumes whose volume handle is
+//! the name of a filesystem in the block store of the node:
+//!
+//! ```yaml
+//! csi:
+//!   driver: wnfs-fuse.csi
+//!   volumeHandle: photos
+//!   volumeAttributes:
+//!     dbPath: /var/lib/wnfs/blocks.db  # default: --db-path of the plugin
+//!     fsName: photos                   # default: the volume handle
+//!   nodePublishSecretRef:              # for protected filesystems
+//!     name: photos-passphrase          # with the key `passphrase`
+//!     namespace: default
+//! ```
+//!
+//! Publishing a volume runs a background `mount` of the filesystem at the target path, like
+//! `mount --daemon`, which holds the writer lock while it is mounted writable.
+
+use std::ffi::OsString;
+use std::path::Path;
+
+use tokio::net::UnixListener;
+use tonic::{Request, Response, Status};
+use tracing::{debug, info};
+
+use crate::daemon;
+use pb::identity_server::{Identity, IdentityServer};
+use pb::node_server::{Node, NodeServer};
+use pb::volume_capability::access_mode::Mode;
+use pb::volume_capability::AccessType;
+
+/// Types generated from `proto/csi.proto`.
+pub mod pb {
+    tonic::include_proto!("csi.v1");
+}
+
+/// Default name under which the plugin registers.
+pub const DRIVER_NAME: &str = "wnfs-fuse.csi";
+
+/// Options for the CSI plugin.
+#[derive(Debug, Clone)]
+pub struct CsiConfig {
+    /// Name of the driver, as used in the `driver` field of volumes.
+    pub driver_name: String,
+    /// Name of the Kubernetes node the plugin runs on.
+    pub node_id: String,
+    /// Block store of volumes without a `dbPath` attribute.
+    pub db_path: Option<String>,
+}
+
+/// Serve the CSI services on a unix socket (`unix:///path` or a plain path) until the server
+/// fails.
+pub async fn serve(endpoint: &str, config: CsiConfig) -> anyhow::Result<()> {
+    let path = endpoint.strip_prefix("unix://").unwrap_or(endpoint);
+    if let Some(dir) = Path::new(path).parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    // Left behind by a plugin that did not exit cleanly.
+    let _ = std::fs::remove_file(path);
+    let listener = UnixListener::bind(path)?;
+    info!("serving CSI plugin {} on {path}", config.driver_name);
+    let incoming = futures::stream::unfold(listener, |listener| async move {
+        let accepted = listener.accept().await.map(|(stream, _addr)| stream);
+        Some((accepted, listener))
+    });
+    let plugin = CsiPlugin { config };
+    tonic::transport::Server::builder()
+        .add_service(IdentityServer::new(plugin.clone()))
+        .add_service(NodeServer::new(plugin))
+        .serve_with_incoming(incoming)
+        .await?;
+    Ok(())
+}
+
+/// Implementation of the identity and node services.
+#[derive(Debug, Clone)]
+pub struct CsiPlugin {
+    config: CsiConfig,
+}
+
+#[tonic::async_trait]
+impl Identity for CsiPlugin {
+    async fn get_plugin_info(
+        &self,
+        _request: Request<pb::GetPluginInfoRequest>,
+    ) -> Result<Response<pb::GetPluginInfoResponse>, Status> {
+        Ok(Response::new(pb::GetPluginInfoResponse {
+            name: self.config.driver_name.clone(),
+            vendor_version: env!("CARGO_PKG_VERSION").to_string(),
+            manifest: Default::default(),
+        }))
+    }
+
+    async fn get_plugin_capabilities(
+        &self,
+        _request: Request<pb::GetPluginCapabilitiesRequest>,
+    ) -> Result<Response<pb::GetPluginCapabilitiesResponse>, Status> {
+        // Only the node service, without a controller.
+        Ok(Response::new(pb::GetPluginCapabilitiesResponse {
+            capabilities: vec![],
+        }))
+    }
+
+    async fn probe(
+        &self,
+        _request: Request<pb::ProbeRequest>,
+    ) -> Result<Response<pb::ProbeResponse>, Status> {
+        Ok(Response::new(pb::ProbeResponse {
+            ready: Some(pb::BoolValue { value: true }),
+        }))
+    }
+}
+
+#[tonic::async_trait]
+impl Node for CsiPlugin {
+    async fn node_publish_volume(
+        &self,
+        request: Request<pb::NodePublishVolumeRequest>,
+    ) -> Result<Response<pb::NodePublishVolumeResponse>, Status> {
+        let request = request.into_inner();
+        if request.volume_id.is_empty() || request.target_path.is_empty() {
+            return Err(Status::invalid_argument("Missing volume id or target path"));
+        }
+        let capability = request
+            .volume_capability
+            .ok_or_else(|| Status::invalid_argument("Missing volume capability"))?;
+        let mut read_only = request.readonly;
+        let mut gid = None;
+        match capability.access_type {
+            Some(AccessType::Mount(mount)) => {
+                read_only |= mount.mount_flags.iter().any(|flag| flag == "ro");
+                if !mount.volume_mount_group.is_empty() {
+                    gid = Some(mount.volume_mount_group);
+                }
+            }
+            Some(AccessType::Block(_)) => {
+                return Err(Status::invalid_argument("Block volumes are not supported"))
+            }
+            None => return Err(Status::invalid_argument("Missing access type")),
+        }
+        if let Some(access_mode) = capability.access_mode {
+            read_only |= matches!(
+                Mode::from_i32(access_mode.mode),
+                Some(Mode::SingleNodeReaderOnly | Mode::MultiNodeReaderOnly)
+            );
+        }
+
+        let db_path = request
+            .volume_context
+            .get("dbPath")
+            .or(self.config.db_path.as_ref())
+            .ok_or_else(|| Status::invalid_argument("Missing dbPath volume attribute"))?;
+        let fs_name = request
+            .volume_context
+            .get("fsName")
+            .unwrap_or(&request.volume_id);
+        let mut args: Vec<OsString> = vec![
+            "--db-path".into(),
+            db_path.into(),
+            "--fs-name".into(),
+            fs_name.into(),
+            "mount".into(),
+            request.target_path.clone().into(),
+            // Pods run as other users than the plugin.
+            "--allow-other".into(),
+        ];
+        if read_only {
+            args.push("--read-only".into());
+        }
+        if let Some(gid) = gid {
+            args.extend(["--gid".into(), gid.into()]);
+        }
+        let passphrase = request.secrets.get("passphrase").cloned();
+        let target = request.target_path;
+        debug!("publish volume {} at {target}", request.volume_id);
+        tokio::task::spawn_blocking(move || {
+            if daemon::is_mounted(&target)? {
+                // Published before, e.g. by a retried request.
+                return Ok(());
+            }
+            std::fs::create_dir_all(&target)?;
+            let pid = daemon::spawn_with_logs(&target, args, passphrase.as_deref())?;
+            daemon::wait_until_mounted(&target, pid)
+        })
+        .await
+        .map_err(internal)?
+        .map_err(internal)?;
+        Ok(Response::new(pb::NodePublishVolumeResponse {}))
+    }
+
+    async fn node_unpublish_volume(
+        &self,
+        request: Request<pb::NodeUnpublishVolumeRequest>,
+    ) -> Result<Response<pb::NodeUnpublishVolumeResponse>, Status> {
+        let request = request.into_inner();
+        if request.target_path.is_empty() {
+            return Err(Status::invalid_argument("Missing target path"));
+        }
+        let target = request.target_path;
+        debug!("unpublish volume {} from {target}", request.volume_id);
+        tokio::task::spawn_blocking(move || -> anyhow::Result<()> {
+            if daemon::is_mounted(&target)? {
+                // Flushes and waits for the mount process to exit.
+                daemon::unmount(&target)?;
+            }
+            match std::fs::remove_dir(&target) {
+                Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err.into()),
+                _ => Ok(()),
+            }
+        })
+        .await
+        .map_err(internal)?
+        .map_err(internal)?;
+        Ok(Response::new(pb::NodeUnpublishVolumeResponse {}))
+    }
+
+    async fn node_get_capabilities(
+        &self,
+        _request: Request<pb::NodeGetCapabilitiesRequest>,
+    ) -> Result<Response<pb::NodeGetCapabilitiesResponse>, Status> {
+        // Volumes are mounted directly at the target path, without staging.
+        Ok(Response::new(pb::NodeGetCapabilitiesResponse {
+            capabilities: vec![],
+        }))
+    }
+
+    async fn node_get_info(
+        &self,
+        _request: Request<pb::NodeGetInfoRequest>,
+    ) -> Result<Response<pb::NodeGetInfoResponse>, Status> {
+        Ok(Response::new(pb::NodeGetInfoResponse {
+            node_id: self.config.node_id.clone(),
+            max_volumes_per_node: 0,
+            accessible_topology: None,
+        }))
+    }
+}
+
+fn internal(err: impl ToString) -> Status {
+    Status::internal(err.to_string())
+}
@@ -0,0 +1,151 @@
+//! Background mounts and their PID files.
+//!
+//! `mount --daemon` re-executes the binary in the background and records its PID in a file
+//! derived from the mountpoint, so that `umount` can find and signal the process later.
+
+use std::ffi::OsString;
+use std::io::Write;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::time::{Duration, Instant};
+
+use crate::fuse;
+
+const UNMOUNT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Opening a filesystem can take a while on large stores.
+const MOUNT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Path of the PID file for a background mount at `mountpoint`.
+pub fn pid_file(mountpoint: impl AsRef<Path>) -> anyhow::Result<PathBuf> {
+    let mountpoint = std::fs::canonicalize(mountpoint)?;
+    let dir = std::env::var_os("XDG_RUNTIME_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(std::env::temp_dir)
+        .join("wnfs-fuse");
+    let hash = blake3::hash(mountpoint.to_string_lossy().as_bytes());
+    Ok(dir.join(format!("{}.pid", &hash.to_hex()[..16])))
+}
+
+/// Run the current executable with `args` in the background and record its PID.
+///
+/// The passphrase, if any, is passed to the child on STDIN.
+pub fn spawn(
+    mountpoint: impl AsRef<Path>,
+    args: Vec<OsString>,
+    passphrase: Option<&str>,
+) -> anyhow::Result<u32> {
+    spawn_child(mountpoint, args, passphrase, Stdio::null())
+}
+
+/// Like [`spawn`], but the child logs to STDERR of this process, e.g. for services whose
+/// output is collected.
+pub fn spawn_with_logs(
+    mountpoint: impl AsRef<Path>,
+    args: Vec<OsString>,
+    passphrase: Option<&str>,
+) -> anyhow::Result<u32> {
+    spawn_child(mountpoint, args, passphrase, Stdio::inherit())
+}
+
+fn spawn_child(
+    mountpoint: impl AsRef<Path>,
+    args: Vec<OsString>,
+    passphrase: Option<&str>,
+    stderr: Stdio,
+) -> anyhow::Result<u32> {
+    let pid_file = pid_file(&mountpoint)?;
+    if let Some(pid) = read_pid(&pid_file)? {
+        anyhow::bail!("{:?} is already mounted by PID {pid}", mountpoint.as_ref());
+    }
+    let mut child = std::process::Command::new(std::env::current_exe()?)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(stderr)
+        .spawn()?;
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    if let Some(passphrase) = passphrase {
+        writeln!(stdin, "{passphrase}")?;
+    }
+    drop(stdin);
+    std::fs::create_dir_all(pid_file.parent().expect("pid file has a parent"))?;
+    std::fs::write(&pid_file, child.id().to_string())?;
+    Ok(child.id())
+}
+
+/// Remove the PID file of a mount, if any.
+pub fn remove_pid_file(mountpoint: impl AsRef<Path>) -> anyhow::Result<()> {
+    match std::fs::remove_file(pid_file(mountpoint)?) {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err.into()),
+        _ => Ok(()),
+    }
+}
+
+/// Unmount a filesystem.
+///
+/// If the mount is a background mount of this binary, its process is asked to unmount, which
+/// flushes the filesystem first, and this waits for it to exit. Otherwise the mountpoint is
+/// unmounted directly.
+pub fn unmount(mountpoint: impl AsRef<Path>) -> anyhow::Result<()> {
+    let pid_file = pid_file(&mountpoint)?;
+    let Some(pid) = read_pid(&pid_file)? else {
+        return fuse::unmount(mountpoint);
+    };
+    if unsafe { libc::kill(pid, libc::SIGTERM) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    let start = Instant::now();
+    while is_alive(pid) {
+        if start.elapsed() > UNMOUNT_TIMEOUT {
+            anyhow::bail!("Mount process {pid} did not exit");
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    remove_pid_file(mountpoint)?;
+    Ok(())
+}
+
+/// Whether a filesystem is mounted at a path, i.e. it has another device than its parent.
+pub fn is_mounted(mountpoint: impl AsRef<Path>) -> anyhow::Result<bool> {
+    let mountpoint = mountpoint.as_ref();
+    let parent = mountpoint.parent().unwrap_or(Path::new("/"));
+    let dev = match std::fs::metadata(mountpoint) {
+        Ok(metadata) => metadata.dev(),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+        Err(err) => return Err(err.into()),
+    };
+    Ok(dev != std::fs::metadata(parent)?.dev())
+}
+
+/// Wait until the background mount started by [`spawn`] is ready, or fail if its process
+/// exits first.
+pub fn wait_until_mounted(mountpoint: impl AsRef<Path>, pid: u32) -> anyhow::Result<()> {
+    let mountpoint = mountpoint.as_ref();
+    let start = Instant::now();
+    while !is_mounted(mountpoint)? {
+        if !is_alive(pid as i32) {
+            remove_pid_file(mountpoint)?;
+            anyhow::bail!("Mount process {pid} exited before mounting {mountpoint:?}");
+        }
+        if start.elapsed() > MOUNT_TIMEOUT {
+            anyhow::bail!("Timed out waiting for the mount at {mountpoint:?}");
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    Ok(())
+}
+
+/// Read a PID file, ignoring it if the process is no longer running.
+fn read_pid(pid_file: &Path) -> anyhow::Result<Option<i32>> {
+    let pid = match std::fs::read_to_string(pid_file) {
+        Ok(pid) => pid.trim().parse()?,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+    Ok(is_alive(pid).then_some(pid))
+}
+
+fn is_alive(pid: i32) -> bool {
+    unsafe { libc::kill(pid, 0) == 0 }
+}
@@ -0,0 +1,70 @@
+//! Translation of errors to the errno values that mounts reply with.
+
+use libc::{c_int, EEXIST, EFBIG, EINVAL, EIO, EISDIR, ENAMETOOLONG, ENOENT, ENOTDIR, ENOTEMPTY};
+
+use crate::error::FsError;
+
+/// The errno for a failed operation.
+///
+/// [`FsError`]s map to their errno and I/O errors keep theirs. Everything else, e.g. a block
+/// store that failed or an operation that timed out, is `EIO`.
+pub fn errno(err: &anyhow::Error) -> c_int {
+    if let Some(err) = FsError::find(err) {
+        return fs_errno(err);
+    }
+    err.chain()
+        .find_map(|cause| cause.downcast_ref::<std::io::Error>())
+        .and_then(std::io::Error::raw_os_error)
+        .unwrap_or(EIO)
+}
+
+pub fn fs_errno(err: FsError) -> c_int {
+    match err {
+        FsError::NotFound => ENOENT,
+        FsError::IsADirectory => EISDIR,
+        FsError::NotADirectory => ENOTDIR,
+        FsError::NotEmpty => ENOTEMPTY,
+        FsError::AlreadyExists => EEXIST,
+        FsError::NameTooLong => ENAMETOOLONG,
+        FsError::InvalidName => EINVAL,
+        FsError::FileTooLarge => EFBIG,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libc::ENOSPC;
+
+    use super::*;
+
+    #[test]
+    fn maps_fs_errors() {
+        assert_eq!(errno(&FsError::NotFound.into()), ENOENT);
+        assert_eq!(errno(&FsError::NotADirectory.into()), ENOTDIR);
+        assert_eq!(errno(&FsError::NotEmpty.into()), ENOTEMPTY);
+        assert_eq!(errno(&FsError::NameTooLong.into()), ENAMETOOLONG);
+        assert_eq!(errno(&FsError::InvalidName.into()), EINVAL);
+        assert_eq!(errno(&FsError::FileTooLarge.into()), EFBIG);
+    }
+
+    #[test]
+    fn finds_fs_errors_below_context() {
+        let err = anyhow::Error::new(FsError::IsADirectory)
+            .context("Failed to read /a")
+            .context("Failed to copy /a");
+        assert_eq!(errno(&err), EISDIR);
+    }
+
+    #[test]
+    fn keeps_the_code_of_io_errors() {
+        let err = anyhow::Error::new(std::io::Error::from_raw_os_error(ENOSPC)).context("write");
+        assert_eq!(errno(&err), ENOSPC);
+    }
+
+    #[test]
+    fn other_errors_are_eio() {
+        assert_eq!(errno(&anyhow::anyhow!("Block not found")), EIO);
+        let err = anyhow::Error::new(std::io::Error::other("no code"));
+        assert_eq!(errno(&err), EIO);
+    }
+}
@@ -0,0 +1,45 @@
+//! Errors of filesystem operations that callers tell apart, e.g. to reply a matching errno.
+//!
+//! [`crate::fs`] and [`crate::path`] return them inside [`anyhow::Error`]s, sometimes with a
+//! more specific message as context. Find them with [`FsError::find`].
+
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsError {
+    NotFound,
+    IsADirectory,
+    NotADirectory,
+    /// A directory that has to be empty, e.g. to be removed or replaced by a rename.
+    NotEmpty,
+    AlreadyExists,
+    NameTooLong,
+    InvalidName,
+    /// Larger than what may be read into memory, see [`crate::fs::Wnfs::set_max_read_size`].
+    FileTooLarge,
+}
+
+impl FsError {
+    /// The first `FsError` in the chain of `err`.
+    pub fn find(err: &anyhow::Error) -> Option<Self> {
+        err.chain()
+            .find_map(|cause| cause.downcast_ref::<Self>().copied())
+    }
+}
+
+impl fmt::Display for FsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::NotFound => "Not found",
+            Self::IsADirectory => "Is a directory, not a file",
+            Self::NotADirectory => "Not a directory",
+            Self::NotEmpty => "Directory is not empty",
+            Self::AlreadyExists => "Already exists",
+            Self::NameTooLong => "Name is too long",
+            Self::InvalidName => "Invalid name",
+            Self::FileTooLarge => "File is too large",
+        })
+    }
+}
+
+impl std::error::Error for FsError {}
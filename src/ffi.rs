@@ -0,0 +1,313 @@
+//! C bindings for embedding the filesystem in C, C++ or Swift applications.
+//!
+//! All functions return one of the `WNFS_*` status codes. On failure, [`wnfs_last_error`]
+//! returns a message describing the error of the last failed call on the current thread.
+//!
+//! Paths are UTF-8, NUL-terminated and separated by `/`, with the empty string naming the
+//! root directory. Buffers and entry lists returned by the library must be released with
+//! [`wnfs_free_buffer`] and [`wnfs_free_entries`].
+//!
+//! Changes are only persisted by [`wnfs_flush`] and [`wnfs_close`].
+//!
+//! A [`WnfsFs`] must not be used from more than one thread at a time.
+
+use std::cell::RefCell;
+use std::ffi::{c_char, c_int, CStr, CString};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::ptr;
+
+use crate::fs::{EntryKind, Wnfs};
+
+pub const WNFS_OK: c_int = 0;
+/// A pointer was null or a string was not valid UTF-8.
+pub const WNFS_ERR_INVALID_ARGUMENT: c_int = -1;
+pub const WNFS_ERR_NOT_FOUND: c_int = -2;
+/// Any other error, see [`wnfs_last_error`].
+pub const WNFS_ERR_FAILED: c_int = -3;
+/// The library panicked. The filesystem should not be used anymore.
+pub const WNFS_ERR_PANIC: c_int = -4;
+
+pub const WNFS_ENTRY_FILE: c_int = 0;
+pub const WNFS_ENTRY_DIR: c_int = 1;
+
+/// An open filesystem.
+pub struct WnfsFs {
+    runtime: tokio::runtime::Runtime,
+    fs: Wnfs,
+}
+
+/// A directory entry, as returned by [`wnfs_ls`].
+#[repr(C)]
+pub struct WnfsDirEntry {
+    pub name: *mut c_char,
+    /// `WNFS_ENTRY_FILE` or `WNFS_ENTRY_DIR`.
+    pub kind: c_int,
+    /// Upper bound of the content size for files, 0 for directories.
+    pub size: u64,
+    /// Modification time in seconds since the Unix epoch, or -1 if unknown.
+    pub modified: i64,
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = RefCell::new(None);
+}
+
+enum Error {
+    InvalidArgument(&'static str),
+    NotFound,
+    Failed(anyhow::Error),
+}
+
+impl From<anyhow::Error> for Error {
+    fn from(err: anyhow::Error) -> Self {
+        Error::Failed(err)
+    }
+}
+
+/// Run an FFI call, recording its error and turning it into a status code.
+fn wrap(f: impl FnOnce() -> Result<(), Error>) -> c_int {
+    let (code, message) = match catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(())) => return WNFS_OK,
+        Ok(Err(Error::InvalidArgument(message))) => {
+            (WNFS_ERR_INVALID_ARGUMENT, message.to_string())
+        }
+        Ok(Err(Error::NotFound)) => (WNFS_ERR_NOT_FOUND, "Not found".to_string()),
+        Ok(Err(Error::Failed(err))) => (WNFS_ERR_FAILED, format!("{err:#}")),
+        Err(_) => (WNFS_ERR_PANIC, "Panicked".to_string()),
+    };
+    // Messages cannot contain NUL bytes, apart from those we put there ourselves.
+    let message = CString::new(message.replace('\0', "")).expect("no NUL bytes");
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(message));
+    code
+}
+
+unsafe fn to_str<'a>(s: *const c_char) -> Result<&'a str, Error> {
+    if s.is_null() {
+        return Err(Error::InvalidArgument("Null string"));
+    }
+    CStr::from_ptr(s)
+        .to_str()
+        .map_err(|_| Error::InvalidArgument("String is not valid UTF-8"))
+}
+
+unsafe fn to_segments(path: *const c_char) -> Result<Vec<String>, Error> {
+    let path = to_str(path)?;
+    Ok(path
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .map(str::to_string)
+        .collect())
+}
+
+unsafe fn to_fs<'a>(fs: *mut WnfsFs) -> Result<&'a mut WnfsFs, Error> {
+    fs.as_mut().ok_or(Error::InvalidArgument("Null filesystem"))
+}
+
+/// Open an existing filesystem.
+///
+/// `passphrase` may be null for filesystems that are not protected by a passphrase. On
+/// success, `*out` is set to the filesystem, which has to be closed with [`wnfs_close`].
+#[no_mangle]
+pub unsafe extern "C" fn wnfs_open(
+    db_path: *const c_char,
+    name: *const c_char,
+    passphrase: *const c_char,
+    out: *mut *mut WnfsFs,
+) -> c_int {
+    wrap(|| {
+        let db_path = to_str(db_path)?;
+        let name = to_str(name)?.to_string();
+        let passphrase = if passphrase.is_null() {
+            None
+        } else {
+            Some(to_str(passphrase)?)
+        };
+        if out.is_null() {
+            return Err(Error::InvalidArgument("Null output pointer"));
+        }
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .map_err(anyhow::Error::from)?;
+        let fs = runtime.block_on(Wnfs::open_with_passphrase(db_path, name, passphrase))?;
+        *out = Box::into_raw(Box::new(WnfsFs { runtime, fs }));
+        Ok(())
+    })
+}
+
+/// Flush and close a filesystem. Passing null does nothing.
+#[no_mangle]
+pub unsafe extern "C" fn wnfs_close(fs: *mut WnfsFs) -> c_int {
+    if fs.is_null() {
+        return WNFS_OK;
+    }
+    let mut fs = Box::from_raw(fs);
+    wrap(move || {
+        let WnfsFs { runtime, fs } = &mut *fs;
+        runtime.block_on(fs.flush())?;
+        Ok(())
+    })
+}
+
+/// Persist all changes.
+#[no_mangle]
+pub unsafe extern "C" fn wnfs_flush(fs: *mut WnfsFs) -> c_int {
+    wrap(|| {
+        let WnfsFs { runtime, fs } = to_fs(fs)?;
+        runtime.block_on(fs.flush())?;
+        Ok(())
+    })
+}
+
+/// Read up to `size` bytes of a file, starting at `offset`.
+///
+/// On success, `*out` points to the data and `*out_len` is its length, which is less than
+/// `size` at the end of the file. Release the data with [`wnfs_free_buffer`].
+#[no_mangle]
+pub unsafe extern "C" fn wnfs_read(
+    fs: *mut WnfsFs,
+    path: *const c_char,
+    offset: u64,
+    size: usize,
+    out: *mut *mut u8,
+    out_len: *mut usize,
+) -> c_int {
+    wrap(|| {
+        let WnfsFs { runtime, fs } = to_fs(fs)?;
+        let path_segments = to_segments(path)?;
+        if out.is_null() || out_len.is_null() {
+            return Err(Error::InvalidArgument("Null output pointer"));
+        }
+        if runtime.block_on(fs.get_node(&path_segments))?.is_none() {
+            return Err(Error::NotFound);
+        }
+        let data = runtime.block_on(fs.read_file_at(&path_segments, offset as usize, size))?;
+        let data = data.into_boxed_slice();
+        *out_len = data.len();
+        *out = Box::into_raw(data) as *mut u8;
+        Ok(())
+    })
+}
+
+/// Release a buffer returned by [`wnfs_read`].
+#[no_mangle]
+pub unsafe extern "C" fn wnfs_free_buffer(buf: *mut u8, len: usize) {
+    if !buf.is_null() {
+        drop(Box::from_raw(ptr::slice_from_raw_parts_mut(buf, len)));
+    }
+}
+
+/// Replace the content of a file, creating it and its parent directories if needed.
+#[no_mangle]
+pub unsafe extern "C" fn wnfs_write(
+    fs: *mut WnfsFs,
+    path: *const c_char,
+    data: *const u8,
+    len: usize,
+) -> c_int {
+    wrap(|| {
+        let WnfsFs { runtime, fs } = to_fs(fs)?;
+        let path_segments = to_segments(path)?;
+        let content = match len {
+            0 => vec![],
+            _ if data.is_null() => return Err(Error::InvalidArgument("Null data")),
+            _ => std::slice::from_raw_parts(data, len).to_vec(),
+        };
+        runtime.block_on(fs.write_file(&path_segments, content))?;
+        Ok(())
+    })
+}
+
+/// List a directory.
+///
+/// On success, `*out` points to `*out_len` entries. Release them with [`wnfs_free_entries`].
+#[no_mangle]
+pub unsafe extern "C" fn wnfs_ls(
+    fs: *mut WnfsFs,
+    path: *const c_char,
+    out: *mut *mut WnfsDirEntry,
+    out_len: *mut usize,
+) -> c_int {
+    wrap(|| {
+        let WnfsFs { runtime, fs } = to_fs(fs)?;
+        let path_segments = to_segments(path)?;
+        if out.is_null() || out_len.is_null() {
+            return Err(Error::InvalidArgument("Null output pointer"));
+        }
+        if runtime
+            .block_on(fs.get_node_or_root(&path_segments))?
+            .is_none()
+        {
+            return Err(Error::NotFound);
+        }
+        let entries = runtime.block_on(fs.ls_entries(&path_segments))?;
+        let entries: Box<[WnfsDirEntry]> = entries
+            .into_iter()
+            .map(|entry| WnfsDirEntry {
+                // Names cannot contain NUL bytes.
+                name: CString::new(entry.name).unwrap_or_default().into_raw(),
+                kind: match entry.kind {
+                    EntryKind::File => WNFS_ENTRY_FILE,
+                    EntryKind::Dir => WNFS_ENTRY_DIR,
+                },
+                size: entry.size,
+                modified: entry.modified.map_or(-1, |modified| modified.timestamp()),
+            })
+            .collect();
+        *out_len = entries.len();
+        *out = Box::into_raw(entries) as *mut WnfsDirEntry;
+        Ok(())
+    })
+}
+
+/// Release entries returned by [`wnfs_ls`].
+#[no_mangle]
+pub unsafe extern "C" fn wnfs_free_entries(entries: *mut WnfsDirEntry, len: usize) {
+    if entries.is_null() {
+        return;
+    }
+    let entries = Box::from_raw(ptr::slice_from_raw_parts_mut(entries, len));
+    for entry in entries.iter() {
+        drop(CString::from_raw(entry.name));
+    }
+}
+
+/// Create a directory and its parent directories.
+#[no_mangle]
+pub unsafe extern "C" fn wnfs_mkdir(fs: *mut WnfsFs, path: *const c_char) -> c_int {
+    wrap(|| {
+        let WnfsFs { runtime, fs } = to_fs(fs)?;
+        let path_segments = to_segments(path)?;
+        runtime.block_on(fs.mkdir(&path_segments))?;
+        Ok(())
+    })
+}
+
+/// Remove a file or directory (including its contents).
+#[no_mangle]
+pub unsafe extern "C" fn wnfs_rm(fs: *mut WnfsFs, path: *const c_char) -> c_int {
+    wrap(|| {
+        let WnfsFs { runtime, fs } = to_fs(fs)?;
+        let path_segments = to_segments(path)?;
+        if path_segments.is_empty() {
+            return Err(Error::InvalidArgument("Cannot remove the root directory"));
+        }
+        if runtime.block_on(fs.get_node(&path_segments))?.is_none() {
+            return Err(Error::NotFound);
+        }
+        runtime.block_on(fs.rm(&path_segments))?;
+        Ok(())
+    })
+}
+
+/// Message of the last error on the current thread, or null if there was none.
+///
+/// The string is valid until the next failing call on the same thread.
+#[no_mangle]
+pub extern "C" fn wnfs_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| {
+        last.borrow()
+            .as_ref()
+            .map_or(ptr::null(), |message| message.as_ptr())
+    })
+}
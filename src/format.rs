@@ -0,0 +1,187 @@
+//! Version of the on-disk format of a store.
+//!
+//! The layout of root records and the alias scheme are described by a format version, which
+//! is recorded in a manifest under the `manifest` alias. Stores written before the manifest
+//! existed have version 0. A store with a newer version than [`FORMAT_VERSION`] is refused,
+//! since writing it could silently break it, and older stores are upgraded in place by
+//! [`migrate`] (the `migrate` command).
+
+use serde::{Deserialize, Serialize};
+
+use crate::store::Store;
+
+/// Format written by this version of the crate.
+pub const FORMAT_VERSION: u32 = 1;
+/// Stores older than this have to be migrated before they can be opened.
+const OLDEST_READABLE_VERSION: u32 = 0;
+const MANIFEST_ALIAS: &str = "manifest";
+
+/// Describes the format of a store.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Manifest {
+    pub version: u32,
+}
+
+/// An upgrade of a store by one format version.
+#[derive(Debug, Clone, Serialize)]
+pub struct Migration {
+    pub from: u32,
+    pub to: u32,
+    pub description: &'static str,
+}
+
+/// The format version of a store, 0 if it has no manifest.
+pub async fn version(store: &impl Store) -> anyhow::Result<u32> {
+    match store.get_from_alias(MANIFEST_ALIAS).await? {
+        None => Ok(0),
+        Some(bytes) => {
+            let manifest: Manifest = serde_ipld_dagcbor::from_slice(&bytes)
+                .map_err(|err| anyhow::anyhow!("Failed to read the store manifest: {err}"))?;
+            Ok(manifest.version)
+        }
+    }
+}
+
+/// Fail if this version of the crate cannot open the store.
+pub async fn check(store: &impl Store) -> anyhow::Result<()> {
+    let version = version(store).await?;
+    if version > FORMAT_VERSION {
+        anyhow::bail!(
+            "The store has format version {version}, but this program only supports up to \
+             version {FORMAT_VERSION}. Upgrade it to open the store."
+        );
+    }
+    if version < OLDEST_READABLE_VERSION {
+        anyhow::bail!(
+            "The store has the old format version {version}. Run `migrate` to upgrade it."
+        );
+    }
+    Ok(())
+}
+
+/// Record the current version in a store that has no aliases yet.
+///
+/// Stores with filesystems but without a manifest keep version 0 until they are migrated.
+pub async fn init(store: &mut impl Store) -> anyhow::Result<()> {
+    if store.aliases_with_prefix("").await?.is_empty() {
+        write_version(store, FORMAT_VERSION).await?;
+    }
+    Ok(())
+}
+
+/// The migrations that [`migrate`] would run, oldest first.
+pub async fn pending(store: &impl Store) -> anyhow::Result<Vec<Migration>> {
+    let version = version(store).await?;
+    if version > FORMAT_VERSION {
+        check(store).await?;
+    }
+    Ok((version..FORMAT_VERSION).map(migration).collect())
+}
+
+/// Upgrade a store to [`FORMAT_VERSION`] and return the migrations that ran.
+///
+/// The version is recorded after every step, so an interrupted migration continues where it
+/// stopped. Writers of the store must be stopped while it runs.
+pub async fn migrate(store: &mut impl Store) -> anyhow::Result<Vec<Migration>> {
+    let migrations = pending(store).await?;
+    for migration in &migrations {
+        tracing::info!(
+            "migrating store from version {} to {}: {}",
+            migration.from,
+            migration.to,
+            migration.description
+        );
+        match migration.from {
+            // Version 0 only lacks the manifest, which is written below.
+            0 => {}
+            from => unreachable!("no migration from version {from}"),
+        }
+        write_version(store, migration.to).await?;
+    }
+    Ok(migrations)
+}
+
+fn migration(from: u32) -> Migration {
+    let description = match from {
+        0 => "record the format version in a manifest",
+        from => unreachable!("no migration from version {from}"),
+    };
+    Migration {
+        from,
+        to: from + 1,
+        description,
+    }
+}
+
+async fn write_version(store: &mut impl Store, version: u32) -> anyhow::Result<()> {
+    store
+        .put_serializable_with_alias(MANIFEST_ALIAS, &Manifest { version })
+        .await?;
+    store.sync().await
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+
+    use super::*;
+    use crate::fs::Wnfs;
+    use crate::store::MemoryStore;
+
+    /// A store as written before the manifest existed, with a file in filesystem `test`.
+    async fn version_0_store() -> anyhow::Result<MemoryStore> {
+        let mut fs = Wnfs::init_in_store(MemoryStore::new(), "test".to_string(), None).await?;
+        fs.write_file(&["hello.txt".to_string()], b"hello".to_vec())
+            .await?;
+        fs.flush().await?;
+        let store = fs.store().clone();
+        store.alias(MANIFEST_ALIAS, None).await?;
+        Ok(store)
+    }
+
+    #[test]
+    fn new_stores_have_the_current_version() {
+        block_on(async {
+            let fs = Wnfs::init_in_store(MemoryStore::new(), "test".to_string(), None).await?;
+            assert_eq!(version(fs.store()).await?, FORMAT_VERSION);
+            assert!(pending(fs.store()).await?.is_empty());
+            anyhow::Ok(())
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn migrates_version_0() {
+        block_on(async {
+            let mut store = version_0_store().await?;
+            assert_eq!(version(&store).await?, 0);
+            check(&store).await?;
+
+            let migrations = migrate(&mut store).await?;
+            assert_eq!(migrations.len(), 1);
+            assert_eq!((migrations[0].from, migrations[0].to), (0, 1));
+            assert_eq!(version(&store).await?, FORMAT_VERSION);
+            assert!(migrate(&mut store).await?.is_empty());
+
+            let fs = Wnfs::open_in_store(store, "test".to_string(), None).await?;
+            assert_eq!(fs.read_file(&["hello.txt".to_string()]).await?, b"hello");
+            anyhow::Ok(())
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn refuses_newer_versions() {
+        block_on(async {
+            let mut store = version_0_store().await?;
+            write_version(&mut store, FORMAT_VERSION + 1).await?;
+            assert!(check(&store).await.is_err());
+            assert!(migrate(&mut store).await.is_err());
+            assert!(Wnfs::open_in_store(store, "test".to_string(), None)
+                .await
+                .is_err());
+            anyhow::Ok(())
+        })
+        .unwrap();
+    }
+}
@@ -1,111 +1,1174 @@
-use std::{path::Path, rc::Rc};
+use std::collections::{BTreeMap, BTreeSet};
+#[cfg(feature = "native")]
+use std::path::Path;
+use std::rc::Rc;
 
-use chrono::Utc;
-use futures::StreamExt;
-use libipld::Cid;
+use chrono::{DateTime, Utc};
+use futures::future::{self, LocalBoxFuture};
+use futures::stream::{self, LocalBoxStream};
+use futures::{AsyncRead, AsyncReadExt, FutureExt, StreamExt};
+use libipld::cid::multibase::{self, Base};
+use libipld::{Cid, Ipld, IpldCodec};
+use metrics::counter;
 use rand::RngCore;
 use serde::{Deserialize, Serialize};
-use wnfs::private::{PrivateDirectory, PrivateForest, PrivateNode, RevisionRef};
+use wnfs::private::{
+    PrivateDirectory, PrivateFile, PrivateForest, PrivateNode, RevisionRef, MAX_BLOCK_CONTENT_SIZE,
+};
 use wnfs_namefilter::Namefilter;
 
+use crate::error::FsError;
+use crate::format;
+use crate::hooks::{self, FileEvent, FileHook};
+use crate::journal::{self, JournalEntry, JournalOp, PendingOp};
+use crate::passphrase::PassphraseKey;
+use crate::path::WnfsPath;
+#[cfg(feature = "search")]
+use crate::search::{self, IndexChange, SearchHit, SearchIndex};
+use crate::share::{self, ExchangeKey};
+use crate::store::{DefaultStore, Store};
+#[cfg(feature = "native")]
 use crate::SqliteBlockStore;
 use wnfs_common::{BlockStore, Metadata};
+use x25519_dalek::PublicKey;
 
 /// Wrapper around a wnfs PrivateDirectory, PrivateForest and Blockstore.
 /// TODO: Store at least the keys outside of the blockstore.
-pub struct Wnfs {
-    store: SqliteBlockStore,
+///
+/// `Wnfs` is neither `Send` nor `Sync`: the nodes of wnfs are shared with `Rc`, which its
+/// API takes and returns, and hooks are not `Send` either. To use a filesystem from several
+/// threads or tasks, open it behind a `handle::WnfsHandle` (with the `native` feature),
+/// which is cheap to clone and runs the operations on the thread that owns the filesystem.
+pub struct Wnfs<S = DefaultStore> {
+    store: S,
     // signing_key: SigningKey,
     name: String,
+    passphrase_key: Option<PassphraseKey>,
+    autoflush: bool,
+    max_read_size: u64,
+    /// Number of mutations that were not flushed yet.
+    unflushed: usize,
+    /// Number of heads when the filesystem was opened or last flushed, see [`Wnfs::heads`].
+    heads: usize,
     forest: Rc<PrivateForest>,
     private_dir: Rc<PrivateDirectory>,
+    journal_head: Option<Cid>,
+    /// Actor of journaled operations, if the journal is enabled.
+    journal_actor: Option<String>,
+    pending_ops: Vec<PendingOp>,
+    hooks: Vec<Box<dyn FileHook>>,
+    #[cfg(feature = "search")]
+    search_index: Option<SearchIndex>,
 }
 
 const PRIVATE_ROOT_PREFIX: &str = "private-root:";
+const COMMIT_PREFIX: &str = "commit:";
+const SHARE_PREFIX: &str = "share:";
+const SNAPSHOT_PREFIX: &str = "snapshot:";
+const MODE_KEY: &str = "mode";
+/// Default of [`Wnfs::set_max_read_size`].
+pub const DEFAULT_MAX_READ_SIZE: u64 = 1024 * 1024 * 1024;
+/// Conflict copies of a path that [`Wnfs::conflict_copy_path`] tries before giving up.
+const MAX_CONFLICT_COPIES: usize = 1000;
+/// Number of content blocks that reads fetch and decrypt ahead of the block being copied.
+const READ_AHEAD_BLOCKS: usize = 8;
+
+pub(crate) fn private_root_alias(name: &str) -> String {
+    format!("{}{}", PRIVATE_ROOT_PREFIX, name)
+}
+
+/// Alias of a root record that is being committed, see [`store_private_root`].
+fn commit_alias(name: &str) -> String {
+    format!("{}{}", COMMIT_PREFIX, name)
+}
+
+pub(crate) fn snapshot_prefix(name: &str) -> String {
+    format!("{}{}:", SNAPSHOT_PREFIX, name)
+}
+
+/// Recursive logical size and entry counts of a subtree.
+#[derive(Debug, Default, Clone, Copy, Serialize)]
+pub struct DiskUsage {
+    /// Sum of the (plaintext) content sizes of all files.
+    pub size: u64,
+    pub files: u64,
+    pub dirs: u64,
+}
+
+impl DiskUsage {
+    fn add(&mut self, other: &DiskUsage) {
+        self.size += other.size;
+        self.files += other.files;
+        self.dirs += other.dirs;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EntryKind {
+    File,
+    Dir,
+}
+
+/// Information about a file or directory, as returned by [`Wnfs::stat`].
+#[derive(Debug, Clone, Serialize)]
+pub struct DirEntry {
+    pub name: String,
+    pub kind: EntryKind,
+    /// Upper bound of the content size for files, 0 for directories.
+    pub size: u64,
+    pub modified: Option<DateTime<Utc>>,
+    pub created: Option<DateTime<Utc>>,
+    /// Permission bits set with [`Wnfs::set_mode`], if any.
+    pub mode: Option<u32>,
+}
+
+/// Permission bits stored in the metadata of a node, if any.
+pub fn node_mode(node: &PrivateNode) -> Option<u32> {
+    let metadata = match node {
+        PrivateNode::File(file) => file.get_metadata(),
+        PrivateNode::Dir(dir) => dir.get_metadata(),
+    };
+    match metadata.get(MODE_KEY)? {
+        Ipld::Integer(mode) => Some(*mode as u32 & 0o7777),
+        _ => None,
+    }
+}
+
+/// A numeric ID for a path that is stable across sessions, e.g. for NFS file IDs.
+///
+/// The root directory has ID 1, other paths get a hash that is never 0 or 1.
+pub fn path_id(path_segments: &[String]) -> u64 {
+    if path_segments.is_empty() {
+        return 1;
+    }
+    let mut hasher = blake3::Hasher::new();
+    for segment in path_segments {
+        // Names cannot contain NUL bytes, so this separates segments unambiguously.
+        hasher.update(segment.as_bytes());
+        hasher.update(&[0]);
+    }
+    let hash = hasher.finalize();
+    let id = u64::from_le_bytes(hash.as_bytes()[..8].try_into().unwrap());
+    id.max(2)
+}
+
+impl DirEntry {
+    pub fn from_node(name: String, node: &PrivateNode) -> Self {
+        let (kind, size, metadata) = match node {
+            PrivateNode::File(file) => (
+                EntryKind::File,
+                file.get_content_size_upper_bound() as u64,
+                file.get_metadata(),
+            ),
+            PrivateNode::Dir(dir) => (EntryKind::Dir, 0, dir.get_metadata()),
+        };
+        Self {
+            name,
+            kind,
+            size,
+            modified: metadata.get_modified(),
+            created: metadata.get_created(),
+            mode: node_mode(node),
+        }
+    }
+}
+
+/// A named filesystem in a store, as returned by [`Wnfs::list`].
+#[derive(Debug, Clone)]
+pub struct FsInfo {
+    pub name: String,
+    /// CID of the root record.
+    pub root_cid: Cid,
+    /// Size of all blocks reachable from the root record.
+    pub stored_size: u64,
+    pub protected: bool,
+    pub modified: Option<DateTime<Utc>>,
+}
+
+/// Progress of a long-running operation, passed to progress callbacks.
+#[derive(Debug, Clone, Default)]
+pub struct Progress {
+    /// Number of files or blocks processed so far.
+    pub items: u64,
+    pub bytes: u64,
+    /// Total number of files or blocks, if known in advance.
+    pub total: Option<u64>,
+    /// Path of the file that is currently processed, if any.
+    pub path: Option<String>,
+}
+
+/// Callback for [`Progress`] reports. Use `&|_| {}` to ignore progress.
+pub type OnProgress<'a> = &'a dyn Fn(&Progress);
+
+/// A named snapshot of a filesystem, as returned by [`Wnfs::list_snapshots`].
+#[derive(Debug, Clone, Serialize)]
+pub struct SnapshotInfo {
+    pub name: String,
+    /// CID of the root record at the time of the snapshot.
+    #[serde(serialize_with = "serialize_cid")]
+    pub root_cid: Cid,
+    /// Modification time of the root directory at the time of the snapshot.
+    pub modified: Option<DateTime<Utc>>,
+}
+
+/// A root record in the store, as returned by [`Wnfs::scan_roots`].
+#[derive(Debug, Clone, Serialize)]
+pub struct RootCandidate {
+    #[serde(serialize_with = "serialize_cid")]
+    pub root_cid: Cid,
+    pub protected: bool,
+    /// Modification time of the root directory, unknown for protected records without the
+    /// passphrase.
+    pub modified: Option<DateTime<Utc>>,
+}
+
+/// A revision of the root directory at the revision of the root record, as returned by
+/// [`Wnfs::heads`].
+#[derive(Debug, Clone, Serialize)]
+pub struct HeadInfo {
+    pub index: usize,
+    /// Modification time of the root directory.
+    pub modified: Option<DateTime<Utc>>,
+}
+
+/// Where [`Wnfs::recover_root`] takes the new root record from.
+#[derive(Debug, Clone, Copy)]
+pub enum RootSource<'a> {
+    /// An access key, see [`Wnfs::export_access_key`].
+    AccessKey(&'a str),
+    /// A snapshot of the filesystem.
+    Snapshot(&'a str),
+    /// A root record in the store, e.g. one found by [`Wnfs::scan_roots`].
+    Record(Cid),
+}
+
+fn serialize_cid<S: serde::Serializer>(cid: &Cid, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(cid)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChangeKind {
+    Added,
+    Removed,
+    Modified,
+}
+
+/// A changed path between two revisions, as returned by [`Wnfs::diff`].
+#[derive(Debug, Clone, Serialize)]
+pub struct Change {
+    pub path: WnfsPath,
+    pub kind: ChangeKind,
+    pub entry_kind: EntryKind,
+    /// Size before the change, 0 for added paths and directories.
+    pub size_before: u64,
+    /// Size after the change, 0 for removed paths and directories.
+    pub size_after: u64,
+}
+
+/// State to return to when an operation is abandoned half way, see [`Wnfs::checkpoint`].
+pub struct Checkpoint {
+    forest: Rc<PrivateForest>,
+    private_dir: Rc<PrivateDirectory>,
+    journal_head: Option<Cid>,
+    pending_ops: usize,
+    unflushed: usize,
+}
+
+/// Reads the content of file nodes, see [`Wnfs::content_reader`].
+///
+/// Nodes are immutable, so a read returns the revision of the node it was given even if the
+/// file changes meanwhile.
+#[derive(Clone)]
+pub struct ContentReader<S = DefaultStore> {
+    forest: Rc<PrivateForest>,
+    store: S,
+}
+
+impl<S: Store> ContentReader<S> {
+    /// See [`Wnfs::read_node_at`].
+    pub async fn read_at(
+        &self,
+        file: &PrivateFile,
+        offset: usize,
+        size: usize,
+    ) -> anyhow::Result<Vec<u8>> {
+        let available = file.get_content_size_upper_bound().saturating_sub(offset);
+        let mut content = Vec::with_capacity(size.min(available));
+        let mut chunks = self.stream_at(file, offset, size);
+        while let Some(chunk) = chunks.next().await {
+            content.extend_from_slice(&chunk?);
+        }
+        Ok(content)
+    }
+
+    /// Stream a range of a file, as the parts of the content blocks that cover it.
+    ///
+    /// Only the covering blocks are fetched, up to [`READ_AHEAD_BLOCKS`] ahead of the one
+    /// that is streamed, so large ranges can be copied without holding them in memory.
+    pub fn stream_at<'a>(
+        &'a self,
+        file: &'a PrivateFile,
+        offset: usize,
+        size: usize,
+    ) -> LocalBoxStream<'a, anyhow::Result<Vec<u8>>> {
+        let end = offset
+            .saturating_add(size)
+            .min(file.get_content_size_upper_bound());
+        let blocks = if offset < end {
+            offset / MAX_BLOCK_CONTENT_SIZE..(end - 1) / MAX_BLOCK_CONTENT_SIZE + 1
+        } else {
+            0..0
+        };
+        stream::iter(blocks)
+            .map(move |index| async move { (index, self.read_block(file, index).await) })
+            .buffered(READ_AHEAD_BLOCKS)
+            // The size is an upper bound, the last block ends the content.
+            .take_while(|(_, block)| future::ready(!matches!(block, Ok(None))))
+            .map(move |(index, block)| -> anyhow::Result<Vec<u8>> {
+                let mut block = block?.expect("the stream ends at the last block");
+                let start = index * MAX_BLOCK_CONTENT_SIZE;
+                block.truncate(end - start);
+                block.drain(..offset.saturating_sub(start).min(block.len()));
+                counter!("wnfs_read_bytes_total", block.len() as u64);
+                Ok(block)
+            })
+            .boxed_local()
+    }
+
+    /// Decrypt content block `index` of a file, `None` past the end of the content.
+    ///
+    /// Blocks hold [`MAX_BLOCK_CONTENT_SIZE`] bytes, except for the last one.
+    pub async fn read_block(
+        &self,
+        file: &PrivateFile,
+        index: usize,
+    ) -> anyhow::Result<Option<Vec<u8>>> {
+        let mut chunks = file
+            .stream_content(index, &self.forest, &self.store)
+            .boxed_local();
+        chunks.next().await.transpose()
+    }
+}
+
+/// Problems found by [`Wnfs::verify`].
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    /// Blocks referenced from the root record that are not in the store.
+    pub missing_blocks: Vec<Cid>,
+    /// Paths of nodes that could not be loaded or decrypted, with the error.
+    pub undecryptable: Vec<(WnfsPath, String)>,
+    /// Number of root revisions besides the one that is being followed.
+    pub orphaned_revisions: usize,
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.missing_blocks.is_empty()
+            && self.undecryptable.is_empty()
+            && self.orphaned_revisions == 0
+    }
+}
 
 #[derive(Debug, Serialize, Deserialize)]
 struct PrivateRoot {
     forest_cid: Cid,
     revision_ref: RevisionRef,
+    /// Latest entry of the journal, if enabled.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    journal: Option<Cid>,
+}
+
+impl PrivateRoot {
+    /// Encode as a portable access key string.
+    fn to_access_key(&self) -> anyhow::Result<String> {
+        let bytes = serde_ipld_dagcbor::to_vec(self)?;
+        Ok(multibase::encode(Base::Base58Btc, bytes))
+    }
+
+    fn from_access_key(key: &str) -> anyhow::Result<Self> {
+        let (_base, bytes) = multibase::decode(key.trim())?;
+        let root = serde_ipld_dagcbor::from_slice(&bytes)?;
+        Ok(root)
+    }
+}
+
+/// Root record of a passphrase protected filesystem.
+///
+/// The forest CID stays in the clear so that the forest remains reachable from the root alias
+/// (e.g. for garbage collection). Only the revision ref holds key material.
+#[derive(Debug, Serialize, Deserialize)]
+struct ProtectedRoot {
+    forest_cid: Cid,
+    salt: Vec<u8>,
+    sealed_root: Vec<u8>,
+    /// In the clear like the forest CID, to keep the journal reachable.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    journal: Option<Cid>,
+}
+
+impl ProtectedRoot {
+    fn open(&self, key: &PassphraseKey) -> anyhow::Result<PrivateRoot> {
+        let bytes = key.open(&self.sealed_root)?;
+        let root = serde_ipld_dagcbor::from_slice(&bytes)?;
+        Ok(root)
+    }
+}
+
+enum StoredRoot {
+    Plain(PrivateRoot),
+    Protected(ProtectedRoot),
+}
+
+impl StoredRoot {
+    async fn load(store: &impl Store, cid: &Cid) -> anyhow::Result<Self> {
+        let bytes = store.get_block(cid).await?;
+        if let Ok(root) = serde_ipld_dagcbor::from_slice::<PrivateRoot>(&bytes) {
+            return Ok(StoredRoot::Plain(root));
+        }
+        let protected = serde_ipld_dagcbor::from_slice::<ProtectedRoot>(&bytes)?;
+        Ok(StoredRoot::Protected(protected))
+    }
+
+    fn open(self, passphrase_key: Option<&PassphraseKey>) -> anyhow::Result<PrivateRoot> {
+        match (self, passphrase_key) {
+            (StoredRoot::Plain(root), _) => Ok(root),
+            (StoredRoot::Protected(protected), Some(key)) => protected.open(key),
+            (StoredRoot::Protected(_), None) => anyhow::bail!("Root is protected by a passphrase"),
+        }
+    }
 }
 
-impl Wnfs {
+async fn load_stored_root(store: &impl Store, name: &str) -> anyhow::Result<Option<StoredRoot>> {
+    let Some(cid) = store.resolve_alias(&private_root_alias(name)).await? else {
+        return Ok(None);
+    };
+    Ok(Some(StoredRoot::load(store, &cid).await?))
+}
+
+/// Load the forest and root directory of a root record.
+async fn load_root_dir(
+    store: &impl Store,
+    private_root: &PrivateRoot,
+) -> anyhow::Result<(PrivateForest, Rc<PrivateDirectory>)> {
+    let private_forest = store
+        .get_deserializable::<PrivateForest>(&private_root.forest_cid)
+        .await?;
+    let node = private_forest
+        .get_multivalue(&private_root.revision_ref, store)
+        .next()
+        .await
+        .ok_or_else(|| anyhow::anyhow!("Failed to load private forest: {private_forest:?}"))??;
+    // The latest revision within this forest, so older root records load older states.
+    let private_dir = node
+        .search_latest(&private_forest, store)
+        .await?
+        .as_dir()?;
+    Ok((private_forest, private_dir))
+}
+
+/// Check that a root record can be opened and its root directory loaded.
+async fn check_root_record(
+    store: &impl Store,
+    cid: &Cid,
+    passphrase: Option<&str>,
+) -> anyhow::Result<()> {
+    let root = match StoredRoot::load(store, cid).await? {
+        StoredRoot::Plain(root) => root,
+        StoredRoot::Protected(protected) => {
+            let passphrase = passphrase
+                .ok_or_else(|| anyhow::anyhow!("Root record is protected by a passphrase"))?;
+            protected.open(&PassphraseKey::derive(passphrase, &protected.salt)?)?
+        }
+    };
+    load_root_dir(store, &root).await?;
+    Ok(())
+}
+
+async fn ensure_new_name(store: &impl Store, name: &str) -> anyhow::Result<()> {
+    let existing = store.resolve_alias(&private_root_alias(name)).await?;
+    if existing.is_some() {
+        anyhow::bail!("A filesystem named {name} already exists");
+    }
+    Ok(())
+}
+
+/// Store a root record and point the root alias to it.
+///
+/// The record is first written under the commit alias, and only becomes the root once the
+/// store reports all blocks as durable. A crash in between leaves the commit alias behind,
+/// which [`Wnfs::recover_commit`] completes or rolls back.
+async fn store_private_root(
+    store: &mut impl Store,
+    name: &str,
+    root: &PrivateRoot,
+    passphrase_key: Option<&PassphraseKey>,
+) -> anyhow::Result<Cid> {
+    let record = match passphrase_key {
+        None => serde_ipld_dagcbor::to_vec(root)?,
+        Some(key) => {
+            let protected = ProtectedRoot {
+                forest_cid: root.forest_cid,
+                salt: key.salt().to_vec(),
+                sealed_root: key.seal(&serde_ipld_dagcbor::to_vec(root)?)?,
+                journal: root.journal,
+            };
+            serde_ipld_dagcbor::to_vec(&protected)?
+        }
+    };
+    let cid = store.put_block(record, IpldCodec::DagCbor).await?;
+    let pending = commit_alias(name);
+    store.alias(&pending, Some(&cid)).await?;
+    store.sync().await?;
+    store.alias(&private_root_alias(name), Some(&cid)).await?;
+    store.alias(&pending, None).await?;
+    Ok(cid)
+}
+
+#[cfg(feature = "native")]
+impl Wnfs<SqliteBlockStore> {
+    /// Open an existing filesystem that is not protected by a passphrase.
     pub async fn open_from_path(db_path: impl AsRef<Path>, name: String) -> anyhow::Result<Self> {
-        let mut store = SqliteBlockStore::new(db_path)?;
-        let private_root_alias = format!("{}{}", PRIVATE_ROOT_PREFIX, name);
-        let private_root: PrivateRoot = {
-            match store
-                .get_deserializable_from_alias::<PrivateRoot>(&private_root_alias)
-                .await
-            {
-                Err(_err) => {
-                    let mut rng = rand::rngs::OsRng;
-                    let root = create_private_dir(&mut store, &mut rng).await?;
-                    store
-                        .put_serializable_with_alias(&private_root_alias, &root)
-                        .await?;
-                    tracing::debug!("created private root");
-                    root
-                }
-                Ok(root) => {
-                    tracing::debug!("loaded private root");
-                    root
+        Self::open_with_passphrase(db_path, name, None).await
+    }
+
+    /// Open an existing filesystem.
+    ///
+    /// `passphrase` is required if the filesystem was created with a passphrase.
+    pub async fn open_with_passphrase(
+        db_path: impl AsRef<Path>,
+        name: String,
+        passphrase: Option<&str>,
+    ) -> anyhow::Result<Self> {
+        let store = SqliteBlockStore::new(db_path)?;
+        Self::open_in_store(store, name, passphrase).await
+    }
+
+    /// Create a new, empty filesystem, optionally protected by a passphrase.
+    pub async fn init(
+        db_path: impl AsRef<Path>,
+        name: String,
+        passphrase: Option<&str>,
+    ) -> anyhow::Result<Self> {
+        let store = SqliteBlockStore::new(&db_path)?;
+        Self::init_in_store(store, name, passphrase).await
+    }
+
+    /// Find the root records in a store, newest first, to recover a filesystem with
+    /// [`Self::recover_root`].
+    ///
+    /// Besides the current roots and snapshots of all filesystems, this finds earlier
+    /// revisions until they are garbage collected. Protected records are only opened with
+    /// their `passphrase`, and skipped if it does not match. Records whose root directory
+    /// cannot be loaded are skipped.
+    pub async fn scan_roots(
+        store: &SqliteBlockStore,
+        passphrase: Option<&str>,
+    ) -> anyhow::Result<Vec<RootCandidate>> {
+        // Keys by salt, since deriving a key is slow on purpose.
+        let mut keys = BTreeMap::new();
+        let mut candidates = vec![];
+        for cid in store.block_cids().await? {
+            if cid.codec() != u64::from(IpldCodec::DagCbor) {
+                continue;
+            }
+            let Ok(stored) = StoredRoot::load(store, &cid).await else {
+                continue;
+            };
+            let protected = matches!(stored, StoredRoot::Protected(_));
+            let root = match stored {
+                StoredRoot::Plain(root) => root,
+                StoredRoot::Protected(protected) => {
+                    let Some(passphrase) = passphrase else {
+                        candidates.push(RootCandidate {
+                            root_cid: cid,
+                            protected: true,
+                            modified: None,
+                        });
+                        continue;
+                    };
+                    if !keys.contains_key(&protected.salt) {
+                        let key = PassphraseKey::derive(passphrase, &protected.salt)?;
+                        keys.insert(protected.salt.clone(), key);
+                    }
+                    match protected.open(&keys[&protected.salt]) {
+                        Ok(root) => root,
+                        Err(_) => continue,
+                    }
                 }
+            };
+            let Ok((_forest, dir)) = load_root_dir(store, &root).await else {
+                continue;
+            };
+            candidates.push(RootCandidate {
+                root_cid: cid,
+                protected,
+                modified: dir.get_metadata().get_modified(),
+            });
+        }
+        candidates.sort_by(|a, b| b.modified.cmp(&a.modified));
+        Ok(candidates)
+    }
+}
+
+impl<S: Store> Wnfs<S> {
+    /// Open an existing filesystem in an already opened store.
+    pub async fn open_in_store(
+        store: S,
+        name: String,
+        passphrase: Option<&str>,
+    ) -> anyhow::Result<Self> {
+        format::check(&store).await?;
+        let (private_root, passphrase_key) = match load_stored_root(&store, &name).await? {
+            None => anyhow::bail!("Filesystem {name} does not exist"),
+            Some(StoredRoot::Plain(root)) => (root, None),
+            Some(StoredRoot::Protected(protected)) => {
+                let passphrase = passphrase.ok_or_else(|| {
+                    anyhow::anyhow!("Filesystem {name} is protected by a passphrase")
+                })?;
+                let key = PassphraseKey::derive(passphrase, &protected.salt)?;
+                (protected.open(&key)?, Some(key))
             }
         };
         tracing::debug!("load private root: {private_root:?}");
-        let private_forest = store
-            .get_deserializable::<PrivateForest>(&private_root.forest_cid)
-            .await?;
-        let node = private_forest
+        let (private_forest, private_dir) = load_root_dir(&store, &private_root).await?;
+        let heads = private_forest
             .get_multivalue(&private_root.revision_ref, &store)
-            .next()
-            .await
-            .ok_or_else(|| {
-                anyhow::anyhow!("Failed to load private forest: {private_forest:?}")
-            })??;
-        let private_dir = node
-            .search_latest(&private_forest, &store)
-            .await?
-            .as_dir()?;
+            .count()
+            .await;
+        if heads > 1 {
+            tracing::warn!("filesystem {name} has {heads} divergent heads, opening the first");
+        }
 
         Ok(Self {
             private_dir,
             forest: Rc::new(private_forest),
             // signing_key,
             name,
+            passphrase_key,
+            autoflush: false,
+            max_read_size: DEFAULT_MAX_READ_SIZE,
+            unflushed: 0,
+            heads,
             store,
+            journal_head: private_root.journal,
+            journal_actor: private_root.journal.map(|_| journal::default_actor()),
+            pending_ops: vec![],
+            hooks: vec![],
+            #[cfg(feature = "search")]
+            search_index: None,
         })
     }
 
+    /// Create a new, empty filesystem in an already opened store.
+    pub async fn init_in_store(
+        mut store: S,
+        name: String,
+        passphrase: Option<&str>,
+    ) -> anyhow::Result<Self> {
+        format::check(&store).await?;
+        format::init(&mut store).await?;
+        ensure_new_name(&store, &name).await?;
+        let passphrase_key = passphrase.map(PassphraseKey::generate).transpose()?;
+        let mut rng = rand::rngs::OsRng;
+        let root = create_private_dir(&mut store, &mut rng).await?;
+        store_private_root(&mut store, &name, &root, passphrase_key.as_ref()).await?;
+        tracing::debug!("created private root");
+        Self::open_in_store(store, name, passphrase).await
+    }
+
+    /// List all named filesystems in a store.
+    ///
+    /// The modification time is only available for filesystems that are not protected by a
+    /// passphrase.
+    pub async fn list(store: &S) -> anyhow::Result<Vec<FsInfo>> {
+        let mut list = vec![];
+        for (name, root_cid) in store.aliases_with_prefix(PRIVATE_ROOT_PREFIX).await? {
+            let stored_size = store.dag_size(&root_cid).await?;
+            let protected = Self::is_protected(store, &name).await?;
+            let modified = if protected {
+                None
+            } else {
+                match Self::open_in_store(store.clone(), name.clone(), None).await {
+                    Ok(fs) => fs.private_root().get_metadata().get_modified(),
+                    Err(err) => {
+                        tracing::warn!("failed to open filesystem {name}: {err}");
+                        None
+                    }
+                }
+            };
+            list.push(FsInfo {
+                name,
+                root_cid,
+                stored_size,
+                protected,
+                modified,
+            });
+        }
+        Ok(list)
+    }
+
+    /// Finish a commit of a process that crashed while flushing.
+    ///
+    /// The commit is completed if all blocks below its root record are in the store, and rolled
+    /// back otherwise, so that the root alias never points to an incomplete tree. Returns
+    /// whether there was an interrupted commit. Only call this while holding the writer lock
+    /// (see [`crate::lock`]), as it would also interfere with the commits of a running writer.
+    pub async fn recover_commit(store: &S, name: &str) -> anyhow::Result<bool> {
+        let pending = commit_alias(name);
+        let Some(cid) = store.resolve_alias(&pending).await? else {
+            return Ok(false);
+        };
+        let missing = store.missing_blocks(&cid).await?;
+        if missing.is_empty() {
+            tracing::warn!("completing interrupted commit of {name}");
+            store.alias(&private_root_alias(name), Some(&cid)).await?;
+        } else {
+            tracing::warn!(
+                "rolling back interrupted commit of {name}, {} blocks are missing",
+                missing.len()
+            );
+        }
+        store.alias(&pending, None).await?;
+        Ok(true)
+    }
+
+    /// Replace the root record of a filesystem whose root alias is missing or damaged.
+    ///
+    /// Fails unless the root directory of the new record can be loaded. A protected record
+    /// needs its `passphrase`. A record rebuilt from an access key is protected by
+    /// `passphrase` if one is given.
+    pub async fn recover_root(
+        store: &mut S,
+        name: &str,
+        source: RootSource<'_>,
+        passphrase: Option<&str>,
+    ) -> anyhow::Result<Cid> {
+        let root_cid = match source {
+            RootSource::AccessKey(access_key) => {
+                let root = PrivateRoot::from_access_key(access_key)?;
+                load_root_dir(store, &root).await?;
+                let key = passphrase.map(PassphraseKey::generate).transpose()?;
+                return store_private_root(store, name, &root, key.as_ref()).await;
+            }
+            RootSource::Snapshot(snapshot) => {
+                let alias = format!("{}{snapshot}", snapshot_prefix(name));
+                store
+                    .resolve_alias(&alias)
+                    .await?
+                    .ok_or_else(|| anyhow::anyhow!("Snapshot {snapshot} does not exist"))?
+            }
+            RootSource::Record(root_cid) => root_cid,
+        };
+        check_root_record(store, &root_cid, passphrase).await?;
+        store
+            .alias(&private_root_alias(name), Some(&root_cid))
+            .await?;
+        Ok(root_cid)
+    }
+
+    /// Remove a named filesystem from a store.
+    ///
+    /// This only removes the root alias. The blocks of the filesystem are deleted by the next
+    /// garbage collection run, unless they are still reachable from another root.
+    pub async fn delete(store: &S, name: &str) -> anyhow::Result<()> {
+        for alias in Self::aliases(store, name).await? {
+            store.alias(&alias, None).await?;
+        }
+        Ok(())
+    }
+
+    /// The aliases that [`Self::delete`] removes: the root and all snapshots of a filesystem.
+    pub async fn aliases(store: &S, name: &str) -> anyhow::Result<Vec<String>> {
+        let alias = private_root_alias(name);
+        if store.resolve_alias(&alias).await?.is_none() {
+            anyhow::bail!("Filesystem {name} does not exist");
+        }
+        let mut aliases = vec![alias];
+        let pending = commit_alias(name);
+        if store.resolve_alias(&pending).await?.is_some() {
+            aliases.push(pending);
+        }
+        let prefix = snapshot_prefix(name);
+        for (snapshot, _cid) in store.aliases_with_prefix(&prefix).await? {
+            aliases.push(format!("{prefix}{snapshot}"));
+        }
+        Ok(aliases)
+    }
+
+    /// Check whether opening a filesystem requires a passphrase.
+    pub async fn is_protected(store: &S, name: &str) -> anyhow::Result<bool> {
+        let stored = load_stored_root(store, name).await?;
+        Ok(matches!(stored, Some(StoredRoot::Protected(_))))
+    }
+
+    /// Register a share received from another store as a new named root.
+    ///
+    /// The share block and the blocks of the shared directory have to be available in the
+    /// store.
+    pub async fn accept_share(store: &mut S, label: &Cid, name: &str) -> anyhow::Result<()> {
+        ensure_new_name(store, name).await?;
+        let exchange_key = ExchangeKey::load_or_create(store).await?;
+        let sealed = store.get_block(label).await?;
+        let payload = exchange_key.open(&sealed)?;
+        let root: PrivateRoot = serde_ipld_dagcbor::from_slice(&payload)?;
+        store_private_root(store, name, &root, None).await?;
+        Ok(())
+    }
+
+    /// Create a named root from an access key exported with [`Self::export_access_key`].
+    ///
+    /// The blocks of the filesystem have to be available in the store.
+    pub async fn import_access_key(
+        store: &mut S,
+        name: &str,
+        access_key: &str,
+    ) -> anyhow::Result<()> {
+        ensure_new_name(store, name).await?;
+        let root = PrivateRoot::from_access_key(access_key)?;
+        store_private_root(store, name, &root, None).await?;
+        Ok(())
+    }
+
+    /// Export the access key of the filesystem.
+    ///
+    /// Anyone with the access key and the blocks of the store can read the filesystem.
+    pub async fn export_access_key(&self) -> anyhow::Result<String> {
+        let root = self
+            .load_private_root()
+            .await?
+            .ok_or_else(|| anyhow::anyhow!("Filesystem {} does not exist", self.name))?;
+        root.to_access_key()
+    }
+
+    /// Flush and record the current state under a snapshot name.
+    ///
+    /// Snapshots keep their blocks alive during garbage collection until they are deleted.
+    pub async fn create_snapshot(&mut self, snapshot: &str) -> anyhow::Result<()> {
+        let alias = format!("{}{snapshot}", snapshot_prefix(&self.name));
+        if self.store.resolve_alias(&alias).await?.is_some() {
+            anyhow::bail!("Snapshot {snapshot} already exists");
+        }
+        self.flush().await?;
+        let root_cid = self
+            .store
+            .resolve_alias(&private_root_alias(&self.name))
+            .await?
+            .ok_or_else(|| anyhow::anyhow!("Filesystem {} does not exist", self.name))?;
+        self.store.alias(&alias, Some(&root_cid)).await?;
+        Ok(())
+    }
+
+    /// List the snapshots of this filesystem, oldest first.
+    pub async fn list_snapshots(&self) -> anyhow::Result<Vec<SnapshotInfo>> {
+        let prefix = snapshot_prefix(&self.name);
+        let mut snapshots = vec![];
+        for (name, root_cid) in self.store.aliases_with_prefix(&prefix).await? {
+            let modified = match self.load_snapshot_dir(&root_cid).await {
+                Ok((_forest, dir)) => dir.get_metadata().get_modified(),
+                Err(err) => {
+                    tracing::warn!("failed to load snapshot {name}: {err}");
+                    None
+                }
+            };
+            snapshots.push(SnapshotInfo {
+                name,
+                root_cid,
+                modified,
+            });
+        }
+        snapshots.sort_by_key(|snapshot| snapshot.modified);
+        Ok(snapshots)
+    }
+
+    /// Replace the current state with a snapshot.
+    ///
+    /// Changes made after the snapshot are lost, unless they are recorded in another snapshot.
+    pub async fn restore_snapshot(&mut self, snapshot: &str) -> anyhow::Result<()> {
+        let root_cid = self.resolve_snapshot(snapshot).await?;
+        self.restore_root(&root_cid).await
+    }
+
+    /// Replace the current state with the state of a root record, e.g. one that was synced
+    /// from another device.
+    pub async fn restore_root(&mut self, root_cid: &Cid) -> anyhow::Result<()> {
+        let root = StoredRoot::load(&self.store, root_cid)
+            .await?
+            .open(self.passphrase_key.as_ref())?;
+        let (forest, private_dir) = load_root_dir(&self.store, &root).await?;
+        self.heads = forest
+            .get_multivalue(&root.revision_ref, &self.store)
+            .count()
+            .await;
+        self.store
+            .alias(&private_root_alias(&self.name), Some(root_cid))
+            .await?;
+        self.forest = Rc::new(forest);
+        self.private_dir = private_dir;
+        self.journal_head = root.journal;
+        self.pending_ops.clear();
+        self.unflushed = 0;
+        #[cfg(feature = "search")]
+        if let Some(index) = &mut self.search_index {
+            index.rebuild_later();
+            self.update_search_index().await?;
+        }
+        Ok(())
+    }
+
+    /// Record committed operations in the journal from now on, attributed to `actor`.
+    ///
+    /// Once enabled, the journal is continued whenever the filesystem is opened, with
+    /// [`journal::default_actor`] as actor.
+    pub async fn enable_journal(&mut self, actor: String) -> anyhow::Result<()> {
+        let enabled = self.journal_actor.is_some();
+        self.journal_actor = Some(actor);
+        if !enabled {
+            self.record(JournalOp::Start, &[], None);
+            self.flush().await?;
+        }
+        Ok(())
+    }
+
+    /// Journaled operations, newest first, up to `limit`.
+    pub async fn journal(&self, limit: usize) -> anyhow::Result<Vec<JournalEntry>> {
+        journal::load(&self.store, self.journal_head, limit).await
+    }
+
+    fn record(&mut self, op: JournalOp, path: &[String], to: Option<&[String]>) {
+        self.unflushed += 1;
+        #[cfg(feature = "search")]
+        if let Some(index) = &mut self.search_index {
+            index.record(op, path, to);
+        }
+        if self.journal_actor.is_none() {
+            return;
+        }
+        self.pending_ops.push(PendingOp {
+            op,
+            path: path.to_vec(),
+            to: to.map(<[String]>::to_vec),
+            timestamp: Utc::now(),
+        });
+    }
+
+    /// Delete a snapshot. Its blocks are deleted by the next garbage collection run, unless
+    /// they are still reachable.
+    pub async fn delete_snapshot(&self, snapshot: &str) -> anyhow::Result<()> {
+        self.resolve_snapshot(snapshot).await?;
+        let alias = format!("{}{snapshot}", snapshot_prefix(&self.name));
+        self.store.alias(&alias, None).await?;
+        Ok(())
+    }
+
+    /// List the paths that differ between two revisions.
+    ///
+    /// A revision is the name of a snapshot, the CID of a root record, or `current`. Paths
+    /// inside added or removed directories are listed individually.
+    pub async fn diff(&self, from: &str, to: &str) -> anyhow::Result<Vec<Change>> {
+        let (from_forest, from_dir) = self.load_revision(from).await?;
+        let (to_forest, to_dir) = self.load_revision(to).await?;
+        let mut changes = vec![];
+        diff_nodes(
+            &self.store,
+            WnfsPath::root(),
+            Some((PrivateNode::Dir(from_dir), &*from_forest)),
+            Some((PrivateNode::Dir(to_dir), &*to_forest)),
+            &mut changes,
+        )
+        .await?;
+        Ok(changes)
+    }
+
+    /// Get information about the node at a path in a revision (see [`Self::diff`]).
+    pub async fn stat_in_revision(
+        &self,
+        revision: &str,
+        path_segments: &[String],
+    ) -> anyhow::Result<Option<DirEntry>> {
+        let (forest, dir) = self.load_revision(revision).await?;
+        let node = if path_segments.is_empty() {
+            Some(PrivateNode::Dir(dir))
+        } else {
+            dir.get_node(path_segments, false, &forest, &self.store)
+                .await?
+        };
+        let name = path_segments.last().cloned().unwrap_or_default();
+        Ok(node.map(|node| DirEntry::from_node(name, &node)))
+    }
+
+    /// Read a file as it was in a revision (see [`Self::diff`]).
+    pub async fn read_file_in_revision(
+        &self,
+        revision: &str,
+        path_segments: &[String],
+    ) -> anyhow::Result<Vec<u8>> {
+        let (forest, dir) = self.load_revision(revision).await?;
+        dir.read(path_segments, false, &forest, &self.store).await
+    }
+
+    async fn load_revision(
+        &self,
+        revision: &str,
+    ) -> anyhow::Result<(Rc<PrivateForest>, Rc<PrivateDirectory>)> {
+        if revision == "current" {
+            return Ok((self.forest.clone(), self.private_dir.clone()));
+        }
+        let root_cid = match self.resolve_snapshot(revision).await {
+            Ok(root_cid) => root_cid,
+            Err(err) => Cid::try_from(revision).map_err(|_| err)?,
+        };
+        let (forest, dir) = self.load_snapshot_dir(&root_cid).await?;
+        Ok((Rc::new(forest), dir))
+    }
+
+    async fn resolve_snapshot(&self, snapshot: &str) -> anyhow::Result<Cid> {
+        let alias = format!("{}{snapshot}", snapshot_prefix(&self.name));
+        self.store
+            .resolve_alias(&alias)
+            .await?
+            .ok_or_else(|| anyhow::anyhow!("Snapshot {snapshot} does not exist"))
+    }
+
+    async fn load_snapshot_dir(
+        &self,
+        root_cid: &Cid,
+    ) -> anyhow::Result<(PrivateForest, Rc<PrivateDirectory>)> {
+        let root = StoredRoot::load(&self.store, root_cid)
+            .await?
+            .open(self.passphrase_key.as_ref())?;
+        load_root_dir(&self.store, &root).await
+    }
+
+    /// Persist the mutations since the last flush as a new revision.
+    ///
+    /// Mutations only change the in-memory state, so they are lost if the filesystem is
+    /// dropped without a flush. Does nothing if there are no such mutations.
+    #[tracing::instrument(level = "debug", skip(self))]
     pub async fn flush(&mut self) -> anyhow::Result<()> {
+        if self.unflushed == 0 {
+            return Ok(());
+        }
+        self.commit().await?;
+        Ok(())
+    }
+
+    /// Remember the current state, to return to it with [`Self::rollback`] if a mutation does
+    /// not complete, e.g. because it timed out and its future was dropped.
+    ///
+    /// Mutations copy the nodes they change instead of changing them in place, so the nodes
+    /// of a checkpoint stay intact. Blocks that an abandoned mutation stored stay in the store
+    /// until they are garbage collected.
+    pub fn checkpoint(&self) -> Checkpoint {
+        Checkpoint {
+            forest: Rc::clone(&self.forest),
+            private_dir: Rc::clone(&self.private_dir),
+            journal_head: self.journal_head,
+            pending_ops: self.pending_ops.len(),
+            unflushed: self.unflushed,
+        }
+    }
+
+    /// Return to a checkpoint, dropping the mutations since.
+    ///
+    /// Do not roll back an abandoned [`Self::flush`]: nodes that it stored are marked as
+    /// stored in the current forest only. The filesystem stays dirty until a flush completes,
+    /// so the next flush retries it.
+    pub fn rollback(&mut self, checkpoint: Checkpoint) {
+        self.forest = checkpoint.forest;
+        self.private_dir = checkpoint.private_dir;
+        self.journal_head = checkpoint.journal_head;
+        self.pending_ops.truncate(checkpoint.pending_ops);
+        self.unflushed = checkpoint.unflushed;
+    }
+
+    /// Whether there are mutations that [`Self::flush`] would persist.
+    pub fn is_dirty(&self) -> bool {
+        self.unflushed > 0
+    }
+
+    /// Number of mutations since the last flush, which the next flush commits as one revision.
+    pub fn unflushed(&self) -> usize {
+        self.unflushed
+    }
+
+    /// Set whether mutations are flushed immediately.
+    ///
+    /// Autoflush is disabled by default: storing the directory, the forest and the root on
+    /// every mutation makes bulk changes slow, and creates a revision for each of them.
+    pub fn set_autoflush(&mut self, autoflush: bool) {
+        self.autoflush = autoflush;
+    }
+
+    /// Set the largest file size that [`Self::read_file`] reads into memory, in bytes.
+    ///
+    /// Larger files have to be read in ranges with [`Self::read_file_at`].
+    pub fn set_max_read_size(&mut self, max_read_size: u64) {
+        self.max_read_size = max_read_size;
+    }
+
+    /// The largest file size that is read into memory as a whole, in bytes.
+    pub fn max_read_size(&self) -> u64 {
+        self.max_read_size
+    }
+
+    /// Run a hook after every write and every read of a whole file, see [`hooks`].
+    pub fn add_hook(&mut self, hook: impl FileHook + 'static) {
+        self.hooks.push(Box::new(hook));
+    }
+
+    async fn maybe_flush(&mut self) -> anyhow::Result<()> {
+        if self.autoflush {
+            self.flush().await?;
+        }
+        Ok(())
+    }
+
+    async fn commit(&mut self) -> anyhow::Result<PrivateRoot> {
+        counter!("wnfs_flushes_total", 1);
         let mut rng = rand::rngs::OsRng;
-        // let forest = self.private_forest.clone();
         let private_ref = self
             .private_dir
             .store(&mut self.forest, &mut self.store, &mut rng)
-            .await
-            .unwrap();
+            .await?;
 
         // Persist encoded private forest to the block store.
-        let forest_cid = self
-            .store
-            .put_async_serializable(&self.forest)
-            .await
-            .unwrap();
+        let forest_cid = self.store.put_async_serializable(&self.forest).await?;
+        // The pending operations and the journal head only change once the root is stored, so
+        // that a failed commit is retried with them by the next flush.
+        let journal_head = match &self.journal_actor {
+            Some(actor) => {
+                let ops = self.pending_ops.clone();
+                journal::append(&mut self.store, self.journal_head, ops, forest_cid, actor)
+                    .await?
+            }
+            None => self.journal_head,
+        };
         let root = PrivateRoot {
             revision_ref: private_ref.as_revision_ref(),
             forest_cid,
+            journal: journal_head,
         };
         tracing::debug!("persist private root: {root:?}");
-        let private_root_alias = format!("{}{}", PRIVATE_ROOT_PREFIX, self.name);
-        let _cid = self
-            .store
-            .put_serializable_with_alias(&private_root_alias, &root)
-            .await?;
-        Ok(())
+        let _cid = store_private_root(
+            &mut self.store,
+            &self.name,
+            &root,
+            self.passphrase_key.as_ref(),
+        )
+        .await?;
+        self.journal_head = journal_head;
+        self.pending_ops.clear();
+        self.unflushed = 0;
+        // The root directory was stored as a new revision, which no other writer has.
+        self.heads = 1;
+        #[cfg(feature = "search")]
+        self.update_search_index().await?;
+        Ok(root)
     }
 
+    #[tracing::instrument(level = "debug", skip(self))]
     pub async fn mkdir(&mut self, path_segments: &[String]) -> anyhow::Result<()> {
         let mut rng = rand::rngs::OsRng;
         self.private_dir
@@ -118,15 +1181,20 @@ impl Wnfs {
                 &mut rng,
             )
             .await?;
-        self.flush().await?;
+        self.record(JournalOp::Mkdir, path_segments, None);
+        self.maybe_flush().await?;
         Ok(())
     }
 
+    #[tracing::instrument(level = "debug", skip(self, content), fields(len = content.len()))]
     pub async fn write_file(
         &mut self,
         path_segments: &[String],
         content: Vec<u8>,
     ) -> anyhow::Result<()> {
+        counter!("wnfs_written_bytes_total", content.len() as u64);
+        // Only copied if hooks need the content afterwards.
+        let hook_content = (!self.hooks.is_empty()).then(|| content.clone());
         let mut rng = rand::rngs::OsRng;
         self.private_dir
             .write(
@@ -139,16 +1207,179 @@ impl Wnfs {
                 &mut rng,
             )
             .await?;
-        self.flush().await?;
+        self.record(JournalOp::Write, path_segments, None);
+        self.maybe_flush().await?;
+        if let Some(content) = hook_content {
+            let event = FileEvent {
+                path: path_segments,
+                size: content.len() as u64,
+            };
+            for hook in &self.hooks {
+                hook.on_write(&event, hooks::content_stream(&content)).await?;
+            }
+        }
         Ok(())
     }
 
+    /// Write a file from a reader, encrypting the content into blocks as it is read, so that
+    /// large files do not have to fit into memory.
+    ///
+    /// Hooks need the whole content, so with hooks the content is read into memory first.
+    #[tracing::instrument(level = "debug", skip(self, content))]
+    pub async fn write_file_stream(
+        &mut self,
+        path_segments: &[String],
+        mut content: impl AsyncRead + Unpin,
+    ) -> anyhow::Result<()> {
+        if !self.hooks.is_empty() {
+            let mut buf = Vec::new();
+            content.read_to_end(&mut buf).await?;
+            return self.write_file(path_segments, buf).await;
+        }
+        if let Some(PrivateNode::Dir(_)) = self.get_node(path_segments).await? {
+            anyhow::bail!(FsError::IsADirectory);
+        }
+        let mut rng = rand::rngs::OsRng;
+        let file = self
+            .private_dir
+            .open_file_mut(
+                path_segments,
+                true,
+                Utc::now(),
+                &self.forest,
+                &self.store,
+                &mut rng,
+            )
+            .await?;
+        file.set_content(
+            Utc::now(),
+            content,
+            &mut self.forest,
+            &mut self.store,
+            &mut rng,
+        )
+        .await?;
+        let size = file.get_content_size_upper_bound();
+        counter!("wnfs_written_bytes_total", size as u64);
+        self.record(JournalOp::Write, path_segments, None);
+        self.maybe_flush().await
+    }
+
+    /// Create an empty file, or update the modification time of an existing node.
+    ///
+    /// Files are rewritten with their current content to bump the time.
+    pub async fn touch(&mut self, path_segments: &[String]) -> anyhow::Result<()> {
+        match self.get_node(path_segments).await? {
+            Some(PrivateNode::File(_)) => {
+                let content = self.read_file(path_segments).await?;
+                self.write_file(path_segments, content).await
+            }
+            Some(PrivateNode::Dir(_)) => self.mkdir(path_segments).await,
+            None => self.write_file(path_segments, vec![]).await,
+        }
+    }
+
+    /// Append to a file, creating it if it does not exist.
+    ///
+    /// File content cannot be extended in place, so this rewrites the whole file.
+    pub async fn append_file(
+        &mut self,
+        path_segments: &[String],
+        content: Vec<u8>,
+    ) -> anyhow::Result<()> {
+        let mut existing = match self.get_node(path_segments).await? {
+            Some(PrivateNode::File(_)) => self.read_file(path_segments).await?,
+            Some(PrivateNode::Dir(_)) => anyhow::bail!(FsError::IsADirectory),
+            None => vec![],
+        };
+        existing.extend(content);
+        self.write_file(path_segments, existing).await
+    }
+
+    /// Write `data` at `offset` into a file, creating it if it does not exist. A gap between
+    /// the end of the file and `offset` is filled with zeros.
+    ///
+    /// Like [`Self::append_file`], this rewrites the whole file.
+    pub async fn write_file_at(
+        &mut self,
+        path_segments: &[String],
+        offset: usize,
+        data: &[u8],
+    ) -> anyhow::Result<()> {
+        let mut content = self
+            .read_for_rewrite(path_segments)
+            .await?
+            .unwrap_or_default();
+        let end = offset + data.len();
+        if content.len() < end {
+            content.resize(end, 0);
+        }
+        content[offset..end].copy_from_slice(data);
+        self.write_file(path_segments, content).await
+    }
+
+    /// Cut a file to `size` bytes, or extend it with zeros.
+    ///
+    /// Like [`Self::append_file`], this rewrites the whole file, so `size` is limited like
+    /// reads into memory.
+    pub async fn truncate(&mut self, path_segments: &[String], size: usize) -> anyhow::Result<()> {
+        if size as u64 > self.max_read_size {
+            return Err(anyhow::Error::new(FsError::FileTooLarge).context(format!(
+                "Files cannot be extended beyond {} bytes",
+                self.max_read_size
+            )));
+        }
+        let Some(mut content) = self.read_for_rewrite(path_segments).await? else {
+            anyhow::bail!(FsError::NotFound);
+        };
+        content.resize(size, 0);
+        self.write_file(path_segments, content).await
+    }
+
+    /// The content of a file that is about to be rewritten, `None` if it does not exist.
+    async fn read_for_rewrite(&self, path_segments: &[String]) -> anyhow::Result<Option<Vec<u8>>> {
+        let file = match self.get_node(path_segments).await? {
+            Some(PrivateNode::File(file)) => file,
+            Some(PrivateNode::Dir(_)) => anyhow::bail!(FsError::IsADirectory),
+            None => return Ok(None),
+        };
+        let size = file.get_content_size_upper_bound();
+        if size as u64 > self.max_read_size {
+            return Err(anyhow::Error::new(FsError::FileTooLarge)
+                .context(format!("File is larger than {} bytes", self.max_read_size)));
+        }
+        // Without read hooks, which would run on every write of a mount.
+        Ok(Some(self.read_node_at(&file, 0, size).await?))
+    }
+
+    /// Read a whole file. Fails for files larger than the maximum set with
+    /// [`Self::set_max_read_size`].
+    #[tracing::instrument(level = "debug", skip(self))]
     pub async fn read_file(&self, path_segments: &[String]) -> anyhow::Result<Vec<u8>> {
-        self.private_dir
-            .read(path_segments, true, &self.forest, &self.store)
-            .await
+        let file = match self.get_node(path_segments).await? {
+            None => anyhow::bail!(FsError::NotFound),
+            Some(PrivateNode::Dir(_)) => anyhow::bail!(FsError::IsADirectory),
+            Some(PrivateNode::File(file)) => file,
+        };
+        let size = file.get_content_size_upper_bound() as u64;
+        if size > self.max_read_size {
+            return Err(anyhow::Error::new(FsError::FileTooLarge).context(format!(
+                "File is larger than {} bytes, read it in ranges instead",
+                self.max_read_size
+            )));
+        }
+        let content = self.read_node_at(&file, 0, usize::MAX).await?;
+        let event = FileEvent {
+            path: path_segments,
+            size: content.len() as u64,
+        };
+        for hook in &self.hooks {
+            hook.on_read(&event, hooks::content_stream(&content)).await?;
+        }
+        Ok(content)
     }
 
+    #[tracing::instrument(level = "debug", skip(self))]
     pub async fn read_file_at(
         &self,
         path_segments: &[String],
@@ -157,32 +1388,830 @@ impl Wnfs {
     ) -> anyhow::Result<Vec<u8>> {
         let node = self.get_node(&path_segments).await?;
         match node {
-            None => Err(anyhow::anyhow!("Not found")),
-            Some(PrivateNode::Dir(_)) => Err(anyhow::anyhow!("Is a directory, not a file")),
-            Some(PrivateNode::File(file)) => {
-                file.read_at(offset, size, &self.forest, &self.store)
-                    .await
-            }
+            None => Err(FsError::NotFound.into()),
+            Some(PrivateNode::Dir(_)) => Err(FsError::IsADirectory.into()),
+            Some(PrivateNode::File(file)) => self.read_node_at(&file, offset, size).await,
         }
     }
 
+    /// Read a range of a file node that was resolved before, e.g. by [`Self::get_node`].
+    ///
+    /// Blocks are fetched and decrypted concurrently, up to [`READ_AHEAD_BLOCKS`] ahead, so
+    /// that large reads are not bound by the latency of each block.
+    pub async fn read_node_at(
+        &self,
+        file: &PrivateFile,
+        offset: usize,
+        size: usize,
+    ) -> anyhow::Result<Vec<u8>> {
+        self.content_reader().read_at(file, offset, size).await
+    }
+
+    /// A reader of file nodes that does not borrow the filesystem, to read while other
+    /// operations run.
+    pub fn content_reader(&self) -> ContentReader<S> {
+        ContentReader {
+            forest: Rc::clone(&self.forest),
+            store: self.store.clone(),
+        }
+    }
+
+    #[tracing::instrument(level = "debug", skip(self))]
     pub async fn ls(&self, path_segments: &[String]) -> anyhow::Result<Vec<(String, Metadata)>> {
         self.private_dir
             .ls(path_segments, false, &self.forest, &self.store)
             .await
     }
 
+    /// List the nodes in a directory.
+    #[tracing::instrument(level = "debug", skip(self))]
+    pub async fn ls_nodes(
+        &self,
+        path_segments: &[String],
+    ) -> anyhow::Result<Vec<(String, PrivateNode)>> {
+        let Some(PrivateNode::Dir(dir)) = self.get_node_or_root(path_segments).await? else {
+            anyhow::bail!(FsError::NotADirectory);
+        };
+        let mut nodes = vec![];
+        for name in dir.entries() {
+            if let Some(node) = self.lookup_child(&dir, name).await? {
+                nodes.push((name.clone(), node));
+            }
+        }
+        Ok(nodes)
+    }
+
+    /// Look up an entry of a directory node, without resolving its path from the root again.
+    pub async fn lookup_child(
+        &self,
+        dir: &PrivateDirectory,
+        name: &str,
+    ) -> anyhow::Result<Option<PrivateNode>> {
+        let node = dir
+            .lookup_node(name, false, &self.forest, &self.store)
+            .await?;
+        Ok(node)
+    }
+
+    /// List the entries of a directory with their kind, size and timestamps.
+    pub async fn ls_entries(&self, path_segments: &[String]) -> anyhow::Result<Vec<DirEntry>> {
+        let nodes = self.ls_nodes(path_segments).await?;
+        let entries = nodes
+            .into_iter()
+            .map(|(name, node)| DirEntry::from_node(name, &node))
+            .collect();
+        Ok(entries)
+    }
+
+    /// Get information about the node at a path.
+    pub async fn stat(&self, path_segments: &[String]) -> anyhow::Result<Option<DirEntry>> {
+        let node = self.get_node_or_root(path_segments).await?;
+        let name = path_segments.last().cloned().unwrap_or_default();
+        Ok(node.map(|node| DirEntry::from_node(name, &node)))
+    }
+
+    /// Get the metadata of a node, including the built-in `created` and `modified` times.
+    pub async fn get_metadata(
+        &self,
+        path_segments: &[String],
+    ) -> anyhow::Result<BTreeMap<String, serde_json::Value>> {
+        let node = self
+            .get_node_or_root(path_segments)
+            .await?
+            .ok_or(FsError::NotFound)?;
+        let metadata = match &node {
+            PrivateNode::File(file) => file.get_metadata(),
+            PrivateNode::Dir(dir) => dir.get_metadata(),
+        };
+        // Round-trip through dag-cbor to get at the generic map.
+        let bytes = serde_ipld_dagcbor::to_vec(metadata)?;
+        Ok(serde_ipld_dagcbor::from_slice(&bytes)?)
+    }
+
+    /// Set a metadata key of a file to a string value.
+    pub async fn set_metadata(
+        &mut self,
+        path_segments: &[String],
+        key: &str,
+        value: &str,
+    ) -> anyhow::Result<()> {
+        if matches!(key, "created" | "modified" | MODE_KEY) {
+            anyhow::bail!("Metadata key {key} is reserved");
+        }
+        let value = Ipld::String(value.to_string());
+        self.put_file_metadata(path_segments, vec![(key, value)])
+            .await
+    }
+
+    /// Set the permission bits of a file.
+    ///
+    /// Modes are stored in the metadata and reported by the FUSE and NFS servers. Setting the
+    /// mode of directories is not supported yet.
+    pub async fn set_mode(&mut self, path_segments: &[String], mode: u32) -> anyhow::Result<()> {
+        let mode = Ipld::Integer((mode & 0o7777) as i128);
+        self.put_file_metadata(path_segments, vec![(MODE_KEY, mode)])
+            .await
+    }
+
+    /// Set the modification and creation times of a file, leaving those that are `None`.
+    pub async fn set_times(
+        &mut self,
+        path_segments: &[String],
+        modified: Option<DateTime<Utc>>,
+        created: Option<DateTime<Utc>>,
+    ) -> anyhow::Result<()> {
+        // Stored like wnfs stores them, in seconds.
+        let entries = [("modified", modified), ("created", created)]
+            .into_iter()
+            .filter_map(|(key, time)| Some((key, Ipld::Integer(time?.timestamp() as i128))))
+            .collect();
+        self.put_file_metadata(path_segments, entries).await
+    }
+
+    async fn put_file_metadata(
+        &mut self,
+        path_segments: &[String],
+        entries: Vec<(&str, Ipld)>,
+    ) -> anyhow::Result<()> {
+        match self.get_node(path_segments).await? {
+            Some(PrivateNode::File(_)) => {}
+            Some(PrivateNode::Dir(_)) => anyhow::bail!(FsError::IsADirectory),
+            None => anyhow::bail!(FsError::NotFound),
+        }
+        let mut rng = rand::rngs::OsRng;
+        let file = self
+            .private_dir
+            .open_file_mut(
+                path_segments,
+                true,
+                Utc::now(),
+                &self.forest,
+                &self.store,
+                &mut rng,
+            )
+            .await?;
+        for (key, value) in entries {
+            file.get_metadata_mut().put(key, value);
+        }
+        self.record(JournalOp::SetMetadata, path_segments, None);
+        self.maybe_flush().await
+    }
+
+    /// Remove a file or directory (including its contents).
+    #[tracing::instrument(level = "debug", skip(self))]
+    pub async fn rm(&mut self, path_segments: &[String]) -> anyhow::Result<()> {
+        self.private_dir
+            .rm(path_segments, true, &self.forest, &self.store)
+            .await?;
+        self.record(JournalOp::Remove, path_segments, None);
+        self.maybe_flush().await?;
+        Ok(())
+    }
+
+    /// Move a file or directory. Fails if the target exists.
+    #[tracing::instrument(level = "debug", skip(self))]
+    pub async fn mv(&mut self, from: &[String], to: &[String]) -> anyhow::Result<()> {
+        if self.get_node(to).await?.is_some() {
+            anyhow::bail!(FsError::AlreadyExists);
+        }
+        let mut rng = rand::rngs::OsRng;
+        self.private_dir
+            .basic_mv(
+                from,
+                to,
+                true,
+                Utc::now(),
+                &self.forest,
+                &self.store,
+                &mut rng,
+            )
+            .await?;
+        self.record(JournalOp::Move, from, Some(to));
+        self.maybe_flush().await?;
+        Ok(())
+    }
+
+    /// Local name of the filesystem.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The store of this filesystem.
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    /// CID of the last flushed root record.
+    pub async fn root_cid(&self) -> anyhow::Result<Option<Cid>> {
+        self.store.resolve_alias(&private_root_alias(&self.name)).await
+    }
+
     pub fn private_root(&self) -> Rc<PrivateDirectory> {
         Rc::clone(&self.private_dir)
     }
 
+    /// The node at a path, `None` if there is none.
+    ///
+    /// Fails with [`FsError::NotADirectory`] if one of the parents is a file.
+    #[tracing::instrument(level = "debug", skip(self))]
     pub async fn get_node(&self, path_segments: &[String]) -> anyhow::Result<Option<PrivateNode>> {
-        self.private_dir
+        let result = self
+            .private_dir
             .get_node(path_segments, false, &self.forest, &self.store)
-            .await
+            .await;
+        if !matches!(result, Ok(Some(_))) && self.has_file_parent(path_segments).await {
+            anyhow::bail!(FsError::NotADirectory);
+        }
+        result
+    }
+
+    /// Whether the closest parent of a path that exists is a file, which wnfs does not tell
+    /// apart from a missing path.
+    async fn has_file_parent(&self, path_segments: &[String]) -> bool {
+        for end in (1..path_segments.len()).rev() {
+            match self
+                .private_dir
+                .get_node(&path_segments[..end], false, &self.forest, &self.store)
+                .await
+            {
+                Ok(Some(PrivateNode::File(_))) => return true,
+                Ok(Some(PrivateNode::Dir(_))) => return false,
+                _ => {}
+            }
+        }
+        false
+    }
+
+    /// Like [`Self::get_node`], but returns the root directory for an empty path.
+    pub async fn get_node_or_root(
+        &self,
+        path_segments: &[String],
+    ) -> anyhow::Result<Option<PrivateNode>> {
+        if path_segments.is_empty() {
+            Ok(Some(PrivateNode::Dir(self.private_root())))
+        } else {
+            self.get_node(path_segments).await
+        }
+    }
+
+    /// Compute the recursive disk usage of the node at a path.
+    pub async fn du(&self, path_segments: &[String]) -> anyhow::Result<DiskUsage> {
+        let node = self
+            .get_node_or_root(path_segments)
+            .await?
+            .ok_or(FsError::NotFound)?;
+        self.du_node(&node).await
+    }
+
+    /// Check the filesystem for missing blocks, undecryptable nodes and orphaned revisions.
+    pub async fn verify(&self) -> anyhow::Result<VerifyReport> {
+        let mut report = VerifyReport::default();
+        if let Some(cid) = self
+            .store
+            .resolve_alias(&private_root_alias(&self.name))
+            .await?
+        {
+            report.missing_blocks = self.store.missing_blocks(&cid).await?;
+        }
+        if let Some(root) = self.load_private_root().await? {
+            let revisions: Vec<_> = self
+                .forest
+                .get_multivalue(&root.revision_ref, &self.store)
+                .collect()
+                .await;
+            report.orphaned_revisions = revisions.len().saturating_sub(1);
+        }
+        let root = PrivateNode::Dir(self.private_root());
+        self.verify_node(&root, WnfsPath::root(), &mut report).await;
+        Ok(report)
+    }
+
+    /// Load the persisted root record of this filesystem.
+    async fn load_private_root(&self) -> anyhow::Result<Option<PrivateRoot>> {
+        match load_stored_root(&self.store, &self.name).await? {
+            None => Ok(None),
+            Some(StoredRoot::Plain(root)) => Ok(Some(root)),
+            Some(StoredRoot::Protected(protected)) => {
+                let key = self
+                    .passphrase_key
+                    .as_ref()
+                    .ok_or_else(|| anyhow::anyhow!("Missing passphrase key"))?;
+                Ok(Some(protected.open(key)?))
+            }
+        }
+    }
+
+    /// Number of heads when the filesystem was opened or last flushed, see [`Self::heads`].
+    pub fn head_count(&self) -> usize {
+        self.heads
+    }
+
+    /// The revisions of the root directory that the root record points to.
+    ///
+    /// There is more than one head if writers diverged, e.g. when two devices changed the
+    /// same revision or a crashed writer left a revision behind. Only the first head is
+    /// opened, the others are hidden until they are resolved with [`Self::choose_head`] or
+    /// [`Self::merge_heads`].
+    pub async fn heads(&self) -> anyhow::Result<Vec<HeadInfo>> {
+        let heads = self.load_heads().await?;
+        Ok(heads
+            .iter()
+            .enumerate()
+            .map(|(index, dir)| HeadInfo {
+                index,
+                modified: dir.get_metadata().get_modified(),
+            })
+            .collect())
+    }
+
+    /// Continue from the head at `index` of [`Self::heads`] and drop the others.
+    ///
+    /// The dropped heads stay in the store until they are garbage collected.
+    pub async fn choose_head(&mut self, index: usize) -> anyhow::Result<()> {
+        if self.is_dirty() {
+            anyhow::bail!("Flush the filesystem before choosing a head");
+        }
+        let mut heads = self.divergent_heads().await?;
+        let count = heads.len();
+        if index >= count {
+            anyhow::bail!("There is no head {index}, the filesystem has {count} heads");
+        }
+        self.private_dir = heads.swap_remove(index);
+        self.supersede_heads().await
+    }
+
+    /// Merge the other heads into the current state.
+    ///
+    /// Paths that only exist in other heads are added. Files that differ are added as
+    /// conflict copies, e.g. `notes (conflict 1).txt`, whose paths are returned. Paths that
+    /// are missing from other heads are kept.
+    pub async fn merge_heads(&mut self) -> anyhow::Result<Vec<WnfsPath>> {
+        let heads = self.divergent_heads().await?;
+        let forest = Rc::clone(&self.forest);
+        let mut copies = vec![];
+        for head in heads.iter().skip(1) {
+            let mut changes = vec![];
+            diff_nodes(
+                &self.store,
+                WnfsPath::root(),
+                Some((PrivateNode::Dir(self.private_root()), &*forest)),
+                Some((PrivateNode::Dir(Rc::clone(head)), &*forest)),
+                &mut changes,
+            )
+            .await?;
+            // Directories of the head that are files here, and the copies that take their
+            // content instead.
+            let mut moved: Vec<(WnfsPath, WnfsPath)> = vec![];
+            for change in changes {
+                if change.kind == ChangeKind::Removed {
+                    continue;
+                }
+                let path = match moved.iter().find(|(dir, _)| change.path.starts_with(dir)) {
+                    Some((dir, copy)) => WnfsPath::from_segments(
+                        [copy.segments(), &change.path[dir.len()..]].concat(),
+                    ),
+                    None => change.path.clone(),
+                };
+                let local = self.stat(&path).await?;
+                if change.entry_kind == EntryKind::Dir {
+                    match local {
+                        Some(local) if local.kind == EntryKind::Dir => {}
+                        Some(_) => {
+                            let copy = self.conflict_copy_path(&path).await?;
+                            self.mkdir(&copy).await?;
+                            moved.push((change.path, copy.clone()));
+                            copies.push(copy);
+                        }
+                        None => self.mkdir(&path).await?,
+                    }
+                    continue;
+                }
+                let theirs = match head
+                    .get_node(&change.path, false, &forest, &self.store)
+                    .await?
+                {
+                    Some(PrivateNode::File(file)) => file,
+                    _ => anyhow::bail!("Failed to load {} from a head", change.path),
+                };
+                if local.is_some() && self.has_content(&path, &theirs).await? {
+                    continue;
+                }
+                let content = head.read(&change.path, false, &forest, &self.store).await?;
+                match local {
+                    None => self.write_file(&path, content).await?,
+                    Some(_) => {
+                        let copy = self.conflict_copy_path(&path).await?;
+                        self.write_file(&copy, content).await?;
+                        copies.push(copy);
+                    }
+                }
+            }
+        }
+        self.supersede_heads().await?;
+        Ok(copies)
+    }
+
+    /// Whether the file at `path` has the same content as `file`.
+    ///
+    /// Compared block by block, so that files beyond [`Self::max_read_size`] can be compared.
+    async fn has_content(&self, path: &[String], file: &PrivateFile) -> anyhow::Result<bool> {
+        let Some(PrivateNode::File(local)) = self.get_node(path).await? else {
+            return Ok(false);
+        };
+        let reader = self.content_reader();
+        let mut index = 0;
+        loop {
+            let (ours, theirs) = future::try_join(
+                reader.read_block(&local, index),
+                reader.read_block(file, index),
+            )
+            .await?;
+            if ours != theirs {
+                return Ok(false);
+            }
+            if ours.is_none() {
+                return Ok(true);
+            }
+            index += 1;
+        }
+    }
+
+    /// A free path next to `path` for another version of a conflicting file, e.g.
+    /// `notes (conflict 1).txt`.
+    pub(crate) async fn conflict_copy_path(&self, path: &WnfsPath) -> anyhow::Result<WnfsPath> {
+        let (Some(name), Some(parent)) = (path.file_name(), path.parent()) else {
+            anyhow::bail!("The root directory cannot have a conflict copy");
+        };
+        let (stem, extension) = match name.rsplit_once('.') {
+            Some((stem, extension)) if !stem.is_empty() => (stem, format!(".{extension}")),
+            _ => (name, String::new()),
+        };
+        for i in 1..=MAX_CONFLICT_COPIES {
+            let copy = parent.join(format!("{stem} (conflict {i}){extension}"));
+            if self.get_node(&copy).await?.is_none() {
+                return Ok(copy);
+            }
+        }
+        anyhow::bail!("{path} already has {MAX_CONFLICT_COPIES} conflict copies")
+    }
+
+    /// Load the heads, failing if there is only one.
+    async fn divergent_heads(&self) -> anyhow::Result<Vec<Rc<PrivateDirectory>>> {
+        let heads = self.load_heads().await?;
+        if heads.len() < 2 {
+            anyhow::bail!("Filesystem {} has no divergent heads", self.name);
+        }
+        Ok(heads)
+    }
+
+    async fn load_heads(&self) -> anyhow::Result<Vec<Rc<PrivateDirectory>>> {
+        let root = self
+            .load_private_root()
+            .await?
+            .ok_or_else(|| anyhow::anyhow!("Filesystem {} has no root record", self.name))?;
+        let nodes: Vec<_> = self
+            .forest
+            .get_multivalue(&root.revision_ref, &self.store)
+            .collect()
+            .await;
+        nodes.into_iter().map(|node| node?.as_dir()).collect()
+    }
+
+    /// Move the root directory to a new revision, which follows all heads once it is flushed.
+    async fn supersede_heads(&mut self) -> anyhow::Result<()> {
+        let mut rng = rand::rngs::OsRng;
+        // Creating the root directory only prepares its next revision.
+        self.private_dir
+            .mkdir(&[], true, Utc::now(), &self.forest, &self.store, &mut rng)
+            .await?;
+        self.record(JournalOp::Resolve, &[], None);
+        self.maybe_flush().await
+    }
+
+    /// Share a directory with the owner of an exchange key.
+    ///
+    /// Returns the share label, which the recipient passes to [`Self::accept_share`].
+    pub async fn share(
+        &mut self,
+        path_segments: &[String],
+        recipient: &PublicKey,
+    ) -> anyhow::Result<Cid> {
+        let node = self
+            .get_node_or_root(path_segments)
+            .await?
+            .ok_or(FsError::NotFound)?;
+        if !matches!(node, PrivateNode::Dir(_)) {
+            anyhow::bail!("Only directories can be shared");
+        }
+        let mut rng = rand::rngs::OsRng;
+        let private_ref = node
+            .store(&mut self.forest, &mut self.store, &mut rng)
+            .await?;
+        let root = self.commit().await?;
+        let shared_root = PrivateRoot {
+            forest_cid: root.forest_cid,
+            revision_ref: private_ref.as_revision_ref(),
+            journal: None,
+        };
+        let payload = serde_ipld_dagcbor::to_vec(&shared_root)?;
+        let sealed = share::seal(recipient, &payload)?;
+        let label = self.store.put_block(sealed, IpldCodec::Raw).await?;
+        // Pin the share block so that it survives garbage collection.
+        self.store
+            .alias(&format!("{SHARE_PREFIX}{label}"), Some(&label))
+            .await?;
+        Ok(label)
+    }
+
+    fn verify_node<'a>(
+        &'a self,
+        node: &'a PrivateNode,
+        path: WnfsPath,
+        report: &'a mut VerifyReport,
+    ) -> LocalBoxFuture<'a, ()> {
+        async move {
+            let PrivateNode::Dir(dir) = node else {
+                return;
+            };
+            for name in dir.entries() {
+                let child_path = path.join(name);
+                match dir
+                    .lookup_node(name, false, &self.forest, &self.store)
+                    .await
+                {
+                    Ok(Some(child)) => self.verify_node(&child, child_path, report).await,
+                    Ok(None) => report
+                        .undecryptable
+                        .push((child_path, "entry not found in forest".to_string())),
+                    Err(err) => report.undecryptable.push((child_path, err.to_string())),
+                }
+            }
+        }
+        .boxed_local()
+    }
+
+    /// Visit all nodes below a path (not including the path itself) in depth-first order.
+    ///
+    /// Nodes are loaded one at a time while walking, so this works on arbitrarily large trees.
+    pub async fn walk(
+        &self,
+        path_segments: &[String],
+        visit: &mut dyn FnMut(&[String], &DirEntry) -> anyhow::Result<()>,
+    ) -> anyhow::Result<()> {
+        let node = self
+            .get_node_or_root(path_segments)
+            .await?
+            .ok_or(FsError::NotFound)?;
+        self.walk_node(path_segments.into(), &node, visit).await
+    }
+
+    fn walk_node<'a>(
+        &'a self,
+        path: WnfsPath,
+        node: &'a PrivateNode,
+        visit: &'a mut dyn FnMut(&[String], &DirEntry) -> anyhow::Result<()>,
+    ) -> LocalBoxFuture<'a, anyhow::Result<()>> {
+        async move {
+            let PrivateNode::Dir(dir) = node else {
+                return Ok(());
+            };
+            for name in dir.entries() {
+                let child = dir
+                    .lookup_node(name, false, &self.forest, &self.store)
+                    .await?;
+                if let Some(child) = child {
+                    let child_path = path.join(name);
+                    visit(&child_path, &DirEntry::from_node(name.clone(), &child))?;
+                    self.walk_node(child_path, &child, visit).await?;
+                }
+            }
+            Ok(())
+        }
+        .boxed_local()
+    }
+
+    fn du_node<'a>(
+        &'a self,
+        node: &'a PrivateNode,
+    ) -> LocalBoxFuture<'a, anyhow::Result<DiskUsage>> {
+        async move {
+            let mut usage = DiskUsage::default();
+            match node {
+                PrivateNode::File(file) => {
+                    usage.files = 1;
+                    usage.size = file.get_content_size_upper_bound() as u64;
+                }
+                PrivateNode::Dir(dir) => {
+                    usage.dirs = 1;
+                    for name in dir.entries() {
+                        let child = dir
+                            .lookup_node(name, false, &self.forest, &self.store)
+                            .await?;
+                        if let Some(child) = child {
+                            usage.add(&self.du_node(&child).await?);
+                        }
+                    }
+                }
+            }
+            Ok(usage)
+        }
+        .boxed_local()
+    }
+}
+
+#[cfg(feature = "search")]
+impl<S: Store> Wnfs<S> {
+    /// Keep a search index up to date with the changes of every flush.
+    pub fn set_search_index(&mut self, index: SearchIndex) {
+        self.search_index = Some(index);
+    }
+
+    /// Find files by name or text content, best matches first.
+    pub fn search(&self, query: &str, limit: usize) -> anyhow::Result<Vec<SearchHit>> {
+        self.search_index
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("No search index"))?
+            .search(query, limit)
+    }
+
+    /// Index the whole filesystem again, e.g. after creating the index.
+    pub async fn rebuild_search_index(&mut self) -> anyhow::Result<()> {
+        if let Some(index) = &mut self.search_index {
+            index.rebuild_later();
+        }
+        self.update_search_index().await
+    }
+
+    async fn update_search_index(&mut self) -> anyhow::Result<()> {
+        // Taken out of self while indexing, which reads files.
+        let Some(mut index) = self.search_index.take() else {
+            return Ok(());
+        };
+        let result = self.apply_index_changes(&mut index).await;
+        self.search_index = Some(index);
+        result
+    }
+
+    async fn apply_index_changes(&self, index: &mut SearchIndex) -> anyhow::Result<()> {
+        let changes = index.take_pending();
+        if changes.is_empty() {
+            return Ok(());
+        }
+        for change in changes {
+            match change {
+                IndexChange::Update(path) => {
+                    index.remove(&path);
+                    self.index_tree(index, &path).await?;
+                }
+                IndexChange::Remove(path) => index.remove(&path),
+                IndexChange::Rebuild => {
+                    index.remove_all()?;
+                    self.index_tree(index, &[]).await?;
+                }
+            }
+        }
+        index.commit()
+    }
+
+    /// Add the file at a path, or all files below a directory.
+    async fn index_tree(&self, index: &mut SearchIndex, path: &[String]) -> anyhow::Result<()> {
+        let Some(node) = self.get_node_or_root(path).await? else {
+            return Ok(());
+        };
+        let mut files = vec![];
+        match &node {
+            PrivateNode::File(_) => {
+                let entry = DirEntry::from_node(String::new(), &node);
+                files.push((path.to_vec(), entry.size));
+            }
+            PrivateNode::Dir(_) => {
+                self.walk(path, &mut |path, entry| {
+                    if matches!(entry.kind, EntryKind::File) {
+                        files.push((path.to_vec(), entry.size));
+                    }
+                    Ok(())
+                })
+                .await?
+            }
+        }
+        for (path, size) in files {
+            let content = if size <= search::MAX_INDEXED_SIZE {
+                Some(self.read_file(&path).await?)
+            } else {
+                None
+            };
+            index.add_file(&path, content.as_deref())?;
+        }
+        Ok(())
     }
 }
 
+type SideOfDiff<'a> = Option<(PrivateNode, &'a PrivateForest)>;
+
+fn diff_nodes<'a, S: Store>(
+    store: &'a S,
+    path: WnfsPath,
+    before: SideOfDiff<'a>,
+    after: SideOfDiff<'a>,
+    changes: &'a mut Vec<Change>,
+) -> LocalBoxFuture<'a, anyhow::Result<()>> {
+    async move {
+        match (before, after) {
+            (Some((PrivateNode::File(a), _)), Some((PrivateNode::File(b), _))) => {
+                // Unchanged files load to the same header revision and content.
+                if a != b {
+                    changes.push(Change {
+                        path,
+                        kind: ChangeKind::Modified,
+                        entry_kind: EntryKind::File,
+                        size_before: a.get_content_size_upper_bound() as u64,
+                        size_after: b.get_content_size_upper_bound() as u64,
+                    });
+                }
+            }
+            (Some((PrivateNode::Dir(a), a_forest)), Some((PrivateNode::Dir(b), b_forest))) => {
+                let mut a_children = dir_children(store, &a, a_forest).await?;
+                let mut b_children = dir_children(store, &b, b_forest).await?;
+                let names = a_children
+                    .keys()
+                    .chain(b_children.keys())
+                    .cloned()
+                    .collect::<BTreeSet<_>>();
+                for name in names {
+                    let child_path = path.join(&name);
+                    let before = a_children.remove(&name).map(|node| (node, a_forest));
+                    let after = b_children.remove(&name).map(|node| (node, b_forest));
+                    diff_nodes(store, child_path, before, after, changes).await?;
+                }
+            }
+            (Some(before), Some(after)) => {
+                // A file was replaced by a directory or the other way round.
+                diff_nodes(store, path.clone(), Some(before), None, changes).await?;
+                diff_nodes(store, path, None, Some(after), changes).await?;
+            }
+            (Some((node, forest)), None) => {
+                push_subtree(store, path, &node, forest, ChangeKind::Removed, changes).await?;
+            }
+            (None, Some((node, forest))) => {
+                push_subtree(store, path, &node, forest, ChangeKind::Added, changes).await?;
+            }
+            (None, None) => {}
+        }
+        Ok(())
+    }
+    .boxed_local()
+}
+
+/// Record a node and all of its descendants as added or removed.
+fn push_subtree<'a, S: Store>(
+    store: &'a S,
+    path: WnfsPath,
+    node: &'a PrivateNode,
+    forest: &'a PrivateForest,
+    kind: ChangeKind,
+    changes: &'a mut Vec<Change>,
+) -> LocalBoxFuture<'a, anyhow::Result<()>> {
+    async move {
+        let (entry_kind, size) = match node {
+            PrivateNode::File(file) => (EntryKind::File, file.get_content_size_upper_bound()),
+            PrivateNode::Dir(_) => (EntryKind::Dir, 0),
+        };
+        let (size_before, size_after) = match kind {
+            ChangeKind::Removed => (size as u64, 0),
+            _ => (0, size as u64),
+        };
+        changes.push(Change {
+            path: path.clone(),
+            kind,
+            entry_kind,
+            size_before,
+            size_after,
+        });
+        if let PrivateNode::Dir(dir) = node {
+            for (name, child) in dir_children(store, dir, forest).await? {
+                let child_path = path.join(name);
+                push_subtree(store, child_path, &child, forest, kind, changes).await?;
+            }
+        }
+        Ok(())
+    }
+    .boxed_local()
+}
+
+async fn dir_children(
+    store: &impl Store,
+    dir: &PrivateDirectory,
+    forest: &PrivateForest,
+) -> anyhow::Result<BTreeMap<String, PrivateNode>> {
+    let mut children = BTreeMap::new();
+    for name in dir.entries() {
+        if let Some(node) = dir.lookup_node(name, false, forest, store).await? {
+            children.insert(name.clone(), node);
+        }
+    }
+    Ok(children)
+}
+
 async fn create_private_dir(
     store: &mut impl BlockStore,
     rng: &mut impl RngCore,
@@ -204,5 +2233,209 @@ async fn create_private_dir(
     Ok(PrivateRoot {
         revision_ref: private_ref.as_revision_ref(),
         forest_cid,
+        journal: None,
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+
+    use super::*;
+    use crate::store::MemoryStore;
+
+    fn path(path: &str) -> WnfsPath {
+        WnfsPath::parse(path).unwrap()
+    }
+
+    async fn reopen(fs: &Wnfs<MemoryStore>) -> anyhow::Result<Wnfs<MemoryStore>> {
+        Wnfs::open_in_store(fs.store().clone(), fs.name().to_string(), None).await
+    }
+
+    /// A filesystem whose root has two heads: `files` were written to one of them and
+    /// `other_files` to the other, as if two writers started from the same revision.
+    async fn diverged(
+        files: &[(&str, &str)],
+        other_files: &[(&str, &str)],
+    ) -> anyhow::Result<Wnfs<MemoryStore>> {
+        let mut fs = Wnfs::init_in_store(MemoryStore::new(), "test".to_string(), None).await?;
+        let base = fs.private_root();
+        for (name, content) in files {
+            fs.write_file(&path(name), content.into()).await?;
+        }
+        fs.flush().await?;
+        // The next revision of the same directory again, stored into the same forest.
+        fs.private_dir = base;
+        for (name, content) in other_files {
+            fs.write_file(&path(name), content.into()).await?;
+        }
+        fs.flush().await?;
+        reopen(&fs).await
+    }
+
+    #[test]
+    fn mutations_are_durable_after_flush() {
+        block_on(async {
+            let mut fs = Wnfs::init_in_store(MemoryStore::new(), "test".to_string(), None).await?;
+            fs.write_file(&path("a.txt"), b"a".to_vec()).await?;
+            assert_eq!(fs.unflushed(), 1);
+            assert!(reopen(&fs).await?.stat(&path("a.txt")).await?.is_none());
+
+            fs.flush().await?;
+            assert!(!fs.is_dirty());
+            assert_eq!(reopen(&fs).await?.read_file(&path("a.txt")).await?, b"a");
+            anyhow::Ok(())
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn completes_interrupted_commits() {
+        block_on(async {
+            let mut fs = Wnfs::init_in_store(MemoryStore::new(), "test".to_string(), None).await?;
+            let store = fs.store().clone();
+            let root_alias = private_root_alias("test");
+            let before = store.resolve_alias(&root_alias).await?;
+            fs.write_file(&path("a.txt"), b"a".to_vec()).await?;
+            fs.flush().await?;
+            let record = store.resolve_alias(&root_alias).await?.unwrap();
+
+            // Crashed after the record was durable, before the root alias pointed to it.
+            store.alias(&root_alias, before.as_ref()).await?;
+            store.alias(&commit_alias("test"), Some(&record)).await?;
+            assert!(Wnfs::recover_commit(&store, "test").await?);
+            assert_eq!(store.resolve_alias(&root_alias).await?, Some(record));
+            assert_eq!(store.resolve_alias(&commit_alias("test")).await?, None);
+            assert!(!Wnfs::recover_commit(&store, "test").await?);
+
+            assert_eq!(reopen(&fs).await?.read_file(&path("a.txt")).await?, b"a");
+            anyhow::Ok(())
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn rolls_back_commits_with_missing_blocks() {
+        block_on(async {
+            let mut fs = Wnfs::init_in_store(MemoryStore::new(), "test".to_string(), None).await?;
+            let mut store = fs.store().clone();
+            let root_alias = private_root_alias("test");
+            let before = store.resolve_alias(&root_alias).await?;
+
+            // A record of a forest that never made it into the store.
+            let mut root = fs.load_private_root().await?.unwrap();
+            root.forest_cid = MemoryStore::new()
+                .put_block(b"lost".to_vec(), IpldCodec::Raw)
+                .await?;
+            let record = store
+                .put_block(serde_ipld_dagcbor::to_vec(&root)?, IpldCodec::DagCbor)
+                .await?;
+            store.alias(&commit_alias("test"), Some(&record)).await?;
+            assert!(Wnfs::recover_commit(&store, "test").await?);
+            assert_eq!(store.resolve_alias(&root_alias).await?, before);
+            assert_eq!(store.resolve_alias(&commit_alias("test")).await?, None);
+
+            // The filesystem still opens at its last complete commit.
+            fs.write_file(&path("a.txt"), b"a".to_vec()).await?;
+            fs.flush().await?;
+            assert_eq!(reopen(&fs).await?.read_file(&path("a.txt")).await?, b"a");
+            anyhow::Ok(())
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn detects_divergent_heads() {
+        block_on(async {
+            let fs = diverged(&[("a.txt", "a")], &[("b.txt", "b")]).await?;
+            assert_eq!(fs.head_count(), 2);
+            assert_eq!(fs.heads().await?.len(), 2);
+            anyhow::Ok(())
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn chooses_a_head() {
+        block_on(async {
+            let mut fs = diverged(&[("a.txt", "a")], &[("b.txt", "b")]).await?;
+            fs.choose_head(1).await?;
+            fs.flush().await?;
+
+            let mut fs = reopen(&fs).await?;
+            assert_eq!(fs.head_count(), 1);
+            let a = fs.stat(&path("a.txt")).await?.is_some();
+            let b = fs.stat(&path("b.txt")).await?.is_some();
+            assert!(a != b, "only one of the heads is kept");
+            assert!(fs.choose_head(0).await.is_err());
+            anyhow::Ok(())
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn paths_below_files_are_not_directories() {
+        block_on(async {
+            let mut fs = Wnfs::init_in_store(MemoryStore::new(), "test".to_string(), None).await?;
+            fs.write_file(&path("docs/a.txt"), b"a".to_vec()).await?;
+            for below_file in ["docs/a.txt/b", "docs/a.txt/b/c"] {
+                let err = fs.get_node(&path(below_file)).await.unwrap_err();
+                assert_eq!(FsError::find(&err), Some(FsError::NotADirectory));
+            }
+            assert!(fs.get_node(&path("docs/b.txt")).await?.is_none());
+            assert!(fs.get_node(&path("other/b.txt")).await?.is_none());
+            anyhow::Ok(())
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn names_conflict_copies() {
+        block_on(async {
+            let mut fs = Wnfs::init_in_store(MemoryStore::new(), "test".to_string(), None).await?;
+            let notes = path("docs/notes.txt");
+            assert_eq!(
+                fs.conflict_copy_path(&notes).await?,
+                path("docs/notes (conflict 1).txt")
+            );
+            fs.write_file(&path("docs/notes (conflict 1).txt"), vec![])
+                .await?;
+            assert_eq!(
+                fs.conflict_copy_path(&notes).await?,
+                path("docs/notes (conflict 2).txt")
+            );
+            assert!(fs.conflict_copy_path(&WnfsPath::root()).await.is_err());
+            anyhow::Ok(())
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn merges_heads() {
+        block_on(async {
+            let mut fs = diverged(
+                &[("a.txt", "a"), ("notes.txt", "one"), ("same.txt", "same")],
+                &[("b.txt", "b"), ("notes.txt", "two"), ("same.txt", "same")],
+            )
+            .await?;
+            // Files are compared without reading them as a whole.
+            fs.set_max_read_size(1);
+            let copies = fs.merge_heads().await?;
+            assert_eq!(copies, vec![path("notes (conflict 1).txt")]);
+            fs.flush().await?;
+
+            let fs = reopen(&fs).await?;
+            assert_eq!(fs.head_count(), 1);
+            assert_eq!(fs.read_file(&path("a.txt")).await?, b"a");
+            assert_eq!(fs.read_file(&path("b.txt")).await?, b"b");
+            let mut versions = vec![
+                fs.read_file(&path("notes.txt")).await?,
+                fs.read_file(&copies[0]).await?,
+            ];
+            versions.sort();
+            assert_eq!(versions, vec![b"one".to_vec(), b"two".to_vec()]);
+            anyhow::Ok(())
+        })
+        .unwrap();
+    }
+}
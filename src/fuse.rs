@@ -1,35 +1,200 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::ffi::OsStr;
 use std::future::Future;
-use std::path::Path;
-use std::time::{Duration, UNIX_EPOCH};
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::Arc;
+use std::task::{Context, Poll, Wake, Waker};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
+use chrono::{DateTime, Utc};
 use fuser::{
-    FileAttr, FileType, Filesystem, MountOption, ReplyAttr, ReplyData, ReplyDirectory, ReplyEntry,
-    Request,
+    FileAttr, FileType, Filesystem, MountOption, ReplyAttr, ReplyCreate, ReplyData, ReplyDirectory,
+    ReplyEmpty, ReplyEntry, ReplyOpen, ReplyWrite, Request, TimeOrNow,
 };
-use libc::ENOENT;
-use tracing::{debug, trace};
-use wnfs::private::PrivateNode;
+use futures::channel::mpsc;
+use futures::executor::{LocalPool, LocalSpawner};
+use futures::future::{FutureExt, LocalBoxFuture, Shared};
+use futures::task::LocalSpawnExt;
+use futures::StreamExt;
+use libc::{c_int, EEXIST, EINVAL, EIO, EISDIR, ENOENT, ENOTDIR, EPERM};
+use metrics::{counter, gauge};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, instrument, trace, Instrument};
+use wnfs::private::{PrivateDirectory, PrivateFile, PrivateNode, MAX_BLOCK_CONTENT_SIZE};
 
-use crate::fs::Wnfs;
+use crate::errno::{errno, fs_errno};
+use crate::error::FsError;
+use crate::fs::{node_mode, Checkpoint, ContentReader, Wnfs};
+use crate::path::{check_name, WnfsPath};
+use crate::store::{DefaultStore, Store};
+use crate::telemetry::OpTimer;
 
 const TTL: Duration = Duration::from_secs(1); // 1 second
 const ROOT_INO: u64 = 1;
 const BLOCK_SIZE: usize = 512;
+/// Default of [`CacheBudget::nodes`].
+pub const DEFAULT_NODE_CACHE_BYTES: u64 = 64 * 1024 * 1024;
+/// Estimated memory of a cached node, without the names of its entries.
+const NODE_BYTES: u64 = 1024;
+/// Estimated memory of an entry of a cached directory, without its name.
+const ENTRY_BYTES: u64 = 64;
+/// Estimated memory of an inode, without its path.
+const INODE_BYTES: u64 = 128;
+/// Cache usage is reported at most this often.
+const USAGE_INTERVAL: Duration = Duration::from_secs(5);
+/// Changes are committed as one revision by the first mutation or close after this interval,
+/// after this long without requests, and on `fsync` and unmount.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(5);
+/// Changes are also committed once this many mutations are pending, to bound what is lost
+/// on a crash during bulk operations.
+const FLUSH_MUTATIONS: usize = 10_000;
+/// Blocks after a read that are fetched ahead, for the reads that usually follow.
+const READ_AHEAD_BLOCKS: usize = 4;
+/// Default of [`MountOptions::timeout`].
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Options for [`mount_with_options`].
+#[derive(Debug, Default, Clone)]
+pub struct MountOptions {
+    pub read_only: bool,
+    /// Allow all users to access the mount, instead of only the owner and root.
+    pub allow_other: bool,
+    /// Owner of all files, defaults to the user running the mount.
+    pub uid: Option<u32>,
+    /// Group of all files, defaults to the group of the user running the mount.
+    pub gid: Option<u32>,
+    /// Permission bits to clear from all files.
+    pub umask: Option<u16>,
+    /// Time after which operations fail with `EIO`, e.g. because the block store hangs.
+    /// Defaults to [`DEFAULT_TIMEOUT`].
+    pub timeout: Option<Duration>,
+    pub cache: CacheBudget,
+    /// File to record the [`CacheUsage`] in while mounted, see [`usage_path`].
+    pub usage_file: Option<PathBuf>,
+}
+
+/// Memory budgets of the caches of a mount, in bytes.
+///
+/// Sizes are estimates of what the entries hold. A cache that is over its budget is cleared.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CacheBudget {
+    /// All caches together. Inodes cannot be evicted while mounted, so they take their share
+    /// from the other caches.
+    pub total: Option<u64>,
+    /// Decrypted nodes, [`DEFAULT_NODE_CACHE_BYTES`] by default.
+    pub nodes: Option<u64>,
+}
+
+/// Entries and estimated bytes of one cache.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct CacheStats {
+    pub entries: u64,
+    pub bytes: u64,
+    pub budget: Option<u64>,
+}
+
+/// Memory used by the caches of a mount.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct CacheUsage {
+    pub nodes: CacheStats,
+    pub inodes: CacheStats,
+    pub total: CacheStats,
+}
+
+/// File in which a mount of a named filesystem records its [`CacheUsage`], for `status`.
+pub fn usage_path(db_path: impl AsRef<Path>, name: &str) -> PathBuf {
+    let mut dir = db_path.as_ref().as_os_str().to_owned();
+    dir.push(".mounts");
+    PathBuf::from(dir).join(format!("{name}.json"))
+}
+
+/// The usage recorded by a running mount, if any.
+pub fn read_usage(db_path: impl AsRef<Path>, name: &str) -> anyhow::Result<Option<CacheUsage>> {
+    match std::fs::read(usage_path(db_path, name)) {
+        Ok(data) => Ok(Some(serde_json::from_slice(&data)?)),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
 
 /// Mount a filesystem
-pub fn mount(fs: Wnfs, mountpoint: impl AsRef<Path>) -> anyhow::Result<()> {
-    let fs = WnfsFuse::new(fs);
+pub fn mount<S: Store + 'static>(fs: Wnfs<S>, mountpoint: impl AsRef<Path>) -> anyhow::Result<()> {
+    mount_with_options(fs, mountpoint, &MountOptions::default())
+}
+
+/// Mount a filesystem with custom options
+///
+/// The FUSE session runs on a new thread and forwards requests to this thread, which owns
+/// the filesystem and serves them until the filesystem is unmounted. Mutations run one after
+/// another, while reads of file content run concurrently.
+pub fn mount_with_options<S: Store + 'static>(
+    fs: Wnfs<S>,
+    mountpoint: impl AsRef<Path>,
+    mount_options: &MountOptions,
+) -> anyhow::Result<()> {
+    // Reads time out with the timer of tokio and hooks run commands as tokio processes, which
+    // need a runtime.
+    let runtime = match tokio::runtime::Handle::try_current() {
+        Ok(_) => None,
+        Err(_) => Some(
+            tokio::runtime::Builder::new_multi_thread()
+                .worker_threads(1)
+                .enable_all()
+                .build()?,
+        ),
+    };
+    let _runtime = runtime.as_ref().map(|runtime| runtime.enter());
+    let mut pool = LocalPool::new();
+    let mut fs = WnfsFuse::new(fs, mount_options.clone(), pool.spawner());
+    let (jobs_tx, jobs) = mpsc::unbounded();
+    let dispatcher = Dispatcher { jobs: jobs_tx };
     let mountpoint = mountpoint.as_ref().to_owned();
     let options = vec![
-        MountOption::RW,
+        if mount_options.read_only {
+            MountOption::RO
+        } else {
+            MountOption::RW
+        },
         MountOption::FSName("wnfs".to_string()),
         MountOption::AutoUnmount,
-        MountOption::AllowRoot,
+        if mount_options.allow_other {
+            MountOption::AllowOther
+        } else {
+            MountOption::AllowRoot
+        },
     ];
     debug!("mount FUSE at {mountpoint:?}");
-    fuser::mount2(fs, mountpoint, &options)?;
+    let session = std::thread::Builder::new()
+        .name("fuse".to_string())
+        .spawn(move || fuser::mount2(dispatcher, mountpoint, &options))?;
+    pool.run_until(fs.serve(jobs));
+    // Reads that are still running.
+    pool.run();
+    session
+        .join()
+        .map_err(|_| anyhow::anyhow!("FUSE session panicked"))??;
+    Ok(())
+}
+
+/// Unmount a FUSE filesystem.
+pub fn unmount(mountpoint: impl AsRef<Path>) -> anyhow::Result<()> {
+    let mountpoint = mountpoint.as_ref();
+    let status = if cfg!(target_os = "linux") {
+        std::process::Command::new("fusermount")
+            .arg("-u")
+            .arg(mountpoint)
+            .status()?
+    } else {
+        std::process::Command::new("umount")
+            .arg(mountpoint)
+            .status()?
+    };
+    if !status.success() {
+        anyhow::bail!("Failed to unmount {mountpoint:?}: {status}");
+    }
     Ok(())
 }
 
@@ -40,17 +205,18 @@ pub fn mount(fs: Wnfs, mountpoint: impl AsRef<Path>) -> anyhow::Result<()> {
 #[derive(Default, Debug)]
 pub struct Inodes {
     inodes: HashMap<u64, Inode>,
-    by_path: HashMap<Vec<String>, u64>,
+    by_path: HashMap<WnfsPath, u64>,
     counter: u64,
+    bytes: u64,
 }
 
 impl Inodes {
-    pub fn push(&mut self, path_segments: Vec<String>) -> u64 {
-        // pub fn push(&mut self, path_segments: Vec<String>, kind: FileType) -> u64 {
+    pub fn push(&mut self, path: WnfsPath) -> u64 {
         self.counter += 1;
         let ino = self.counter;
-        let inode = Inode::new(ino, path_segments);
-        self.by_path.insert(inode.path_segments.clone(), ino);
+        self.bytes += inode_bytes(&path);
+        let inode = Inode::new(ino, path);
+        self.by_path.insert(inode.path.clone(), ino);
         self.inodes.insert(ino, inode);
         ino
     }
@@ -58,68 +224,902 @@ impl Inodes {
         self.inodes.get(&ino)
     }
 
-    pub fn get_path_segments(&self, ino: u64) -> Option<&Vec<String>> {
-        self.get(ino).map(|node| &node.path_segments)
+    pub fn get_path(&self, ino: u64) -> Option<&WnfsPath> {
+        self.get(ino).map(|node| &node.path)
     }
 
     pub fn get_by_path(&self, path: &[String]) -> Option<&Inode> {
         self.by_path.get(path).and_then(|ino| self.inodes.get(ino))
     }
 
-    pub fn get_or_push(&mut self, path: &[String]) -> Inode {
-        let path = path.to_vec();
-        let id = if let Some(id) = self.by_path.get(&path) {
-            *id
-        } else {
-            self.push(path)
+    pub fn get_or_push(&mut self, path: &WnfsPath) -> Inode {
+        let id = match self.by_path.get(path) {
+            Some(id) => *id,
+            None => self.push(path.clone()),
         };
         self.get(id).unwrap().clone()
     }
+
+    /// Forget the inode of a removed path.
+    pub fn remove(&mut self, path: &[String]) -> Option<u64> {
+        let ino = self.by_path.remove(path)?;
+        if let Some(inode) = self.inodes.remove(&ino) {
+            self.bytes -= inode_bytes(&inode.path);
+        }
+        Some(ino)
+    }
+
+    /// Move the inodes of a path and of everything below it to a new path.
+    pub fn rename(&mut self, from: &[String], to: &WnfsPath) {
+        let moved: Vec<u64> = self
+            .inodes
+            .values()
+            .filter(|inode| inode.path.starts_with(from))
+            .map(|inode| inode.ino)
+            .collect();
+        for ino in moved {
+            let inode = self.inodes.get_mut(&ino).expect("inode was just listed");
+            self.by_path.remove(&inode.path);
+            self.bytes -= inode_bytes(&inode.path);
+            let below = &inode.path[from.len()..];
+            let path = WnfsPath::from_segments(to.iter().chain(below).cloned().collect());
+            self.bytes += inode_bytes(&path);
+            self.by_path.insert(path.clone(), ino);
+            inode.path = path;
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.inodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inodes.is_empty()
+    }
+
+    /// Estimated memory of the index.
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+}
+
+/// Estimated memory of the inode of a path, which is stored twice.
+fn inode_bytes(path: &WnfsPath) -> u64 {
+    let path_bytes: usize = path.iter().map(String::len).sum();
+    INODE_BYTES + 2 * path_bytes as u64
 }
 
 #[derive(Debug, Clone)]
 pub struct Inode {
-    pub path_segments: Vec<String>,
+    pub path: WnfsPath,
     pub ino: u64,
 }
 
 impl Inode {
-    pub fn new(ino: u64, path_segments: Vec<String>) -> Self {
-        Self { path_segments, ino }
+    pub fn new(ino: u64, path: WnfsPath) -> Self {
+        Self { path, ino }
+    }
+}
+
+/// Decrypted nodes by inode, so that repeated operations on the same inode do not resolve
+/// the path through the forest again.
+///
+/// Every change to the filesystem replaces the root directory (the cache keeps it alive, so
+/// it is never changed in place). The cache is valid as long as the root is the same.
+#[derive(Default)]
+pub(crate) struct NodeCache {
+    root: Option<Rc<PrivateDirectory>>,
+    nodes: HashMap<u64, PrivateNode>,
+    bytes: u64,
+}
+
+impl NodeCache {
+    /// Clear the cache if the root changed since the nodes were cached.
+    fn validate(&mut self, root: &Rc<PrivateDirectory>) {
+        let unchanged = matches!(&self.root, Some(cached) if Rc::ptr_eq(cached, root));
+        if !unchanged {
+            self.clear();
+            self.root = Some(root.clone());
+        }
+    }
+
+    /// Cache a node, after clearing the cache if the node would take it over `budget`.
+    fn insert(&mut self, ino: u64, node: PrivateNode, budget: u64) {
+        let bytes = node_bytes(&node);
+        if self.bytes + bytes > budget {
+            self.clear();
+        }
+        self.bytes += bytes;
+        if let Some(replaced) = self.nodes.insert(ino, node) {
+            self.bytes -= node_bytes(&replaced);
+        }
+    }
+
+    fn clear(&mut self) {
+        self.nodes.clear();
+        self.bytes = 0;
+    }
+}
+
+/// Estimated memory of a decrypted node.
+fn node_bytes(node: &PrivateNode) -> u64 {
+    match node {
+        PrivateNode::File(_) => NODE_BYTES,
+        PrivateNode::Dir(dir) => {
+            let entries: u64 = dir
+                .entries()
+                .map(|name| ENTRY_BYTES + name.len() as u64)
+                .sum();
+            NODE_BYTES + entries
+        }
+    }
+}
+
+/// Content blocks around the last read of each file that is being read.
+///
+/// The kernel splits reads into requests that are smaller than a block, so without this,
+/// sequential reads would fetch and decrypt every block several times. The blocks after a
+/// read are fetched ahead while the kernel handles the reply. A file keeps at most the
+/// blocks of its last read and [`READ_AHEAD_BLOCKS`], until its last handle is released.
+#[derive(Default)]
+struct ReadCache {
+    files: HashMap<u64, CachedFile>,
+}
+
+/// Blocks of one revision of a file.
+struct CachedFile {
+    file: Rc<PrivateFile>,
+    blocks: BTreeMap<usize, BlockFetch>,
+}
+
+/// A fetch of a content block, shared by the reads that need it. `None` past the end of the
+/// content.
+type BlockFetch = Shared<LocalBoxFuture<'static, Result<Option<Rc<Vec<u8>>>, Rc<anyhow::Error>>>>;
+
+impl ReadCache {
+    /// Fetches of the `blocks` of a file, started on `spawner` as needed along with the
+    /// blocks ahead. Failed fetches are retried.
+    fn fetch<S: Store + 'static>(
+        &mut self,
+        ino: u64,
+        file: &Rc<PrivateFile>,
+        blocks: RangeInclusive<usize>,
+        reader: &ContentReader<S>,
+        spawner: &LocalSpawner,
+        timeout: Duration,
+    ) -> Vec<BlockFetch> {
+        let cached = self.files.entry(ino).or_insert_with(|| CachedFile {
+            file: file.clone(),
+            blocks: BTreeMap::new(),
+        });
+        // Nodes are immutable, a changed file is a different node.
+        if !Rc::ptr_eq(&cached.file, file) {
+            cached.file = file.clone();
+            cached.blocks.clear();
+        }
+        let (first, last) = blocks.into_inner();
+        let count = file
+            .get_content_size_upper_bound()
+            .div_ceil(MAX_BLOCK_CONTENT_SIZE);
+        let ahead = (last + READ_AHEAD_BLOCKS).min(count.saturating_sub(1));
+        let mut kept = std::mem::take(&mut cached.blocks).split_off(&first);
+        kept.split_off(&(ahead + 1));
+        cached.blocks = kept;
+        for index in first..=ahead {
+            let fetch = cached.blocks.get(&index);
+            if fetch.is_none() || matches!(fetch.and_then(Shared::peek), Some(Err(_))) {
+                let fetch = fetch_block(reader.clone(), file.clone(), index, timeout);
+                // A fetch that cannot be spawned runs when a read awaits it.
+                let _ = spawner.spawn_local(fetch.clone().map(|_| ()));
+                cached.blocks.insert(index, fetch);
+            }
+        }
+        (first..=last)
+            .filter_map(|index| cached.blocks.get(&index).cloned())
+            .collect()
+    }
+}
+
+fn fetch_block<S: Store + 'static>(
+    reader: ContentReader<S>,
+    file: Rc<PrivateFile>,
+    index: usize,
+    timeout: Duration,
+) -> BlockFetch {
+    async move {
+        let block = with_timeout(reader.read_block(&file, index), timeout).await;
+        block.map(|block| block.map(Rc::new)).map_err(Rc::new)
+    }
+    .boxed_local()
+    .shared()
+}
+
+/// The range `offset..end` of a file, from the fetches of its blocks from `first` on.
+async fn read_range(
+    fetches: Vec<BlockFetch>,
+    first: usize,
+    offset: usize,
+    end: usize,
+) -> anyhow::Result<Vec<u8>> {
+    let mut content = Vec::with_capacity(end - offset);
+    for (index, fetch) in (first..).zip(fetches) {
+        // The size is an upper bound, the last block ends the content.
+        let Some(block) = fetch.await.map_err(|err| anyhow::anyhow!("{err:#}"))? else {
+            break;
+        };
+        let start = index * MAX_BLOCK_CONTENT_SIZE;
+        let from = offset.saturating_sub(start).min(block.len());
+        let to = (end - start).min(block.len());
+        content.extend_from_slice(&block[from..to]);
+    }
+    counter!("wnfs_read_bytes_total", content.len() as u64);
+    Ok(content)
+}
+
+/// A file that is open through one or more handles.
+#[derive(Default)]
+struct OpenFile {
+    handles: usize,
+    /// The whole content, once the file was written through a handle.
+    buffer: Option<Vec<u8>>,
+    /// Whether the buffer has changes that were not written back.
+    dirty: bool,
+}
+
+/// Open file handles, and the content written through them.
+///
+/// Writes go to a buffer of the file, which is written back as one revision when the last
+/// handle is released or the file is synced, instead of rewriting the file on every write.
+#[derive(Default)]
+pub(crate) struct FileHandles {
+    /// Inode of each handle.
+    handles: HashMap<u64, u64>,
+    files: HashMap<u64, OpenFile>,
+    counter: u64,
+}
+
+impl FileHandles {
+    fn open(&mut self, ino: u64) -> u64 {
+        self.counter += 1;
+        self.handles.insert(self.counter, ino);
+        self.files.entry(ino).or_default().handles += 1;
+        self.counter
+    }
+
+    /// Close a handle and return its inode, and whether it was the last handle of the file.
+    fn release(&mut self, fh: u64) -> Option<(u64, bool)> {
+        let ino = self.handles.remove(&fh)?;
+        let file = self.files.get_mut(&ino)?;
+        file.handles -= 1;
+        Some((ino, file.handles == 0))
+    }
+
+    fn buffer(&self, ino: u64) -> Option<&Vec<u8>> {
+        self.files.get(&ino)?.buffer.as_ref()
     }
 }
 
-pub struct WnfsFuse {
-    pub(crate) wnfs: Wnfs,
+/// The state of a mount, owned by the thread that serves it.
+pub struct WnfsFuse<S: Store = DefaultStore> {
+    pub(crate) wnfs: Wnfs<S>,
     pub(crate) inodes: Inodes,
+    pub(crate) options: MountOptions,
+    pub(crate) nodes: NodeCache,
+    pub(crate) handles: FileHandles,
+    reads: ReadCache,
+    /// Runs reads of file content, which do not need the state once they started.
+    spawner: LocalSpawner,
+    last_flush: Instant,
+    last_usage: Option<Instant>,
 }
 
-impl WnfsFuse {
-    pub fn new(wnfs: Wnfs) -> Self {
+impl<S: Store> WnfsFuse<S> {
+    pub fn new(wnfs: Wnfs<S>, options: MountOptions, spawner: LocalSpawner) -> Self {
         let mut inodes = Inodes::default();
         // Init root inode.
-        inodes.push(vec![]);
-        Self { wnfs, inodes }
+        inodes.push(WnfsPath::root());
+        Self {
+            wnfs,
+            inodes,
+            options,
+            nodes: NodeCache::default(),
+            handles: FileHandles::default(),
+            reads: ReadCache::default(),
+            spawner,
+            last_flush: Instant::now(),
+            last_usage: None,
+        }
+    }
+
+    /// Run the operations that a [`Dispatcher`] sends until it is dropped.
+    ///
+    /// Pending changes are flushed once no requests arrived for [`FLUSH_INTERVAL`], so that
+    /// the last changes of a burst do not wait for the next mutation.
+    async fn serve(&mut self, mut jobs: mpsc::UnboundedReceiver<Job<S>>) {
+        loop {
+            let job = if self.wnfs.is_dirty() {
+                match tokio::time::timeout(FLUSH_INTERVAL, jobs.next()).await {
+                    Ok(job) => job,
+                    Err(_) => {
+                        self.flush_if_due();
+                        continue;
+                    }
+                }
+            } else {
+                jobs.next().await
+            };
+            let Some(job) = job else {
+                break;
+            };
+            job(self);
+        }
+    }
+
+    fn timeout(&self) -> Duration {
+        self.options.timeout.unwrap_or(DEFAULT_TIMEOUT)
+    }
+
+    /// Flush if the last flush is longer ago than [`FLUSH_INTERVAL`], or if
+    /// [`FLUSH_MUTATIONS`] mutations are pending.
+    ///
+    /// Mutations in between only change the in-memory state, so that bulk operations like
+    /// extracting an archive create a few revisions instead of one per file.
+    fn flush_if_due(&mut self) {
+        if self.last_flush.elapsed() < FLUSH_INTERVAL && self.wnfs.unflushed() < FLUSH_MUTATIONS {
+            return;
+        }
+        // An abandoned flush leaves the filesystem dirty, so the next one retries it.
+        let timeout = self.timeout();
+        if let Err(err) = block_on(self.wnfs.flush(), timeout) {
+            tracing::error!("failed to flush: {err}");
+        }
+        self.last_flush = Instant::now();
+    }
+
+    /// Bytes the node cache may use: its own budget, or what the inodes leave of the total.
+    fn node_budget(&self) -> u64 {
+        let budget = self.options.cache.nodes.unwrap_or(DEFAULT_NODE_CACHE_BYTES);
+        match self.options.cache.total {
+            Some(total) => budget.min(total.saturating_sub(self.inodes.bytes())),
+            None => budget,
+        }
+    }
+
+    pub fn cache_usage(&self) -> CacheUsage {
+        let nodes = CacheStats {
+            entries: self.nodes.nodes.len() as u64,
+            bytes: self.nodes.bytes,
+            budget: Some(self.node_budget()),
+        };
+        let inodes = CacheStats {
+            entries: self.inodes.len() as u64,
+            bytes: self.inodes.bytes(),
+            budget: None,
+        };
+        let total = CacheStats {
+            entries: nodes.entries + inodes.entries,
+            bytes: nodes.bytes + inodes.bytes,
+            budget: self.options.cache.total,
+        };
+        CacheUsage {
+            nodes,
+            inodes,
+            total,
+        }
+    }
+
+    /// Record the cache usage in metrics and the usage file, if the last report is longer ago
+    /// than [`USAGE_INTERVAL`].
+    fn report_usage_if_due(&mut self) {
+        if matches!(self.last_usage, Some(last) if last.elapsed() < USAGE_INTERVAL) {
+            return;
+        }
+        self.last_usage = Some(Instant::now());
+        let usage = self.cache_usage();
+        gauge!("wnfs_fuse_cache_bytes", usage.nodes.bytes as f64, "cache" => "nodes");
+        gauge!("wnfs_fuse_cache_bytes", usage.inodes.bytes as f64, "cache" => "inodes");
+        let Some(path) = &self.options.usage_file else {
+            return;
+        };
+        let result = path
+            .parent()
+            .map_or(Ok(()), std::fs::create_dir_all)
+            .and_then(|_| std::fs::write(path, serde_json::to_vec(&usage)?));
+        if let Err(err) = result {
+            tracing::warn!("failed to record cache usage in {path:?}: {err}");
+        }
+    }
+
+    /// The path of `name` in the directory `parent`, or the error to reply.
+    fn child_path(&self, parent: u64, name: &OsStr) -> Result<WnfsPath, c_int> {
+        let Some(parent_path) = self.inodes.get_path(parent) else {
+            trace!("  ENOENT: parent not found");
+            return Err(ENOENT);
+        };
+        let name = name.to_string_lossy();
+        if let Err(err) = check_name(&name) {
+            trace!("  {err}");
+            return Err(errno(&err));
+        }
+        Ok(parent_path.join(name))
+    }
+
+    /// Create an empty file and return its attributes, or the error to reply.
+    fn create_file(&mut self, parent: u64, name: &OsStr) -> Result<FileAttr, c_int> {
+        let path = self.child_path(parent, name)?;
+        let timeout = self.timeout();
+        match block_on(self.wnfs.get_node(&path), timeout) {
+            Ok(None) => {}
+            Ok(Some(_)) => {
+                trace!("  EEXIST");
+                return Err(EEXIST);
+            }
+            Err(err) => {
+                trace!("  failed to look up {path}: {err}");
+                return Err(errno(&err));
+            }
+        }
+        let checkpoint = self.wnfs.checkpoint();
+        let result = block_on(self.wnfs.write_file(&path, vec![]), timeout);
+        self.rollback_if_timed_out(checkpoint, &result);
+        if let Err(err) = result {
+            trace!("  failed to create file: {err}");
+            return Err(errno(&err));
+        }
+        let node = match block_on(self.wnfs.get_node(&path), timeout) {
+            Ok(Some(node)) => node,
+            Err(_) | Ok(None) => {
+                trace!("  ENOENT, failed to find created file");
+                return Err(ENOENT);
+            }
+        };
+        let ino = self.inodes.get_or_push(&path).ino;
+        trace!("  ok, created! ino {ino}");
+        Ok(node_to_attr(ino, &node, &self.options))
+    }
+
+    /// Remove a file, or an empty directory if `dir` is set, and forget its inode.
+    fn remove(&mut self, parent: u64, name: &OsStr, dir: bool) -> Result<(), c_int> {
+        let path = self.child_path(parent, name)?;
+        let timeout = self.timeout();
+        let node = match block_on(self.wnfs.get_node(&path), timeout) {
+            Ok(Some(node)) => node,
+            Ok(None) => {
+                trace!("  ENOENT");
+                return Err(ENOENT);
+            }
+            Err(err) => {
+                trace!("  failed to look up {path}: {err}");
+                return Err(errno(&err));
+            }
+        };
+        match (node, dir) {
+            (PrivateNode::File(_), true) => {
+                trace!("  ENOTDIR");
+                return Err(ENOTDIR);
+            }
+            (PrivateNode::Dir(_), false) => {
+                trace!("  EISDIR");
+                return Err(EISDIR);
+            }
+            (PrivateNode::Dir(node), true) if node.entries().next().is_some() => {
+                trace!("  ENOTEMPTY");
+                return Err(fs_errno(FsError::NotEmpty));
+            }
+            _ => {}
+        }
+        let checkpoint = self.wnfs.checkpoint();
+        let result = block_on(self.wnfs.rm(&path), timeout);
+        self.rollback_if_timed_out(checkpoint, &result);
+        if let Err(err) = result {
+            trace!("  failed to remove: {err}");
+            return Err(errno(&err));
+        }
+        self.inodes.remove(&path);
+        trace!("  ok, removed");
+        Ok(())
+    }
+
+    /// Move an entry, replacing the target like `rename(2)`.
+    fn rename_entry(
+        &mut self,
+        parent: u64,
+        name: &OsStr,
+        new_parent: u64,
+        new_name: &OsStr,
+        flags: u32,
+    ) -> Result<(), c_int> {
+        if flags & libc::RENAME_EXCHANGE != 0 {
+            trace!("  EINVAL (exchange is not supported)");
+            return Err(EINVAL);
+        }
+        let from = self.child_path(parent, name)?;
+        let to = self.child_path(new_parent, new_name)?;
+        let timeout = self.timeout();
+        let lookup = |fs: &Self, path: &WnfsPath| {
+            block_on(fs.wnfs.get_node(path), timeout).map_err(|err| {
+                trace!("  failed to look up {path}: {err}");
+                errno(&err)
+            })
+        };
+        let Some(node) = lookup(self, &from)? else {
+            trace!("  ENOENT");
+            return Err(ENOENT);
+        };
+        if from == to {
+            return Ok(());
+        }
+        let target = lookup(self, &to)?;
+        match (&node, &target) {
+            (_, None) => {}
+            (_, Some(_)) if flags & libc::RENAME_NOREPLACE != 0 => {
+                trace!("  EEXIST");
+                return Err(EEXIST);
+            }
+            (PrivateNode::File(_), Some(PrivateNode::Dir(_))) => {
+                trace!("  EISDIR");
+                return Err(EISDIR);
+            }
+            (PrivateNode::Dir(_), Some(PrivateNode::File(_))) => {
+                trace!("  ENOTDIR");
+                return Err(ENOTDIR);
+            }
+            (_, Some(PrivateNode::Dir(dir))) if dir.entries().next().is_some() => {
+                trace!("  ENOTEMPTY");
+                return Err(fs_errno(FsError::NotEmpty));
+            }
+            _ => {}
+        }
+        // Replacing the target takes two steps, which must not be left half done.
+        let checkpoint = self.wnfs.checkpoint();
+        let replace = target.is_some();
+        let wnfs = &mut self.wnfs;
+        let result = block_on(
+            async {
+                if replace {
+                    wnfs.rm(&to).await?;
+                }
+                wnfs.mv(&from, &to).await
+            },
+            timeout,
+        );
+        if let Err(err) = result {
+            trace!("  failed to move: {err}");
+            self.wnfs.rollback(checkpoint);
+            return Err(errno(&err));
+        }
+        if let Some(replaced) = self.inodes.remove(&to) {
+            if self.handles.files.contains_key(&replaced) {
+                trace!("  replaced i{replaced} is still open, its writes will be discarded");
+            }
+        }
+        self.inodes.rename(&from, &to);
+        trace!("  ok, moved to {to}");
+        Ok(())
+    }
+
+    /// Change the size, mode or times of a node and return its new attributes.
+    ///
+    /// Directories have no size or mode that can be set, and their times are left as they are.
+    fn set_attributes(
+        &mut self,
+        ino: u64,
+        size: Option<u64>,
+        mode: Option<u32>,
+        modified: Option<DateTime<Utc>>,
+        created: Option<DateTime<Utc>>,
+    ) -> Result<FileAttr, c_int> {
+        let node = match self.node(ino) {
+            Ok(Some(node)) => node,
+            Ok(None) => {
+                trace!("  ENOENT (not found)");
+                return Err(ENOENT);
+            }
+            Err(err) => {
+                trace!("  error ({err})");
+                return Err(errno(&err));
+            }
+        };
+        if let PrivateNode::Dir(_) = node {
+            if size.is_some() {
+                trace!("  EISDIR");
+                return Err(EISDIR);
+            }
+            let attr = self.attr(ino, &node);
+            // Setting the mode that a directory already has is accepted, e.g. for the trash.
+            if matches!(mode, Some(mode) if mode as u16 & 0o7777 != attr.perm) {
+                trace!("  EPERM (modes of directories are not supported)");
+                return Err(EPERM);
+            }
+            return Ok(attr);
+        }
+        let path = self.inodes.get_path(ino).cloned().ok_or(ENOENT)?;
+        // Files are rewritten as a whole, which is limited like writes into buffers.
+        if matches!(size, Some(size) if size > self.wnfs.max_read_size()) {
+            trace!("  EFBIG");
+            return Err(fs_errno(FsError::FileTooLarge));
+        }
+        // Open files are resized in their buffer, which is written back later anyway. Files
+        // are usually truncated to 0 right after opening, so that needs no content.
+        let size = match (size, self.handles.files.get_mut(&ino)) {
+            (Some(size), Some(file)) if file.buffer.is_some() || size == 0 => {
+                file.buffer
+                    .get_or_insert_with(Vec::new)
+                    .resize(size as usize, 0);
+                file.dirty = true;
+                None
+            }
+            (size, _) => size,
+        };
+        let timeout = self.timeout();
+        // All changes or none.
+        let checkpoint = self.wnfs.checkpoint();
+        let wnfs = &mut self.wnfs;
+        let result = block_on(
+            async {
+                if let Some(size) = size {
+                    wnfs.truncate(&path, size as usize).await?;
+                }
+                if let Some(mode) = mode {
+                    wnfs.set_mode(&path, mode).await?;
+                }
+                if modified.is_some() || created.is_some() {
+                    wnfs.set_times(&path, modified, created).await?;
+                }
+                anyhow::Ok(())
+            },
+            timeout,
+        );
+        if let Err(err) = result {
+            trace!("  failed to set attributes: {err}");
+            self.wnfs.rollback(checkpoint);
+            return Err(errno(&err));
+        }
+        match self.node(ino) {
+            Ok(Some(node)) => Ok(self.attr(ino, &node)),
+            Ok(None) => Err(ENOENT),
+            Err(err) => Err(errno(&err)),
+        }
+    }
+
+    /// Attributes of a node, with the size of its buffer if it is open and was written.
+    fn attr(&self, ino: u64, node: &PrivateNode) -> FileAttr {
+        let mut attr = node_to_attr(ino, node, &self.options);
+        if let Some(buffer) = self.handles.buffer(ino) {
+            attr.size = buffer.len() as u64;
+            attr.blocks = attr.size / BLOCK_SIZE as u64;
+        }
+        if let PrivateNode::Dir(_) = node {
+            if self.is_trash_dir(ino, attr.uid) {
+                attr.perm = 0o700;
+            }
+        }
+        attr
+    }
+
+    /// Whether a directory is the trash of the owner of the mount, `.Trash-$uid` at the root
+    /// as in the freedesktop.org Trash specification.
+    ///
+    /// Directories have no stored modes, but file managers only use a trash directory that
+    /// nobody else can access, so it is reported with mode 0700.
+    fn is_trash_dir(&self, ino: u64, uid: u32) -> bool {
+        let trash = format!(".Trash-{uid}");
+        matches!(self.inodes.get_path(ino), Some(path) if path.len() == 1 && path[0] == trash)
+    }
+
+    /// Write `data` into the buffer of an open file, loading its content first if needed.
+    ///
+    /// Buffers hold whole files, so they are limited to [`Wnfs::max_read_size`] like other
+    /// reads into memory.
+    fn write_buffered(&mut self, ino: u64, offset: usize, data: &[u8]) -> anyhow::Result<()> {
+        let max_size = self.wnfs.max_read_size();
+        let end = offset + data.len();
+        if end as u64 > max_size {
+            return Err(anyhow::Error::new(FsError::FileTooLarge)
+                .context(format!("Writes beyond {max_size} bytes are not supported")));
+        }
+        if self.handles.buffer(ino).is_none() {
+            let content = match self.node(ino)? {
+                Some(PrivateNode::File(file)) => {
+                    let size = file.get_content_size_upper_bound();
+                    if size as u64 > max_size {
+                        return Err(anyhow::Error::new(FsError::FileTooLarge).context(format!(
+                            "Files larger than {max_size} bytes cannot be written"
+                        )));
+                    }
+                    let timeout = self.timeout();
+                    block_on(self.wnfs.read_node_at(&file, 0, size), timeout)?
+                }
+                Some(PrivateNode::Dir(_)) => anyhow::bail!(FsError::IsADirectory),
+                None => anyhow::bail!(FsError::NotFound),
+            };
+            self.handles.files.entry(ino).or_default().buffer = Some(content);
+        }
+        let file = self.handles.files.entry(ino).or_default();
+        let content = file.buffer.as_mut().expect("buffer was just loaded");
+        if content.len() < end {
+            content.resize(end, 0);
+        }
+        content[offset..end].copy_from_slice(data);
+        file.dirty = true;
+        Ok(())
+    }
+
+    /// Store the buffer of an open file as a new revision if it has changes. The buffer is
+    /// dropped once the file is no longer open.
+    fn write_back(&mut self, ino: u64) -> anyhow::Result<()> {
+        let Some(file) = self.handles.files.get(&ino) else {
+            return Ok(());
+        };
+        let content = match &file.buffer {
+            // Copied, so that the changes are kept if storing them fails.
+            Some(buffer) if file.dirty => Some(buffer.clone()),
+            _ => None,
+        };
+        match (content, self.inodes.get_path(ino).cloned()) {
+            (Some(content), Some(path)) => {
+                let timeout = self.timeout();
+                let checkpoint = self.wnfs.checkpoint();
+                let result = block_on(self.wnfs.write_file(&path, content), timeout);
+                self.rollback_if_timed_out(checkpoint, &result);
+                result?;
+            }
+            // A file that was removed or replaced while open has nowhere to go.
+            (Some(content), None) => {
+                tracing::warn!(
+                    "discarding {} buffered bytes of i{ino}, which was removed or replaced",
+                    content.len()
+                );
+            }
+            (None, _) => {}
+        }
+        let file = self.handles.files.get_mut(&ino).expect("file is open");
+        file.dirty = false;
+        if file.handles == 0 {
+            self.handles.files.remove(&ino);
+        }
+        Ok(())
+    }
+
+    /// Write back the buffer of a file, if any, and commit all changes to the store.
+    ///
+    /// The store persists the root alias before [`Wnfs::flush`] returns, so the changes are
+    /// durable once this succeeds.
+    fn sync(&mut self, ino: Option<u64>) -> anyhow::Result<()> {
+        if let Some(ino) = ino {
+            self.write_back(ino)?;
+        }
+        let timeout = self.timeout();
+        block_on(self.wnfs.flush(), timeout)?;
+        self.last_flush = Instant::now();
+        Ok(())
+    }
+
+    /// Return to the state before a mutation if it timed out half way.
+    fn rollback_if_timed_out<T>(&mut self, checkpoint: Checkpoint, result: &anyhow::Result<T>) {
+        if matches!(result, Err(err) if err.is::<TimedOut>()) {
+            self.wnfs.rollback(checkpoint);
+        }
+    }
+
+    /// The node of an inode, from the cache if the filesystem did not change since.
+    fn node(&mut self, ino: u64) -> anyhow::Result<Option<PrivateNode>> {
+        let root = self.wnfs.private_root();
+        if ino == ROOT_INO {
+            return Ok(Some(PrivateNode::Dir(root)));
+        }
+        self.nodes.validate(&root);
+        self.report_usage_if_due();
+        if let Some(node) = self.nodes.nodes.get(&ino) {
+            counter!("wnfs_fuse_node_lookups_total", 1, "cached" => "true");
+            return Ok(Some(node.clone()));
+        }
+        counter!("wnfs_fuse_node_lookups_total", 1, "cached" => "false");
+        let timeout = self.timeout();
+        let Some(path) = self.inodes.get_path(ino) else {
+            return Ok(None);
+        };
+        let node = block_on(self.wnfs.get_node(path), timeout)?;
+        if let Some(node) = &node {
+            let budget = self.node_budget();
+            self.nodes.insert(ino, node.clone(), budget);
+        }
+        Ok(node)
     }
 }
 
-fn block_on<F: Future>(future: F) -> F::Output {
-    futures::executor::block_on(future)
+/// An operation did not complete within [`MountOptions::timeout`].
+#[derive(Debug)]
+struct TimedOut;
+
+impl std::fmt::Display for TimedOut {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("operation timed out")
+    }
 }
 
-impl Filesystem for WnfsFuse {
-    fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+impl std::error::Error for TimedOut {}
+
+/// Wakes the thread that serves the mount when a future can make progress.
+struct ThreadWaker(std::thread::Thread);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+/// Run a future on the thread that serves the mount, or fail with [`TimedOut`] after
+/// `timeout`.
+///
+/// A future that times out is dropped, which abandons its work at the point where it waits,
+/// e.g. for a block store request. Reads can be abandoned at any point; mutations have to be
+/// rolled back (see [`Wnfs::checkpoint`]).
+fn block_on<T>(
+    future: impl Future<Output = anyhow::Result<T>>,
+    timeout: Duration,
+) -> anyhow::Result<T> {
+    let deadline = Instant::now() + timeout;
+    let waker = Waker::from(Arc::new(ThreadWaker(std::thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    futures::pin_mut!(future);
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            return output;
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            return Err(TimedOut.into());
+        }
+        std::thread::park_timeout(deadline - now);
+    }
+}
+
+/// Like [`block_on`], for futures that are spawned on the thread that serves the mount.
+async fn with_timeout<T>(
+    future: impl Future<Output = anyhow::Result<T>>,
+    timeout: Duration,
+) -> anyhow::Result<T> {
+    tokio::time::timeout(timeout, future)
+        .await
+        .map_err(|_| TimedOut)?
+}
+
+/// Operations, called by [`Dispatcher`] on the thread that serves the mount.
+impl<S: Store + 'static> WnfsFuse<S> {
+    fn destroy(&mut self) {
+        // The kernel releases all handles before, but a failed write back is retried here.
+        let open: Vec<u64> = self.handles.files.keys().copied().collect();
+        for ino in open {
+            if let Err(err) = self.write_back(ino) {
+                tracing::error!("failed to write back i{ino} on unmount: {err}");
+            }
+        }
+        debug!("destroy: flush");
+        let timeout = self.timeout();
+        if let Err(err) = block_on(self.wnfs.flush(), timeout) {
+            tracing::error!("failed to flush on unmount: {err}");
+        }
+        if let Some(path) = &self.options.usage_file {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+
+    #[instrument(level = "debug", skip(self, reply))]
+    fn lookup(&mut self, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        let _timer = OpTimer::new("lookup");
         trace!("lookup: i{parent} {name:?}");
-        let Some(path_segments) = self.inodes.get_path_segments(parent) else {
-            trace!("  ENOENT");
-            reply.error(ENOENT);
-            return;
+        let path = match self.child_path(parent, name) {
+            Ok(path) => path,
+            Err(code) => {
+                reply.error(code);
+                return;
+            }
         };
-        let path = push_segment(&path_segments, &name.to_str().unwrap());
         let Inode { ino, .. } = self.inodes.get_or_push(&path);
-        match block_on(self.wnfs.get_node(&path)) {
+        match self.node(ino) {
             Ok(Some(node)) => {
-                let attr = node_to_attr(ino, &node);
+                let attr = self.attr(ino, &node);
                 trace!("  ok {attr:?}");
                 reply.entry(&TTL, &attr, 0);
             }
@@ -128,159 +1128,315 @@ impl Filesystem for WnfsFuse {
                 reply.error(ENOENT);
             }
             Err(err) => {
-                trace!("  ENOENT ({err})");
-                reply.error(ENOENT);
+                trace!("  error ({err})");
+                reply.error(errno(&err));
             }
         }
     }
 
-    fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
+    #[instrument(level = "debug", skip(self, reply))]
+    fn getattr(&mut self, ino: u64, reply: ReplyAttr) {
+        let _timer = OpTimer::new("getattr");
         trace!("getattr: i{ino}");
 
-        let node = if ino == ROOT_INO {
-            PrivateNode::Dir(self.wnfs.private_root())
-        } else {
-            let Some(path_segments) = self.inodes.get_path_segments(ino) else {
-                trace!("  ENOENT (ino not found)");
+        let node = match self.node(ino) {
+            Ok(Some(node)) => node,
+            Ok(None) => {
+                trace!("  ENOENT (not found)");
                 reply.error(ENOENT);
                 return;
-            };
-            let Ok(Some(node)) = block_on(self.wnfs.get_node(&path_segments)) else {
-                trace!("  ENOENT (path not found)");
-                reply.error(ENOENT);
+            }
+            Err(err) => {
+                trace!("  error ({err})");
+                reply.error(errno(&err));
                 return;
-            };
-            node
+            }
         };
-        let attr = node_to_attr(ino, &node);
+        let attr = self.attr(ino, &node);
         trace!("  ok {attr:?}");
         reply.attr(&TTL, &attr)
     }
 
-    fn read(
+    #[instrument(level = "debug", skip(self, reply))]
+    fn setattr(
         &mut self,
-        _req: &Request,
         ino: u64,
-        _fh: u64,
-        offset: i64,
-        size: u32,
-        _flags: i32,
-        _lock: Option<u64>,
-        reply: ReplyData,
+        mode: Option<u32>,
+        size: Option<u64>,
+        mtime: Option<TimeOrNow>,
+        crtime: Option<SystemTime>,
+        reply: ReplyAttr,
     ) {
-        trace!("read: i{ino} offset {offset} size {size}");
-        let Some(path_segments) = self.inodes.get_path_segments(ino) else {
-              trace!("  ENOENT (ino not found)");
-              reply.error(ENOENT);
-              return;
+        let _timer = OpTimer::new("setattr");
+        trace!("setattr: i{ino} size {size:?} mode {mode:?} mtime {mtime:?}");
+        let modified = mtime.map(|mtime| match mtime {
+            TimeOrNow::SpecificTime(time) => DateTime::<Utc>::from(time),
+            TimeOrNow::Now => Utc::now(),
+        });
+        let created = crtime.map(DateTime::<Utc>::from);
+        match self.set_attributes(ino, size, mode, modified, created) {
+            Ok(attr) => reply.attr(&TTL, &attr),
+            Err(code) => reply.error(code),
+        }
+        self.flush_if_due();
+    }
+
+    #[instrument(level = "debug", skip(self, reply))]
+    fn open(&mut self, ino: u64, reply: ReplyOpen) {
+        let _timer = OpTimer::new("open");
+        trace!("open: i{ino}");
+        match self.node(ino) {
+            Ok(Some(PrivateNode::File(_))) => {
+                let fh = self.handles.open(ino);
+                trace!("  ok, fh {fh}");
+                reply.opened(fh, 0);
+            }
+            Ok(Some(PrivateNode::Dir(_))) => {
+                trace!("  EISDIR");
+                reply.error(EISDIR);
+            }
+            Ok(None) => {
+                trace!("  ENOENT (not found)");
+                reply.error(ENOENT);
+            }
+            Err(err) => {
+                trace!("  error ({err})");
+                reply.error(errno(&err));
+            }
+        }
+    }
+
+    #[instrument(level = "debug", skip(self, reply))]
+    fn release(&mut self, ino: u64, fh: u64, reply: ReplyEmpty) {
+        let _timer = OpTimer::new("release");
+        trace!("release: i{ino} fh {fh}");
+        let Some((ino, last)) = self.handles.release(fh) else {
+            reply.ok();
+            return;
         };
-        let content = block_on(self.wnfs.read_file_at(
-            &path_segments,
-            offset as usize,
-            size as usize,
-        ));
-        // let content = block_on(self.wnfs.read_file(&path_segments));
-        match content {
-            Ok(data) => {
-                trace!("  ok, len {}", data.len());
-                reply.data(&data)
+        if !last {
+            reply.ok();
+            return;
+        }
+        self.reads.files.remove(&ino);
+        match self.write_back(ino) {
+            Ok(()) => reply.ok(),
+            Err(err) => {
+                tracing::error!("failed to write back i{ino}: {err}");
+                reply.error(errno(&err));
+            }
+        }
+        self.flush_if_due();
+    }
+
+    #[instrument(level = "debug", skip(self, reply))]
+    fn fsync(&mut self, ino: u64, fh: u64, reply: ReplyEmpty) {
+        let _timer = OpTimer::new("fsync");
+        trace!("fsync: i{ino} fh {fh}");
+        match self.sync(Some(ino)) {
+            Ok(()) => reply.ok(),
+            Err(err) => {
+                tracing::error!("failed to sync i{ino}: {err}");
+                reply.error(errno(&err));
+            }
+        }
+    }
+
+    #[instrument(level = "debug", skip(self, reply))]
+    fn fsyncdir(&mut self, ino: u64, fh: u64, reply: ReplyEmpty) {
+        let _timer = OpTimer::new("fsyncdir");
+        trace!("fsyncdir: i{ino} fh {fh}");
+        // Entries are part of the directory, which is committed with everything else.
+        match self.sync(None) {
+            Ok(()) => reply.ok(),
+            Err(err) => {
+                tracing::error!("failed to sync: {err}");
+                reply.error(errno(&err));
+            }
+        }
+    }
+
+    /// Called on every `close` of a handle, so that errors of writing back reach the program.
+    ///
+    /// Unlike `fsync`, closing a file does not ask for durability, so the changes are only
+    /// committed with the next due flush.
+    #[instrument(level = "debug", skip(self, reply))]
+    fn flush(&mut self, ino: u64, fh: u64, reply: ReplyEmpty) {
+        let _timer = OpTimer::new("flush");
+        trace!("flush: i{ino} fh {fh}");
+        match self.write_back(ino) {
+            Ok(()) => reply.ok(),
+            Err(err) => {
+                tracing::error!("failed to write back i{ino}: {err}");
+                reply.error(errno(&err));
             }
+        }
+        self.flush_if_due();
+    }
+
+    #[instrument(level = "debug", skip(self, reply))]
+    fn read(&mut self, ino: u64, offset: i64, size: u32, reply: ReplyData) {
+        let timer = OpTimer::new("read");
+        trace!("read: i{ino} offset {offset} size {size}");
+        if let Some(buffer) = self.handles.buffer(ino) {
+            let start = (offset as usize).min(buffer.len());
+            let end = start.saturating_add(size as usize).min(buffer.len());
+            trace!("  ok, len {} from buffer", end - start);
+            reply.data(&buffer[start..end]);
+            return;
+        }
+        let file = match self.node(ino) {
+            Ok(Some(PrivateNode::File(file))) => file,
             Err(err) => {
-                trace!("  ENOENT ({err})");
+                trace!("  error ({err})");
+                reply.error(errno(&err));
+                return;
+            }
+            _ => {
+                trace!("  ENOENT (file not found)");
                 reply.error(ENOENT);
+                return;
+            }
+        };
+        let offset = offset as usize;
+        let end = offset
+            .saturating_add(size as usize)
+            .min(file.get_content_size_upper_bound());
+        if offset >= end {
+            trace!("  ok, len 0");
+            reply.data(&[]);
+            return;
+        }
+        let (first, last) = (
+            offset / MAX_BLOCK_CONTENT_SIZE,
+            (end - 1) / MAX_BLOCK_CONTENT_SIZE,
+        );
+        let timeout = self.timeout();
+        let reader = self.wnfs.content_reader();
+        let fetches = self
+            .reads
+            .fetch(ino, &file, first..=last, &reader, &self.spawner, timeout);
+        // The content is read while the next operations run.
+        let read = async move {
+            let _timer = timer;
+            match read_range(fetches, first, offset, end).await {
+                Ok(data) => {
+                    trace!("  ok, len {}", data.len());
+                    reply.data(&data)
+                }
+                Err(err) => {
+                    trace!("  error ({err})");
+                    reply.error(errno(&err));
+                }
             }
+        };
+        if let Err(err) = self.spawner.spawn_local(read.in_current_span()) {
+            tracing::error!("failed to start read of i{ino}: {err}");
         }
     }
 
-    fn readdir(
-        &mut self,
-        _req: &Request,
-        ino: u64,
-        _fh: u64,
-        offset: i64,
-        mut reply: ReplyDirectory,
-    ) {
+    #[instrument(level = "debug", skip(self, reply))]
+    fn readdir(&mut self, ino: u64, offset: i64, mut reply: ReplyDirectory) {
+        let _timer = OpTimer::new("readdir");
         trace!("readdir: i{ino} offset {offset}");
-        let path_segments = {
-            // We're cloning the path segments here to not keep an immutable borrow to self.inodes around.
+        let dir_path = {
+            // We're cloning the path here to not keep an immutable borrow to self.inodes around.
             // TODO: Maybe always wrap Inode an Rc
-            let Some(path_segments) = self.inodes.get_path_segments(ino) else {
+            let Some(dir_path) = self.inodes.get_path(ino) else {
                 trace!("  ENOENT (ino not found)");
                 reply.error(ENOENT);
                 return;
             };
-            path_segments.to_owned()
+            dir_path.clone()
         };
-        let dir = if path_segments.len() == 0 {
-            self.wnfs.private_root()
-        } else {
-            let Ok(Some(PrivateNode::Dir(dir))) = block_on(self.wnfs.get_node(&path_segments)) else {
-                  trace!("  ENOENT (dir not found)");
-                  reply.error(ENOENT);
-                  return;
-            };
-            dir
+        let dir = match self.node(ino) {
+            Ok(Some(PrivateNode::Dir(dir))) => dir,
+            Err(err) => {
+                trace!("  error ({err})");
+                reply.error(errno(&err));
+                return;
+            }
+            _ => {
+                trace!("  ENOENT (dir not found)");
+                reply.error(ENOENT);
+                return;
+            }
         };
+        let timeout = self.timeout();
 
-        let mut entries = vec![
-            (ino, FileType::Directory, "."),
-            (ino, FileType::Directory, ".."),
-        ];
-
-        for name in dir.entries() {
-            let path = push_segment(&path_segments, name);
-            let node = block_on(self.wnfs.get_node(&path));
-            match node {
-                Ok(Some(node)) => match node {
-                    PrivateNode::Dir(_dir) => {
-                        let ino = self.inodes.get_or_push(&path);
-                        entries.push((ino.ino, FileType::Directory, name));
-                    }
-                    PrivateNode::File(_file) => {
-                        let ino = self.inodes.get_or_push(&path);
-                        entries.push((ino.ino, FileType::RegularFile, name));
-                    }
-                },
-                _ => {
-                    // todo
-                }
+        // Children are looked up in the directory node, and only from the offset on.
+        let children = dir.entries().map(|name| name.as_str());
+        for (i, name) in [".", ".."].into_iter().chain(children).enumerate() {
+            if i < offset as usize {
+                continue;
             }
-        }
-        trace!("  ok {entries:?}");
-
-        for (i, entry) in entries.into_iter().enumerate().skip(offset as usize) {
+            let (entry_ino, kind) = if i < 2 {
+                (ino, FileType::Directory)
+            } else {
+                let path = dir_path.join(name);
+                let child_ino = self.inodes.get_or_push(&path).ino;
+                let node = match block_on(self.wnfs.lookup_child(&dir, name), timeout) {
+                    Ok(Some(node)) => node,
+                    // Every further lookup would most likely time out as well.
+                    Err(err) if err.is::<TimedOut>() => {
+                        trace!("  EIO ({err})");
+                        reply.error(EIO);
+                        return;
+                    }
+                    _ => continue,
+                };
+                let kind = match node {
+                    PrivateNode::Dir(_) => FileType::Directory,
+                    PrivateNode::File(_) => FileType::RegularFile,
+                };
+                // For the lookups that usually follow.
+                let budget = self.node_budget();
+                self.nodes.insert(child_ino, node, budget);
+                (child_ino, kind)
+            };
+            trace!("  entry {name} i{entry_ino}");
             // i + 1 means the index of the next entry
-            if reply.add(entry.0, (i + 1) as i64, entry.1, entry.2) {
+            if reply.add(entry_ino, (i + 1) as i64, kind, name) {
                 break;
             }
         }
         reply.ok();
     }
 
-    // fn open(&mut self, _req: &Request<'_>, _ino: u64, _flags: i32, reply: fuser::ReplyOpen) {
-    // }
-
-    fn mkdir(
-        &mut self,
-        _req: &Request<'_>,
-        parent: u64,
-        name: &OsStr,
-        _mode: u32,
-        _umask: u32,
-        reply: ReplyEntry,
-    ) {
+    #[instrument(level = "debug", skip(self, reply))]
+    fn mkdir(&mut self, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        let _timer = OpTimer::new("mkdir");
         trace!("mkdir : i{parent} {name:?}");
-        let Some(path_segments) = self.inodes.get_path_segments(parent) else {
-            trace!("  ENOENT: parent not found");
-            reply.error(ENOENT);
-            return;
+        let path = match self.child_path(parent, name) {
+            Ok(path) => path,
+            Err(code) => {
+                reply.error(code);
+                return;
+            }
         };
-        let path = push_segment(path_segments, name.to_string_lossy());
-        match block_on(self.wnfs.mkdir(&path)) {
-            Ok(_) => match block_on(self.wnfs.get_node(&path_segments)) {
+        let timeout = self.timeout();
+        match block_on(self.wnfs.get_node(&path), timeout) {
+            Ok(None) => {}
+            // Creating a directory in the store succeeds if it exists.
+            Ok(Some(_)) => {
+                trace!("  EEXIST");
+                reply.error(EEXIST);
+                return;
+            }
+            Err(err) => {
+                trace!("  failed to look up {path}: {err}");
+                reply.error(errno(&err));
+                return;
+            }
+        }
+        let checkpoint = self.wnfs.checkpoint();
+        let result = block_on(self.wnfs.mkdir(&path), timeout);
+        self.rollback_if_timed_out(checkpoint, &result);
+        match result {
+            Ok(_) => match block_on(self.wnfs.get_node(&path), timeout) {
                 Ok(Some(node)) => {
                     let ino = self.inodes.get_or_push(&path);
-                    let attr = node_to_attr(ino.ino, &node);
+                    let attr = self.attr(ino.ino, &node);
                     trace!("  ok, created! ino {}", ino.ino);
                     reply.entry(&TTL, &attr, 0);
                 }
@@ -290,10 +1446,308 @@ impl Filesystem for WnfsFuse {
                 }
             },
             Err(err) => {
-                trace!("  ENOENT, failed to create dir: {err}");
-                reply.error(ENOENT);
+                trace!("  failed to create dir: {err}");
+                reply.error(errno(&err));
+            }
+        }
+        self.flush_if_due();
+    }
+
+    #[instrument(level = "debug", skip(self, reply))]
+    fn create(&mut self, parent: u64, name: &OsStr, flags: i32, reply: ReplyCreate) {
+        let _timer = OpTimer::new("create");
+        trace!("create: i{parent} {name:?}");
+        match self.create_file(parent, name) {
+            Ok(attr) => {
+                let fh = self.handles.open(attr.ino);
+                reply.created(&TTL, &attr, 0, fh, flags as u32);
             }
+            Err(code) => reply.error(code),
+        }
+        self.flush_if_due();
+    }
+
+    #[instrument(level = "debug", skip(self, reply))]
+    fn mknod(&mut self, parent: u64, name: &OsStr, mode: u32, reply: ReplyEntry) {
+        let _timer = OpTimer::new("mknod");
+        trace!("mknod: i{parent} {name:?} mode {mode:o}");
+        // Devices, FIFOs and sockets cannot be stored.
+        if mode & libc::S_IFMT != libc::S_IFREG {
+            trace!("  EPERM (not a regular file)");
+            reply.error(EPERM);
+            return;
+        }
+        match self.create_file(parent, name) {
+            Ok(attr) => reply.entry(&TTL, &attr, 0),
+            Err(code) => reply.error(code),
         }
+        self.flush_if_due();
+    }
+
+    #[instrument(level = "debug", skip(self, reply))]
+    fn unlink(&mut self, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        let _timer = OpTimer::new("unlink");
+        trace!("unlink: i{parent} {name:?}");
+        match self.remove(parent, name, false) {
+            Ok(()) => reply.ok(),
+            Err(code) => reply.error(code),
+        }
+        self.flush_if_due();
+    }
+
+    #[instrument(level = "debug", skip(self, reply))]
+    fn rmdir(&mut self, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        let _timer = OpTimer::new("rmdir");
+        trace!("rmdir: i{parent} {name:?}");
+        match self.remove(parent, name, true) {
+            Ok(()) => reply.ok(),
+            Err(code) => reply.error(code),
+        }
+        self.flush_if_due();
+    }
+
+    #[instrument(level = "debug", skip(self, reply))]
+    fn rename(
+        &mut self,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        flags: u32,
+        reply: ReplyEmpty,
+    ) {
+        let _timer = OpTimer::new("rename");
+        trace!("rename: i{parent} {name:?} to i{newparent} {newname:?}");
+        match self.rename_entry(parent, name, newparent, newname, flags) {
+            Ok(()) => reply.ok(),
+            Err(code) => reply.error(code),
+        }
+        self.flush_if_due();
+    }
+
+    #[instrument(level = "debug", skip(self, data, reply), fields(len = data.len()))]
+    fn write(&mut self, ino: u64, offset: i64, data: &[u8], reply: ReplyWrite) {
+        let _timer = OpTimer::new("write");
+        let size = data.len();
+        trace!("write i{ino} offset {offset} size {size}");
+        if self.handles.files.contains_key(&ino) {
+            match self.write_buffered(ino, offset as usize, data) {
+                Ok(()) => {
+                    trace!("  ok, buffered {size}");
+                    reply.written(size as u32);
+                }
+                Err(err) => {
+                    trace!("  failed to write: {err}");
+                    reply.error(errno(&err));
+                }
+            }
+            return;
+        }
+        let Some(path) = self.inodes.get_path(ino).cloned() else {
+            trace!("  ENOENT (ino not found)");
+            reply.error(ENOENT);
+            return;
+        };
+        let timeout = self.timeout();
+        let checkpoint = self.wnfs.checkpoint();
+        let result = block_on(
+            self.wnfs.write_file_at(&path, offset as usize, data),
+            timeout,
+        );
+        self.rollback_if_timed_out(checkpoint, &result);
+        match result {
+            Ok(()) => {
+                trace!("  ok, written {size}");
+                reply.written(size as u32);
+            }
+            Err(err) => {
+                trace!("  failed to write: {err}");
+                reply.error(errno(&err));
+            }
+        }
+        self.flush_if_due();
+    }
+}
+
+/// An operation for the thread that serves a mount.
+type Job<S> = Box<dyn FnOnce(&mut WnfsFuse<S>) + Send>;
+
+/// Receives the requests of the FUSE session and sends them to the thread that serves the
+/// mount, so that the session does not wait for operations to complete.
+///
+/// Replies are sent by the operations. A reply that is dropped, e.g. because the mount is
+/// shutting down, fails the request with `EIO`.
+struct Dispatcher<S: Store> {
+    jobs: mpsc::UnboundedSender<Job<S>>,
+}
+
+impl<S: Store> Dispatcher<S> {
+    fn dispatch(&self, job: impl FnOnce(&mut WnfsFuse<S>) + Send + 'static) {
+        if self.jobs.unbounded_send(Box::new(job)).is_err() {
+            tracing::error!("request after the mount stopped serving");
+        }
+    }
+}
+
+impl<S: Store + 'static> Filesystem for Dispatcher<S> {
+    fn destroy(&mut self) {
+        self.dispatch(|fs| fs.destroy());
+    }
+
+    fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        let name = name.to_owned();
+        self.dispatch(move |fs| fs.lookup(parent, &name, reply));
+    }
+
+    fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
+        self.dispatch(move |fs| fs.getattr(ino, reply));
+    }
+
+    fn setattr(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        mode: Option<u32>,
+        _uid: Option<u32>,
+        _gid: Option<u32>,
+        size: Option<u64>,
+        _atime: Option<TimeOrNow>,
+        mtime: Option<TimeOrNow>,
+        _ctime: Option<SystemTime>,
+        _fh: Option<u64>,
+        crtime: Option<SystemTime>,
+        _chgtime: Option<SystemTime>,
+        _bkuptime: Option<SystemTime>,
+        _flags: Option<u32>,
+        reply: ReplyAttr,
+    ) {
+        self.dispatch(move |fs| fs.setattr(ino, mode, size, mtime, crtime, reply));
+    }
+
+    fn open(&mut self, _req: &Request<'_>, ino: u64, _flags: i32, reply: ReplyOpen) {
+        self.dispatch(move |fs| fs.open(ino, reply));
+    }
+
+    fn release(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        _flush: bool,
+        reply: ReplyEmpty,
+    ) {
+        self.dispatch(move |fs| fs.release(ino, fh, reply));
+    }
+
+    fn fsync(&mut self, _req: &Request<'_>, ino: u64, fh: u64, _datasync: bool, reply: ReplyEmpty) {
+        self.dispatch(move |fs| fs.fsync(ino, fh, reply));
+    }
+
+    fn fsyncdir(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        _datasync: bool,
+        reply: ReplyEmpty,
+    ) {
+        self.dispatch(move |fs| fs.fsyncdir(ino, fh, reply));
+    }
+
+    fn flush(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        _lock_owner: u64,
+        reply: ReplyEmpty,
+    ) {
+        self.dispatch(move |fs| fs.flush(ino, fh, reply));
+    }
+
+    fn read(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock: Option<u64>,
+        reply: ReplyData,
+    ) {
+        self.dispatch(move |fs| fs.read(ino, offset, size, reply));
+    }
+
+    fn readdir(&mut self, _req: &Request, ino: u64, _fh: u64, offset: i64, reply: ReplyDirectory) {
+        self.dispatch(move |fs| fs.readdir(ino, offset, reply));
+    }
+
+    fn mkdir(
+        &mut self,
+        _req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        _mode: u32,
+        _umask: u32,
+        reply: ReplyEntry,
+    ) {
+        let name = name.to_owned();
+        self.dispatch(move |fs| fs.mkdir(parent, &name, reply));
+    }
+
+    fn create(
+        &mut self,
+        _req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        _mode: u32,
+        _umask: u32,
+        flags: i32,
+        reply: ReplyCreate,
+    ) {
+        let name = name.to_owned();
+        self.dispatch(move |fs| fs.create(parent, &name, flags, reply));
+    }
+
+    fn mknod(
+        &mut self,
+        _req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        _umask: u32,
+        _rdev: u32,
+        reply: ReplyEntry,
+    ) {
+        let name = name.to_owned();
+        self.dispatch(move |fs| fs.mknod(parent, &name, mode, reply));
+    }
+
+    fn unlink(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        let name = name.to_owned();
+        self.dispatch(move |fs| fs.unlink(parent, &name, reply));
+    }
+
+    fn rmdir(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        let name = name.to_owned();
+        self.dispatch(move |fs| fs.rmdir(parent, &name, reply));
+    }
+
+    fn rename(
+        &mut self,
+        _req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        flags: u32,
+        reply: ReplyEmpty,
+    ) {
+        let (name, newname) = (name.to_owned(), newname.to_owned());
+        self.dispatch(move |fs| fs.rename(parent, &name, newparent, &newname, flags, reply));
     }
 
     fn write(
@@ -306,15 +1760,14 @@ impl Filesystem for WnfsFuse {
         _write_flags: u32,
         _flags: i32,
         _lock_owner: Option<u64>,
-        reply: fuser::ReplyWrite,
+        reply: ReplyWrite,
     ) {
-        let size = data.len();
-        trace!("write i{ino} offset {offset} size {size}");
-        reply.error(ENOENT);
+        let data = data.to_vec();
+        self.dispatch(move |fs| fs.write(ino, offset, &data, reply));
     }
 }
 
-fn node_to_attr(ino: u64, node: &PrivateNode) -> FileAttr {
+fn node_to_attr(ino: u64, node: &PrivateNode, options: &MountOptions) -> FileAttr {
     let metadata = match node {
         PrivateNode::File(file) => file.get_metadata(),
         PrivateNode::Dir(dir) => dir.get_metadata(),
@@ -323,10 +1776,13 @@ fn node_to_attr(ino: u64, node: &PrivateNode) -> FileAttr {
         PrivateNode::File(_) => FileType::RegularFile,
         PrivateNode::Dir(_) => FileType::Directory,
     };
-    let perm = match node {
-        PrivateNode::File(_) => 0o444,
-        PrivateNode::Dir(_) => 0o555,
+    let perm = match (node, options.read_only) {
+        (PrivateNode::File(_), true) => 0o444,
+        (PrivateNode::File(_), false) => 0o644,
+        (PrivateNode::Dir(_), true) => 0o555,
+        (PrivateNode::Dir(_), false) => 0o755,
     };
+    let perm = node_mode(node).map_or(perm, |mode| mode as u16) & !options.umask.unwrap_or(0);
     let size = match node {
         PrivateNode::File(file) => file.get_content_size_upper_bound(),
         PrivateNode::Dir(_) => 0,
@@ -350,8 +1806,8 @@ fn node_to_attr(ino: u64, node: &PrivateNode) -> FileAttr {
         blocks: blocks as u64,
         nlink,
         perm,
-        uid: 1000,
-        gid: 1000,
+        uid: options.uid.unwrap_or_else(|| unsafe { libc::getuid() }),
+        gid: options.gid.unwrap_or_else(|| unsafe { libc::getgid() }),
         rdev: 0,
         flags: 0,
         blksize: BLOCK_SIZE as u32,
@@ -362,9 +1818,3 @@ fn node_to_attr(ino: u64, node: &PrivateNode) -> FileAttr {
         crtime: ctime,
     }
 }
-
-fn push_segment(path_segments: &Vec<String>, name: impl ToString) -> Vec<String> {
-    let mut path = path_segments.clone();
-    path.push(name.to_string());
-    path
-}
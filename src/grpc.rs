@@ -0,0 +1,310 @@
+//! gRPC service for driving a filesystem from other languages.
+//!
+//! The service is defined in `proto/wnfs.proto`. File content is streamed in chunks in both
+//! directions. Change events are found by polling the root record and diffing consecutive
+//! roots, so they include changes made by other processes on the same store.
+
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use futures::stream::BoxStream;
+use futures::{FutureExt, StreamExt};
+use tokio::sync::broadcast;
+use tonic::{Request, Response, Status, Streaming};
+use tracing::{debug, warn};
+
+use crate::fs::{ChangeKind, DirEntry, EntryKind};
+use crate::handle::WnfsHandle;
+use pb::filesystem_server::{Filesystem, FilesystemServer};
+
+/// Types generated from `proto/wnfs.proto`.
+pub mod pb {
+    tonic::include_proto!("wnfs.v1");
+}
+
+const READ_CHUNK_SIZE: usize = 64 * 1024;
+/// How often the root record is checked for changes.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// Change events buffered per subscriber before it lags behind.
+const EVENT_BUFFER: usize = 1024;
+
+/// Serve a filesystem over gRPC until the server fails.
+pub async fn serve(fs: WnfsHandle, addr: SocketAddr) -> anyhow::Result<()> {
+    debug!("serve gRPC on {addr}");
+    tonic::transport::Server::builder()
+        .add_service(FilesystemServer::new(GrpcService::new(fs)))
+        .serve(addr)
+        .await?;
+    Ok(())
+}
+
+/// Implementation of the `Filesystem` service, to be added to an application's own server.
+#[derive(Clone)]
+pub struct GrpcService {
+    fs: WnfsHandle,
+    events: broadcast::Sender<pb::ChangeEvent>,
+}
+
+impl GrpcService {
+    /// Create the service and start watching the filesystem for changes.
+    pub fn new(fs: WnfsHandle) -> Self {
+        let (events, _) = broadcast::channel(EVENT_BUFFER);
+        tokio::spawn(watch_root(fs.clone(), events.clone()));
+        Self { fs, events }
+    }
+
+    async fn lookup(&self, path_segments: Vec<String>) -> Result<DirEntry, Status> {
+        self.fs
+            .call(move |fs| async move { fs.stat(&path_segments).await }.boxed_local())
+            .await
+            .map_err(internal)?
+            .ok_or_else(|| Status::not_found("Not found"))
+    }
+}
+
+#[tonic::async_trait]
+impl Filesystem for GrpcService {
+    type ReadStream = BoxStream<'static, Result<pb::Chunk, Status>>;
+    type WatchStream = BoxStream<'static, Result<pb::ChangeEvent, Status>>;
+
+    async fn ls(
+        &self,
+        request: Request<pb::PathRequest>,
+    ) -> Result<Response<pb::LsResponse>, Status> {
+        let path_segments = into_segments(&request.into_inner().path);
+        let entries = self
+            .fs
+            .call(move |fs| async move { fs.ls_entries(&path_segments).await }.boxed_local())
+            .await
+            .map_err(internal)?;
+        let entries = entries.into_iter().map(to_entry).collect();
+        Ok(Response::new(pb::LsResponse { entries }))
+    }
+
+    async fn stat(&self, request: Request<pb::PathRequest>) -> Result<Response<pb::Entry>, Status> {
+        let path_segments = into_segments(&request.into_inner().path);
+        let entry = self.lookup(path_segments).await?;
+        Ok(Response::new(to_entry(entry)))
+    }
+
+    async fn read(
+        &self,
+        request: Request<pb::ReadRequest>,
+    ) -> Result<Response<Self::ReadStream>, Status> {
+        let request = request.into_inner();
+        let path_segments = into_segments(&request.path);
+        if self.lookup(path_segments.clone()).await?.kind == EntryKind::Dir {
+            return Err(Status::invalid_argument("Is a directory"));
+        }
+        let end = request.length.map(|length| request.offset + length);
+        let fs = self.fs.clone();
+        // The state is the offset of the next chunk, or `None` after the last one.
+        let stream = futures::stream::unfold(Some(request.offset), move |offset| {
+            let fs = fs.clone();
+            let path_segments = path_segments.clone();
+            async move {
+                let offset = offset?;
+                let len = match end {
+                    Some(end) => (end.saturating_sub(offset) as usize).min(READ_CHUNK_SIZE),
+                    None => READ_CHUNK_SIZE,
+                };
+                if len == 0 {
+                    return None;
+                }
+                let data = fs
+                    .call(move |fs| {
+                        async move { fs.read_file_at(&path_segments, offset as usize, len).await }
+                            .boxed_local()
+                    })
+                    .await;
+                match data {
+                    Ok(data) if data.is_empty() => None,
+                    Ok(data) => {
+                        let next = (data.len() == len).then_some(offset + data.len() as u64);
+                        Some((Ok(pb::Chunk { data }), next))
+                    }
+                    Err(err) => Some((Err(internal(err)), None)),
+                }
+            }
+        });
+        Ok(Response::new(stream.boxed()))
+    }
+
+    async fn write(
+        &self,
+        request: Request<Streaming<pb::WriteRequest>>,
+    ) -> Result<Response<pb::WriteResponse>, Status> {
+        let mut stream = request.into_inner();
+        let mut first = None;
+        let mut content = vec![];
+        while let Some(message) = stream.message().await? {
+            content.extend_from_slice(&message.data);
+            first.get_or_insert((message.path, message.append));
+        }
+        let (path, append) = first.ok_or_else(|| Status::invalid_argument("Empty write"))?;
+        let path_segments = into_segments(&path);
+        let bytes = content.len() as u64;
+        self.fs
+            .call(move |fs| {
+                async move {
+                    if append {
+                        fs.append_file(&path_segments, content).await
+                    } else {
+                        fs.write_file(&path_segments, content).await
+                    }
+                }
+                .boxed_local()
+            })
+            .await
+            .map_err(internal)?;
+        Ok(Response::new(pb::WriteResponse { bytes }))
+    }
+
+    async fn mkdir(
+        &self,
+        request: Request<pb::PathRequest>,
+    ) -> Result<Response<pb::Empty>, Status> {
+        let path_segments = into_segments(&request.into_inner().path);
+        self.fs
+            .call(move |fs| async move { fs.mkdir(&path_segments).await }.boxed_local())
+            .await
+            .map_err(internal)?;
+        Ok(Response::new(pb::Empty {}))
+    }
+
+    async fn rm(&self, request: Request<pb::PathRequest>) -> Result<Response<pb::Empty>, Status> {
+        let path_segments = into_segments(&request.into_inner().path);
+        self.lookup(path_segments.clone()).await?;
+        self.fs
+            .call(move |fs| async move { fs.rm(&path_segments).await }.boxed_local())
+            .await
+            .map_err(internal)?;
+        Ok(Response::new(pb::Empty {}))
+    }
+
+    async fn mv(&self, request: Request<pb::MvRequest>) -> Result<Response<pb::Empty>, Status> {
+        let request = request.into_inner();
+        let from = into_segments(&request.from);
+        let to = into_segments(&request.to);
+        self.lookup(from.clone()).await?;
+        self.fs
+            .call(move |fs| async move { fs.mv(&from, &to).await }.boxed_local())
+            .await
+            .map_err(internal)?;
+        Ok(Response::new(pb::Empty {}))
+    }
+
+    async fn watch(
+        &self,
+        request: Request<pb::PathRequest>,
+    ) -> Result<Response<Self::WatchStream>, Status> {
+        let prefix = into_segments(&request.into_inner().path).join("/");
+        let events = self.events.subscribe();
+        let stream = futures::stream::unfold(events, move |mut events| {
+            let prefix = prefix.clone();
+            async move {
+                loop {
+                    match events.recv().await {
+                        Ok(event) if is_below(&event.path, &prefix) => {
+                            return Some((Ok(event), events))
+                        }
+                        Ok(_) => {}
+                        Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                            warn!("watcher lagged behind, skipped {skipped} events");
+                        }
+                        Err(broadcast::error::RecvError::Closed) => return None,
+                    }
+                }
+            }
+        });
+        Ok(Response::new(stream.boxed()))
+    }
+}
+
+/// Broadcast the changes between consecutive root records.
+async fn watch_root(fs: WnfsHandle, events: broadcast::Sender<pb::ChangeEvent>) {
+    let mut last_root = None;
+    let mut interval = tokio::time::interval(POLL_INTERVAL);
+    loop {
+        interval.tick().await;
+        let root = match fs
+            .call(|fs| async move { fs.root_cid().await }.boxed_local())
+            .await
+        {
+            Ok(root) => root,
+            Err(err) => {
+                debug!("stop watching: {err}");
+                return;
+            }
+        };
+        let (Some(last), Some(root)) = (last_root, root) else {
+            last_root = root;
+            continue;
+        };
+        if last == root {
+            continue;
+        }
+        last_root = Some(root);
+        // Without subscribers, only keep track of the root.
+        if events.receiver_count() == 0 {
+            continue;
+        }
+        let (from, to) = (last.to_string(), root.to_string());
+        let changes = fs
+            .call(move |fs| async move { fs.diff(&from, &to).await }.boxed_local())
+            .await;
+        let changes = match changes {
+            Ok(changes) => changes,
+            Err(err) => {
+                warn!("failed to diff {last} and {root}: {err}");
+                continue;
+            }
+        };
+        for change in changes {
+            let kind = match change.kind {
+                ChangeKind::Added => pb::ChangeKind::Added,
+                ChangeKind::Removed => pb::ChangeKind::Removed,
+                ChangeKind::Modified => pb::ChangeKind::Modified,
+            };
+            let _ = events.send(pb::ChangeEvent {
+                path: change.path.segments().join("/"),
+                kind: kind as i32,
+                root_cid: root.to_string(),
+            });
+        }
+    }
+}
+
+fn is_below(path: &str, prefix: &str) -> bool {
+    prefix.is_empty()
+        || path == prefix
+        || path
+            .strip_prefix(prefix)
+            .map_or(false, |rest| rest.starts_with('/'))
+}
+
+fn to_entry(entry: DirEntry) -> pb::Entry {
+    let kind = match entry.kind {
+        EntryKind::File => pb::EntryKind::File,
+        EntryKind::Dir => pb::EntryKind::Dir,
+    };
+    pb::Entry {
+        name: entry.name,
+        kind: kind as i32,
+        size: entry.size,
+        modified: entry.modified.map(|modified| modified.timestamp()),
+        mode: entry.mode,
+    }
+}
+
+fn into_segments(path: &str) -> Vec<String> {
+    path.split('/')
+        .filter(|segment| !segment.is_empty())
+        .map(|segment| segment.to_owned())
+        .collect()
+}
+
+fn internal(err: anyhow::Error) -> Status {
+    debug!("grpc error: {err}");
+    Status::internal(err.to_string())
+}
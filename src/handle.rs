@@ -0,0 +1,112 @@
+use std::future::Future;
+use std::time::Duration;
+
+use futures::future::LocalBoxFuture;
+use futures::FutureExt;
+use tokio::sync::{mpsc, oneshot};
+
+use crate::fs::Wnfs;
+
+/// Interval in which changes are flushed while the filesystem is open.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(5);
+
+type Job = Box<dyn for<'a> FnOnce(&'a mut Wnfs) -> LocalBoxFuture<'a, ()> + Send>;
+
+/// A cloneable, thread-safe handle to a [`Wnfs`].
+///
+/// [`Wnfs`] is not `Send`, so it lives on a dedicated thread with a single-threaded runtime.
+/// Operations are sent to that thread as closures and run one after another.
+#[derive(Clone, Debug)]
+pub struct WnfsHandle {
+    tx: mpsc::UnboundedSender<Job>,
+}
+
+impl WnfsHandle {
+    /// Open a filesystem on a new thread.
+    ///
+    /// Changes are flushed every few seconds, and the filesystem is flushed and closed once
+    /// all handles are dropped.
+    pub async fn spawn<F, Fut>(open: F) -> anyhow::Result<Self>
+    where
+        F: FnOnce() -> Fut + Send + 'static,
+        Fut: Future<Output = anyhow::Result<Wnfs>> + 'static,
+    {
+        let (tx, mut rx) = mpsc::unbounded_channel::<Job>();
+        let (ready_tx, ready_rx) = oneshot::channel();
+        std::thread::spawn(move || {
+            let rt = match tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+            {
+                Ok(rt) => rt,
+                Err(err) => {
+                    let _ = ready_tx.send(Err(err.into()));
+                    return;
+                }
+            };
+            rt.block_on(async move {
+                let mut fs = match open().await {
+                    Ok(fs) => fs,
+                    Err(err) => {
+                        let _ = ready_tx.send(Err(err));
+                        return;
+                    }
+                };
+                let _ = ready_tx.send(Ok(()));
+                let mut interval = tokio::time::interval(FLUSH_INTERVAL);
+                loop {
+                    tokio::select! {
+                        job = rx.recv() => match job {
+                            Some(job) => job(&mut fs).await,
+                            None => break,
+                        },
+                        _ = interval.tick() => {
+                            if let Err(err) = fs.flush().await {
+                                tracing::error!("failed to flush: {err}");
+                            }
+                        }
+                    }
+                }
+                if let Err(err) = fs.flush().await {
+                    tracing::error!("failed to flush on close: {err}");
+                }
+            });
+        });
+        ready_rx.await??;
+        Ok(Self { tx })
+    }
+
+    /// Run an operation on the filesystem.
+    ///
+    /// ```ignore
+    /// let entries = handle
+    ///     .call(move |fs| async move { fs.ls_entries(&path).await }.boxed_local())
+    ///     .await?;
+    /// ```
+    pub async fn call<T, F>(&self, f: F) -> anyhow::Result<T>
+    where
+        F: for<'a> FnOnce(&'a mut Wnfs) -> LocalBoxFuture<'a, anyhow::Result<T>> + Send + 'static,
+        T: Send + 'static,
+    {
+        let (tx, rx) = oneshot::channel();
+        let job = job(move |fs| {
+            async move {
+                let _ = tx.send(f(fs).await);
+            }
+            .boxed_local()
+        });
+        self.tx
+            .send(job)
+            .map_err(|_| anyhow::anyhow!("Filesystem is closed"))?;
+        rx.await
+            .map_err(|_| anyhow::anyhow!("Filesystem is closed"))?
+    }
+}
+
+// Helps type inference for the higher-ranked closure.
+fn job<F>(f: F) -> Job
+where
+    F: for<'a> FnOnce(&'a mut Wnfs) -> LocalBoxFuture<'a, ()> + Send + 'static,
+{
+    Box::new(f)
+}
@@ -0,0 +1,178 @@
+//! Hooks that run when a file was written or read, to plug in virus scanning, thumbnailing or
+//! compression without changing the crate.
+//!
+//! Hooks are registered with [`Wnfs::add_hook`](crate::fs::Wnfs::add_hook) and called in
+//! order after every completed write and every read of a whole file. Ranged reads, which
+//! mounts use, do not run hooks. An error of a hook is returned from the operation, but a
+//! written file stays written.
+//!
+//! [`CommandHook`] is a sample hook that pipes the content to a command. The CLI runs the
+//! commands configured in the config file:
+//!
+//! ```toml
+//! [[hooks]]
+//! command = ["clamdscan", "--no-summary", "-"]
+//! on = ["write", "read"]
+//! ```
+
+use async_trait::async_trait;
+use futures::stream::{self, LocalBoxStream, StreamExt};
+use serde::Deserialize;
+
+/// Size of the chunks of [`ContentStream`]s.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Content of a file, in chunks.
+pub type ContentStream<'a> = LocalBoxStream<'a, &'a [u8]>;
+
+/// A file that was written or read.
+#[derive(Debug, Clone)]
+pub struct FileEvent<'a> {
+    pub path: &'a [String],
+    pub size: u64,
+}
+
+/// Code that runs on file operations. All methods do nothing by default.
+#[async_trait(?Send)]
+pub trait FileHook {
+    /// Called after a file was written.
+    async fn on_write(
+        &self,
+        _event: &FileEvent<'_>,
+        _content: ContentStream<'_>,
+    ) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Called after a whole file was read, before the content is returned.
+    async fn on_read(
+        &self,
+        _event: &FileEvent<'_>,
+        _content: ContentStream<'_>,
+    ) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+pub(crate) fn content_stream(content: &[u8]) -> ContentStream<'_> {
+    stream::iter(content.chunks(CHUNK_SIZE)).boxed_local()
+}
+
+/// Operations that run a configured hook.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Trigger {
+    Write,
+    Read,
+}
+
+/// A [`CommandHook`] in the config file.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HookConfig {
+    /// Program and arguments.
+    pub command: Vec<String>,
+    #[serde(default = "default_triggers")]
+    pub on: Vec<Trigger>,
+}
+
+fn default_triggers() -> Vec<Trigger> {
+    vec![Trigger::Write]
+}
+
+/// Pipes the content of files to a command and fails if the command exits with an error,
+/// e.g. to scan files for viruses.
+///
+/// The command gets the path in `WNFS_HOOK_PATH`, the size in `WNFS_HOOK_SIZE` and `write` or
+/// `read` in `WNFS_HOOK_EVENT`. Its standard output is discarded. Commands run as tokio
+/// processes, so they need a tokio runtime with I/O enabled.
+#[cfg(feature = "native")]
+#[derive(Debug, Clone)]
+pub struct CommandHook {
+    program: String,
+    args: Vec<String>,
+    on: Vec<Trigger>,
+}
+
+#[cfg(feature = "native")]
+impl CommandHook {
+    pub fn new(config: &HookConfig) -> anyhow::Result<Self> {
+        let (program, args) = config
+            .command
+            .split_first()
+            .ok_or_else(|| anyhow::anyhow!("Hook command is empty"))?;
+        Ok(Self {
+            program: program.clone(),
+            args: args.to_vec(),
+            on: config.on.clone(),
+        })
+    }
+
+    async fn run(
+        &self,
+        trigger: &str,
+        event: &FileEvent<'_>,
+        mut content: ContentStream<'_>,
+    ) -> anyhow::Result<()> {
+        use std::process::Stdio;
+
+        use tokio::io::AsyncWriteExt;
+        use tokio::process::Command;
+
+        let path = event.path.join("/");
+        // Waited for asynchronously, so that a slow command does not block the thread that
+        // serves the filesystem, and operations that wait for it can time out.
+        let mut child = Command::new(&self.program)
+            .args(&self.args)
+            .env("WNFS_HOOK_PATH", &path)
+            .env("WNFS_HOOK_SIZE", event.size.to_string())
+            .env("WNFS_HOOK_EVENT", trigger)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .spawn()
+            .map_err(|err| anyhow::anyhow!("Failed to run hook {}: {err}", self.program))?;
+        let mut stdin = child.stdin.take().expect("stdin is piped");
+        while let Some(chunk) = content.next().await {
+            match stdin.write_all(chunk).await {
+                // The command does not need the rest of the content.
+                Err(err) if err.kind() == std::io::ErrorKind::BrokenPipe => break,
+                result => result?,
+            }
+        }
+        drop(stdin);
+        let status = child.wait().await?;
+        if !status.success() {
+            anyhow::bail!(
+                "Hook {} rejected {trigger} of {path} ({status})",
+                self.program
+            );
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "native")]
+#[async_trait(?Send)]
+impl FileHook for CommandHook {
+    async fn on_write(
+        &self,
+        event: &FileEvent<'_>,
+        content: ContentStream<'_>,
+    ) -> anyhow::Result<()> {
+        if !self.on.contains(&Trigger::Write) {
+            return Ok(());
+        }
+        self.run("write", event, content).await
+    }
+
+    async fn on_read(
+        &self,
+        event: &FileEvent<'_>,
+        content: ContentStream<'_>,
+    ) -> anyhow::Result<()> {
+        if !self.on.contains(&Trigger::Read) {
+            return Ok(());
+        }
+        self.run("read", event, content).await
+    }
+}
@@ -0,0 +1,168 @@
+//! HTTP server exposing a filesystem.
+//!
+//! * `GET /files/<path>` returns the content of a file, honoring single `Range` headers
+//! * `PUT /files/<path>` writes the request body to a file, creating parent directories
+//! * `DELETE /files/<path>` removes a file or directory
+//! * `GET /ls/<path>` returns a JSON listing of a directory
+
+use std::net::SocketAddr;
+
+use axum::body::Bytes;
+use axum::extract::{Path, State};
+use axum::http::header::{CONTENT_RANGE, RANGE};
+use axum::http::{HeaderMap, HeaderValue, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::{Json, Router};
+use futures::FutureExt;
+use tracing::debug;
+
+use crate::fs::{DirEntry, EntryKind};
+use crate::handle::WnfsHandle;
+
+/// Serve a filesystem over HTTP until the server fails.
+pub async fn serve(fs: WnfsHandle, addr: SocketAddr) -> anyhow::Result<()> {
+    let app = Router::new()
+        .route("/ls", get(ls_root))
+        .route("/ls/*path", get(ls))
+        .route(
+            "/files/*path",
+            get(get_file).put(put_file).delete(delete_file),
+        )
+        .with_state(fs);
+    debug!("serve HTTP on {addr}");
+    axum::Server::bind(&addr)
+        .serve(app.into_make_service())
+        .await?;
+    Ok(())
+}
+
+async fn ls_root(state: State<WnfsHandle>) -> Result<Json<Vec<DirEntry>>, Error> {
+    ls(state, Path(String::new())).await
+}
+
+async fn ls(
+    State(fs): State<WnfsHandle>,
+    Path(path): Path<String>,
+) -> Result<Json<Vec<DirEntry>>, Error> {
+    let path_segments = into_segments(&path);
+    match stat(&fs, &path_segments).await?.kind {
+        EntryKind::File => Err(Error(StatusCode::BAD_REQUEST, "Not a directory".into())),
+        EntryKind::Dir => {
+            let entries = fs
+                .call(move |fs| async move { fs.ls_entries(&path_segments).await }.boxed_local())
+                .await?;
+            Ok(Json(entries))
+        }
+    }
+}
+
+async fn get_file(
+    State(fs): State<WnfsHandle>,
+    Path(path): Path<String>,
+    headers: HeaderMap,
+) -> Result<Response, Error> {
+    let path_segments = into_segments(&path);
+    let entry = stat(&fs, &path_segments).await?;
+    if entry.kind == EntryKind::Dir {
+        return Err(Error(StatusCode::BAD_REQUEST, "Is a directory".into()));
+    }
+    let range = headers
+        .get(RANGE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(parse_range);
+    match range {
+        None => {
+            let data = fs
+                .call(move |fs| async move { fs.read_file(&path_segments).await }.boxed_local())
+                .await?;
+            Ok(data.into_response())
+        }
+        Some((start, end)) => {
+            // The size is an upper bound, so the total length is reported as unknown.
+            let end = end.map(|end| end + 1).unwrap_or(entry.size).min(entry.size);
+            let len = end.saturating_sub(start) as usize;
+            let data = fs
+                .call(move |fs| {
+                    async move { fs.read_file_at(&path_segments, start as usize, len).await }
+                        .boxed_local()
+                })
+                .await?;
+            if data.is_empty() {
+                return Err(Error(
+                    StatusCode::RANGE_NOT_SATISFIABLE,
+                    "Range not satisfiable".into(),
+                ));
+            }
+            let content_range = format!("bytes {}-{}/*", start, start + data.len() as u64 - 1);
+            let mut response = (StatusCode::PARTIAL_CONTENT, data).into_response();
+            response
+                .headers_mut()
+                .insert(CONTENT_RANGE, HeaderValue::from_str(&content_range)?);
+            Ok(response)
+        }
+    }
+}
+
+async fn put_file(
+    State(fs): State<WnfsHandle>,
+    Path(path): Path<String>,
+    body: Bytes,
+) -> Result<StatusCode, Error> {
+    let path_segments = into_segments(&path);
+    fs.call(move |fs| {
+        async move { fs.write_file(&path_segments, body.to_vec()).await }.boxed_local()
+    })
+    .await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn delete_file(
+    State(fs): State<WnfsHandle>,
+    Path(path): Path<String>,
+) -> Result<StatusCode, Error> {
+    let path_segments = into_segments(&path);
+    stat(&fs, &path_segments).await?;
+    fs.call(move |fs| async move { fs.rm(&path_segments).await }.boxed_local())
+        .await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn stat(fs: &WnfsHandle, path_segments: &[String]) -> Result<DirEntry, Error> {
+    let path_segments = path_segments.to_vec();
+    fs.call(move |fs| async move { fs.stat(&path_segments).await }.boxed_local())
+        .await?
+        .ok_or_else(|| Error(StatusCode::NOT_FOUND, "Not found".into()))
+}
+
+/// Parse a single `bytes=start-end` range. Suffix ranges and multiple ranges are not supported.
+fn parse_range(value: &str) -> Option<(u64, Option<u64>)> {
+    let (start, end) = value.strip_prefix("bytes=")?.split_once('-')?;
+    let start = start.trim().parse().ok()?;
+    let end = match end.trim() {
+        "" => None,
+        end => Some(end.parse().ok()?),
+    };
+    Some((start, end))
+}
+
+fn into_segments(path: &str) -> Vec<String> {
+    path.split('/')
+        .filter(|segment| !segment.is_empty())
+        .map(|segment| segment.to_owned())
+        .collect()
+}
+
+struct Error(StatusCode, String);
+
+impl<E: Into<anyhow::Error>> From<E> for Error {
+    fn from(err: E) -> Self {
+        Self(StatusCode::INTERNAL_SERVER_ERROR, err.into().to_string())
+    }
+}
+
+impl IntoResponse for Error {
+    fn into_response(self) -> Response {
+        (self.0, self.1).into_response()
+    }
+}
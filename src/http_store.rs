@@ -0,0 +1,568 @@
+//! A block store served over authenticated HTTP(S).
+//!
+//! `serve blocks` exposes a local [`SqliteBlockStore`] with [`serve`], and [`HttpBlockStore`]
+//! is the client, so that one machine can host the store and others mount it remotely. Blocks
+//! are content addressed and checked against their CID on both sides, and the filesystems in
+//! them stay encrypted, so the server never learns their content. The server can still drop
+//! or roll back blocks and aliases, and needs TLS outside of trusted networks.
+//!
+//! All endpoints are below `/blocks/v1` and require an `Authorization: Bearer <token>` header.
+//! Errors are returned as `{"error": "..."}` with a matching status code.
+//!
+//! * `GET /block/<cid>` returns a block, `PUT /block/<cid>` stores the request body
+//! * `POST /missing` with a JSON list of CIDs returns those the store does not have
+//! * `POST /get` with a JSON list of CIDs returns the blocks as frames
+//! * `POST /put` stores a body of frames, a CID followed by its block
+//! * `GET /alias/<name>` returns `{"cid": "..."}`, `PUT` sets it, `DELETE` removes it
+//! * `GET /aliases?prefix=` lists aliases as `[{"name": "...", "cid": "..."}]`
+//!
+//! A frame is a 4 byte big endian length followed by that many bytes.
+
+use std::borrow::Cow;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+use async_trait::async_trait;
+use axum::body::Bytes;
+use axum::extract::{DefaultBodyLimit, Path, Query, State};
+use axum::http::header::AUTHORIZATION;
+use axum::http::{Request, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use axum_server::tls_rustls::RustlsConfig;
+use libipld::cid::Version;
+use libipld::{Block, Cid, IpldCodec};
+use multihash::{Code, MultihashDigest};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tracing::debug;
+use wnfs_common::BlockStore;
+
+use crate::fs::private_root_alias;
+use crate::remote::RemoteStore;
+use crate::store::{DefaultParams, Store};
+use crate::SqliteBlockStore;
+
+/// Environment variable with the token of a remote store, for clients.
+pub const TOKEN_ENV: &str = "WNFS_BLOCKS_TOKEN";
+/// Most blocks in one batch request.
+const MAX_BATCH: usize = 1024;
+/// Largest request body the server accepts.
+const MAX_BODY_SIZE: usize = 256 * 1024 * 1024;
+/// Clients split batches of blocks into requests of at most this size.
+const MAX_PUT_SIZE: usize = 32 * 1024 * 1024;
+
+/// Options for the block server.
+#[derive(Debug, Clone)]
+pub struct BlockServerConfig {
+    /// Token that clients have to send as bearer token.
+    pub token: String,
+    /// Certificate and private key files (PEM) to serve HTTPS instead of HTTP.
+    pub tls: Option<(PathBuf, PathBuf)>,
+}
+
+#[derive(Clone)]
+struct ServerState {
+    store: SqliteBlockStore,
+    /// Hash of the token, compared in constant time.
+    token_hash: blake3::Hash,
+}
+
+/// Serve a block store until the server fails.
+pub async fn serve(
+    store: SqliteBlockStore,
+    addr: SocketAddr,
+    config: BlockServerConfig,
+) -> anyhow::Result<()> {
+    let app = router(store, &config.token);
+    debug!("serve blocks on {addr}");
+    match config.tls {
+        Some((cert, key)) => {
+            let tls = RustlsConfig::from_pem_file(cert, key).await?;
+            axum_server::bind_rustls(addr, tls)
+                .serve(app.into_make_service())
+                .await?;
+        }
+        None => {
+            axum::Server::bind(&addr)
+                .serve(app.into_make_service())
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+/// Create the block routes, to be served by an application's own server.
+pub fn router(store: SqliteBlockStore, token: &str) -> Router {
+    let state = ServerState {
+        store,
+        token_hash: blake3::hash(token.as_bytes()),
+    };
+    let blocks = Router::new()
+        .route("/block/:cid", get(get_block).put(put_block))
+        .route("/missing", post(missing))
+        .route("/get", post(get_blocks))
+        .route("/put", post(put_blocks))
+        .route(
+            "/alias/*name",
+            get(get_alias).put(put_alias).delete(delete_alias),
+        )
+        .route("/aliases", get(list_aliases))
+        .route_layer(middleware::from_fn_with_state(state.clone(), authenticate))
+        .layer(DefaultBodyLimit::max(MAX_BODY_SIZE))
+        .with_state(state);
+    Router::new().nest("/blocks/v1", blocks)
+}
+
+async fn authenticate<B>(
+    State(state): State<ServerState>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Result<Response, Error> {
+    let token = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    match token {
+        // blake3::Hash compares in constant time.
+        Some(token) if blake3::hash(token.as_bytes()) == state.token_hash => {
+            Ok(next.run(request).await)
+        }
+        _ => Err(Error(StatusCode::UNAUTHORIZED, "Invalid token".into())),
+    }
+}
+
+async fn get_block(
+    State(state): State<ServerState>,
+    Path(cid): Path<String>,
+) -> Result<Vec<u8>, Error> {
+    let cid = parse_cid(&cid)?;
+    state
+        .store
+        .get_block_if_exists(&cid)
+        .await?
+        .ok_or_else(|| Error(StatusCode::NOT_FOUND, format!("Block {cid} not found")))
+}
+
+async fn put_block(
+    State(state): State<ServerState>,
+    Path(cid): Path<String>,
+    body: Bytes,
+) -> Result<StatusCode, Error> {
+    let cid = parse_cid(&cid)?;
+    state
+        .store
+        .put_block_with_cid(&cid, body.to_vec())
+        .await
+        .map_err(bad_request)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn missing(
+    State(state): State<ServerState>,
+    Json(cids): Json<Vec<String>>,
+) -> Result<Json<Vec<String>>, Error> {
+    let cids = parse_batch(&cids)?;
+    let mut missing = vec![];
+    for cid in cids {
+        if state.store.get_block_if_exists(&cid).await?.is_none() {
+            missing.push(cid.to_string());
+        }
+    }
+    Ok(Json(missing))
+}
+
+async fn get_blocks(
+    State(state): State<ServerState>,
+    Json(cids): Json<Vec<String>>,
+) -> Result<Vec<u8>, Error> {
+    let mut body = vec![];
+    for cid in parse_batch(&cids)? {
+        let block = state
+            .store
+            .get_block_if_exists(&cid)
+            .await?
+            .ok_or_else(|| Error(StatusCode::NOT_FOUND, format!("Block {cid} not found")))?;
+        push_frame(&mut body, &block);
+    }
+    Ok(body)
+}
+
+async fn put_blocks(State(state): State<ServerState>, body: Bytes) -> Result<StatusCode, Error> {
+    let frames = split_frames(&body).map_err(bad_request)?;
+    if frames.len() % 2 != 0 {
+        return Err(bad_request("Block without CID"));
+    }
+    if frames.len() / 2 > MAX_BATCH {
+        return Err(bad_request(format!(
+            "Batch of {} blocks is too large",
+            frames.len() / 2
+        )));
+    }
+    for pair in frames.chunks(2) {
+        let cid = Cid::try_from(pair[0]).map_err(bad_request)?;
+        state
+            .store
+            .put_block_with_cid(&cid, pair[1].to_vec())
+            .await
+            .map_err(bad_request)?;
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Serialize, Deserialize)]
+struct AliasTarget {
+    cid: String,
+}
+
+async fn get_alias(
+    State(state): State<ServerState>,
+    Path(name): Path<String>,
+) -> Result<Json<AliasTarget>, Error> {
+    match state.store.resolve_alias(&name).await? {
+        Some(cid) => Ok(Json(AliasTarget {
+            cid: cid.to_string(),
+        })),
+        None => Err(Error(
+            StatusCode::NOT_FOUND,
+            format!("Alias {name} not found"),
+        )),
+    }
+}
+
+async fn put_alias(
+    State(state): State<ServerState>,
+    Path(name): Path<String>,
+    Json(target): Json<AliasTarget>,
+) -> Result<StatusCode, Error> {
+    let cid = parse_cid(&target.cid)?;
+    state.store.alias(&name, Some(&cid)).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn delete_alias(
+    State(state): State<ServerState>,
+    Path(name): Path<String>,
+) -> Result<StatusCode, Error> {
+    state.store.alias(&name, None).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Deserialize)]
+struct AliasQuery {
+    #[serde(default)]
+    prefix: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct AliasEntry {
+    name: String,
+    cid: String,
+}
+
+async fn list_aliases(
+    State(state): State<ServerState>,
+    Query(query): Query<AliasQuery>,
+) -> Result<Json<Vec<AliasEntry>>, Error> {
+    let aliases = state.store.aliases_with_prefix(&query.prefix).await?;
+    Ok(Json(
+        aliases
+            .into_iter()
+            .map(|(name, cid)| AliasEntry {
+                name,
+                cid: cid.to_string(),
+            })
+            .collect(),
+    ))
+}
+
+fn parse_cid(cid: &str) -> Result<Cid, Error> {
+    Cid::try_from(cid).map_err(bad_request)
+}
+
+fn parse_batch(cids: &[String]) -> Result<Vec<Cid>, Error> {
+    if cids.len() > MAX_BATCH {
+        return Err(bad_request(format!(
+            "Batch of {} blocks is too large",
+            cids.len()
+        )));
+    }
+    cids.iter().map(|cid| parse_cid(cid)).collect()
+}
+
+fn bad_request(err: impl ToString) -> Error {
+    Error(StatusCode::BAD_REQUEST, err.to_string())
+}
+
+struct Error(StatusCode, String);
+
+impl<E: Into<anyhow::Error>> From<E> for Error {
+    fn from(err: E) -> Self {
+        Self(StatusCode::INTERNAL_SERVER_ERROR, err.into().to_string())
+    }
+}
+
+impl IntoResponse for Error {
+    fn into_response(self) -> Response {
+        (self.0, Json(json!({ "error": self.1 }))).into_response()
+    }
+}
+
+fn push_frame(buf: &mut Vec<u8>, frame: &[u8]) {
+    buf.extend_from_slice(&(frame.len() as u32).to_be_bytes());
+    buf.extend_from_slice(frame);
+}
+
+fn split_frames(mut buf: &[u8]) -> anyhow::Result<Vec<&[u8]>> {
+    let mut frames = vec![];
+    while !buf.is_empty() {
+        if buf.len() < 4 {
+            anyhow::bail!("Truncated frame");
+        }
+        let (len, rest) = buf.split_at(4);
+        let len = u32::from_be_bytes(len.try_into()?) as usize;
+        if rest.len() < len {
+            anyhow::bail!("Truncated frame");
+        }
+        frames.push(&rest[..len]);
+        buf = &rest[len..];
+    }
+    Ok(frames)
+}
+
+/// The client of a block store served by `serve blocks`.
+///
+/// Reads and writes go to the server on every call, so a mount of this store is only as
+/// fast as the round trip. Requests run on the tokio runtime the store was created in, so the
+/// store can also be used from other executors, like the FUSE thread.
+#[derive(Clone)]
+pub struct HttpBlockStore {
+    client: reqwest::Client,
+    runtime: tokio::runtime::Handle,
+    /// Base URL including `/blocks/v1`, without a trailing slash.
+    url: String,
+    token: String,
+}
+
+impl HttpBlockStore {
+    /// Connect to a server at `http(s)://host:port`.
+    ///
+    /// Must be called within a tokio runtime.
+    pub fn new(url: &str, token: &str) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            runtime: tokio::runtime::Handle::current(),
+            url: format!("{}/blocks/v1", url.trim_end_matches('/')),
+            token: token.to_string(),
+        }
+    }
+
+    /// Connect with the token from `$WNFS_BLOCKS_TOKEN`.
+    pub fn from_env(url: &str) -> anyhow::Result<Self> {
+        let token = std::env::var(TOKEN_ENV)
+            .map_err(|_| anyhow::anyhow!("Set {TOKEN_ENV} to the token of {url}"))?;
+        Ok(Self::new(url, &token))
+    }
+
+    fn request(&self, method: reqwest::Method, path: &str) -> reqwest::RequestBuilder {
+        self.client
+            .request(method, format!("{}{path}", self.url))
+            .bearer_auth(&self.token)
+    }
+
+    /// Send a request and read the response body, `None` on 404.
+    async fn send(&self, request: reqwest::RequestBuilder) -> anyhow::Result<Option<Bytes>> {
+        let task = self.runtime.spawn(async move {
+            let response = request.send().await?;
+            let status = response.status();
+            if status == reqwest::StatusCode::NOT_FOUND {
+                return Ok(None);
+            }
+            if !status.is_success() {
+                let message = response
+                    .json::<serde_json::Value>()
+                    .await
+                    .ok()
+                    .and_then(|body| body["error"].as_str().map(str::to_string))
+                    .unwrap_or_default();
+                anyhow::bail!("Block server returned {status}: {message}");
+            }
+            Ok(Some(response.bytes().await?))
+        });
+        task.await?
+    }
+
+    /// Get a block if the server has it, verifying that it matches its CID.
+    pub async fn get_block_if_exists(&self, cid: &Cid) -> anyhow::Result<Option<Vec<u8>>> {
+        let request = self.request(reqwest::Method::GET, &format!("/block/{cid}"));
+        let Some(body) = self.send(request).await? else {
+            return Ok(None);
+        };
+        let (_cid, bytes) = Block::<DefaultParams>::new(*cid, body.to_vec())?.into_inner();
+        Ok(Some(bytes))
+    }
+
+    pub async fn put_block_with_cid(&self, cid: &Cid, bytes: Vec<u8>) -> anyhow::Result<()> {
+        let request = self
+            .request(reqwest::Method::PUT, &format!("/block/{cid}"))
+            .body(bytes);
+        self.send(request).await?;
+        Ok(())
+    }
+
+    pub async fn resolve_alias(&self, name: &str) -> anyhow::Result<Option<Cid>> {
+        let request = self.request(reqwest::Method::GET, &format!("/alias/{name}"));
+        let Some(body) = self.send(request).await? else {
+            return Ok(None);
+        };
+        let target: AliasTarget = serde_json::from_slice(&body)?;
+        Ok(Some(Cid::try_from(target.cid.as_str())?))
+    }
+
+    /// Point an alias to a CID, or remove it if `cid` is `None`.
+    pub async fn alias(&self, name: &str, cid: Option<&Cid>) -> anyhow::Result<()> {
+        let path = format!("/alias/{name}");
+        let request = match cid {
+            Some(cid) => self
+                .request(reqwest::Method::PUT, &path)
+                .json(&AliasTarget {
+                    cid: cid.to_string(),
+                }),
+            None => self.request(reqwest::Method::DELETE, &path),
+        };
+        self.send(request).await?;
+        Ok(())
+    }
+
+    /// List all aliases starting with `prefix`, with the prefix stripped.
+    pub async fn aliases_with_prefix(&self, prefix: &str) -> anyhow::Result<Vec<(String, Cid)>> {
+        let request = self
+            .request(reqwest::Method::GET, "/aliases")
+            .query(&[("prefix", prefix)]);
+        let Some(body) = self.send(request).await? else {
+            return Ok(vec![]);
+        };
+        let entries: Vec<AliasEntry> = serde_json::from_slice(&body)?;
+        entries
+            .into_iter()
+            .map(|entry| Ok((entry.name, Cid::try_from(entry.cid.as_str())?)))
+            .collect()
+    }
+}
+
+#[async_trait(?Send)]
+impl BlockStore for HttpBlockStore {
+    async fn get_block<'a>(&'a self, cid: &Cid) -> anyhow::Result<Cow<'a, Vec<u8>>> {
+        let block = self
+            .get_block_if_exists(cid)
+            .await?
+            .ok_or_else(|| anyhow::anyhow!("Block not found"))?;
+        Ok(Cow::Owned(block))
+    }
+
+    async fn put_block(&mut self, bytes: Vec<u8>, codec: IpldCodec) -> anyhow::Result<Cid> {
+        let hash = Code::Blake3_256.digest(&bytes);
+        let cid = Cid::new(Version::V1, codec.into(), hash)?;
+        self.put_block_with_cid(&cid, bytes).await?;
+        Ok(cid)
+    }
+}
+
+#[async_trait(?Send)]
+impl Store for HttpBlockStore {
+    async fn resolve_alias(&self, name: &str) -> anyhow::Result<Option<Cid>> {
+        HttpBlockStore::resolve_alias(self, name).await
+    }
+
+    async fn alias(&self, name: &str, cid: Option<&Cid>) -> anyhow::Result<()> {
+        HttpBlockStore::alias(self, name, cid).await
+    }
+
+    async fn aliases_with_prefix(&self, prefix: &str) -> anyhow::Result<Vec<(String, Cid)>> {
+        HttpBlockStore::aliases_with_prefix(self, prefix).await
+    }
+}
+
+#[async_trait]
+impl RemoteStore for HttpBlockStore {
+    async fn has_block(&self, cid: &Cid) -> anyhow::Result<bool> {
+        Ok(RemoteStore::missing_blocks(self, &[*cid]).await?.is_empty())
+    }
+
+    async fn get_block(&self, cid: &Cid) -> anyhow::Result<Vec<u8>> {
+        self.get_block_if_exists(cid)
+            .await?
+            .ok_or_else(|| anyhow::anyhow!("Block not found on remote: {cid}"))
+    }
+
+    async fn put_block(&self, cid: &Cid, bytes: &[u8]) -> anyhow::Result<()> {
+        self.put_block_with_cid(cid, bytes.to_vec()).await
+    }
+
+    async fn get_root(&self, name: &str) -> anyhow::Result<Option<Cid>> {
+        HttpBlockStore::resolve_alias(self, &private_root_alias(name)).await
+    }
+
+    async fn put_root(&self, name: &str, cid: &Cid) -> anyhow::Result<()> {
+        HttpBlockStore::alias(self, &private_root_alias(name), Some(cid)).await
+    }
+
+    async fn missing_blocks(&self, cids: &[Cid]) -> anyhow::Result<Vec<Cid>> {
+        let mut missing = vec![];
+        for batch in cids.chunks(MAX_BATCH) {
+            let batch: Vec<String> = batch.iter().map(Cid::to_string).collect();
+            let request = self.request(reqwest::Method::POST, "/missing").json(&batch);
+            let body = self
+                .send(request)
+                .await?
+                .ok_or_else(|| anyhow::anyhow!("Block server has no batch endpoints"))?;
+            for cid in serde_json::from_slice::<Vec<String>>(&body)? {
+                missing.push(Cid::try_from(cid.as_str())?);
+            }
+        }
+        Ok(missing)
+    }
+
+    async fn get_blocks(&self, cids: &[Cid]) -> anyhow::Result<Vec<Vec<u8>>> {
+        let mut blocks = vec![];
+        for batch in cids.chunks(MAX_BATCH) {
+            let names: Vec<String> = batch.iter().map(Cid::to_string).collect();
+            let request = self.request(reqwest::Method::POST, "/get").json(&names);
+            let body = self
+                .send(request)
+                .await?
+                .ok_or_else(|| anyhow::anyhow!("Block not found on remote"))?;
+            let frames = split_frames(&body)?;
+            if frames.len() != batch.len() {
+                anyhow::bail!("Block server returned {} blocks", frames.len());
+            }
+            for (cid, bytes) in batch.iter().zip(frames) {
+                let (_cid, bytes) = Block::<DefaultParams>::new(*cid, bytes.to_vec())?.into_inner();
+                blocks.push(bytes);
+            }
+        }
+        Ok(blocks)
+    }
+
+    async fn put_blocks(&self, blocks: &[(Cid, Vec<u8>)]) -> anyhow::Result<()> {
+        let mut body = vec![];
+        let mut count = 0;
+        for (index, (cid, bytes)) in blocks.iter().enumerate() {
+            push_frame(&mut body, &cid.to_bytes());
+            push_frame(&mut body, bytes);
+            count += 1;
+            let last = index + 1 == blocks.len();
+            if last || count == MAX_BATCH || body.len() >= MAX_PUT_SIZE {
+                let request = self
+                    .request(reqwest::Method::POST, "/put")
+                    .body(std::mem::take(&mut body));
+                self.send(request).await?;
+                count = 0;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,155 @@
+//! Block store in the browser's IndexedDB.
+//!
+//! Blocks are keyed by their CID string and aliases by their name, so a store exported from a
+//! SQLite store (e.g. via CAR files) opens unchanged in the browser:
+//!
+//! ```ignore
+//! let store = IdbBlockStore::open("wnfs").await?;
+//! let fs = Wnfs::open_in_store(store, "demo".to_string(), None).await?;
+//! ```
+
+use std::borrow::Cow;
+use std::rc::Rc;
+
+use async_trait::async_trait;
+use js_sys::Uint8Array;
+use libipld::cid::Version;
+use libipld::{Cid, IpldCodec};
+use multihash::{Code, MultihashDigest};
+use rexie::{ObjectStore, Rexie, TransactionMode};
+use wasm_bindgen::JsValue;
+use wnfs_common::BlockStore;
+
+use crate::store::Store;
+
+const BLOCKS: &str = "blocks";
+const ALIASES: &str = "aliases";
+
+#[derive(Clone)]
+pub struct IdbBlockStore(Rc<Rexie>);
+
+impl IdbBlockStore {
+    /// Open or create the IndexedDB database with this name.
+    pub async fn open(name: &str) -> anyhow::Result<Self> {
+        let db = Rexie::builder(name)
+            .version(1)
+            .add_object_store(ObjectStore::new(BLOCKS))
+            .add_object_store(ObjectStore::new(ALIASES))
+            .build()
+            .await
+            .map_err(to_anyhow)?;
+        Ok(Self(Rc::new(db)))
+    }
+
+    async fn get(&self, object_store: &str, key: &str) -> anyhow::Result<Option<JsValue>> {
+        let tx = self
+            .0
+            .transaction(&[object_store], TransactionMode::ReadOnly)
+            .map_err(to_anyhow)?;
+        let value = tx
+            .store(object_store)
+            .map_err(to_anyhow)?
+            .get(&JsValue::from_str(key))
+            .await
+            .map_err(to_anyhow)?;
+        tx.done().await.map_err(to_anyhow)?;
+        Ok((!value.is_undefined()).then_some(value))
+    }
+
+    async fn put(&self, object_store: &str, key: &str, value: &JsValue) -> anyhow::Result<()> {
+        let tx = self
+            .0
+            .transaction(&[object_store], TransactionMode::ReadWrite)
+            .map_err(to_anyhow)?;
+        tx.store(object_store)
+            .map_err(to_anyhow)?
+            .put(value, Some(&JsValue::from_str(key)))
+            .await
+            .map_err(to_anyhow)?;
+        tx.done().await.map_err(to_anyhow)?;
+        Ok(())
+    }
+}
+
+#[async_trait(?Send)]
+impl BlockStore for IdbBlockStore {
+    async fn get_block<'a>(&'a self, cid: &Cid) -> anyhow::Result<Cow<'a, Vec<u8>>> {
+        let value = self
+            .get(BLOCKS, &cid.to_string())
+            .await?
+            .ok_or_else(|| anyhow::anyhow!("Block not found"))?;
+        Ok(Cow::Owned(Uint8Array::new(&value).to_vec()))
+    }
+
+    async fn put_block(&mut self, bytes: Vec<u8>, codec: IpldCodec) -> anyhow::Result<Cid> {
+        let hash = Code::Blake3_256.digest(&bytes);
+        let cid = Cid::new(Version::V1, codec.into(), hash)?;
+        let value = Uint8Array::from(&bytes[..]);
+        self.put(BLOCKS, &cid.to_string(), &value.into()).await?;
+        Ok(cid)
+    }
+}
+
+#[async_trait(?Send)]
+impl Store for IdbBlockStore {
+    async fn resolve_alias(&self, name: &str) -> anyhow::Result<Option<Cid>> {
+        match self.get(ALIASES, name).await? {
+            None => Ok(None),
+            Some(value) => {
+                let cid = value
+                    .as_string()
+                    .ok_or_else(|| anyhow::anyhow!("Invalid alias {name}"))?;
+                Ok(Some(Cid::try_from(cid.as_str())?))
+            }
+        }
+    }
+
+    async fn alias(&self, name: &str, cid: Option<&Cid>) -> anyhow::Result<()> {
+        match cid {
+            Some(cid) => {
+                self.put(ALIASES, name, &JsValue::from_str(&cid.to_string()))
+                    .await
+            }
+            None => {
+                let tx = self
+                    .0
+                    .transaction(&[ALIASES], TransactionMode::ReadWrite)
+                    .map_err(to_anyhow)?;
+                tx.store(ALIASES)
+                    .map_err(to_anyhow)?
+                    .delete(&JsValue::from_str(name))
+                    .await
+                    .map_err(to_anyhow)?;
+                tx.done().await.map_err(to_anyhow)?;
+                Ok(())
+            }
+        }
+    }
+
+    async fn aliases_with_prefix(&self, prefix: &str) -> anyhow::Result<Vec<(String, Cid)>> {
+        let tx = self
+            .0
+            .transaction(&[ALIASES], TransactionMode::ReadOnly)
+            .map_err(to_anyhow)?;
+        let entries = tx
+            .store(ALIASES)
+            .map_err(to_anyhow)?
+            .get_all(None, None, None, None)
+            .await
+            .map_err(to_anyhow)?;
+        tx.done().await.map_err(to_anyhow)?;
+        let aliases = entries
+            .into_iter()
+            .filter_map(|(name, cid)| {
+                let name = name.as_string()?.strip_prefix(prefix)?.to_string();
+                let cid = Cid::try_from(cid.as_string()?.as_str()).ok()?;
+                Some((name, cid))
+            })
+            .collect();
+        Ok(aliases)
+    }
+}
+
+fn to_anyhow(err: rexie::Error) -> anyhow::Error {
+    anyhow::anyhow!("IndexedDB error: {err}")
+}
@@ -0,0 +1,103 @@
+//! Append-only journal of committed operations.
+//!
+//! Each commit appends one [`JournalEntry`] per operation since the previous commit. Entries
+//! are dag-cbor blocks that link to the previous entry, and the root record links to the
+//! latest one, so the journal is synced and garbage collected together with the filesystem.
+//!
+//! The journal is off by default: entries are not encrypted, so they reveal paths to anyone
+//! with access to the block store.
+
+use chrono::{DateTime, Utc};
+use libipld::Cid;
+use serde::{Deserialize, Serialize};
+
+use crate::store::Store;
+
+/// Kind of a journaled operation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JournalOp {
+    /// The journal was enabled.
+    Start,
+    Write,
+    Mkdir,
+    Remove,
+    Move,
+    SetMetadata,
+    /// Divergent heads were resolved, see [`crate::fs::Wnfs::heads`].
+    Resolve,
+}
+
+/// A committed operation, as returned by [`crate::fs::Wnfs::journal`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JournalEntry {
+    pub op: JournalOp,
+    pub path: Vec<String>,
+    /// Target path of moves.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub to: Option<Vec<String>>,
+    /// CID of the forest of the commit that included the operation.
+    pub revision: Cid,
+    pub timestamp: DateTime<Utc>,
+    pub actor: String,
+    /// The previous entry.
+    pub prev: Option<Cid>,
+}
+
+/// An operation that is journaled with the next commit.
+#[derive(Debug, Clone)]
+pub(crate) struct PendingOp {
+    pub op: JournalOp,
+    pub path: Vec<String>,
+    pub to: Option<Vec<String>>,
+    pub timestamp: DateTime<Utc>,
+}
+
+/// The actor recorded for operations: `WNFS_ACTOR`, or the user name.
+pub fn default_actor() -> String {
+    std::env::var("WNFS_ACTOR")
+        .or_else(|_| std::env::var("USER"))
+        .unwrap_or_else(|_| "unknown".to_string())
+}
+
+/// Append entries for pending operations and return the new head.
+pub(crate) async fn append(
+    store: &mut impl Store,
+    mut head: Option<Cid>,
+    ops: Vec<PendingOp>,
+    revision: Cid,
+    actor: &str,
+) -> anyhow::Result<Option<Cid>> {
+    for op in ops {
+        let entry = JournalEntry {
+            op: op.op,
+            path: op.path,
+            to: op.to,
+            revision,
+            timestamp: op.timestamp,
+            actor: actor.to_string(),
+            prev: head,
+        };
+        head = Some(store.put_serializable(&entry).await?);
+    }
+    Ok(head)
+}
+
+/// Load up to `limit` entries, newest first.
+pub(crate) async fn load(
+    store: &impl Store,
+    head: Option<Cid>,
+    limit: usize,
+) -> anyhow::Result<Vec<JournalEntry>> {
+    let mut entries = vec![];
+    let mut next = head;
+    while let Some(cid) = next {
+        if entries.len() >= limit {
+            break;
+        }
+        let entry: JournalEntry = store.get_deserializable(&cid).await?;
+        next = entry.prev;
+        entries.push(entry);
+    }
+    Ok(entries)
+}
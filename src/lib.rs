@@ -1,4 +1,95 @@
+//! The core (`error`, `format`, `fs`, `hooks`, `journal`, `path`, `share` and `store`) compiles
+//! to wasm32 with `--no-default-features`. Everything else needs the `native` feature.
+
+#[cfg(feature = "native")]
+pub mod agent;
+#[cfg(feature = "native")]
+pub mod api;
+#[cfg(feature = "native")]
+pub mod backup;
+#[cfg(feature = "native")]
+pub mod bench;
+#[cfg(feature = "native")]
+pub mod bitswap;
+#[cfg(feature = "native")]
 mod blockstore;
+#[cfg(feature = "native")]
+pub mod car;
+#[cfg(feature = "native")]
+pub mod config;
+#[cfg(feature = "csi")]
+pub mod csi;
+#[cfg(feature = "native")]
+pub mod daemon;
+#[cfg(feature = "native")]
+pub mod errno;
+pub mod error;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod format;
 pub mod fs;
+#[cfg(feature = "native")]
 pub use blockstore::*;
+#[cfg(feature = "native")]
 pub mod fuse;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+#[cfg(feature = "native")]
+pub mod handle;
+pub mod hooks;
+#[cfg(feature = "native")]
+pub mod http;
+#[cfg(feature = "native")]
+pub mod http_store;
+#[cfg(target_arch = "wasm32")]
+pub mod idb;
+pub mod journal;
+#[cfg(feature = "native")]
+pub mod lock;
+#[cfg(feature = "native")]
+pub mod mirror;
+#[cfg(feature = "native")]
+pub mod mount_helper;
+#[cfg(feature = "native")]
+pub mod nfs;
+#[cfg(feature = "native")]
+pub mod ninep;
+mod passphrase;
+pub mod path;
+#[cfg(feature = "native")]
+pub mod peer;
+#[cfg(feature = "native")]
+pub mod pin;
+#[cfg(feature = "python")]
+pub mod python;
+#[cfg(feature = "native")]
+pub mod quic;
+#[cfg(feature = "native")]
+pub mod remote;
+#[cfg(feature = "native")]
+pub mod s3_gateway;
+#[cfg(feature = "native")]
+pub mod schedule;
+#[cfg(feature = "search")]
+pub mod search;
+#[cfg(feature = "native")]
+pub mod sftp;
+pub mod share;
+#[cfg(feature = "native")]
+pub mod shell;
+#[cfg(feature = "native")]
+pub mod ssh;
+#[cfg(feature = "native")]
+pub mod stats;
+pub mod store;
+pub use store::{DefaultParams, Store};
+#[cfg(feature = "native")]
+pub mod sync;
+#[cfg(feature = "native")]
+pub mod telemetry;
+#[cfg(feature = "native")]
+pub mod testing;
+#[cfg(feature = "native")]
+pub mod ucan;
+#[cfg(feature = "native")]
+pub mod webdav;
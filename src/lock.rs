@@ -0,0 +1,65 @@
+//! Advisory lock that allows one writer per filesystem across processes.
+//!
+//! Two processes that write the same filesystem would both move its root alias forward from
+//! the state they loaded, and the last flush would silently drop the commits of the other.
+//! Writers therefore take a [`WriterLock`] first, and a second writer fails with the PID of
+//! the first. Readers do not lock.
+//!
+//! The lock is an `flock` on `<db path>.locks/<name>`, so it is released by the kernel when
+//! the process exits, however it exits.
+
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+use tracing::debug;
+
+/// An exclusive lock on writing a filesystem, held until dropped.
+#[derive(Debug)]
+pub struct WriterLock {
+    _file: File,
+}
+
+/// Path of the lock file of a named filesystem.
+pub fn lock_path(db_path: impl AsRef<Path>, name: &str) -> PathBuf {
+    let mut dir = db_path.as_ref().as_os_str().to_owned();
+    dir.push(".locks");
+    PathBuf::from(dir).join(name)
+}
+
+impl WriterLock {
+    /// Take the lock of a named filesystem, or fail if another process holds it.
+    pub fn acquire(db_path: impl AsRef<Path>, name: &str) -> anyhow::Result<Self> {
+        let path = lock_path(db_path, name);
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
+        // SAFETY: the file descriptor is valid for the lifetime of `file`.
+        let result = unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) };
+        if result != 0 {
+            let err = std::io::Error::last_os_error();
+            if err.raw_os_error() != Some(libc::EWOULDBLOCK) {
+                return Err(err.into());
+            }
+            let mut pid = String::new();
+            file.read_to_string(&mut pid)?;
+            match pid.trim() {
+                "" => anyhow::bail!("Filesystem {name} is in use by another process"),
+                pid => anyhow::bail!("Filesystem {name} is in use by PID {pid}"),
+            }
+        }
+        file.set_len(0)?;
+        file.rewind()?;
+        write!(file, "{}", std::process::id())?;
+        file.flush()?;
+        debug!("locked {path:?}");
+        Ok(Self { _file: file })
+    }
+}
@@ -1,23 +1,88 @@
-//! This example shows how to add a directory to a private forest (a HAMT) where encrypted ciphertexts are stored.
-//! It also shows how to retrieve encrypted nodes from the forest using `PrivateRef`s.
+//! Command line interface to the filesystems in a block store.
+//!
+//! `init` creates a named filesystem (`--fs-name`), optionally protected by a passphrase. The
+//! other commands read and change it, mount it, or serve it over the network, e.g.
+//!
+//! ```text
+//! wnfs-experiments --fs-name work init --passphrase
+//! echo "hello world" | wnfs-experiments --fs-name work write hello.txt
+//! wnfs-experiments --fs-name work mount /tmp/mnt
+//! ```
+//!
+//! Filesystems are only created by `init`, so that a mistyped name fails instead of opening
+//! a new, empty filesystem.
 
-use clap::{Parser, Subcommand};
+use chrono::{DateTime, Utc};
+use clap::{CommandFactory, Parser, Subcommand};
+use futures::io::AllowStdIo;
+use indicatif::{ProgressBar, ProgressStyle};
+use libipld::Cid;
+use libp2p::Multiaddr;
+use serde::Serialize;
+use serde_json::json;
+use std::ffi::OsString;
+use std::io::{IsTerminal, Write as _};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use wnfs_experiments::{fs::Wnfs, fuse};
+use tokio::signal::unix::{signal, SignalKind};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, Layer};
+use wnfs_experiments::bitswap::{BitswapNode, BitswapOptions};
+use wnfs_experiments::share::{self, ExchangeKey};
+use wnfs_experiments::config::{Config, MountConfig};
+use wnfs_experiments::handle::WnfsHandle;
+use wnfs_experiments::hooks::{CommandHook, HookConfig};
+use wnfs_experiments::http_store::{self, HttpBlockStore};
+use wnfs_experiments::lock::WriterLock;
+use wnfs_experiments::remote::open_remote;
+#[cfg(feature = "search")]
+use wnfs_experiments::search::{self, SearchIndex};
+use wnfs_experiments::sync::{ConflictPolicy, Resolution};
+use wnfs_experiments::ucan::{self, DeviceKey, WriteAuth};
+use wnfs_experiments::{
+    agent, api, backup, bench, car, daemon, format, journal,
+    fs::{ChangeKind, EntryKind, Progress, RootSource, Wnfs},
+    fuse, http,
+    mirror::{self, MismatchKind},
+    mount_helper,
+    nfs, ninep,
+    path::WnfsPath,
+    peer, pin, quic, s3_gateway, schedule, sftp, ssh, shell, stats, sync, telemetry, webdav,
+    SqliteBlockStore,
+};
+
+const CAT_CHUNK_SIZE: usize = 1024 * 1024;
+const DEFAULT_FILE_MODE: u32 = 0o644;
+/// Ask before removing more entries than this at once.
+const RM_CONFIRM_THRESHOLD: usize = 100;
 
 #[derive(Debug, Parser)]
 pub struct Args {
-    /// Path to SQLite block store
-    #[clap(short, long, default_value = "blocks.db")]
-    db_path: String,
-    /// Local name (alias) of the private root directory
-    #[clap(short, long, default_value = "demo")]
-    fs_name: String,
+    /// Path to SQLite block store, or the URL of `serve blocks` to mount it remotely
+    /// [default: from config file, or blocks.db]
+    #[clap(short, long, env = "WNFS_DB_PATH")]
+    db_path: Option<String>,
+    /// Local name (alias) of the private root directory [default: from config file, or demo]
+    #[clap(short, long, env = "WNFS_FS_NAME")]
+    fs_name: Option<String>,
+    /// Print structured JSON instead of text, where supported
+    #[clap(long, global = true)]
+    json: bool,
     #[command(subcommand)]
     command: Command,
 }
 #[derive(Debug, Subcommand)]
 pub enum Command {
+    /// Create a new, empty filesystem
+    Init {
+        /// Protect the filesystem with a passphrase
+        #[clap(long)]
+        passphrase: bool,
+    },
     /// Create a directory
     Mkdir {
         path: String,
@@ -25,48 +90,2637 @@ pub enum Command {
     /// Print a file to STDOUT
     Cat {
         path: String,
+        /// Start reading at this byte offset
+        #[clap(long, default_value_t = 0)]
+        offset: usize,
+        /// Read at most this many bytes
+        #[clap(long)]
+        length: Option<usize>,
     },
-    /// Write STDIN into a file at a path
+    /// List a directory
+    Ls {
+        #[clap(default_value = "")]
+        path: String,
+        /// Also show the kind, permissions, size and modification time of entries
+        #[clap(short, long)]
+        long: bool,
+    },
+    /// Show information about a file or directory
+    Stat {
+        path: String,
+    },
+    /// Create an empty file or update the modification time
+    Touch {
+        path: String,
+    },
+    /// Write STDIN (or a host file) into a file at a path
     Write {
         path: String,
+        /// Read the content from this host file instead of STDIN
+        #[clap(long)]
+        input: Option<PathBuf>,
+        /// Append to the file instead of replacing it
+        #[clap(long)]
+        append: bool,
+    },
+    /// Remove a file or directory
+    Rm {
+        path: String,
+        /// Also remove directories that are not empty, with their contents
+        #[clap(short, long)]
+        recursive: bool,
+        /// List what would be removed without removing anything
+        #[clap(long)]
+        dry_run: bool,
+        /// Do not ask for confirmation, however many entries are affected
+        #[clap(short, long)]
+        force: bool,
     },
+    /// Move or rename a file or directory, into `to` if it is a directory
+    Mv { from: String, to: String },
     /// Mount the filesystem with FUSE
     Mount {
         mountpoint: String,
+        /// Run the mount in the background
+        #[clap(long)]
+        daemon: bool,
+        #[command(flatten)]
+        flags: MountFlags,
+        /// Serve Prometheus metrics on this address (at /metrics)
+        #[clap(long)]
+        metrics_addr: Option<SocketAddr>,
+    },
+    /// Flush and unmount a mounted filesystem
+    Umount {
+        mountpoint: String,
+    },
+    /// Show recursive size and file counts of each entry in a directory
+    Du {
+        #[clap(default_value = "")]
+        path: String,
+    },
+    /// Check the filesystem for missing blocks and undecryptable nodes
+    Fsck,
+    /// Show the root record and heads of the filesystem, and resolve divergent heads
+    Status {
+        /// Continue from the head with this index and drop the others
+        #[clap(long, conflicts_with = "merge")]
+        choose: Option<usize>,
+        /// Merge the other heads into the first one, keeping differing files as conflict copies
+        #[clap(long)]
+        merge: bool,
+    },
+    /// Replace a missing or damaged root record of the filesystem
+    Recover {
+        /// Rebuild the root record from an access key (see `key export`)
+        #[clap(long, conflicts_with_all = ["snapshot", "root", "scan"])]
+        access_key: Option<String>,
+        /// Use the root record of a snapshot
+        #[clap(long, conflicts_with_all = ["root", "scan"])]
+        snapshot: Option<String>,
+        /// Use a root record by CID, e.g. one listed by --scan
+        #[clap(long, conflicts_with = "scan")]
+        root: Option<String>,
+        /// List the root records in the store, newest first
+        #[clap(long)]
+        scan: bool,
+        /// The root record is protected by a passphrase, or protect the rebuilt record with one
+        #[clap(long)]
+        passphrase: bool,
+        /// Replace the root record even if the filesystem can be opened
+        #[clap(long)]
+        force: bool,
+    },
+    /// Find files by name or text content (indexes the filesystem on first use)
+    #[cfg(feature = "search")]
+    Search {
+        query: String,
+        #[clap(long, default_value_t = 20)]
+        limit: usize,
+        /// Index the whole filesystem again
+        #[clap(long)]
+        rebuild: bool,
+    },
+    /// Delete blocks that are no longer reachable from any filesystem root (stop the mounts and
+    /// servers of the store first)
+    Gc {
+        /// Only report what would be deleted
+        #[clap(long)]
+        dry_run: bool,
+    },
+    /// Upgrade the store to the current format version
+    Migrate {
+        /// Only list the migrations that would run
+        #[clap(long)]
+        dry_run: bool,
+    },
+    /// Show how much of the store is used by which filesystems and snapshots
+    Stats {
+        /// Also compare the content of the filesystem with its stored size (reads every file)
+        #[clap(long)]
+        dedup: bool,
+    },
+    /// Run standard workloads against a temporary filesystem and print the results
+    Bench {
+        /// Size of the file for sequential writes and reads, in MiB
+        #[clap(long, default_value_t = 64)]
+        size_mb: usize,
+        /// Number of files for the small-file workload
+        #[clap(long, default_value_t = 200)]
+        files: usize,
+        /// Nesting depth for the readdir workload
+        #[clap(long, default_value_t = 16)]
+        depth: usize,
+    },
+    /// Write the filesystem to a CAR file (`-` for STDOUT)
+    ExportCar {
+        file: String,
+        /// Only include blocks not reachable from this snapshot or root record CID
+        #[clap(long)]
+        since: Option<String>,
+    },
+    /// Read a filesystem from a CAR file (`-` for STDIN)
+    ImportCar {
+        file: String,
+        /// Replace the local filesystem even if the CAR file does not apply on top of it
+        #[clap(long)]
+        force: bool,
+    },
+    /// Write the blocks that are new since the last backup to a CAR file in a directory
+    Backup { target_dir: PathBuf },
+    /// Import all CAR files of a backup directory
+    Restore {
+        backup_dir: PathBuf,
+        /// Name of the restored filesystem [default: the name of the backed-up filesystem]
+        #[clap(long = "as")]
+        name: Option<String>,
+        /// Replace the local filesystem even if it differs from all backups
+        #[clap(long)]
+        force: bool,
+    },
+    /// Print the exchange key of this store, which others need to share with you
+    ExchangeKey,
+    /// Share a directory with the owner of an exchange key and print the share label
+    Share {
+        path: String,
+        /// Exchange key of the recipient, or a file containing it
+        #[clap(long)]
+        to: String,
+    },
+    /// Accept a share and register it as a new named filesystem
+    AcceptShare {
+        /// Share label printed by the `share` command
+        label: String,
+        /// Local name for the shared filesystem
+        #[clap(long = "as")]
+        name: String,
+    },
+    /// Change the permission bits of files
+    Chmod {
+        /// Octal mode (e.g. 755) or symbolic mode (e.g. u+x,go-w)
+        mode: String,
+        path: String,
+        /// Change all files below a directory
+        #[clap(short = 'R', long)]
+        recursive: bool,
+    },
+    /// Get or set metadata of files
+    Meta {
+        #[command(subcommand)]
+        command: MetaCommand,
+    },
+    /// Print the paths of all entries below a path that match the filters
+    Find {
+        #[clap(default_value = "")]
+        path: String,
+        /// Glob pattern for the entry name, e.g. '*.jpg'
+        #[clap(long)]
+        name: Option<glob::Pattern>,
+        /// Only files larger than this size, e.g. 10M
+        #[clap(long, value_parser = parse_size)]
+        larger_than: Option<u64>,
+        /// Only entries modified after this date (YYYY-MM-DD or RFC 3339)
+        #[clap(long, value_parser = parse_time)]
+        modified_after: Option<DateTime<Utc>>,
+    },
+    /// List paths that changed between two revisions
+    ///
+    /// A revision is a snapshot name, the CID of a root record, or `current`.
+    Diff {
+        #[clap(required_unless_present = "since")]
+        from: Option<String>,
+        #[clap(default_value = "current")]
+        to: String,
+        /// Compare a snapshot with the current state
+        #[clap(long, conflicts_with = "from")]
+        since: Option<String>,
+        /// Show the size change of each path
+        #[clap(long)]
+        stat: bool,
+    },
+    /// Show the journal of committed operations, newest first
+    Log {
+        /// Number of entries to show
+        #[clap(short = 'n', long, default_value_t = 20)]
+        limit: usize,
+        /// Start recording operations (as $WNFS_ACTOR or $USER)
+        #[clap(long)]
+        enable: bool,
+    },
+    /// Create, list, restore or delete named snapshots
+    Snapshot {
+        #[command(subcommand)]
+        command: SnapshotCommand,
+    },
+    /// Export or import the access key of a filesystem
+    Key {
+        #[command(subcommand)]
+        command: KeyCommand,
+    },
+    /// Manage the filesystems in the store
+    Fs {
+        #[command(subcommand)]
+        command: FsCommand,
+    },
+    /// Serve the filesystem over the network
+    Serve {
+        #[command(subcommand)]
+        command: ServeCommand,
+        /// Serve Prometheus metrics on this address (at /metrics)
+        #[clap(long, global = true)]
+        metrics_addr: Option<SocketAddr>,
+    },
+    /// Import a host directory and keep applying its changes
+    Mirror {
+        host_dir: PathBuf,
+        #[clap(default_value = "")]
+        path: String,
+        /// Also write changes made to the filesystem by other processes to the host directory
+        #[clap(long)]
+        two_way: bool,
+    },
+    /// Compare a directory with a host directory by content hashes
+    VerifyAgainst { path: String, host_dir: PathBuf },
+    /// Open an interactive shell on the filesystem
+    Shell,
+    /// Serve the blocks of the store to other nodes over bitswap, or to `sync quic`
+    Daemon {
+        /// Address to listen on, e.g. /ip4/0.0.0.0/tcp/4001 (can be repeated)
+        #[clap(long, required_unless_present = "quic")]
+        listen: Vec<Multiaddr>,
+        /// UDP port to answer `sync quic` of the authorized nodes on
+        #[clap(long)]
+        quic: Option<u16>,
+    },
+    /// Serve the encrypted blocks of the store without access to any filesystem, e.g. on a
+    /// server that should provide availability but not be able to read anything
+    Seed {
+        /// Address to serve bitswap on, e.g. /ip4/0.0.0.0/tcp/4001 (can be repeated)
+        #[clap(long, required_unless_present = "quic")]
+        listen: Vec<Multiaddr>,
+        /// UDP port to answer `sync quic` of the authorized nodes on, for pulls only
+        #[clap(long)]
+        quic: Option<u16>,
+    },
+    /// Transfer the filesystem to or from a remote store
+    Sync {
+        #[command(subcommand)]
+        command: SyncCommand,
+    },
+    /// Keep the filesystem with remote services
+    Remote {
+        #[command(subcommand)]
+        command: RemoteCommand,
+    },
+    /// Keep passphrases in memory, so that they are only entered once
+    Agent {
+        #[command(subcommand)]
+        command: AgentCommand,
+    },
+    /// Run a Kubernetes CSI node plugin that mounts filesystems as volumes of pods
+    #[cfg(feature = "csi")]
+    Csi {
+        /// Socket to serve on
+        #[clap(long, default_value = "unix:///csi/csi.sock")]
+        endpoint: String,
+        /// Name of the Kubernetes node
+        #[clap(long, env = "NODE_ID")]
+        node_id: String,
+        /// Name of the driver in persistent volumes
+        #[clap(long, default_value = wnfs_experiments::csi::DRIVER_NAME)]
+        driver_name: String,
+    },
+    /// Delegate write access to shared filesystems with UCANs
+    Ucan {
+        #[command(subcommand)]
+        command: UcanCommand,
+    },
+    /// Print shell completions to STDOUT
+    Completions { shell: clap_complete::Shell },
+    /// Write man pages for all commands to a directory
+    Manpages { dir: PathBuf },
+}
+
+/// Mount options, overriding those from the config file.
+#[derive(Debug, clap::Args)]
+pub struct MountFlags {
+    /// Owner of all files [default: current user]
+    #[clap(long)]
+    uid: Option<u32>,
+    /// Group of all files [default: current group]
+    #[clap(long)]
+    gid: Option<u32>,
+    /// Permission bits to clear, in octal (e.g. 027)
+    #[clap(long, value_parser = parse_umask)]
+    umask: Option<u16>,
+    /// Seconds after which an operation fails with an I/O error, e.g. if the store hangs
+    /// [default: 30]
+    #[clap(long)]
+    timeout: Option<u64>,
+    /// Allow other users to access the mount (needs user_allow_other in /etc/fuse.conf)
+    #[clap(long)]
+    allow_other: bool,
+    /// Mount read-only
+    #[clap(long)]
+    read_only: bool,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum MetaCommand {
+    /// Print all metadata of a file or directory, or a single key
+    Get { path: String, key: Option<String> },
+    /// Set a metadata key of a file
+    Set {
+        path: String,
+        key: String,
+        value: String,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum SnapshotCommand {
+    /// Record the current state under a name
+    Create { name: String },
+    /// List the snapshots of the filesystem
+    List,
+    /// Replace the current state with a snapshot
+    Restore {
+        name: String,
+        /// Do not ask for confirmation
+        #[clap(long)]
+        yes: bool,
+    },
+    /// Delete a snapshot
+    Delete { name: String },
+}
+
+impl Command {
+    /// Address for Prometheus metrics, unless the command only starts a background mount.
+    fn metrics_addr(&self) -> Option<SocketAddr> {
+        match self {
+            Command::Mount {
+                daemon: false,
+                metrics_addr,
+                ..
+            }
+            | Command::Serve { metrics_addr, .. } => *metrics_addr,
+            _ => None,
+        }
+    }
+
+    /// Whether the command may move the root of the filesystem, and so needs the writer lock.
+    ///
+    /// Commands that name another filesystem than `--fs-name` lock it themselves.
+    fn writes(&self) -> bool {
+        match self {
+            Command::Mkdir { .. }
+            | Command::Touch { .. }
+            | Command::Write { .. }
+            | Command::Mv { .. }
+            | Command::ImportCar { .. }
+            | Command::Chmod { .. }
+            | Command::Shell
+            | Command::Mirror { .. }
+            | Command::Meta {
+                command: MetaCommand::Set { .. },
+            }
+            | Command::Snapshot {
+                command: SnapshotCommand::Restore { .. },
+            } => true,
+            Command::Rm { dry_run, .. } => !dry_run,
+            Command::Recover { scan, .. } => !scan,
+            Command::Status { choose, merge } => choose.is_some() || *merge,
+            // The background mount locks in the process that serves it.
+            Command::Mount { daemon, flags, .. } => !daemon && !flags.read_only,
+            Command::Log { enable, .. } => *enable,
+            Command::Serve { command, .. } => match command {
+                ServeCommand::Webdav { read_only, .. } => !read_only,
+                // Writes come from the remote mounts.
+                ServeCommand::Blocks { .. } => false,
+                _ => true,
+            },
+            Command::Sync { command } => match command {
+                SyncCommand::Pull { .. }
+                | SyncCommand::With { .. }
+                | SyncCommand::Peer { .. }
+                | SyncCommand::Quic { .. } => true,
+                SyncCommand::Ssh { pull, .. } => *pull,
+                _ => false,
+            },
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug, Subcommand)]
+pub enum SyncCommand {
+    /// Upload missing blocks and the root to a remote (`s3://bucket/prefix`, the URL of
+    /// `serve blocks`, a directory or the name of a remote in the config file)
+    Push { remote: String },
+    /// Download missing blocks and the root from a remote
+    Pull {
+        remote: String,
+        /// Replace the local root even if it differs from the remote
+        #[clap(long)]
+        force: bool,
+    },
+    /// Merge local and remote changes since the last sync, then upload the result
+    With {
+        remote: String,
+        /// How to resolve files that changed on both sides
+        #[clap(long, value_enum, default_value_t = ConflictPolicy::KeepBoth)]
+        policy: ConflictPolicy,
+    },
+    /// Serve the blocks of the filesystem to other devices and print a ticket for `sync peer`
+    Advertise,
+    /// Download the filesystem directly from a device running `sync advertise`
+    Peer {
+        ticket: String,
+        /// Replace the local root even if it differs from the advertised one
+        #[clap(long)]
+        force: bool,
+    },
+    /// Upload missing blocks and the root to a store on another machine over SSH
+    Ssh {
+        /// Remote store as `[user@]host:/path/blocks.db`
+        target: String,
+        /// Download from the remote store instead
+        #[clap(long)]
+        pull: bool,
+        /// Replace the local root even if it differs from the remote (with --pull)
+        #[clap(long, requires = "pull")]
+        force: bool,
+        /// Name or path of this program on the remote machine
+        #[clap(long, default_value = "wnfs-experiments")]
+        remote_program: String,
+    },
+    /// Answer requests of `sync ssh` on STDIN and STDOUT
+    #[clap(hide = true)]
+    ServeStdio,
+    /// Merge changes with another device running `daemon --quic`
+    Quic {
+        /// The device as `<node id>@<host>:<port>`
+        addr: String,
+        /// How to resolve files that changed on both sides
+        #[clap(long, value_enum, default_value_t = ConflictPolicy::KeepBoth)]
+        policy: ConflictPolicy,
+        /// Only download the filesystem, e.g. from a device running `seed`
+        #[clap(long)]
+        pull: bool,
+        /// Replace the local root even if it differs from the remote (with --pull)
+        #[clap(long, requires = "pull")]
+        force: bool,
+    },
+    /// Print the node id of this store, to authorize it on other devices
+    NodeId,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum RemoteCommand {
+    /// Upload the current root as CAR file to a pinning service from the config file
+    Pin { service: String },
+    /// List the last pinned root per pinning service
+    Pins,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum AgentCommand {
+    /// Run the agent in the foreground
+    Run,
+    /// Ask for the passphrase of the filesystem and hand it to the agent
+    Add,
+    /// Make the agent forget the passphrase of the filesystem
+    Remove,
+    /// Make the agent forget all passphrases
+    Lock,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum UcanCommand {
+    /// Print the DID of this store, which others need to delegate access to it
+    Did,
+    /// Allow another device to write to a directory of the filesystem and print the token
+    Delegate {
+        /// DID of the device
+        did: String,
+        #[clap(default_value = "")]
+        path: String,
+        /// Expiry date (YYYY-MM-DD) or time (RFC 3339)
+        #[clap(long, value_parser = parse_time)]
+        expires: Option<DateTime<Utc>>,
+    },
+    /// Sign revisions of the filesystem with a token delegated to this device
+    Accept { token: String },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ServeCommand {
+    /// Serve files over HTTP (GET, PUT, DELETE on /files/<path>, JSON listings on /ls/<path>)
+    Http {
+        #[clap(long, default_value = "127.0.0.1:8080")]
+        addr: SocketAddr,
+    },
+    /// Serve a JSON API with bearer token authentication (see the `api` module for endpoints)
+    Api {
+        #[clap(long, default_value = "127.0.0.1:8082")]
+        addr: SocketAddr,
+        /// Token that clients have to send [default: random, printed on startup]
+        #[clap(long, env = "WNFS_API_TOKEN", hide_env_values = true)]
+        token: Option<String>,
+        /// Certificate file (PEM) to serve HTTPS
+        #[clap(long, requires = "tls_key")]
+        tls_cert: Option<PathBuf>,
+        /// Private key file (PEM) of the certificate
+        #[clap(long, requires = "tls_cert")]
+        tls_key: Option<PathBuf>,
+    },
+    /// Serve the whole block store to remote mounts (see the `http_store` module for endpoints)
+    ///
+    /// Clients pass the URL as --db-path and the token in WNFS_BLOCKS_TOKEN.
+    Blocks {
+        #[clap(long, default_value = "127.0.0.1:8083")]
+        addr: SocketAddr,
+        /// Token that clients have to send [default: random, printed on startup]
+        #[clap(long, env = "WNFS_BLOCKS_TOKEN", hide_env_values = true)]
+        token: Option<String>,
+        /// Certificate file (PEM) to serve HTTPS
+        #[clap(long, requires = "tls_key")]
+        tls_cert: Option<PathBuf>,
+        /// Private key file (PEM) of the certificate
+        #[clap(long, requires = "tls_cert")]
+        tls_key: Option<PathBuf>,
+    },
+    /// Serve the filesystem to S3 clients, with top-level directories as buckets (see the
+    /// `s3_gateway` module for supported operations)
+    ///
+    /// Clients have to use path-style requests and sign them with the access key pair.
+    S3 {
+        #[clap(long, default_value = "127.0.0.1:9000")]
+        addr: SocketAddr,
+        /// Access key id of clients [default: random, printed on startup]
+        #[clap(long, env = "WNFS_S3_ACCESS_KEY", requires = "secret_key")]
+        access_key: Option<String>,
+        /// Secret access key of clients
+        #[clap(long, env = "WNFS_S3_SECRET_KEY", hide_env_values = true, requires = "access_key")]
+        secret_key: Option<String>,
+        /// Certificate file (PEM) to serve HTTPS
+        #[clap(long, requires = "tls_key")]
+        tls_cert: Option<PathBuf>,
+        /// Private key file (PEM) of the certificate
+        #[clap(long, requires = "tls_cert")]
+        tls_key: Option<PathBuf>,
+    },
+    /// Serve the filesystem over WebDAV, to be mounted by native file managers
+    Webdav {
+        #[clap(long, default_value = "127.0.0.1:8081")]
+        addr: SocketAddr,
+        /// Reject all modifications
+        #[clap(long)]
+        read_only: bool,
+    },
+    /// Serve the filesystem over NFSv3, as an alternative to FUSE
+    ///
+    /// Mount with e.g. `mount -t nfs -o nolocks,vers=3,tcp,port=11111,mountport=11111,soft 127.0.0.1:/ /mnt`
+    Nfs {
+        #[clap(long, default_value = "127.0.0.1:11111")]
+        addr: SocketAddr,
+    },
+    /// Serve the filesystem over 9P2000.L, e.g. for virtio-9p in VMs or WSL2
+    ///
+    /// Mount with e.g. `mount -t 9p -o trans=tcp,port=5640,version=9p2000.L 127.0.0.1 /mnt`
+    #[command(name = "9p")]
+    NineP {
+        /// Dial string, `tcp!<host>!<port>` or `unix!<path>!0`
+        #[clap(long, default_value = "tcp!127.0.0.1!5640")]
+        addr: String,
+    },
+    /// Serve the filesystem over gRPC (see proto/wnfs.proto)
+    #[cfg(feature = "grpc")]
+    Grpc {
+        #[clap(long, default_value = "127.0.0.1:50051")]
+        addr: SocketAddr,
+    },
+    /// Serve the filesystem over SFTP, authenticating clients by public key
+    Sftp {
+        #[clap(long, default_value = "127.0.0.1:2222")]
+        addr: SocketAddr,
+        /// Host key, created if missing [default: sftp_host_key next to the config file]
+        #[clap(long)]
+        host_key: Option<PathBuf>,
+        /// Public keys that may connect [default: ~/.ssh/authorized_keys]
+        #[clap(long)]
+        authorized_keys: Option<PathBuf>,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum FsCommand {
+    /// List all filesystems in the store
+    List,
+    /// Delete a filesystem from the store
+    Delete {
+        name: String,
+        /// Run garbage collection afterwards to reclaim the space
+        #[clap(long)]
+        gc: bool,
+        /// List what would be deleted without deleting anything
+        #[clap(long)]
+        dry_run: bool,
+        /// Do not ask for confirmation
+        #[clap(long, visible_alias = "yes")]
+        force: bool,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum KeyCommand {
+    /// Print the access key of the filesystem
+    Export {
+        /// Do not ask for confirmation
+        #[clap(long)]
+        yes: bool,
+    },
+    /// Create a named filesystem from an access key
+    Import {
+        /// Local name for the filesystem
+        name: String,
+        /// Access key (read from STDIN if omitted)
+        key: Option<String>,
     },
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    tracing_subscriber::fmt::init();
-    let args = Args::parse();
-    let mut fs = Wnfs::open_from_path(args.db_path, args.fs_name).await?;
+    // WNFS_LOG takes precedence over the usual RUST_LOG.
+    let filter =
+        EnvFilter::try_from_env("WNFS_LOG").unwrap_or_else(|_| EnvFilter::from_default_env());
+    let registry = tracing_subscriber::registry().with(
+        tracing_subscriber::fmt::layer()
+            .with_writer(std::io::stderr)
+            .with_filter(filter),
+    );
+    #[cfg(feature = "otlp")]
+    let registry = registry.with(telemetry::otlp_layer()?);
+    registry.init();
+    let result = run_main().await;
+    #[cfg(feature = "otlp")]
+    telemetry::shutdown_otlp();
+    match result {
+        Err(err) if err.is::<Failed>() => std::process::exit(1),
+        result => result,
+    }
+}
+
+/// A command found problems that it already reported, so the process exits with status 1
+/// without printing an error.
+#[derive(Debug)]
+struct Failed;
+
+impl std::fmt::Display for Failed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Failed")
+    }
+}
+
+impl std::error::Error for Failed {}
+
+async fn run_main() -> anyhow::Result<()> {
+    let Some(argv) = cli_args()? else {
+        return Ok(());
+    };
+    let args = Args::parse_from(&argv);
+    // Generated from the CLI definition, so handle these before anything can fail.
+    match &args.command {
+        Command::Completions { shell } => {
+            let name = env!("CARGO_BIN_NAME");
+            clap_complete::generate(*shell, &mut Args::command(), name, &mut std::io::stdout());
+            return Ok(());
+        }
+        Command::Manpages { dir } => {
+            std::fs::create_dir_all(dir)?;
+            let command = Args::command().name(env!("CARGO_BIN_NAME"));
+            write_manpages(&command, dir)?;
+            return Ok(());
+        }
+        _ => {}
+    }
+    let config = Config::load()?;
+    // Per-mountpoint settings take precedence over the global ones.
+    let mount_config = match &args.command {
+        Command::Mount { mountpoint, .. } => config.mount(mountpoint),
+        _ => MountConfig::default(),
+    };
+    let db_path = args
+        .db_path
+        .or(mount_config.db_path)
+        .or(config.db_path.clone())
+        .unwrap_or_else(|| "blocks.db".to_string());
+    let fs_name = args
+        .fs_name
+        .or(mount_config.fs_name)
+        .or(config.fs_name.clone())
+        .unwrap_or_else(|| "demo".to_string());
+    if let Some(addr) = args.command.metrics_addr() {
+        telemetry::serve_metrics(addr)?;
+    }
+    let remote = db_path.starts_with("http://") || db_path.starts_with("https://");
+    // Held until the command is done. Remote stores cannot be locked from here.
+    let _lock = if args.command.writes() && !mount_config.read_only && !remote {
+        let lock = WriterLock::acquire(&db_path, &fs_name)?;
+        // A previous writer may have crashed while flushing.
+        let store = SqliteBlockStore::new(&db_path)?;
+        if Wnfs::recover_commit(&store, &fs_name).await? {
+            eprintln!("recovered an interrupted commit of {fs_name}");
+        }
+        Some(lock)
+    } else {
+        None
+    };
 
     match args.command {
+        Command::Mount {
+            mountpoint,
+            daemon: false,
+            flags,
+            ..
+        } if remote => {
+            let store = HttpBlockStore::from_env(&db_path)?;
+            let passphrase = if Wnfs::is_protected(&store, &fs_name).await? {
+                Some(prompt_passphrase(&db_path, &fs_name).await?)
+            } else {
+                None
+            };
+            let fs = Wnfs::open_in_store(store, fs_name, passphrase.as_deref()).await?;
+            let options = mount_options(&flags, &config.mount(&mountpoint), &config);
+            unmount_on_signal(&mountpoint);
+            println!("mounting {db_path} at {mountpoint}");
+            fuse::mount_with_options(fs, &mountpoint, &options)?;
+        }
+        command if remote && !matches!(command, Command::Umount { .. }) => {
+            anyhow::bail!("Remote block stores only support `mount` without --daemon");
+        }
+        Command::Init { passphrase } => {
+            let passphrase = if !passphrase {
+                None
+            } else if let Some(passphrase) = passphrase_from_file()? {
+                Some(passphrase)
+            } else {
+                let passphrase = rpassword::prompt_password("New passphrase: ")?;
+                let repeated = rpassword::prompt_password("Repeat passphrase: ")?;
+                if passphrase != repeated {
+                    anyhow::bail!("Passphrases do not match");
+                }
+                Some(passphrase)
+            };
+            Wnfs::init(&db_path, fs_name.clone(), passphrase.as_deref()).await?;
+            println!("created filesystem {}", fs_name);
+        }
+        Command::Mount {
+            mountpoint,
+            daemon: true,
+            ..
+        } => {
+            let passphrase = read_passphrase(&db_path, &fs_name).await?;
+            // Run the same command in the background, minus the daemon flag.
+            let child_args = argv
+                .into_iter()
+                .skip(1)
+                .filter(|arg| arg != "--daemon")
+                .collect();
+            let pid = daemon::spawn(&mountpoint, child_args, passphrase.as_deref())?;
+            println!("mounted at {mountpoint} (PID {pid})");
+        }
+        Command::Umount { mountpoint } => {
+            daemon::unmount(&mountpoint)?;
+        }
+        Command::Serve {
+            command: ServeCommand::Http { addr },
+            ..
+        } => {
+            let fs = spawn_fs(&db_path, fs_name, &config).await?;
+            println!("serving on http://{addr}");
+            http::serve(fs, addr).await?;
+        }
+        Command::Serve {
+            command:
+                ServeCommand::Api {
+                    addr,
+                    token,
+                    tls_cert,
+                    tls_key,
+                },
+            ..
+        } => {
+            let token = match token {
+                Some(token) => token,
+                None => {
+                    let token = rand::random::<[u8; 16]>();
+                    let token = token.iter().map(|byte| format!("{byte:02x}")).collect();
+                    println!("API token: {token}");
+                    token
+                }
+            };
+            let fs = spawn_fs(&db_path, fs_name, &config).await?;
+            let scheme = if tls_cert.is_some() { "https" } else { "http" };
+            let config = api::ApiConfig {
+                token,
+                tls: tls_cert.zip(tls_key),
+            };
+            println!("serving API on {scheme}://{addr}/api/v1");
+            api::serve(fs, addr, config).await?;
+        }
+        Command::Serve {
+            command:
+                ServeCommand::Blocks {
+                    addr,
+                    token,
+                    tls_cert,
+                    tls_key,
+                },
+            ..
+        } => {
+            let token = match token {
+                Some(token) => token,
+                None => {
+                    let token = rand::random::<[u8; 16]>();
+                    let token = token.iter().map(|byte| format!("{byte:02x}")).collect();
+                    println!("blocks token: {token}");
+                    token
+                }
+            };
+            let store = SqliteBlockStore::new(&db_path)?;
+            let scheme = if tls_cert.is_some() { "https" } else { "http" };
+            let config = http_store::BlockServerConfig {
+                token,
+                tls: tls_cert.zip(tls_key),
+            };
+            println!("serving blocks on {scheme}://{addr}");
+            http_store::serve(store, addr, config).await?;
+        }
+        Command::Serve {
+            command:
+                ServeCommand::S3 {
+                    addr,
+                    access_key,
+                    secret_key,
+                    tls_cert,
+                    tls_key,
+                },
+            ..
+        } => {
+            let (access_key, secret_key) = match access_key.zip(secret_key) {
+                Some(keys) => keys,
+                None => {
+                    let access_key = rand::random::<[u8; 10]>();
+                    let access_key = access_key.iter().map(|byte| format!("{byte:02X}")).collect();
+                    let secret_key = rand::random::<[u8; 20]>();
+                    let secret_key = secret_key.iter().map(|byte| format!("{byte:02x}")).collect();
+                    println!("S3 access key: {access_key}");
+                    println!("S3 secret key: {secret_key}");
+                    (access_key, secret_key)
+                }
+            };
+            let fs = spawn_fs(&db_path, fs_name, &config).await?;
+            let scheme = if tls_cert.is_some() { "https" } else { "http" };
+            let config = s3_gateway::S3Config {
+                access_key,
+                secret_key,
+                tls: tls_cert.zip(tls_key),
+            };
+            println!("serving S3 on {scheme}://{addr}");
+            s3_gateway::serve(fs, addr, config).await?;
+        }
+        Command::Serve {
+            command: ServeCommand::Webdav { addr, read_only },
+            ..
+        } => {
+            let fs = spawn_fs(&db_path, fs_name, &config).await?;
+            let config = webdav::WebdavConfig {
+                read_only,
+                ..Default::default()
+            };
+            println!("serving WebDAV on http://{addr}");
+            webdav::serve(fs, addr, config).await?;
+        }
+        Command::Serve {
+            command: ServeCommand::Nfs { addr },
+            ..
+        } => {
+            let fs = spawn_fs(&db_path, fs_name, &config).await?;
+            println!("serving NFS on {addr}");
+            nfs::serve(fs, addr).await?;
+        }
+        Command::Serve {
+            command: ServeCommand::NineP { addr },
+            ..
+        } => {
+            let fs = spawn_fs(&db_path, fs_name, &config).await?;
+            println!("serving 9P on {addr}");
+            ninep::serve(fs, &addr).await?;
+        }
+        #[cfg(feature = "grpc")]
+        Command::Serve {
+            command: ServeCommand::Grpc { addr },
+            ..
+        } => {
+            let fs = spawn_fs(&db_path, fs_name, &config).await?;
+            println!("serving gRPC on {addr}");
+            wnfs_experiments::grpc::serve(fs, addr).await?;
+        }
+        Command::Serve {
+            command:
+                ServeCommand::Sftp {
+                    addr,
+                    host_key,
+                    authorized_keys,
+                },
+            ..
+        } => {
+            let config_dir = Config::path().and_then(|path| Some(path.parent()?.to_owned()));
+            let host_key = match host_key {
+                Some(host_key) => host_key,
+                None => {
+                    let config_dir = config_dir
+                        .ok_or_else(|| anyhow::anyhow!("No config directory, set --host-key"))?;
+                    std::fs::create_dir_all(&config_dir)?;
+                    config_dir.join("sftp_host_key")
+                }
+            };
+            let authorized_keys = match authorized_keys {
+                Some(authorized_keys) => authorized_keys,
+                None => PathBuf::from(std::env::var_os("HOME").unwrap_or_default())
+                    .join(".ssh/authorized_keys"),
+            };
+            let fs = spawn_fs(&db_path, fs_name, &config).await?;
+            let config = sftp::SftpConfig {
+                host_key,
+                authorized_keys,
+            };
+            println!("serving SFTP on {addr}");
+            sftp::serve(fs, addr, config).await?;
+        }
+        Command::Shell => {
+            let fs = spawn_fs(&db_path, fs_name, &config).await?;
+            let rt = tokio::runtime::Handle::current();
+            tokio::task::spawn_blocking(move || shell::run(fs, rt)).await??;
+        }
+        // Commands that operate on the block store only.
+        Command::Daemon { listen, quic } => {
+            // Kept until the daemon exits.
+            let mut _node = None;
+            if !listen.is_empty() {
+                let options = BitswapOptions {
+                    listen,
+                    peers: config.peers.clone(),
+                };
+                let node = BitswapNode::spawn(&db_path, options).await?;
+                println!("serving blocks as {}", node.peer_id());
+                _node = Some(node);
+            }
+            if let Some(port) = quic {
+                let store = SqliteBlockStore::new(&db_path)?;
+                let server = quic::QuicServer::bind(store, port, &config.authorized_nodes).await?;
+                for addr in server.local_addrs().await? {
+                    println!("serving sync as {}@{addr}", server.node_id());
+                }
+                tokio::spawn(server.run());
+            }
+            tokio::signal::ctrl_c().await?;
+        }
+        Command::Seed { listen, quic } => {
+            // Never opens a filesystem, so no passphrase or key is ever asked for. Bitswap
+            // only writes blocks that it requested itself, and there are no peers to ask.
+            let mut _node = None;
+            if !listen.is_empty() {
+                let options = BitswapOptions {
+                    listen,
+                    peers: vec![],
+                };
+                let node = BitswapNode::spawn(&db_path, options).await?;
+                println!("seeding blocks as {}", node.peer_id());
+                _node = Some(node);
+            }
+            if let Some(port) = quic {
+                let store = SqliteBlockStore::new(&db_path)?;
+                let server = quic::QuicServer::bind(store, port, &config.authorized_nodes)
+                    .await?
+                    .read_only();
+                for addr in server.local_addrs().await? {
+                    println!("seeding blocks as {}@{addr}", server.node_id());
+                }
+                tokio::spawn(server.run());
+            }
+            tokio::signal::ctrl_c().await?;
+        }
+        Command::Gc { dry_run } => {
+            let store = SqliteBlockStore::new(&db_path)?;
+            let _locks = if dry_run {
+                vec![]
+            } else {
+                lock_all(&db_path, &store).await?
+            };
+            let bar = spinner("collecting garbage");
+            bar.enable_steady_tick(Duration::from_millis(100));
+            let stats = store.gc(dry_run).await?;
+            bar.finish_and_clear();
+            let size = format_size(stats.bytes);
+            if dry_run {
+                println!("would reclaim {} blocks ({size})", stats.blocks);
+            } else {
+                println!("reclaimed {} blocks ({size})", stats.blocks);
+            }
+        }
+        Command::Migrate { dry_run } => {
+            let mut store = SqliteBlockStore::new(&db_path)?;
+            let migrations = if dry_run {
+                format::pending(&store).await?
+            } else {
+                // No filesystem of the store may be written while it is migrated.
+                let mut locks = vec![];
+                if !format::pending(&store).await?.is_empty() {
+                    for info in Wnfs::list(&store).await? {
+                        locks.push(WriterLock::acquire(&db_path, &info.name)?);
+                    }
+                }
+                format::migrate(&mut store).await?
+            };
+            if args.json {
+                print_json(&migrations)?;
+                return Ok(());
+            }
+            if migrations.is_empty() {
+                println!("store is at format version {}", format::FORMAT_VERSION);
+            }
+            for migration in migrations {
+                let verb = if dry_run { "would migrate" } else { "migrated" };
+                println!(
+                    "{verb} from version {} to {}: {}",
+                    migration.from, migration.to, migration.description
+                );
+            }
+        }
+        Command::Stats { dedup } => {
+            let store = SqliteBlockStore::new(&db_path)?;
+            let bar = spinner("analyzing store");
+            bar.enable_steady_tick(Duration::from_millis(100));
+            let store_stats = stats::store_stats(&store).await?;
+            bar.finish_and_clear();
+            let dedup = if dedup {
+                let fs = open_fs(&db_path, fs_name, &config).await?;
+                let bar = spinner("reading files");
+                let on_progress = report_progress(&bar);
+                let report = stats::dedup_report(&fs, &on_progress).await?;
+                bar.finish_and_clear();
+                Some(report)
+            } else {
+                None
+            };
+            if args.json {
+                print_json(&json!({ "store": store_stats, "dedup": dedup }))?;
+                return Ok(());
+            }
+            let usage = |usage: &stats::Usage| {
+                format!("{:>10} in {} blocks", format_size(usage.bytes), usage.blocks)
+            };
+            println!("stored:   {}", usage(&store_stats.total));
+            println!("live:     {}", usage(&store_stats.live));
+            println!("garbage:  {}  (reclaimed by gc)", usage(&store_stats.garbage));
+            if !store_stats.roots.is_empty() {
+                println!();
+                println!("{:>10}  {:>10}  alias", "reachable", "exclusive");
+            }
+            for root in &store_stats.roots {
+                println!(
+                    "{:>10}  {:>10}  {}",
+                    format_size(root.reachable.bytes),
+                    format_size(root.exclusive.bytes),
+                    root.alias
+                );
+            }
+            if let Some(report) = dedup {
+                println!();
+                println!(
+                    "content:     {:>10} in {} files",
+                    format_size(report.logical_bytes),
+                    report.files
+                );
+                println!(
+                    "duplicates:  {:>10} in {} files (stored once per copy)",
+                    format_size(report.duplicate_bytes),
+                    report.duplicate_files
+                );
+                println!("stored:      {:>10}", format_size(report.stored_bytes));
+                println!("current:     {:>10}", format_size(report.current_bytes));
+                println!(
+                    "history:     {:>10}  (kept for earlier revisions)",
+                    format_size(report.history_bytes)
+                );
+            }
+        }
+        Command::Bench {
+            size_mb,
+            files,
+            depth,
+        } => {
+            let options = bench::BenchOptions {
+                size_mb,
+                files,
+                depth,
+            };
+            let results = bench::run(&db_path, &options).await?;
+            if args.json {
+                print_json(&results)?;
+            } else {
+                for result in results {
+                    let throughput = match result.throughput() {
+                        Some(throughput) => format!("{}/s", format_size(throughput as u64)),
+                        None => format!("{:.0} ops/s", result.ops_per_sec()),
+                    };
+                    println!(
+                        "{:<18}  {:>12}  mean {:>10.3?}  max {:>10.3?}",
+                        result.name, throughput, result.mean_latency, result.max_latency
+                    );
+                }
+            }
+        }
+        Command::ExportCar { file, since } => {
+            let store = SqliteBlockStore::new(&db_path)?;
+            let since = match since {
+                Some(since) => Some(car::resolve_since(&store, &fs_name, &since).await?),
+                None => None,
+            };
+            let stats = if file == "-" {
+                car::export(&store, &fs_name, since.as_ref(), tokio::io::stdout()).await?
+            } else {
+                let file = tokio::io::BufWriter::new(tokio::fs::File::create(&file).await?);
+                car::export(&store, &fs_name, since.as_ref(), file).await?
+            };
+            let size = format_size(stats.bytes);
+            eprintln!("exported {} blocks ({size})", stats.blocks);
+        }
+        Command::ImportCar { file, force } => {
+            let store = SqliteBlockStore::new(&db_path)?;
+            let stats = if file == "-" {
+                car::import(&store, &fs_name, tokio::io::stdin(), force).await?
+            } else {
+                let file = tokio::io::BufReader::new(tokio::fs::File::open(&file).await?);
+                car::import(&store, &fs_name, file, force).await?
+            };
+            let size = format_size(stats.bytes);
+            println!("imported {} blocks ({size})", stats.blocks);
+        }
+        Command::Backup { target_dir } => {
+            let store = SqliteBlockStore::new(&db_path)?;
+            match backup::backup(&store, &fs_name, &target_dir).await? {
+                Some(entry) => println!(
+                    "wrote {} with {} blocks ({})",
+                    entry.file,
+                    entry.blocks,
+                    format_size(entry.bytes)
+                ),
+                None => println!("no changes since the last backup"),
+            }
+        }
+        Command::Restore {
+            backup_dir,
+            name,
+            force,
+        } => {
+            let store = SqliteBlockStore::new(&db_path)?;
+            let name = match name {
+                Some(name) => name,
+                None => backup::Catalog::load(&backup_dir).await?.name,
+            };
+            let _lock = WriterLock::acquire(&db_path, &name)?;
+            let stats = backup::restore(&store, &backup_dir, &name, force).await?;
+            let size = format_size(stats.bytes);
+            println!("restored {name} from {} blocks ({size})", stats.blocks);
+        }
+        Command::ExchangeKey => {
+            let mut store = SqliteBlockStore::new(&db_path)?;
+            let key = ExchangeKey::load_or_create(&mut store).await?;
+            println!("{}", share::encode_public_key(&key.public_key()));
+        }
+        Command::AcceptShare { label, name } => {
+            // A mount of the same name would overwrite the accepted root.
+            let _lock = WriterLock::acquire(&db_path, &name)?;
+            let mut store = SqliteBlockStore::new(&db_path)?;
+            let label = Cid::try_from(label.as_str())?;
+            Wnfs::accept_share(&mut store, &label, &name).await?;
+            drop(store);
+            // Make sure the shared directory can actually be loaded.
+            Wnfs::open_from_path(&db_path, name.clone()).await?;
+            println!("accepted share as {name}");
+        }
+        Command::Fs {
+            command: FsCommand::List,
+        } => {
+            let store = SqliteBlockStore::new(&db_path)?;
+            for info in Wnfs::list(&store).await? {
+                let modified = match (info.protected, info.modified) {
+                    (true, _) => "(protected)".to_string(),
+                    (false, Some(modified)) => modified.format("%Y-%m-%d %H:%M:%S").to_string(),
+                    (false, None) => "-".to_string(),
+                };
+                println!(
+                    "{:<16}  {}  {:>10}  {}",
+                    info.name,
+                    info.root_cid,
+                    format_size(info.stored_size),
+                    modified
+                );
+            }
+        }
+        Command::Fs {
+            command:
+                FsCommand::Delete {
+                    name,
+                    gc,
+                    dry_run,
+                    force,
+                },
+        } => {
+            let store = SqliteBlockStore::new(&db_path)?;
+            if dry_run {
+                for alias in Wnfs::aliases(&store, &name).await? {
+                    println!("would remove {alias}");
+                }
+                return Ok(());
+            }
+            // Deleting a mounted filesystem would have the mount write it back.
+            let _lock = WriterLock::acquire(&db_path, &name)?;
+            let prompt = format!("Delete filesystem {name}? This cannot be undone.");
+            if !force && !confirm(&prompt)? {
+                anyhow::bail!("Aborted");
+            }
+            Wnfs::delete(&store, &name).await?;
+            println!("deleted filesystem {name}");
+            if gc {
+                // The collection covers the whole store, so the other filesystems are locked too.
+                let _locks = lock_all(&db_path, &store).await?;
+                let bar = spinner("collecting garbage");
+                bar.enable_steady_tick(Duration::from_millis(100));
+                let stats = store.gc(false).await?;
+                bar.finish_and_clear();
+                let size = format_size(stats.bytes);
+                println!("reclaimed {} blocks ({size})", stats.blocks);
+            }
+        }
+        Command::Recover {
+            access_key,
+            snapshot,
+            root,
+            scan,
+            passphrase,
+            force,
+        } => {
+            let mut store = SqliteBlockStore::new(&db_path)?;
+            let passphrase = if !passphrase {
+                None
+            } else if let Some(passphrase) = passphrase_from_file()? {
+                Some(passphrase)
+            } else {
+                Some(rpassword::prompt_password("Passphrase: ")?)
+            };
+            if scan {
+                let candidates = Wnfs::scan_roots(&store, passphrase.as_deref()).await?;
+                if args.json {
+                    print_json(&candidates)?;
+                    return Ok(());
+                }
+                for candidate in candidates {
+                    let modified = match (candidate.protected, candidate.modified) {
+                        (_, Some(modified)) => modified.format("%Y-%m-%d %H:%M:%S").to_string(),
+                        (true, None) => "(protected)".to_string(),
+                        (false, None) => "-".to_string(),
+                    };
+                    println!("{}  {modified}", candidate.root_cid);
+                }
+                return Ok(());
+            }
+            let root = root.map(|root| Cid::try_from(root.as_str())).transpose()?;
+            let source = match (&access_key, &snapshot, root) {
+                (Some(access_key), _, _) => RootSource::AccessKey(access_key),
+                (_, Some(snapshot), _) => RootSource::Snapshot(snapshot),
+                (_, _, Some(root)) => RootSource::Record(root),
+                _ => anyhow::bail!("Use --access-key, --snapshot, --root or --scan"),
+            };
+            // A protected record that decodes is treated as intact, as it cannot be opened here.
+            let intact = match Wnfs::is_protected(&store, &fs_name).await {
+                Ok(true) => true,
+                Ok(false) => Wnfs::open_in_store(store.clone(), fs_name.clone(), None)
+                    .await
+                    .is_ok(),
+                Err(_) => false,
+            };
+            if intact && !force {
+                anyhow::bail!(
+                    "Filesystem {fs_name} can be opened, use --force to replace its root"
+                );
+            }
+            let root_cid =
+                Wnfs::recover_root(&mut store, &fs_name, source, passphrase.as_deref()).await?;
+            println!("recovered {fs_name} at {root_cid}");
+        }
+        Command::Sync {
+            command: SyncCommand::Push { remote },
+        } => {
+            let store = SqliteBlockStore::new(&db_path)?;
+            let remote = open_remote(config.remote(&remote))?;
+            let bar = progress_bar();
+            let on_progress = report_progress(&bar);
+            let stats =
+                sync::push_with_progress(&store, remote.as_ref(), &fs_name, &on_progress)
+                    .await?;
+            bar.finish_and_clear();
+            let size = format_size(stats.bytes);
+            println!("pushed {} blocks ({size})", stats.blocks);
+        }
+        Command::Sync {
+            command: SyncCommand::Pull { remote, force },
+        } => {
+            let store = SqliteBlockStore::new(&db_path)?;
+            let remote = open_remote(config.remote(&remote))?;
+            let bar = spinner("pulling");
+            let on_progress = report_progress(&bar);
+            let stats = sync::pull_with_progress(
+                &store,
+                remote.as_ref(),
+                &fs_name,
+                force,
+                &on_progress,
+            )
+            .await?;
+            bar.finish_and_clear();
+            let size = format_size(stats.bytes);
+            println!("pulled {} blocks ({size})", stats.blocks);
+        }
+        Command::Sync {
+            command: SyncCommand::With { remote, policy },
+        } => {
+            let url = config.remote(&remote);
+            let remote = open_remote(url)?;
+            let mut store = SqliteBlockStore::new(&db_path)?;
+            let owners = config.owners.get(&fs_name).cloned();
+            let auth = WriteAuth::load(&mut store, &fs_name, owners).await?;
+            drop(store);
+            let mut fs = open_fs(&db_path, fs_name, &config).await?;
+            let bar = spinner("syncing");
+            bar.enable_steady_tick(Duration::from_millis(100));
+            let report = sync::sync_with(&mut fs, remote.as_ref(), url, policy, &auth).await?;
+            bar.finish_and_clear();
+            print_sync_report(&report, args.json)?;
+        }
+        Command::Sync {
+            command:
+                SyncCommand::Quic {
+                    addr,
+                    policy,
+                    pull,
+                    force,
+                },
+        } => {
+            let mut store = SqliteBlockStore::new(&db_path)?;
+            let remote = quic::QuicRemote::connect(&mut store, &addr).await?;
+            if pull {
+                let bar = spinner("pulling");
+                let on_progress = report_progress(&bar);
+                let stats =
+                    sync::pull_with_progress(&store, &remote, &fs_name, force, &on_progress)
+                        .await?;
+                bar.finish_and_clear();
+                let size = format_size(stats.bytes);
+                println!("pulled {} blocks ({size})", stats.blocks);
+                return Ok(());
+            }
+            let owners = config.owners.get(&fs_name).cloned();
+            let auth = WriteAuth::load(&mut store, &fs_name, owners).await?;
+            drop(store);
+            let mut fs = open_fs(&db_path, fs_name, &config).await?;
+            // Sync bases are per remote, and the node id identifies the other device.
+            let (node, _) = addr.split_once('@').unwrap_or((&addr, ""));
+            let remote_id = format!("quic:{node}");
+            let bar = spinner("syncing");
+            bar.enable_steady_tick(Duration::from_millis(100));
+            let report = sync::sync_with(&mut fs, &remote, &remote_id, policy, &auth).await?;
+            bar.finish_and_clear();
+            print_sync_report(&report, args.json)?;
+        }
+        Command::Sync {
+            command: SyncCommand::NodeId,
+        } => {
+            let mut store = SqliteBlockStore::new(&db_path)?;
+            println!("{}", quic::node_key(&mut store).await?.public());
+        }
+        Command::Sync {
+            command: SyncCommand::Advertise,
+        } => {
+            let store = SqliteBlockStore::new(&db_path)?;
+            let advertisement = peer::advertise(store, &fs_name).await?;
+            println!("{}", advertisement.ticket().await?.encode()?);
+            advertisement.run().await?;
+        }
+        Command::Sync {
+            command: SyncCommand::Peer { ticket, force },
+        } => {
+            let store = SqliteBlockStore::new(&db_path)?;
+            let ticket = peer::Ticket::decode(&ticket)?;
+            let remote = peer::PeerRemote::connect(&ticket).await?;
+            let bar = spinner("pulling");
+            let on_progress = report_progress(&bar);
+            let stats =
+                sync::pull_with_progress(&store, &remote, &fs_name, force, &on_progress).await?;
+            bar.finish_and_clear();
+            let size = format_size(stats.bytes);
+            println!("pulled {} blocks ({size})", stats.blocks);
+        }
+        Command::Sync {
+            command:
+                SyncCommand::Ssh {
+                    target,
+                    pull,
+                    force,
+                    remote_program,
+                },
+        } => {
+            let store = SqliteBlockStore::new(&db_path)?;
+            let remote = ssh::SshRemote::connect(&target, &remote_program)?;
+            let stats = if pull {
+                let bar = spinner("pulling");
+                let on_progress = report_progress(&bar);
+                let stats =
+                    sync::pull_with_progress(&store, &remote, &fs_name, force, &on_progress)
+                        .await?;
+                bar.finish_and_clear();
+                stats
+            } else {
+                let bar = progress_bar();
+                let on_progress = report_progress(&bar);
+                let stats =
+                    sync::push_with_progress(&store, &remote, &fs_name, &on_progress).await?;
+                bar.finish_and_clear();
+                stats
+            };
+            let size = format_size(stats.bytes);
+            let action = if pull { "pulled" } else { "pushed" };
+            println!("{action} {} blocks ({size})", stats.blocks);
+        }
+        Command::Sync {
+            command: SyncCommand::ServeStdio,
+        } => {
+            let store = SqliteBlockStore::new(&db_path)?;
+            ssh::serve(&store, tokio::io::stdin(), tokio::io::stdout()).await?;
+        }
+        Command::Remote {
+            command: RemoteCommand::Pin { service },
+        } => {
+            let store = SqliteBlockStore::new(&db_path)?;
+            let pin_config = config.pinning(&service)?;
+            let bar = spinner("uploading");
+            bar.enable_steady_tick(Duration::from_millis(100));
+            let (root, stats) = pin::pin(&store, &fs_name, &service, pin_config).await?;
+            bar.finish_and_clear();
+            let size = format_size(stats.bytes);
+            println!("pinned {root} on {service} ({} blocks, {size})", stats.blocks);
+        }
+        Command::Remote {
+            command: RemoteCommand::Pins,
+        } => {
+            let store = SqliteBlockStore::new(&db_path)?;
+            let pins = pin::list(&store, &fs_name).await?;
+            if args.json {
+                print_json(&pins)?;
+            } else {
+                for pin in pins {
+                    println!("{:<16}  {}", pin.service, pin.root);
+                }
+            }
+        }
+        Command::Agent {
+            command: AgentCommand::Run,
+        } => {
+            agent::run(&agent::socket_path()).await?;
+        }
+        Command::Agent {
+            command: AgentCommand::Add,
+        } => {
+            let store = SqliteBlockStore::new(&db_path)?;
+            if !Wnfs::is_protected(&store, &fs_name).await? {
+                anyhow::bail!("Filesystem {fs_name} is not protected by a passphrase");
+            }
+            let passphrase = match passphrase_from_file()? {
+                Some(passphrase) => passphrase,
+                None => rpassword::prompt_password("Passphrase: ")?,
+            };
+            // Fails for a wrong passphrase.
+            Wnfs::open_in_store(store, fs_name.clone(), Some(&passphrase)).await?;
+            if !agent::add_passphrase(&db_path, &fs_name, &passphrase).await? {
+                anyhow::bail!("No agent is running, start one with `agent run`");
+            }
+            println!("added passphrase of {fs_name}");
+        }
+        Command::Agent {
+            command: AgentCommand::Remove,
+        } => {
+            let request = agent::Request::Remove {
+                db_path: agent::store_id(&db_path),
+                name: fs_name.clone(),
+            };
+            match agent::request(&request).await? {
+                Some(response) if response.passphrase.is_some() => {
+                    println!("removed passphrase of {fs_name}")
+                }
+                Some(_) => println!("the agent has no passphrase for {fs_name}"),
+                None => anyhow::bail!("No agent is running"),
+            }
+        }
+        Command::Agent {
+            command: AgentCommand::Lock,
+        } => {
+            if agent::request(&agent::Request::Clear).await?.is_none() {
+                anyhow::bail!("No agent is running");
+            }
+            println!("removed all passphrases");
+        }
+        #[cfg(feature = "csi")]
+        Command::Csi {
+            endpoint,
+            node_id,
+            driver_name,
+        } => {
+            let config = wnfs_experiments::csi::CsiConfig {
+                driver_name,
+                node_id,
+                db_path: Some(db_path),
+            };
+            wnfs_experiments::csi::serve(&endpoint, config).await?;
+        }
+        Command::Ucan {
+            command: UcanCommand::Did,
+        } => {
+            let mut store = SqliteBlockStore::new(&db_path)?;
+            println!("{}", DeviceKey::load_or_create(&mut store).await?.did());
+        }
+        Command::Ucan {
+            command: UcanCommand::Delegate { did, path, expires },
+        } => {
+            let mut store = SqliteBlockStore::new(&db_path)?;
+            let path_segments = WnfsPath::parse(&path)?;
+            let expires = expires.map(|time| time.timestamp());
+            let token = ucan::delegate(&mut store, &fs_name, &did, &path_segments, expires).await?;
+            println!("{token}");
+        }
+        Command::Ucan {
+            command: UcanCommand::Accept { token },
+        } => {
+            let mut store = SqliteBlockStore::new(&db_path)?;
+            ucan::accept(&mut store, &fs_name, &token).await?;
+            println!("accepted token for {fs_name}");
+        }
+        Command::Key {
+            command: KeyCommand::Import { name, key },
+        } => {
+            let key = match key {
+                Some(key) => key,
+                None => {
+                    let mut key = String::new();
+                    tokio::io::stdin().read_to_string(&mut key).await?;
+                    key
+                }
+            };
+            // A mount of the same name would overwrite the imported root.
+            let _lock = WriterLock::acquire(&db_path, &name)?;
+            let mut store = SqliteBlockStore::new(&db_path)?;
+            Wnfs::import_access_key(&mut store, &name, &key).await?;
+            drop(store);
+            Wnfs::open_from_path(&db_path, name.clone()).await?;
+            println!("imported filesystem {name}");
+        }
+        #[cfg(feature = "search")]
+        Command::Search {
+            query,
+            limit,
+            rebuild,
+        } => {
+            let index_dir = search::index_dir(&db_path, &fs_name);
+            let exists = index_dir.exists();
+            let mut fs = open_fs(&db_path, fs_name, &config).await?;
+            if !exists || rebuild {
+                let bar = spinner("indexing");
+                bar.enable_steady_tick(Duration::from_millis(100));
+                fs.set_search_index(SearchIndex::open(&index_dir)?);
+                fs.rebuild_search_index().await?;
+                bar.finish_and_clear();
+            }
+            let hits = fs.search(&query, limit)?;
+            if args.json {
+                print_json(&hits)?;
+                return Ok(());
+            }
+            for hit in hits {
+                println!("{}", hit.path.join("/"));
+            }
+        }
+        command => {
+            let fs = open_fs(&db_path, fs_name, &config).await?;
+            run(fs, command, args.json, &config, &db_path).await?;
+        }
+    }
+    Ok(())
+}
+
+async fn run(
+    mut fs: Wnfs,
+    command: Command,
+    json: bool,
+    config: &Config,
+    db_path: &str,
+) -> anyhow::Result<()> {
+    // Set by checks that found problems, which fail only after the filesystem is flushed.
+    let mut failed = false;
+    match command {
         Command::Mkdir { path } => {
-            let path_segments = into_segments(path);
+            let path_segments = WnfsPath::parse(&path)?;
             fs.mkdir(&path_segments).await?;
         }
-        Command::Write { path } => {
-            let path_segments = into_segments(path);
-            let mut buf = Vec::new();
-            let _len = tokio::io::stdin().read_to_end(&mut buf).await?;
-            fs.write_file(&path_segments, buf).await?;
+        Command::Rm {
+            path,
+            recursive,
+            dry_run,
+            force,
+        } => {
+            let path_segments = WnfsPath::parse(&path)?;
+            if path_segments.is_empty() {
+                anyhow::bail!("Refusing to remove the root directory");
+            }
+            let entry = fs
+                .stat(&path_segments)
+                .await?
+                .ok_or_else(|| anyhow::anyhow!("Not found"))?;
+            let mut paths = vec![path_segments.clone()];
+            if entry.kind == EntryKind::Dir {
+                if !recursive && !fs.ls_entries(&path_segments).await?.is_empty() {
+                    anyhow::bail!("Directory not empty, use -r to remove it with its contents");
+                }
+                fs.walk(&path_segments, &mut |entry_path, _entry| {
+                    paths.push(entry_path.into());
+                    Ok(())
+                })
+                .await?;
+            }
+            if dry_run {
+                let paths: Vec<_> = paths.iter().map(WnfsPath::to_string).collect();
+                if json {
+                    print_json(&paths)?;
+                } else {
+                    for path in paths {
+                        println!("would remove {path}");
+                    }
+                }
+                return Ok(());
+            }
+            let prompt = format!(
+                "Remove {} entries below {path_segments}? This cannot be undone.",
+                paths.len()
+            );
+            if paths.len() > RM_CONFIRM_THRESHOLD && !force && !confirm(&prompt)? {
+                anyhow::bail!("Aborted");
+            }
+            fs.rm(&path_segments).await?;
+        }
+        Command::Mv { from, to } => {
+            let from = WnfsPath::parse(&from)?;
+            let Some(name) = from.file_name() else {
+                anyhow::bail!("Refusing to move the root directory");
+            };
+            let mut to = WnfsPath::parse(&to)?;
+            if let Some(entry) = fs.stat(&to).await? {
+                if entry.kind == EntryKind::Dir {
+                    to = to.join(name);
+                }
+            }
+            if to.starts_with(&from) {
+                anyhow::bail!("Cannot move {from} into itself");
+            }
+            fs.mv(&from, &to).await?;
+        }
+        Command::Touch { path } => {
+            let path_segments = WnfsPath::parse(&path)?;
+            fs.touch(&path_segments).await?;
+        }
+        Command::Write {
+            path,
+            input,
+            append,
+        } => {
+            let path_segments = WnfsPath::parse(&path)?;
+            if append {
+                let mut buf = Vec::new();
+                match input {
+                    Some(input) => {
+                        tokio::fs::File::open(input)
+                            .await?
+                            .read_to_end(&mut buf)
+                            .await?
+                    }
+                    None => tokio::io::stdin().read_to_end(&mut buf).await?,
+                };
+                fs.append_file(&path_segments, buf).await?;
+            } else {
+                // Streamed, so that files larger than the memory can be written.
+                match input {
+                    Some(input) => {
+                        let file = AllowStdIo::new(std::fs::File::open(input)?);
+                        fs.write_file_stream(&path_segments, file).await?
+                    }
+                    None => {
+                        let stdin = AllowStdIo::new(std::io::stdin());
+                        fs.write_file_stream(&path_segments, stdin).await?
+                    }
+                }
+            }
+        }
+        Command::Cat {
+            path,
+            offset,
+            length,
+        } => {
+            let path_segments = WnfsPath::parse(&path)?;
+            let mut stdout = tokio::io::stdout();
+            let mut offset = offset;
+            let mut remaining = length.unwrap_or(usize::MAX);
+            // Read in chunks so that only the requested range is loaded.
+            while remaining > 0 {
+                let size = remaining.min(CAT_CHUNK_SIZE);
+                let buf = fs.read_file_at(&path_segments, offset, size).await?;
+                stdout.write_all(&buf).await?;
+                if buf.len() < size {
+                    break;
+                }
+                offset += buf.len();
+                remaining -= buf.len();
+            }
+            stdout.flush().await?;
+        }
+        Command::Ls { path, long } => {
+            let path_segments = WnfsPath::parse(&path)?;
+            let entries = fs.ls_entries(&path_segments).await?;
+            if json {
+                print_json(&entries)?;
+            } else if long {
+                for entry in entries {
+                    let (kind, suffix) = match entry.kind {
+                        EntryKind::Dir => ('d', "/"),
+                        EntryKind::File => ('-', ""),
+                    };
+                    let mode = match entry.mode {
+                        Some(mode) => format!("{:04o}", mode & 0o7777),
+                        None => "-".to_string(),
+                    };
+                    let modified = match entry.modified {
+                        Some(modified) => modified.format("%Y-%m-%d %H:%M:%S").to_string(),
+                        None => "-".to_string(),
+                    };
+                    println!(
+                        "{kind} {mode:>4}  {:>12}  {modified:<19}  {}{suffix}",
+                        entry.size, entry.name
+                    );
+                }
+            } else {
+                for entry in entries {
+                    match entry.kind {
+                        EntryKind::Dir => println!("{}/", entry.name),
+                        EntryKind::File => println!("{}", entry.name),
+                    }
+                }
+            }
+        }
+        Command::Stat { path } => {
+            let path_segments = WnfsPath::parse(&path)?;
+            let entry = fs
+                .stat(&path_segments)
+                .await?
+                .ok_or_else(|| anyhow::anyhow!("Not found"))?;
+            if json {
+                print_json(&entry)?;
+            } else {
+                let format_time = |time: Option<DateTime<Utc>>| match time {
+                    Some(time) => time.format("%Y-%m-%d %H:%M:%S").to_string(),
+                    None => "-".to_string(),
+                };
+                println!("name:     {}", entry.name);
+                println!("kind:     {:?}", entry.kind);
+                println!("size:     {}", entry.size);
+                println!("modified: {}", format_time(entry.modified));
+                println!("created:  {}", format_time(entry.created));
+            }
+        }
+        Command::Mount { mountpoint, flags, .. } => {
+            unmount_on_signal(&mountpoint);
+            let mount_config = config.mount(&mountpoint);
+            let mut options = mount_options(&flags, &mount_config, config);
+            options.usage_file = Some(fuse::usage_path(db_path, fs.name()));
+            if let Some(schedule) = mount_config.snapshots.or(config.snapshots) {
+                let store = fs.store().clone();
+                tokio::spawn(schedule::run(store, fs.name().to_string(), schedule));
+            }
+            // The mount owns the filesystem and flushes it when it is unmounted.
+            fuse::mount_with_options(fs, &mountpoint, &options)?;
+            daemon::remove_pid_file(&mountpoint)?;
+            return Ok(());
+        }
+        Command::Du { path } => {
+            let path_segments = WnfsPath::parse(&path)?;
+            let mut entries = vec![];
+            for (name, _metadata) in fs.ls(&path_segments).await? {
+                let entry_path = path_segments.join(name.clone());
+                entries.push((name, fs.du(&entry_path).await?));
+            }
+            entries.sort_by(|(_, a), (_, b)| b.size.cmp(&a.size));
+            let total = fs.du(&path_segments).await?;
+            if json {
+                let entries = entries
+                    .into_iter()
+                    .map(|(name, usage)| json!({ "name": name, "usage": usage }))
+                    .collect::<Vec<_>>();
+                print_json(&json!({ "entries": entries, "total": total }))?;
+                return Ok(());
+            }
+            for (name, usage) in entries {
+                println!(
+                    "{:>10}  {:>7} files  {}",
+                    format_size(usage.size),
+                    usage.files,
+                    name
+                );
+            }
+            println!(
+                "{:>10}  {:>7} files  total",
+                format_size(total.size),
+                total.files
+            );
+        }
+        Command::Mirror {
+            host_dir,
+            path,
+            two_way,
+        } => {
+            let path_segments = WnfsPath::parse(&path)?;
+            let bar = spinner("importing");
+            let on_progress = report_progress(&bar);
+            if two_way {
+                mirror::mirror_two_way(&mut fs, &host_dir, &path_segments, &on_progress).await?;
+            } else {
+                mirror::mirror(&mut fs, &host_dir, &path_segments, &on_progress).await?;
+            }
+        }
+        Command::VerifyAgainst { path, host_dir } => {
+            let path_segments = WnfsPath::parse(&path)?;
+            let bar = spinner("verifying");
+            let on_progress = report_progress(&bar);
+            let mismatches =
+                mirror::verify_against(&fs, &path_segments, &host_dir, &on_progress).await?;
+            bar.finish_and_clear();
+            if json {
+                print_json(&mismatches)?;
+            } else {
+                for mismatch in &mismatches {
+                    let reason = match mismatch.kind {
+                        MismatchKind::MissingInFs => "missing in filesystem",
+                        MismatchKind::MissingOnHost => "missing on host",
+                        MismatchKind::KindDiffers => "file on one side, directory on the other",
+                        MismatchKind::SizeDiffers => "size differs",
+                        MismatchKind::ContentDiffers => "content differs",
+                    };
+                    println!("{}: {reason}", mismatch.path);
+                }
+            }
+            failed = !mismatches.is_empty();
+            if !json && !failed {
+                println!("ok");
+            }
+        }
+        Command::Fsck => {
+            let report = fs.verify().await?;
+            if json {
+                let undecryptable = report
+                    .undecryptable
+                    .iter()
+                    .map(|(path, err)| json!({ "path": path.segments().join("/"), "error": err }))
+                    .collect::<Vec<_>>();
+                print_json(&json!({
+                    "ok": report.is_ok(),
+                    "missing_blocks": report
+                        .missing_blocks
+                        .iter()
+                        .map(|cid| cid.to_string())
+                        .collect::<Vec<_>>(),
+                    "undecryptable": undecryptable,
+                    "orphaned_revisions": report.orphaned_revisions,
+                }))?;
+            } else {
+                for cid in &report.missing_blocks {
+                    println!("missing block {cid}");
+                }
+                for (path, err) in &report.undecryptable {
+                    println!("undecryptable node {path}: {err}");
+                }
+                if report.orphaned_revisions > 0 {
+                    println!("{} orphaned root revisions", report.orphaned_revisions);
+                }
+                if report.is_ok() {
+                    println!("ok");
+                }
+            }
+            failed = !report.is_ok();
+        }
+        Command::Status { choose, merge } => {
+            if let Some(index) = choose {
+                fs.choose_head(index).await?;
+                fs.flush().await?;
+            }
+            let mut copies = vec![];
+            if merge {
+                copies = fs.merge_heads().await?;
+                fs.flush().await?;
+            }
+            let root_cid = fs.root_cid().await?;
+            let heads = fs.heads().await?;
+            let cache = fuse::read_usage(db_path, fs.name())?;
+            if json {
+                let copies: Vec<_> = copies
+                    .iter()
+                    .map(|copy| copy.segments().join("/"))
+                    .collect();
+                print_json(&json!({
+                    "name": fs.name(),
+                    "root_cid": root_cid.map(|cid| cid.to_string()),
+                    "heads": heads,
+                    "conflict_copies": copies,
+                    "cache": cache,
+                }))?;
+                return Ok(());
+            }
+            for copy in copies {
+                println!("conflict copy {copy}");
+            }
+            println!("{}", fs.name());
+            if let Some(root_cid) = root_cid {
+                println!("root {root_cid}");
+            }
+            let plural = if heads.len() == 1 { "" } else { "s" };
+            println!("{} head{plural}", heads.len());
+            if heads.len() > 1 {
+                for head in &heads {
+                    let modified = match head.modified {
+                        Some(modified) => modified.format("%Y-%m-%d %H:%M:%S").to_string(),
+                        None => "-".to_string(),
+                    };
+                    println!("  {}  {modified}", head.index);
+                }
+                println!("resolve with `status --merge` or `status --choose <index>`");
+            }
+            if let Some(cache) = cache {
+                println!("cache of the mount:");
+                for (name, stats) in [
+                    ("nodes", cache.nodes),
+                    ("inodes", cache.inodes),
+                    ("total", cache.total),
+                ] {
+                    let budget = match stats.budget {
+                        Some(budget) => format!(" of {}", format_size(budget)),
+                        None => String::new(),
+                    };
+                    println!(
+                        "  {name:<7} {}{budget}, {} entries",
+                        format_size(stats.bytes),
+                        stats.entries
+                    );
+                }
+            }
+        }
+        Command::Chmod {
+            mode,
+            path,
+            recursive,
+        } => {
+            let path_segments = WnfsPath::parse(&path)?;
+            let mut files = vec![];
+            let entry = fs
+                .stat(&path_segments)
+                .await?
+                .ok_or_else(|| anyhow::anyhow!("Not found"))?;
+            if entry.kind == EntryKind::File {
+                files.push((path_segments.clone(), entry.mode));
+            } else if recursive {
+                fs.walk(&path_segments, &mut |entry_path, entry| {
+                    if entry.kind == EntryKind::File {
+                        files.push((entry_path.into(), entry.mode));
+                    }
+                    Ok(())
+                })
+                .await?;
+            } else {
+                anyhow::bail!("Setting the mode of directories is not supported, use -R");
+            }
+            for (file_path, current) in files {
+                let mode = apply_mode(&mode, current.unwrap_or(DEFAULT_FILE_MODE))?;
+                fs.set_mode(&file_path, mode).await?;
+            }
+        }
+        Command::Meta {
+            command: MetaCommand::Get { path, key },
+        } => {
+            let path_segments = WnfsPath::parse(&path)?;
+            let metadata = fs.get_metadata(&path_segments).await?;
+            match key {
+                Some(key) => {
+                    let value = metadata
+                        .get(&key)
+                        .ok_or_else(|| anyhow::anyhow!("Metadata key {key} not set"))?;
+                    match value {
+                        serde_json::Value::String(value) if !json => println!("{value}"),
+                        value => print_json(value)?,
+                    }
+                }
+                None if json => print_json(&metadata)?,
+                None => {
+                    for (key, value) in metadata {
+                        match value {
+                            serde_json::Value::String(value) => println!("{key}={value}"),
+                            value => println!("{key}={value}"),
+                        }
+                    }
+                }
+            }
+        }
+        Command::Meta {
+            command: MetaCommand::Set { path, key, value },
+        } => {
+            let path_segments = WnfsPath::parse(&path)?;
+            fs.set_metadata(&path_segments, &key, &value).await?;
+        }
+        Command::Find {
+            path,
+            name,
+            larger_than,
+            modified_after,
+        } => {
+            let path_segments = WnfsPath::parse(&path)?;
+            let mut stdout = std::io::stdout().lock();
+            fs.walk(&path_segments, &mut |entry_path, entry| {
+                if let Some(name) = &name {
+                    if !name.matches(&entry.name) {
+                        return Ok(());
+                    }
+                }
+                if let Some(larger_than) = larger_than {
+                    if entry.kind != EntryKind::File || entry.size <= larger_than {
+                        return Ok(());
+                    }
+                }
+                if let Some(modified_after) = modified_after {
+                    if !entry.modified.map_or(false, |modified| modified > modified_after) {
+                        return Ok(());
+                    }
+                }
+                if json {
+                    let value = json!({ "path": entry_path.join("/"), "entry": entry });
+                    writeln!(stdout, "{}", serde_json::to_string(&value)?)?;
+                } else {
+                    writeln!(stdout, "{}", entry_path.join("/"))?;
+                }
+                Ok(())
+            })
+            .await?;
+        }
+        Command::Diff {
+            from,
+            to,
+            since,
+            stat,
+        } => {
+            let from = since.or(from).expect("required by clap");
+            let changes = fs.diff(&from, &to).await?;
+            if json {
+                print_json(&changes)?;
+                return Ok(());
+            }
+            for change in changes {
+                let marker = match change.kind {
+                    ChangeKind::Added => "A",
+                    ChangeKind::Removed => "D",
+                    ChangeKind::Modified => "M",
+                };
+                let suffix = if change.entry_kind == EntryKind::Dir {
+                    "/"
+                } else {
+                    ""
+                };
+                let path = change.path.segments().join("/");
+                if stat && change.entry_kind == EntryKind::File {
+                    let delta = change.size_after as i64 - change.size_before as i64;
+                    println!("{marker}  {delta:>+12}  {path}{suffix}");
+                } else if stat {
+                    println!("{marker}  {:>12}  {path}{suffix}", "");
+                } else {
+                    println!("{marker}  {path}{suffix}");
+                }
+            }
+        }
+        Command::Log { limit, enable } => {
+            if enable {
+                fs.enable_journal(journal::default_actor()).await?;
+            }
+            let entries = fs.journal(limit).await?;
+            if json {
+                print_json(&entries)?;
+            } else if entries.is_empty() && !enable {
+                println!("no journal, enable it with --enable");
+            } else {
+                for entry in entries {
+                    let time = entry.timestamp.format("%Y-%m-%d %H:%M:%S");
+                    let op = serde_json::to_value(entry.op)?;
+                    let op = op.as_str().unwrap_or_default();
+                    let mut path = format!("/{}", entry.path.join("/"));
+                    if let Some(to) = entry.to {
+                        path = format!("{path} -> /{}", to.join("/"));
+                    }
+                    println!("{time}  {:<12}  {op:<12}  {path}", entry.actor);
+                }
+            }
+        }
+        Command::Snapshot {
+            command: SnapshotCommand::Create { name },
+        } => {
+            fs.create_snapshot(&name).await?;
+            println!("created snapshot {name}");
+        }
+        Command::Snapshot {
+            command: SnapshotCommand::List,
+        } => {
+            let snapshots = fs.list_snapshots().await?;
+            if json {
+                print_json(&snapshots)?;
+            } else {
+                for snapshot in snapshots {
+                    let modified = match snapshot.modified {
+                        Some(modified) => modified.format("%Y-%m-%d %H:%M:%S").to_string(),
+                        None => "-".to_string(),
+                    };
+                    println!("{:<16}  {}  {}", snapshot.name, modified, snapshot.root_cid);
+                }
+            }
+        }
+        Command::Snapshot {
+            command: SnapshotCommand::Restore { name, yes },
+        } => {
+            let prompt = format!(
+                "Restore snapshot {name}? Changes since the snapshot are lost unless they are \
+                in another snapshot."
+            );
+            if !yes && !confirm(&prompt)? {
+                anyhow::bail!("Aborted");
+            }
+            fs.restore_snapshot(&name).await?;
+            println!("restored snapshot {name}");
+        }
+        Command::Snapshot {
+            command: SnapshotCommand::Delete { name },
+        } => {
+            fs.delete_snapshot(&name).await?;
+            println!("deleted snapshot {name}");
+        }
+        Command::Share { path, to } => {
+            let path_segments = WnfsPath::parse(&path)?;
+            let recipient = match tokio::fs::read_to_string(&to).await {
+                Ok(key) => share::decode_public_key(&key)?,
+                Err(_) => share::decode_public_key(&to)?,
+            };
+            let label = fs.share(&path_segments, &recipient).await?;
+            println!("{label}");
+        }
+        Command::Key {
+            command: KeyCommand::Export { yes },
+        } => {
+            let prompt = "The access key grants full read access to the filesystem \
+                to anyone who also has its blocks. Anyone you show it to can \
+                read all of your files, forever. Print it?";
+            if !yes && !confirm(prompt)? {
+                anyhow::bail!("Aborted");
+            }
+            println!("{}", fs.export_access_key().await?);
+        }
+        Command::Init { .. }
+        | Command::Umount { .. }
+        | Command::Gc { .. }
+        | Command::Migrate { .. }
+        | Command::Stats { .. }
+        | Command::Bench { .. }
+        | Command::ExportCar { .. }
+        | Command::ImportCar { .. }
+        | Command::Backup { .. }
+        | Command::Restore { .. }
+        | Command::ExchangeKey
+        | Command::AcceptShare { .. }
+        | Command::Fs { .. }
+        | Command::Recover { .. }
+        | Command::Serve { .. }
+        | Command::Daemon { .. }
+        | Command::Seed { .. }
+        | Command::Shell
+        | Command::Sync { .. }
+        | Command::Remote { .. }
+        | Command::Agent { .. }
+        | Command::Ucan { .. }
+        | Command::Completions { .. }
+        | Command::Manpages { .. }
+        | Command::Key {
+            command: KeyCommand::Import { .. },
+        } => unreachable!(),
+        #[cfg(feature = "search")]
+        Command::Search { .. } => unreachable!(),
+        #[cfg(feature = "csi")]
+        Command::Csi { .. } => unreachable!(),
+    }
+    fs.flush().await?;
+    if failed {
+        return Err(Failed.into());
+    }
+    Ok(())
+}
+
+/// The command line arguments, translated from mount syntax when running as `mount.wnfs`.
+///
+/// Returns `None` if there is nothing to do.
+fn cli_args() -> anyhow::Result<Option<Vec<OsString>>> {
+    let mut args = std::env::args_os();
+    let arg0 = args.next().unwrap_or_default();
+    if !mount_helper::is_mount_helper(&arg0) {
+        return Ok(Some(std::iter::once(arg0).chain(args).collect()));
+    }
+    let Some((args, passphrase_file)) = mount_helper::translate_args(args)? else {
+        return Ok(None);
+    };
+    if let Some(path) = passphrase_file {
+        std::env::set_var("WNFS_PASSPHRASE_FILE", path);
+    }
+    Ok(Some(std::iter::once(arg0).chain(args).collect()))
+}
+
+/// Combine the mount flags with the settings of the mountpoint in the config file.
+fn mount_options(
+    flags: &MountFlags,
+    mount_config: &MountConfig,
+    config: &Config,
+) -> fuse::MountOptions {
+    fuse::MountOptions {
+        read_only: flags.read_only || mount_config.read_only,
+        allow_other: flags.allow_other || mount_config.allow_other,
+        uid: flags.uid.or(mount_config.uid),
+        gid: flags.gid.or(mount_config.gid),
+        umask: flags.umask.or(mount_config.umask),
+        timeout: flags
+            .timeout
+            .or(mount_config.timeout)
+            .map(Duration::from_secs),
+        cache: mount_config
+            .cache
+            .clone()
+            .or(config.cache.clone())
+            .unwrap_or_default(),
+        usage_file: None,
+    }
+}
+
+/// Unmount cleanly on SIGTERM (sent by `umount`) and Ctrl-C.
+fn unmount_on_signal(mountpoint: &str) {
+    let mountpoint = mountpoint.to_string();
+    tokio::spawn(async move {
+        let mut terminate = signal(SignalKind::terminate())?;
+        tokio::select! {
+            _ = terminate.recv() => {}
+            _ = tokio::signal::ctrl_c() => {}
+        }
+        fuse::unmount(&mountpoint)
+    });
+}
+
+/// Open a filesystem, asking for the passphrase if it is protected by one.
+async fn open_fs(db_path: &str, name: String, config: &Config) -> anyhow::Result<Wnfs> {
+    let passphrase = read_passphrase(db_path, &name).await?;
+    let store = open_store(db_path, config).await?;
+    let mut fs = Wnfs::open_in_store(store, name, passphrase.as_deref()).await?;
+    add_hooks(&mut fs, &config.hooks)?;
+    if let Some(max_read_size) = config.max_read_size {
+        fs.set_max_read_size(max_read_size);
+    }
+    with_search_index(fs, db_path)
+}
+
+/// Open a filesystem on its own thread and return a handle to it.
+async fn spawn_fs(db_path: &str, name: String, config: &Config) -> anyhow::Result<WnfsHandle> {
+    let passphrase = read_passphrase(db_path, &name).await?;
+    let store = open_store(db_path, config).await?;
+    let db_path = db_path.to_string();
+    let hooks = config.hooks.clone();
+    let max_read_size = config.max_read_size;
+    WnfsHandle::spawn(move || async move {
+        let mut fs = Wnfs::open_in_store(store, name, passphrase.as_deref()).await?;
+        add_hooks(&mut fs, &hooks)?;
+        if let Some(max_read_size) = max_read_size {
+            fs.set_max_read_size(max_read_size);
+        }
+        with_search_index(fs, &db_path)
+    })
+    .await
+}
+
+/// Run the hooks of the config file on file operations.
+fn add_hooks(fs: &mut Wnfs, hooks: &[HookConfig]) -> anyhow::Result<()> {
+    for hook in hooks {
+        fs.add_hook(CommandHook::new(hook)?);
+    }
+    Ok(())
+}
+
+/// Keep the search index of a filesystem up to date, once `search` has created it.
+#[cfg(feature = "search")]
+fn with_search_index(mut fs: Wnfs, db_path: &str) -> anyhow::Result<Wnfs> {
+    let index_dir = search::index_dir(db_path, fs.name());
+    if index_dir.exists() {
+        fs.set_search_index(SearchIndex::open(&index_dir)?);
+    }
+    Ok(fs)
+}
+
+#[cfg(not(feature = "search"))]
+fn with_search_index(fs: Wnfs, _db_path: &str) -> anyhow::Result<Wnfs> {
+    Ok(fs)
+}
+
+/// Lock every filesystem of the store for garbage collection.
+///
+/// Writers keep blocks that no committed root reaches until they flush, which a collection
+/// would delete.
+async fn lock_all(db_path: &str, store: &SqliteBlockStore) -> anyhow::Result<Vec<WriterLock>> {
+    let mut locks = vec![];
+    for info in Wnfs::list(store).await? {
+        let lock = WriterLock::acquire(db_path, &info.name).map_err(|err| {
+            err.context("Stop the mounts and servers of the store before collecting garbage")
+        })?;
+        locks.push(lock);
+    }
+    Ok(locks)
+}
+
+/// Open the block store, fetching missing blocks from the configured bitswap peers.
+async fn open_store(db_path: &str, config: &Config) -> anyhow::Result<SqliteBlockStore> {
+    let mut store = SqliteBlockStore::new(db_path)?;
+    if let Some(size) = config.block_cache {
+        store = store.with_cache_size(size);
+    }
+    if config.peers.is_empty() {
+        return Ok(store);
+    }
+    let options = BitswapOptions {
+        peers: config.peers.clone(),
+        ..Default::default()
+    };
+    let node = BitswapNode::spawn(db_path, options).await?;
+    Ok(store.with_resolver(Arc::new(node)))
+}
+
+/// Ask for the passphrase of a filesystem if it is protected by one.
+///
+/// The passphrase is read from the file in `WNFS_PASSPHRASE_FILE` if set, or else taken from
+/// a running agent. Otherwise, if STDIN is not a terminal, it is read from the first line of
+/// STDIN.
+async fn read_passphrase(db_path: &str, name: &str) -> anyhow::Result<Option<String>> {
+    let store = SqliteBlockStore::new(db_path)?;
+    if !Wnfs::is_protected(&store, name).await? {
+        return Ok(None);
+    }
+    Ok(Some(prompt_passphrase(db_path, name).await?))
+}
+
+/// Get the passphrase of a protected filesystem from a file, the agent or stdin.
+async fn prompt_passphrase(db_path: &str, name: &str) -> anyhow::Result<String> {
+    if let Some(passphrase) = passphrase_from_file()? {
+        Ok(passphrase)
+    } else if let Some(passphrase) = agent::get_passphrase(db_path, name).await? {
+        Ok(passphrase)
+    } else if std::io::stdin().is_terminal() {
+        Ok(rpassword::prompt_password("Passphrase: ")?)
+    } else {
+        let mut passphrase = String::new();
+        std::io::stdin().read_line(&mut passphrase)?;
+        Ok(passphrase.trim_end_matches('\n').to_string())
+    }
+}
+
+/// Read the passphrase from the file named in `WNFS_PASSPHRASE_FILE`, if set.
+///
+/// This is read explicitly instead of through clap, so that the secret never ends up in
+/// `--help` output or in the arguments of a daemonized mount.
+fn passphrase_from_file() -> anyhow::Result<Option<String>> {
+    let Some(path) = std::env::var_os("WNFS_PASSPHRASE_FILE") else {
+        return Ok(None);
+    };
+    let passphrase = std::fs::read_to_string(&path)
+        .map_err(|err| anyhow::anyhow!("Cannot read passphrase file {path:?}: {err}"))?;
+    Ok(Some(passphrase.trim_end_matches(['\r', '\n']).to_string()))
+}
+
+/// Ask a yes/no question on STDERR and read the answer from STDIN.
+fn confirm(prompt: &str) -> anyhow::Result<bool> {
+    eprint!("{prompt} [y/N] ");
+    std::io::stderr().flush()?;
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+/// Progress bar on STDERR for operations with a known total. Hidden if STDERR is not a
+/// terminal.
+fn progress_bar() -> ProgressBar {
+    let style = ProgressStyle::with_template("{bar:40} {pos}/{len} blocks, {msg}")
+        .expect("valid template");
+    ProgressBar::new(0).with_style(style)
+}
+
+/// Spinner on STDERR for operations without a known total.
+fn spinner(prefix: &'static str) -> ProgressBar {
+    let style =
+        ProgressStyle::with_template("{spinner} {prefix}: {pos} {msg}").expect("valid template");
+    ProgressBar::new_spinner().with_style(style).with_prefix(prefix)
+}
+
+fn report_progress(bar: &ProgressBar) -> impl Fn(&Progress) + '_ {
+    move |progress| {
+        if let Some(total) = progress.total {
+            bar.set_length(total);
+        }
+        bar.set_position(progress.items);
+        bar.tick();
+        match &progress.path {
+            Some(path) => bar.set_message(format!("{} {path}", format_size(progress.bytes))),
+            None => bar.set_message(format_size(progress.bytes)),
+        }
+    }
+}
+
+/// Parse a size with an optional binary unit suffix, e.g. `512`, `4K` or `10M`.
+fn parse_size(size: &str) -> anyhow::Result<u64> {
+    let size = size.trim();
+    let (number, multiplier) = match size.char_indices().last() {
+        Some((i, unit)) if unit.is_ascii_alphabetic() => {
+            let multiplier = match unit.to_ascii_uppercase() {
+                'K' => 1 << 10,
+                'M' => 1 << 20,
+                'G' => 1 << 30,
+                'T' => 1 << 40,
+                _ => anyhow::bail!("Unknown size unit {unit}"),
+            };
+            (&size[..i], multiplier)
         }
-        Command::Cat { path } => {
-            let path_segments = into_segments(path);
-            let buf = fs.read_file(&path_segments).await?;
-            tokio::io::stdout().write_all(&buf).await?;
+        _ => (size, 1),
+    };
+    Ok(number.parse::<u64>()? * multiplier)
+}
+
+/// Write a man page for a command and, recursively, for each of its subcommands.
+fn write_manpages(command: &clap::Command, dir: &std::path::Path) -> anyhow::Result<()> {
+    let mut content = vec![];
+    clap_mangen::Man::new(command.clone()).render(&mut content)?;
+    std::fs::write(dir.join(format!("{}.1", command.get_name())), content)?;
+    for subcommand in command.get_subcommands() {
+        // Name subcommand pages like git does, e.g. `wnfs-experiments-snapshot-create.1`.
+        let name = format!("{}-{}", command.get_name(), subcommand.get_name());
+        write_manpages(&subcommand.clone().name(name), dir)?;
+    }
+    Ok(())
+}
+
+/// Apply an octal (`755`) or symbolic (`u+x,go-w`) mode to the current mode.
+fn apply_mode(spec: &str, current: u32) -> anyhow::Result<u32> {
+    if let Ok(mode) = u32::from_str_radix(spec, 8) {
+        return Ok(mode & 0o7777);
+    }
+    let mut mode = current;
+    for clause in spec.split(',') {
+        let op_index = clause
+            .find(['+', '-', '='])
+            .ok_or_else(|| anyhow::anyhow!("Invalid mode {spec}"))?;
+        let (who, rest) = clause.split_at(op_index);
+        let (op, perms) = rest.split_at(1);
+        let mut who_mask = 0;
+        for c in who.chars() {
+            who_mask |= match c {
+                'u' => 0o700,
+                'g' => 0o070,
+                'o' => 0o007,
+                'a' => 0o777,
+                _ => anyhow::bail!("Invalid mode {spec}"),
+            };
         }
-        Command::Mount { mountpoint } => {
-            fuse::mount(fs, mountpoint)?;
-            // tokio::task::spawn_blocking(|| {
-            // });
+        if who_mask == 0 {
+            who_mask = 0o777;
         }
+        let mut bits = 0;
+        for c in perms.chars() {
+            bits |= match c {
+                'r' => 0o444,
+                'w' => 0o222,
+                'x' => 0o111,
+                _ => anyhow::bail!("Invalid mode {spec}"),
+            };
+        }
+        let bits = bits & who_mask;
+        mode = match op {
+            "+" => mode | bits,
+            "-" => mode & !bits,
+            _ => (mode & !who_mask) | bits,
+        };
     }
+    Ok(mode)
+}
+
+fn parse_umask(umask: &str) -> anyhow::Result<u16> {
+    let umask = u16::from_str_radix(umask.trim_start_matches("0o"), 8)?;
+    if umask > 0o777 {
+        anyhow::bail!("Invalid umask {umask:o}");
+    }
+    Ok(umask)
+}
+
+/// Parse a date (`YYYY-MM-DD`, taken as midnight UTC) or an RFC 3339 timestamp.
+fn parse_time(time: &str) -> anyhow::Result<DateTime<Utc>> {
+    if let Ok(date) = chrono::NaiveDate::parse_from_str(time, "%Y-%m-%d") {
+        let time = date.and_hms_opt(0, 0, 0).expect("midnight is valid");
+        return Ok(DateTime::from_utc(time, Utc));
+    }
+    Ok(DateTime::parse_from_rfc3339(time)?.with_timezone(&Utc))
+}
+
+fn print_sync_report(report: &sync::SyncReport, json: bool) -> anyhow::Result<()> {
+    if json {
+        return print_json(report);
+    }
+    for conflict in &report.conflicts {
+        let path = conflict.path.segments().join("/");
+        match &conflict.resolution {
+            Resolution::KeptLocal => println!("conflict: {path} (kept local)"),
+            Resolution::TookRemote => println!("conflict: {path} (took remote)"),
+            Resolution::KeptBoth { copy } => {
+                let copy = copy.segments().join("/");
+                println!("conflict: {path} (remote saved as {copy})")
+            }
+        }
+    }
+    println!(
+        "applied {} remote changes, pulled {} and pushed {} blocks",
+        report.applied, report.pulled.blocks, report.pushed.blocks
+    );
+    Ok(())
+}
+
+fn print_json(value: &impl Serialize) -> anyhow::Result<()> {
+    println!("{}", serde_json::to_string_pretty(value)?);
     Ok(())
 }
 
-fn into_segments(path: String) -> Vec<String> {
-    path.split("/").map(|x| x.to_owned()).collect()
+fn format_size(size: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = size as f64;
+    let mut unit = 0;
+    while size >= 1024. && unit < UNITS.len() - 1 {
+        size /= 1024.;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{size} {}", UNITS[unit])
+    } else {
+        format!("{size:.1} {}", UNITS[unit])
+    }
 }
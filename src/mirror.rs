@@ -0,0 +1,604 @@
+//! Replication of a host directory into a filesystem.
+//!
+//! [`mirror`] imports the directory and then watches it for changes. Changes are collected
+//! until the directory has been quiet for [`DEBOUNCE`] and then applied as a single revision.
+//! [`mirror_two_way`] also writes changes that other processes commit to the filesystem back
+//! to the host directory. [`verify_against`] checks that a tree in the filesystem matches a
+//! host directory.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use futures::future::LocalBoxFuture;
+use futures::FutureExt;
+use libipld::Cid;
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use serde::Serialize;
+use tokio::sync::mpsc;
+use tracing::{debug, warn};
+
+use crate::fs::{Change, ChangeKind, EntryKind, OnProgress, Progress, Wnfs};
+
+/// How long the host directory has to be quiet before changes are committed.
+pub const DEBOUNCE: Duration = Duration::from_secs(1);
+/// How often [`mirror_two_way`] checks for changes committed by other processes.
+pub const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+const VERIFY_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Files and directories written by [`import_dir`].
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ImportStats {
+    pub files: u64,
+    pub dirs: u64,
+    pub bytes: u64,
+}
+
+/// Recursively copy a host directory into the filesystem at `path_segments`.
+///
+/// This does not flush the filesystem unless autoflush is enabled. Progress is reported after
+/// each imported file.
+pub async fn import_dir(
+    fs: &mut Wnfs,
+    host_dir: &Path,
+    path_segments: &[String],
+    on_progress: OnProgress<'_>,
+) -> anyhow::Result<ImportStats> {
+    let mut stats = ImportStats::default();
+    import_path(
+        fs,
+        host_dir,
+        path_segments.to_vec(),
+        &mut stats,
+        on_progress,
+    )
+    .await?;
+    Ok(stats)
+}
+
+fn import_path<'a>(
+    fs: &'a mut Wnfs,
+    host_path: &'a Path,
+    path_segments: Vec<String>,
+    stats: &'a mut ImportStats,
+    on_progress: OnProgress<'a>,
+) -> LocalBoxFuture<'a, anyhow::Result<()>> {
+    async move {
+        let metadata = tokio::fs::symlink_metadata(host_path).await?;
+        if metadata.is_dir() {
+            if !path_segments.is_empty() {
+                fs.mkdir(&path_segments).await?;
+            }
+            stats.dirs += 1;
+            let mut entries = tokio::fs::read_dir(host_path).await?;
+            while let Some(entry) = entries.next_entry().await? {
+                let mut child_path = path_segments.clone();
+                child_path.push(entry.file_name().to_string_lossy().into_owned());
+                import_path(fs, &entry.path(), child_path, stats, on_progress).await?;
+            }
+        } else if metadata.is_file() {
+            let content = tokio::fs::read(host_path).await?;
+            stats.files += 1;
+            stats.bytes += content.len() as u64;
+            fs.write_file(&path_segments, content).await?;
+            on_progress(&Progress {
+                items: stats.files,
+                bytes: stats.bytes,
+                total: None,
+                path: Some(path_segments.join("/")),
+            });
+        } else {
+            debug!("skip {host_path:?}: not a regular file or directory");
+        }
+        Ok(())
+    }
+    .boxed_local()
+}
+
+/// Import a host directory and keep applying its changes to the filesystem until the watcher
+/// fails.
+///
+/// Progress is only reported for the initial import.
+pub async fn mirror(
+    fs: &mut Wnfs,
+    host_dir: &Path,
+    path_segments: &[String],
+    on_progress: OnProgress<'_>,
+) -> anyhow::Result<()> {
+    let host_dir = std::fs::canonicalize(host_dir)?;
+
+    // Start watching before the initial import so that no change is missed.
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let mut watcher = RecommendedWatcher::new(
+        move |event: notify::Result<notify::Event>| {
+            let _ = tx.send(event);
+        },
+        notify::Config::default(),
+    )?;
+    watcher.watch(&host_dir, RecursiveMode::Recursive)?;
+
+    let stats = import_dir(fs, &host_dir, path_segments, on_progress).await?;
+    fs.flush().await?;
+    debug!("initial import: {stats:?}");
+
+    loop {
+        // Wait for the first change, then collect until the directory is quiet.
+        let Some(event) = rx.recv().await else {
+            anyhow::bail!("File watcher stopped");
+        };
+        let mut changed = BTreeSet::new();
+        collect_paths(event, &mut changed);
+        while let Ok(Some(event)) = tokio::time::timeout(DEBOUNCE, rx.recv()).await {
+            collect_paths(event, &mut changed);
+        }
+        for host_path in &changed {
+            if let Err(err) = apply_change(fs, &host_dir, host_path, path_segments).await {
+                warn!("failed to mirror {host_path:?}: {err}");
+            }
+        }
+        fs.flush().await?;
+        debug!("committed {} changes", changed.len());
+    }
+}
+
+/// State of a host path, to recognize the events caused by writing to the host directory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum HostState {
+    Missing,
+    Dir,
+    File(blake3::Hash),
+}
+
+/// Keep a host directory and a tree in the filesystem in sync in both directions.
+///
+/// Host changes are applied as in [`mirror`]. Changes that other processes (e.g. a mount or
+/// `sync`) commit to the filesystem are written to the host directory, without importing them
+/// again. If a file changed on both sides, the host version wins and the filesystem version is
+/// written next to it as a conflict copy, e.g. `notes (conflict 1).txt`. Modifications win over
+/// removals on both sides.
+///
+/// Initially, the host directory is imported and paths that only exist in the filesystem are
+/// written to the host.
+pub async fn mirror_two_way(
+    fs: &mut Wnfs,
+    host_dir: &Path,
+    path_segments: &[String],
+    on_progress: OnProgress<'_>,
+) -> anyhow::Result<()> {
+    let host_dir = std::fs::canonicalize(host_dir)?;
+
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let mut watcher = RecommendedWatcher::new(
+        move |event: notify::Result<notify::Event>| {
+            let _ = tx.send(event);
+        },
+        notify::Config::default(),
+    )?;
+    watcher.watch(&host_dir, RecursiveMode::Recursive)?;
+
+    // Expected states of host paths written by the mirror. Events for them are ignored as long
+    // as the path is in that state.
+    let mut echoes = HashMap::new();
+    let stats = import_dir(fs, &host_dir, path_segments, on_progress).await?;
+    fs.flush().await?;
+    export_missing(fs, &host_dir, path_segments, &mut echoes).await?;
+    debug!("initial import: {stats:?}");
+    let mut last_root = fs
+        .root_cid()
+        .await?
+        .ok_or_else(|| anyhow::anyhow!("Filesystem {} does not exist", fs.name()))?;
+
+    let mut interval = tokio::time::interval(POLL_INTERVAL);
+    loop {
+        tokio::select! {
+            event = rx.recv() => {
+                let Some(event) = event else {
+                    anyhow::bail!("File watcher stopped");
+                };
+                let mut changed = BTreeSet::new();
+                collect_paths(event, &mut changed);
+                while let Ok(Some(event)) = tokio::time::timeout(DEBOUNCE, rx.recv()).await {
+                    collect_paths(event, &mut changed);
+                }
+                // Apply changes of other processes first, so that they are not overwritten.
+                last_root =
+                    export_changes(fs, &host_dir, path_segments, last_root, &mut echoes).await?;
+                let mut applied = 0;
+                for host_path in &changed {
+                    if let Some(expected) = echoes.get(host_path) {
+                        if host_state(host_path).await? == *expected {
+                            continue;
+                        }
+                        echoes.remove(host_path);
+                    }
+                    if let Err(err) = apply_change(fs, &host_dir, host_path, path_segments).await {
+                        warn!("failed to mirror {host_path:?}: {err}");
+                    }
+                    applied += 1;
+                }
+                if applied > 0 {
+                    fs.flush().await?;
+                    last_root = fs.root_cid().await?.unwrap_or(last_root);
+                    debug!("committed {applied} changes");
+                }
+            }
+            _ = interval.tick() => {
+                last_root =
+                    export_changes(fs, &host_dir, path_segments, last_root, &mut echoes).await?;
+            }
+        }
+    }
+}
+
+/// Write the paths below `path_segments` that do not exist on the host to the host.
+async fn export_missing(
+    fs: &Wnfs,
+    host_dir: &Path,
+    path_segments: &[String],
+    echoes: &mut HashMap<PathBuf, HostState>,
+) -> anyhow::Result<()> {
+    let mut entries = vec![];
+    fs.walk(path_segments, &mut |entry_path, entry| {
+        entries.push((entry_path.to_vec(), entry.kind));
+        Ok(())
+    })
+    .await?;
+    for (entry_path, kind) in entries {
+        let Some(host_path) = host_path(host_dir, &entry_path[path_segments.len()..]) else {
+            continue;
+        };
+        if host_state(&host_path).await? != HostState::Missing {
+            continue;
+        }
+        let state = match kind {
+            EntryKind::Dir => {
+                tokio::fs::create_dir_all(&host_path).await?;
+                HostState::Dir
+            }
+            EntryKind::File => {
+                write_host_file(&host_path, &fs.read_file(&entry_path).await?).await?
+            }
+        };
+        echoes.insert(host_path, state);
+    }
+    Ok(())
+}
+
+/// Write the changes that other processes committed since `last_root` to the host directory
+/// and return the current root.
+async fn export_changes(
+    fs: &mut Wnfs,
+    host_dir: &Path,
+    path_segments: &[String],
+    last_root: Cid,
+    echoes: &mut HashMap<PathBuf, HostState>,
+) -> anyhow::Result<Cid> {
+    let Some(root) = fs.root_cid().await? else {
+        return Ok(last_root);
+    };
+    if root == last_root {
+        return Ok(root);
+    }
+    let changes = fs.diff(&last_root.to_string(), &root.to_string()).await?;
+    fs.restore_root(&root).await?;
+    let mut removed_dirs = vec![];
+    for change in changes {
+        let Some(relative) = change.path.strip_prefix(path_segments) else {
+            continue;
+        };
+        let Some(host_path) = host_path(host_dir, relative) else {
+            continue;
+        };
+        if change.kind == ChangeKind::Removed && change.entry_kind == EntryKind::Dir {
+            removed_dirs.push(host_path);
+            continue;
+        }
+        if let Err(err) = export_change(fs, &change, &last_root, &host_path, echoes).await {
+            warn!("failed to write {host_path:?}: {err}");
+        }
+    }
+    // Directories are listed before their content, so remove the deepest first. Directories
+    // with new files on the host are kept.
+    for host_path in removed_dirs.into_iter().rev() {
+        if tokio::fs::remove_dir(&host_path).await.is_ok() {
+            echoes.insert(host_path, HostState::Missing);
+        }
+    }
+    Ok(root)
+}
+
+async fn export_change(
+    fs: &Wnfs,
+    change: &Change,
+    last_root: &Cid,
+    host_path: &Path,
+    echoes: &mut HashMap<PathBuf, HostState>,
+) -> anyhow::Result<()> {
+    let current = host_state(host_path).await?;
+    // The host path is unchanged if it is still in the state of the last root.
+    let previous = match change.kind {
+        ChangeKind::Added => HostState::Missing,
+        _ if change.entry_kind == EntryKind::Dir => HostState::Dir,
+        _ => {
+            let content = fs
+                .read_file_in_revision(&last_root.to_string(), &change.path)
+                .await?;
+            HostState::File(blake3::hash(&content))
+        }
+    };
+    match (change.kind, change.entry_kind) {
+        (ChangeKind::Removed, _) => {
+            if current == previous {
+                tokio::fs::remove_file(host_path).await?;
+                echoes.insert(host_path.to_owned(), HostState::Missing);
+            }
+        }
+        (_, EntryKind::Dir) => {
+            tokio::fs::create_dir_all(host_path).await?;
+            echoes.insert(host_path.to_owned(), HostState::Dir);
+        }
+        (_, EntryKind::File) => {
+            let content = fs.read_file(&change.path).await?;
+            let state = HostState::File(blake3::hash(&content));
+            if current == state {
+                echoes.insert(host_path.to_owned(), state);
+            } else if current == previous {
+                let state = write_host_file(host_path, &content).await?;
+                echoes.insert(host_path.to_owned(), state);
+            } else {
+                // Not recorded as echo, so that the copy is imported as well.
+                let copy = conflict_copy_path(host_path).await?;
+                warn!("conflict on {host_path:?}, writing the filesystem version to {copy:?}");
+                write_host_file(&copy, &content).await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Host path for a path relative to the mirrored directories, if it is a safe one.
+fn host_path(host_dir: &Path, relative: &[String]) -> Option<PathBuf> {
+    if relative.is_empty() {
+        return None;
+    }
+    let mut path = host_dir.to_owned();
+    for segment in relative {
+        if segment == "." || segment == ".." || segment.contains('/') {
+            warn!("skip {relative:?}: not a valid host path");
+            return None;
+        }
+        path.push(segment);
+    }
+    Some(path)
+}
+
+async fn host_state(path: &Path) -> anyhow::Result<HostState> {
+    let metadata = match tokio::fs::symlink_metadata(path).await {
+        Ok(metadata) => metadata,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(HostState::Missing),
+        Err(err) => return Err(err.into()),
+    };
+    if metadata.is_dir() {
+        return Ok(HostState::Dir);
+    }
+    let path = path.to_owned();
+    let (_size, hash) = tokio::task::spawn_blocking(move || hash_host_file(&path)).await??;
+    Ok(HostState::File(hash))
+}
+
+async fn write_host_file(path: &Path, content: &[u8]) -> anyhow::Result<HostState> {
+    if let Some(parent) = path.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    tokio::fs::write(path, content).await?;
+    Ok(HostState::File(blake3::hash(content)))
+}
+
+/// A free path next to `path`, e.g. `notes (conflict 1).txt`.
+async fn conflict_copy_path(path: &Path) -> anyhow::Result<PathBuf> {
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let (stem, extension) = match name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => (stem, format!(".{extension}")),
+        _ => (name.as_str(), String::new()),
+    };
+    for i in 1.. {
+        let copy = path.with_file_name(format!("{stem} (conflict {i}){extension}"));
+        if host_state(&copy).await? == HostState::Missing {
+            return Ok(copy);
+        }
+    }
+    unreachable!()
+}
+
+fn collect_paths(event: notify::Result<notify::Event>, paths: &mut BTreeSet<PathBuf>) {
+    match event {
+        Ok(event) => paths.extend(event.paths),
+        Err(err) => warn!("watch error: {err}"),
+    }
+}
+
+/// Bring the node for a host path in line with the current state of the host path.
+async fn apply_change(
+    fs: &mut Wnfs,
+    host_dir: &Path,
+    host_path: &Path,
+    path_segments: &[String],
+) -> anyhow::Result<()> {
+    let mut target = path_segments.to_vec();
+    for component in host_path.strip_prefix(host_dir)?.components() {
+        target.push(component.as_os_str().to_string_lossy().into_owned());
+    }
+    if target.len() == path_segments.len() {
+        return Ok(());
+    }
+    if tokio::fs::try_exists(host_path).await? {
+        let mut stats = ImportStats::default();
+        import_path(fs, host_path, target, &mut stats, &|_| {}).await
+    } else if fs.get_node(&target).await?.is_some() {
+        fs.rm(&target).await
+    } else {
+        Ok(())
+    }
+}
+
+/// How a path differs between the filesystem and the host directory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MismatchKind {
+    /// The path exists on the host but not in the filesystem.
+    MissingInFs,
+    /// The path exists in the filesystem but not on the host.
+    MissingOnHost,
+    /// A file on one side is a directory on the other.
+    KindDiffers,
+    SizeDiffers,
+    /// Same size, but the content hashes differ.
+    ContentDiffers,
+}
+
+/// A path found to differ by [`verify_against`].
+#[derive(Debug, Clone, Serialize)]
+pub struct Mismatch {
+    /// Path relative to the compared directories.
+    pub path: String,
+    pub kind: MismatchKind,
+    /// Content sizes of files, if the path is a file on that side.
+    pub fs_size: Option<u64>,
+    pub host_size: Option<u64>,
+}
+
+/// Compare the tree at `path_segments` with a host directory.
+///
+/// Files are compared by size and BLAKE3 hash of their content, read in chunks on both sides.
+/// Only regular files and directories on the host are considered. Progress is reported after
+/// each compared file.
+pub async fn verify_against(
+    fs: &Wnfs,
+    path_segments: &[String],
+    host_dir: &Path,
+    on_progress: OnProgress<'_>,
+) -> anyhow::Result<Vec<Mismatch>> {
+    let mut fs_entries = BTreeMap::new();
+    fs.walk(path_segments, &mut |entry_path, entry| {
+        let relative = entry_path[path_segments.len()..].join("/");
+        fs_entries.insert(relative, entry.kind);
+        Ok(())
+    })
+    .await?;
+    let mut host_entries = BTreeMap::new();
+    host_walk(host_dir, String::new(), &mut host_entries).await?;
+
+    let paths: BTreeSet<_> = fs_entries.keys().chain(host_entries.keys()).cloned().collect();
+    let mut mismatches = vec![];
+    let mut progress = Progress {
+        total: Some(paths.len() as u64),
+        ..Default::default()
+    };
+    for path in paths {
+        progress.items += 1;
+        let mismatch = |kind, fs_size, host_size| Mismatch {
+            path: path.clone(),
+            kind,
+            fs_size,
+            host_size,
+        };
+        match (fs_entries.get(&path), host_entries.get(&path)) {
+            (Some(_), None) => mismatches.push(mismatch(MismatchKind::MissingOnHost, None, None)),
+            (None, Some(_)) => mismatches.push(mismatch(MismatchKind::MissingInFs, None, None)),
+            (Some(fs_kind), Some(host_kind)) if fs_kind != host_kind => {
+                mismatches.push(mismatch(MismatchKind::KindDiffers, None, None))
+            }
+            (Some(EntryKind::File), Some(_)) => {
+                let mut file_path = path_segments.to_vec();
+                file_path.extend(path.split('/').map(str::to_string));
+                let (fs_size, fs_hash) = hash_fs_file(fs, &file_path).await?;
+                let host_path = host_dir.join(&path);
+                let (host_size, host_hash) =
+                    tokio::task::spawn_blocking(move || hash_host_file(&host_path)).await??;
+                let kind = if fs_size != host_size {
+                    Some(MismatchKind::SizeDiffers)
+                } else if fs_hash != host_hash {
+                    Some(MismatchKind::ContentDiffers)
+                } else {
+                    None
+                };
+                if let Some(kind) = kind {
+                    mismatches.push(mismatch(kind, Some(fs_size), Some(host_size)));
+                }
+                progress.bytes += fs_size;
+                progress.path = Some(path.clone());
+            }
+            _ => {}
+        }
+        on_progress(&progress);
+    }
+    Ok(mismatches)
+}
+
+/// Collect the regular files and directories below a host directory by relative path.
+fn host_walk<'a>(
+    host_path: &'a Path,
+    relative: String,
+    entries: &'a mut BTreeMap<String, EntryKind>,
+) -> LocalBoxFuture<'a, anyhow::Result<()>> {
+    async move {
+        let mut dir = tokio::fs::read_dir(host_path).await?;
+        while let Some(entry) = dir.next_entry().await? {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let child = if relative.is_empty() {
+                name
+            } else {
+                format!("{relative}/{name}")
+            };
+            let file_type = entry.file_type().await?;
+            if file_type.is_dir() {
+                entries.insert(child.clone(), EntryKind::Dir);
+                host_walk(&entry.path(), child, entries).await?;
+            } else if file_type.is_file() {
+                entries.insert(child, EntryKind::File);
+            } else {
+                debug!("skip {:?}: not a regular file or directory", entry.path());
+            }
+        }
+        Ok(())
+    }
+    .boxed_local()
+}
+
+async fn hash_fs_file(
+    fs: &Wnfs,
+    path_segments: &[String],
+) -> anyhow::Result<(u64, blake3::Hash)> {
+    let mut hasher = blake3::Hasher::new();
+    let mut size = 0;
+    loop {
+        let chunk = fs.read_file_at(path_segments, size, VERIFY_CHUNK_SIZE).await?;
+        hasher.update(&chunk);
+        size += chunk.len();
+        if chunk.len() < VERIFY_CHUNK_SIZE {
+            break;
+        }
+    }
+    Ok((size as u64, hasher.finalize()))
+}
+
+fn hash_host_file(path: &Path) -> anyhow::Result<(u64, blake3::Hash)> {
+    let mut file = std::fs::File::open(path)?;
+    let mut hasher = blake3::Hasher::new();
+    let mut buf = vec![0; VERIFY_CHUNK_SIZE];
+    let mut size = 0;
+    loop {
+        let len = file.read(&mut buf)?;
+        if len == 0 {
+            break;
+        }
+        hasher.update(&buf[..len]);
+        size += len as u64;
+    }
+    Ok((size, hasher.finalize()))
+}
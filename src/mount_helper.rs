@@ -0,0 +1,103 @@
+//! Support for running as `mount.wnfs`, the helper that `mount -t wnfs` calls.
+//!
+//! With the binary linked or copied to `/sbin/mount.wnfs`, filesystems can be declared in
+//! `/etc/fstab` and mounted by systemd:
+//!
+//! ```text
+//! /var/lib/wnfs/blocks.db  /mnt/private  wnfs  fsname=private,allow_other,nofail  0 0
+//! ```
+//!
+//! `mount` calls the helper as `mount.wnfs <db path> <mountpoint> [-sfnv] [-o options]`, which
+//! [`translate_args`] turns into the arguments of a background `mount`.
+
+use std::ffi::OsString;
+use std::path::Path;
+
+/// Names under which the binary acts as mount helper.
+const HELPER_NAMES: [&str; 2] = ["mount.wnfs", "mount.fuse.wnfs"];
+
+/// Options that only concern `mount` itself or other tools, like systemd.
+const IGNORED_OPTIONS: [&str; 19] = [
+    "defaults", "auto", "noauto", "user", "nouser", "users", "owner", "nofail", "_netdev", "dev",
+    "nodev", "suid", "nosuid", "exec", "noexec", "atime", "noatime", "relatime", "rw",
+];
+
+/// Whether the binary was invoked under the name of a mount helper.
+pub fn is_mount_helper(arg0: &OsString) -> bool {
+    Path::new(arg0)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .map_or(false, |name| HELPER_NAMES.contains(&name))
+}
+
+/// Translate the arguments of a mount helper (without the program name) to those of
+/// `--db-path <db> [--fs-name <name>] mount --daemon <mountpoint> ...`.
+///
+/// Returns `None` for fake mounts (`-f`), which should do nothing. `passphrase_file=<path>`
+/// is returned separately, as it is passed through the environment.
+pub fn translate_args(
+    args: impl IntoIterator<Item = OsString>,
+) -> anyhow::Result<Option<(Vec<OsString>, Option<OsString>)>> {
+    let usage = "Usage: mount.wnfs <db path> <mountpoint> [-sfnv] [-o options]";
+    let mut positional = vec![];
+    let mut options = vec![];
+    let mut sloppy = false;
+    let mut fake = false;
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let Some(flags) = arg.to_str().and_then(|arg| arg.strip_prefix('-')) else {
+            positional.push(arg);
+            continue;
+        };
+        if let Some(inline) = flags.strip_prefix('o') {
+            let list = match inline {
+                "" => args.next().ok_or_else(|| anyhow::anyhow!("{usage}"))?,
+                inline => inline.into(),
+            };
+            let list = list
+                .into_string()
+                .map_err(|_| anyhow::anyhow!("Mount options are not valid UTF-8"))?;
+            options.extend(list.split(',').map(str::to_string));
+            continue;
+        }
+        for flag in flags.chars() {
+            match flag {
+                's' => sloppy = true,
+                'f' => fake = true,
+                // Mount helpers do not write mtab, and there is nothing to be verbose about.
+                'n' | 'v' => {}
+                _ => anyhow::bail!("Unknown flag -{flag}\n{usage}"),
+            }
+        }
+    }
+    let [db_path, mountpoint] =
+        <[OsString; 2]>::try_from(positional).map_err(|_| anyhow::anyhow!("{usage}"))?;
+    if fake {
+        return Ok(None);
+    }
+
+    let mut translated: Vec<OsString> = vec!["--db-path".into(), db_path];
+    let mut mount_args: Vec<OsString> = vec!["mount".into(), "--daemon".into(), mountpoint];
+    let mut passphrase_file = None;
+    for option in options.iter().filter(|option| !option.is_empty()) {
+        let (key, value) = match option.split_once('=') {
+            Some((key, value)) => (key, Some(value)),
+            None => (option.as_str(), None),
+        };
+        match (key, value) {
+            ("fsname", Some(name)) => translated.extend(["--fs-name".into(), name.into()]),
+            ("passphrase_file", Some(path)) => passphrase_file = Some(path.into()),
+            ("ro", None) => mount_args.push("--read-only".into()),
+            ("allow_other", None) => mount_args.push("--allow-other".into()),
+            ("uid" | "gid" | "umask" | "timeout", Some(value)) => {
+                mount_args.extend([format!("--{key}").into(), value.into()])
+            }
+            (key, _) if IGNORED_OPTIONS.contains(&key) || key.starts_with("x-") => {}
+            ("comment", _) => {}
+            _ if sloppy => tracing::warn!("ignoring unknown mount option {option}"),
+            _ => anyhow::bail!("Unknown mount option {option}"),
+        }
+    }
+    translated.extend(mount_args);
+    Ok(Some((translated, passphrase_file)))
+}
@@ -0,0 +1,377 @@
+//! NFSv3 server exposing a filesystem.
+//!
+//! This is an alternative to FUSE for machines where loading the FUSE kernel module is not
+//! possible, and allows exporting the filesystem to other hosts on a LAN. There is no
+//! authentication, so only bind to addresses that trusted hosts can reach.
+//!
+//! File IDs are derived from a hash of the path, so file handles stay valid across server
+//! restarts. After a restart, IDs are resolved back to paths by walking the tree once. The
+//! change time of a node is its modification time, so clients invalidate cached attributes and
+//! data whenever a node changes; use the `actimeo` mount option to tune how often they check.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::UNIX_EPOCH;
+
+use async_trait::async_trait;
+use futures::FutureExt;
+use nfsserve::nfs::{
+    fattr3, fileid3, filename3, ftype3, nfs_fh3, nfspath3, nfsstat3, nfstime3, sattr3, specdata3,
+};
+use nfsserve::tcp::{NFSTcp, NFSTcpListener};
+use nfsserve::vfs::{self, NFSFileSystem, ReadDirResult, VFSCapabilities};
+use tracing::{debug, trace};
+
+use crate::fs::{path_id, DirEntry, EntryKind};
+use crate::handle::WnfsHandle;
+
+/// The ID [`path_id`] assigns to the root directory.
+const ROOT_ID: fileid3 = 1;
+
+/// Serve a filesystem over NFSv3 until the server fails.
+pub async fn serve(fs: WnfsHandle, addr: SocketAddr) -> anyhow::Result<()> {
+    let nfs = WnfsNfs::new(fs);
+    let listener = NFSTcpListener::bind(&addr.to_string(), nfs).await?;
+    debug!("serve NFS on {addr}");
+    listener.handle_forever().await?;
+    Ok(())
+}
+
+pub struct WnfsNfs {
+    fs: WnfsHandle,
+    /// Paths of the file IDs handed out so far.
+    paths: Mutex<HashMap<fileid3, Vec<String>>>,
+}
+
+impl WnfsNfs {
+    pub fn new(fs: WnfsHandle) -> Self {
+        let mut paths = HashMap::new();
+        paths.insert(ROOT_ID, vec![]);
+        Self {
+            fs,
+            paths: Mutex::new(paths),
+        }
+    }
+
+    async fn path(&self, id: fileid3) -> Result<Vec<String>, nfsstat3> {
+        let known = self.paths.lock().unwrap().get(&id).cloned();
+        if let Some(path) = known {
+            return Ok(path);
+        }
+        // A handle from before a restart. Register all paths to find it.
+        debug!("unknown file ID {id}, scanning the filesystem");
+        let all_paths = self
+            .fs
+            .call(move |fs| {
+                async move {
+                    let mut paths = vec![];
+                    fs.walk(&[], &mut |path, _entry| {
+                        paths.push(path.to_vec());
+                        Ok(())
+                    })
+                    .await?;
+                    Ok(paths)
+                }
+                .boxed_local()
+            })
+            .await
+            .map_err(io_error)?;
+        for path in &all_paths {
+            self.id(path);
+        }
+        let paths = self.paths.lock().unwrap();
+        paths.get(&id).cloned().ok_or(nfsstat3::NFS3ERR_STALE)
+    }
+
+    async fn child_path(
+        &self,
+        dirid: fileid3,
+        name: &filename3,
+    ) -> Result<Vec<String>, nfsstat3> {
+        let mut path = self.path(dirid).await?;
+        let name = std::str::from_utf8(name).map_err(|_| nfsstat3::NFS3ERR_INVAL)?;
+        path.push(name.to_string());
+        Ok(path)
+    }
+
+    fn id(&self, path_segments: &[String]) -> fileid3 {
+        let id = path_id(path_segments);
+        let mut paths = self.paths.lock().unwrap();
+        paths.entry(id).or_insert_with(|| path_segments.to_vec());
+        id
+    }
+
+    async fn stat(&self, path_segments: Vec<String>) -> Result<DirEntry, nfsstat3> {
+        self.fs
+            .call(move |fs| async move { fs.stat(&path_segments).await }.boxed_local())
+            .await
+            .map_err(io_error)?
+            .ok_or(nfsstat3::NFS3ERR_NOENT)
+    }
+
+    async fn attr(&self, id: fileid3) -> Result<fattr3, nfsstat3> {
+        let entry = self.stat(self.path(id).await?).await?;
+        Ok(entry_to_attr(id, &entry))
+    }
+}
+
+#[async_trait]
+impl NFSFileSystem for WnfsNfs {
+    fn capabilities(&self) -> VFSCapabilities {
+        VFSCapabilities::ReadWrite
+    }
+
+    fn root_dir(&self) -> fileid3 {
+        ROOT_ID
+    }
+
+    // The default handles include the server start time and go stale on restart.
+    fn id_to_fh(&self, id: fileid3) -> nfs_fh3 {
+        nfs_fh3 {
+            data: id.to_le_bytes().to_vec(),
+        }
+    }
+
+    fn fh_to_id(&self, fh: &nfs_fh3) -> Result<fileid3, nfsstat3> {
+        let bytes: [u8; 8] = fh
+            .data
+            .as_slice()
+            .try_into()
+            .map_err(|_| nfsstat3::NFS3ERR_BADHANDLE)?;
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    async fn lookup(&self, dirid: fileid3, filename: &filename3) -> Result<fileid3, nfsstat3> {
+        trace!("lookup: i{dirid} {filename:?}");
+        if &filename[..] == b"." {
+            return Ok(dirid);
+        }
+        if &filename[..] == b".." {
+            let mut path = self.path(dirid).await?;
+            path.pop();
+            return Ok(self.id(&path));
+        }
+        let path = self.child_path(dirid, filename).await?;
+        self.stat(path.clone()).await?;
+        Ok(self.id(&path))
+    }
+
+    async fn getattr(&self, id: fileid3) -> Result<fattr3, nfsstat3> {
+        trace!("getattr: i{id}");
+        self.attr(id).await
+    }
+
+    async fn setattr(&self, id: fileid3, _setattr: sattr3) -> Result<fattr3, nfsstat3> {
+        // Attributes are not persisted yet, so pretend the change was applied.
+        self.attr(id).await
+    }
+
+    async fn read(
+        &self,
+        id: fileid3,
+        offset: u64,
+        count: u32,
+    ) -> Result<(Vec<u8>, bool), nfsstat3> {
+        trace!("read: i{id} offset {offset} count {count}");
+        let path = self.path(id).await?;
+        let data = self
+            .fs
+            .call(move |fs| {
+                async move {
+                    fs.read_file_at(&path, offset as usize, count as usize)
+                        .await
+                }
+                .boxed_local()
+            })
+            .await
+            .map_err(io_error)?;
+        let eof = data.len() < count as usize;
+        Ok((data, eof))
+    }
+
+    async fn write(&self, id: fileid3, offset: u64, data: &[u8]) -> Result<fattr3, nfsstat3> {
+        trace!("write: i{id} offset {offset} len {}", data.len());
+        let path = self.path(id).await?;
+        let data = data.to_vec();
+        self.fs
+            .call(move |fs| {
+                async move {
+                    let mut content = fs.read_file(&path).await?;
+                    let offset = offset as usize;
+                    let end = offset + data.len();
+                    if content.len() < end {
+                        content.resize(end, 0);
+                    }
+                    content[offset..end].copy_from_slice(&data);
+                    fs.write_file(&path, content).await
+                }
+                .boxed_local()
+            })
+            .await
+            .map_err(io_error)?;
+        self.attr(id).await
+    }
+
+    async fn create(
+        &self,
+        dirid: fileid3,
+        filename: &filename3,
+        _attr: sattr3,
+    ) -> Result<(fileid3, fattr3), nfsstat3> {
+        let id = self.create_exclusive(dirid, filename).await?;
+        Ok((id, self.attr(id).await?))
+    }
+
+    async fn create_exclusive(
+        &self,
+        dirid: fileid3,
+        filename: &filename3,
+    ) -> Result<fileid3, nfsstat3> {
+        trace!("create: i{dirid} {filename:?}");
+        let path = self.child_path(dirid, filename).await?;
+        if self.stat(path.clone()).await.is_ok() {
+            return Err(nfsstat3::NFS3ERR_EXIST);
+        }
+        let write_path = path.clone();
+        self.fs
+            .call(move |fs| async move { fs.write_file(&write_path, vec![]).await }.boxed_local())
+            .await
+            .map_err(io_error)?;
+        Ok(self.id(&path))
+    }
+
+    async fn mkdir(
+        &self,
+        dirid: fileid3,
+        dirname: &filename3,
+    ) -> Result<(fileid3, fattr3), nfsstat3> {
+        trace!("mkdir: i{dirid} {dirname:?}");
+        let path = self.child_path(dirid, dirname).await?;
+        if self.stat(path.clone()).await.is_ok() {
+            return Err(nfsstat3::NFS3ERR_EXIST);
+        }
+        let mkdir_path = path.clone();
+        self.fs
+            .call(move |fs| async move { fs.mkdir(&mkdir_path).await }.boxed_local())
+            .await
+            .map_err(io_error)?;
+        let id = self.id(&path);
+        Ok((id, self.attr(id).await?))
+    }
+
+    async fn remove(&self, dirid: fileid3, filename: &filename3) -> Result<(), nfsstat3> {
+        trace!("remove: i{dirid} {filename:?}");
+        let path = self.child_path(dirid, filename).await?;
+        self.stat(path.clone()).await?;
+        self.fs
+            .call(move |fs| async move { fs.rm(&path).await }.boxed_local())
+            .await
+            .map_err(io_error)
+    }
+
+    async fn rename(
+        &self,
+        _from_dirid: fileid3,
+        _from_filename: &filename3,
+        _to_dirid: fileid3,
+        _to_filename: &filename3,
+    ) -> Result<(), nfsstat3> {
+        Err(nfsstat3::NFS3ERR_NOTSUPP)
+    }
+
+    async fn readdir(
+        &self,
+        dirid: fileid3,
+        start_after: fileid3,
+        max_entries: usize,
+    ) -> Result<ReadDirResult, nfsstat3> {
+        trace!("readdir: i{dirid} start_after {start_after}");
+        let path = self.path(dirid).await?;
+        let list_path = path.clone();
+        let entries = self
+            .fs
+            .call(move |fs| async move { fs.ls_entries(&list_path).await }.boxed_local())
+            .await
+            .map_err(io_error)?;
+        let mut entries = entries
+            .into_iter()
+            .map(|entry| {
+                let mut entry_path = path.clone();
+                entry_path.push(entry.name.clone());
+                (self.id(&entry_path), entry)
+            })
+            .collect::<Vec<_>>();
+        // The listing is resumed after the file ID of the last returned entry.
+        entries.sort_by_key(|(id, _)| *id);
+        let remaining = entries
+            .into_iter()
+            .filter(|(id, _)| *id > start_after)
+            .collect::<Vec<_>>();
+        let end = remaining.len() <= max_entries;
+        let entries = remaining
+            .into_iter()
+            .take(max_entries)
+            .map(|(id, entry)| vfs::DirEntry {
+                fileid: id,
+                name: entry.name.as_bytes().into(),
+                attr: entry_to_attr(id, &entry),
+            })
+            .collect();
+        Ok(ReadDirResult { entries, end })
+    }
+
+    async fn symlink(
+        &self,
+        _dirid: fileid3,
+        _linkname: &filename3,
+        _symlink: &nfspath3,
+        _attr: &sattr3,
+    ) -> Result<(fileid3, fattr3), nfsstat3> {
+        Err(nfsstat3::NFS3ERR_NOTSUPP)
+    }
+
+    async fn readlink(&self, _id: fileid3) -> Result<nfspath3, nfsstat3> {
+        Err(nfsstat3::NFS3ERR_NOTSUPP)
+    }
+}
+
+fn entry_to_attr(id: fileid3, entry: &DirEntry) -> fattr3 {
+    let (ftype, mode, nlink) = match entry.kind {
+        EntryKind::File => (ftype3::NF3REG, 0o644, 1),
+        EntryKind::Dir => (ftype3::NF3DIR, 0o755, 2),
+    };
+    let mode = entry.mode.unwrap_or(mode);
+    // Clients revalidate their caches when the change time differs.
+    let mtime = to_nfstime(entry.modified);
+    let ctime = mtime;
+    fattr3 {
+        ftype,
+        mode,
+        nlink,
+        uid: 1000,
+        gid: 1000,
+        size: entry.size,
+        used: entry.size,
+        rdev: specdata3::default(),
+        fsid: 0,
+        fileid: id,
+        atime: mtime,
+        mtime,
+        ctime,
+    }
+}
+
+fn to_nfstime(time: Option<chrono::DateTime<chrono::Utc>>) -> nfstime3 {
+    let time = time.map(std::time::SystemTime::from).unwrap_or(UNIX_EPOCH);
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    nfstime3 {
+        seconds: since_epoch.as_secs() as u32,
+        nseconds: since_epoch.subsec_nanos(),
+    }
+}
+
+fn io_error(err: anyhow::Error) -> nfsstat3 {
+    debug!("nfs error: {err}");
+    nfsstat3::NFS3ERR_IO
+}
@@ -0,0 +1,404 @@
+//! 9P2000.L server exposing a filesystem.
+//!
+//! This allows attaching the filesystem to virtual machines, e.g. QEMU or crosvm guests with
+//! virtio-9p, or WSL2, without running FUSE inside the guest. Mount with e.g.
+//! `mount -t 9p -o trans=tcp,port=5640,version=9p2000.L 127.0.0.1 /mnt`.
+//!
+//! QIDs use the same stable path IDs as the NFS server.
+
+use std::sync::Mutex;
+use std::time::UNIX_EPOCH;
+
+use async_trait::async_trait;
+use futures::FutureExt;
+use rs9p::error::{errno, Error};
+use rs9p::srv::{srv_async, Fid, Filesystem};
+use rs9p::{
+    Data, DirEntry as P9DirEntry, DirEntryData, FCall, GetattrMask, Qid, QidType, SetAttr,
+    SetattrMask, Stat, Statfs, Time,
+};
+use tracing::{debug, trace};
+
+use crate::fs::{path_id, DirEntry, EntryKind};
+use crate::handle::WnfsHandle;
+
+const P9_PROTO_2000L: &str = "9P2000.L";
+/// Filesystem type reported by `statfs`, the magic number of v9fs.
+const V9FS_MAGIC: u32 = 0x01021997;
+const BLOCK_SIZE: u32 = 4096;
+/// Directory entry types as in `dirent.d_type`.
+const DT_DIR: u8 = 4;
+const DT_REG: u8 = 8;
+
+/// Serve a filesystem over 9P until the server fails.
+///
+/// `addr` is in the usual 9P dial string format, e.g. `tcp!127.0.0.1!5640` or
+/// `unix!/run/wnfs.sock!0`.
+pub async fn serve(fs: WnfsHandle, addr: &str) -> anyhow::Result<()> {
+    debug!("serve 9P on {addr}");
+    srv_async(Wnfs9p { fs }, addr).await?;
+    Ok(())
+}
+
+#[derive(Clone)]
+struct Wnfs9p {
+    fs: WnfsHandle,
+}
+
+/// Per-fid state: the path the fid points to.
+#[derive(Default)]
+struct WnfsFid {
+    path: Mutex<Vec<String>>,
+}
+
+impl WnfsFid {
+    fn path(&self) -> Vec<String> {
+        self.path.lock().unwrap().clone()
+    }
+
+    fn set_path(&self, path_segments: Vec<String>) {
+        *self.path.lock().unwrap() = path_segments;
+    }
+}
+
+type Result<T> = rs9p::Result<T>;
+
+impl Wnfs9p {
+    async fn stat(&self, path_segments: Vec<String>) -> Result<DirEntry> {
+        self.fs
+            .call(move |fs| async move { fs.stat(&path_segments).await }.boxed_local())
+            .await
+            .map_err(io_error)?
+            .ok_or(Error::No(errno::ENOENT))
+    }
+
+    async fn qid(&self, path_segments: Vec<String>) -> Result<Qid> {
+        let entry = self.stat(path_segments.clone()).await?;
+        Ok(to_qid(&path_segments, &entry))
+    }
+
+    async fn write_at(&self, path_segments: Vec<String>, offset: u64, data: Vec<u8>) -> Result<()> {
+        self.fs
+            .call(move |fs| {
+                async move {
+                    let mut content = fs.read_file(&path_segments).await?;
+                    let offset = offset as usize;
+                    let end = offset + data.len();
+                    if content.len() < end {
+                        content.resize(end, 0);
+                    }
+                    content[offset..end].copy_from_slice(&data);
+                    fs.write_file(&path_segments, content).await
+                }
+                .boxed_local()
+            })
+            .await
+            .map_err(io_error)
+    }
+
+    async fn truncate(&self, path_segments: Vec<String>, size: u64) -> Result<()> {
+        self.fs
+            .call(move |fs| {
+                async move {
+                    let mut content = fs.read_file(&path_segments).await?;
+                    content.resize(size as usize, 0);
+                    fs.write_file(&path_segments, content).await
+                }
+                .boxed_local()
+            })
+            .await
+            .map_err(io_error)
+    }
+}
+
+#[async_trait]
+impl Filesystem for Wnfs9p {
+    type Fid = WnfsFid;
+
+    async fn rversion(&self, msize: u32, version: &str) -> Result<FCall> {
+        let version = if version == P9_PROTO_2000L {
+            version
+        } else {
+            "unknown"
+        };
+        Ok(FCall::Rversion {
+            msize,
+            version: version.to_string(),
+        })
+    }
+
+    async fn rattach(
+        &self,
+        fid: &Fid<Self::Fid>,
+        _afid: Option<&Fid<Self::Fid>>,
+        _uname: &str,
+        _aname: &str,
+        _n_uname: u32,
+    ) -> Result<FCall> {
+        trace!("attach");
+        fid.aux.set_path(vec![]);
+        let qid = self.qid(vec![]).await?;
+        Ok(FCall::Rattach { qid })
+    }
+
+    async fn rwalk(
+        &self,
+        fid: &Fid<Self::Fid>,
+        newfid: &Fid<Self::Fid>,
+        wnames: &[String],
+    ) -> Result<FCall> {
+        trace!("walk {:?} {wnames:?}", fid.aux.path());
+        let mut path = fid.aux.path();
+        let mut wqids = vec![];
+        for (i, name) in wnames.iter().enumerate() {
+            if name == ".." {
+                path.pop();
+            } else {
+                path.push(name.clone());
+            }
+            match self.qid(path.clone()).await {
+                Ok(qid) => wqids.push(qid),
+                // Only the first element has to exist, otherwise the walk is partial.
+                Err(err) if i == 0 => return Err(err),
+                Err(_) => return Ok(FCall::Rwalk { wqids }),
+            }
+        }
+        newfid.aux.set_path(path);
+        Ok(FCall::Rwalk { wqids })
+    }
+
+    async fn rgetattr(&self, fid: &Fid<Self::Fid>, _req_mask: GetattrMask) -> Result<FCall> {
+        let path = fid.aux.path();
+        trace!("getattr {path:?}");
+        let entry = self.stat(path.clone()).await?;
+        Ok(FCall::Rgetattr {
+            valid: GetattrMask::BASIC,
+            qid: to_qid(&path, &entry),
+            stat: to_stat(&entry),
+        })
+    }
+
+    async fn rsetattr(
+        &self,
+        fid: &Fid<Self::Fid>,
+        valid: SetattrMask,
+        stat: &SetAttr,
+    ) -> Result<FCall> {
+        let path = fid.aux.path();
+        trace!("setattr {path:?} {valid:?}");
+        // Only truncation is applied, other attributes are not persisted here.
+        if valid.contains(SetattrMask::SIZE) {
+            self.truncate(path, stat.size).await?;
+        }
+        Ok(FCall::Rsetattr)
+    }
+
+    async fn rlopen(&self, fid: &Fid<Self::Fid>, flags: u32) -> Result<FCall> {
+        let path = fid.aux.path();
+        trace!("lopen {path:?} {flags:#o}");
+        let entry = self.stat(path.clone()).await?;
+        if flags & libc::O_TRUNC as u32 != 0 && entry.kind == EntryKind::File {
+            self.truncate(path.clone(), 0).await?;
+        }
+        Ok(FCall::Rlopen {
+            qid: to_qid(&path, &entry),
+            iounit: 0,
+        })
+    }
+
+    async fn rlcreate(
+        &self,
+        fid: &Fid<Self::Fid>,
+        name: &str,
+        _flags: u32,
+        _mode: u32,
+        _gid: u32,
+    ) -> Result<FCall> {
+        let mut path = fid.aux.path();
+        path.push(name.to_string());
+        trace!("lcreate {path:?}");
+        if self.stat(path.clone()).await.is_ok() {
+            return Err(Error::No(errno::EEXIST));
+        }
+        let write_path = path.clone();
+        self.fs
+            .call(move |fs| async move { fs.write_file(&write_path, vec![]).await }.boxed_local())
+            .await
+            .map_err(io_error)?;
+        // The fid now refers to the new, open file.
+        let qid = self.qid(path.clone()).await?;
+        fid.aux.set_path(path);
+        Ok(FCall::Rlcreate { qid, iounit: 0 })
+    }
+
+    async fn rread(&self, fid: &Fid<Self::Fid>, offset: u64, count: u32) -> Result<FCall> {
+        let path = fid.aux.path();
+        trace!("read {path:?} offset {offset} count {count}");
+        let data = self
+            .fs
+            .call(move |fs| {
+                async move {
+                    fs.read_file_at(&path, offset as usize, count as usize)
+                        .await
+                }
+                .boxed_local()
+            })
+            .await
+            .map_err(io_error)?;
+        Ok(FCall::Rread { data: Data(data) })
+    }
+
+    async fn rwrite(&self, fid: &Fid<Self::Fid>, offset: u64, data: &Data) -> Result<FCall> {
+        let path = fid.aux.path();
+        trace!("write {path:?} offset {offset} len {}", data.0.len());
+        let count = data.0.len() as u32;
+        self.write_at(path, offset, data.0.clone()).await?;
+        Ok(FCall::Rwrite { count })
+    }
+
+    async fn rreaddir(&self, fid: &Fid<Self::Fid>, offset: u64, count: u32) -> Result<FCall> {
+        let path = fid.aux.path();
+        trace!("readdir {path:?} offset {offset}");
+        let list_path = path.clone();
+        let entries = self
+            .fs
+            .call(move |fs| async move { fs.ls_entries(&list_path).await }.boxed_local())
+            .await
+            .map_err(io_error)?;
+        // Offsets are positions in the listing, the listing resumes after the last returned one.
+        let mut dirents = DirEntryData::new();
+        let mut size = 0;
+        for (i, entry) in entries.into_iter().enumerate().skip(offset as usize) {
+            let mut entry_path = path.clone();
+            entry_path.push(entry.name.clone());
+            let dirent = P9DirEntry {
+                qid: to_qid(&entry_path, &entry),
+                offset: i as u64 + 1,
+                typ: match entry.kind {
+                    EntryKind::Dir => DT_DIR,
+                    EntryKind::File => DT_REG,
+                },
+                name: entry.name,
+            };
+            // qid[13] offset[8] type[1] name[s]
+            let dirent_size = 13 + 8 + 1 + 2 + dirent.name.len() as u32;
+            if size + dirent_size > count {
+                break;
+            }
+            size += dirent_size;
+            dirents.push(dirent);
+        }
+        Ok(FCall::Rreaddir { data: dirents })
+    }
+
+    async fn rmkdir(
+        &self,
+        dfid: &Fid<Self::Fid>,
+        name: &str,
+        _mode: u32,
+        _gid: u32,
+    ) -> Result<FCall> {
+        let mut path = dfid.aux.path();
+        path.push(name.to_string());
+        trace!("mkdir {path:?}");
+        if self.stat(path.clone()).await.is_ok() {
+            return Err(Error::No(errno::EEXIST));
+        }
+        let mkdir_path = path.clone();
+        self.fs
+            .call(move |fs| async move { fs.mkdir(&mkdir_path).await }.boxed_local())
+            .await
+            .map_err(io_error)?;
+        let qid = self.qid(path).await?;
+        Ok(FCall::Rmkdir { qid })
+    }
+
+    async fn runlinkat(&self, dirfid: &Fid<Self::Fid>, name: &str, _flags: u32) -> Result<FCall> {
+        let mut path = dirfid.aux.path();
+        path.push(name.to_string());
+        trace!("unlinkat {path:?}");
+        self.stat(path.clone()).await?;
+        self.fs
+            .call(move |fs| async move { fs.rm(&path).await }.boxed_local())
+            .await
+            .map_err(io_error)?;
+        Ok(FCall::Runlinkat)
+    }
+
+    async fn rfsync(&self, _fid: &Fid<Self::Fid>) -> Result<FCall> {
+        // Every write is flushed already.
+        Ok(FCall::Rfsync)
+    }
+
+    async fn rclunk(&self, _fid: &Fid<Self::Fid>) -> Result<FCall> {
+        Ok(FCall::Rclunk)
+    }
+
+    async fn rstatfs(&self, _fid: &Fid<Self::Fid>) -> Result<FCall> {
+        Ok(FCall::Rstatfs {
+            statfs: Statfs {
+                typ: V9FS_MAGIC,
+                bsize: BLOCK_SIZE,
+                blocks: 0,
+                bfree: 0,
+                bavail: 0,
+                files: 0,
+                ffree: 0,
+                fsid: 0,
+                namelen: 255,
+            },
+        })
+    }
+}
+
+fn to_qid(path_segments: &[String], entry: &DirEntry) -> Qid {
+    let typ = match entry.kind {
+        EntryKind::Dir => QidType::DIR,
+        EntryKind::File => QidType::FILE,
+    };
+    // The version changes with the content, so guests revalidate their caches.
+    let version = entry
+        .modified
+        .map(|modified| modified.timestamp() as u32)
+        .unwrap_or(0);
+    Qid {
+        typ,
+        version,
+        path: path_id(path_segments),
+    }
+}
+
+fn to_stat(entry: &DirEntry) -> Stat {
+    let (file_type, perm, nlink) = match entry.kind {
+        EntryKind::File => (libc::S_IFREG, 0o644, 1),
+        EntryKind::Dir => (libc::S_IFDIR, 0o755, 2),
+    };
+    let mtime = to_time(entry.modified);
+    Stat {
+        mode: file_type as u32 | entry.mode.unwrap_or(perm),
+        uid: 0,
+        gid: 0,
+        nlink,
+        rdev: 0,
+        size: entry.size,
+        blksize: BLOCK_SIZE as u64,
+        blocks: (entry.size + 511) / 512,
+        atime: mtime,
+        mtime,
+        ctime: mtime,
+    }
+}
+
+fn to_time(time: Option<chrono::DateTime<chrono::Utc>>) -> Time {
+    let time = time.map(std::time::SystemTime::from).unwrap_or(UNIX_EPOCH);
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    Time {
+        sec: since_epoch.as_secs(),
+        nsec: since_epoch.subsec_nanos() as u64,
+    }
+}
+
+fn io_error(err: anyhow::Error) -> Error {
+    debug!("9p error: {err}");
+    Error::No(errno::EIO)
+}
@@ -0,0 +1,62 @@
+//! Passphrase protection for root records.
+
+use argon2::Argon2;
+use chacha20poly1305::aead::Aead;
+use chacha20poly1305::{Key, KeyInit, XChaCha20Poly1305, XNonce};
+use rand::RngCore;
+
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 24;
+
+/// Symmetric key derived from a passphrase with Argon2id.
+pub(crate) struct PassphraseKey {
+    salt: Vec<u8>,
+    key: Key,
+}
+
+impl PassphraseKey {
+    /// Derive a key from a passphrase with a new random salt.
+    pub fn generate(passphrase: &str) -> anyhow::Result<Self> {
+        let mut salt = vec![0u8; SALT_LEN];
+        rand::rngs::OsRng.fill_bytes(&mut salt);
+        Self::derive(passphrase, &salt)
+    }
+
+    /// Derive a key from a passphrase and the salt stored alongside the sealed data.
+    pub fn derive(passphrase: &str, salt: &[u8]) -> anyhow::Result<Self> {
+        let mut key = Key::default();
+        Argon2::default()
+            .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+            .map_err(|err| anyhow::anyhow!("Failed to derive key from passphrase: {err}"))?;
+        Ok(Self {
+            salt: salt.to_vec(),
+            key,
+        })
+    }
+
+    pub fn salt(&self) -> &[u8] {
+        &self.salt
+    }
+
+    /// Encrypt with a random nonce, which is prepended to the ciphertext.
+    pub fn seal(&self, plaintext: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let mut nonce = XNonce::default();
+        rand::rngs::OsRng.fill_bytes(&mut nonce);
+        let ciphertext = XChaCha20Poly1305::new(&self.key)
+            .encrypt(&nonce, plaintext)
+            .map_err(|_| anyhow::anyhow!("Failed to encrypt with passphrase"))?;
+        let mut sealed = nonce.to_vec();
+        sealed.extend(ciphertext);
+        Ok(sealed)
+    }
+
+    pub fn open(&self, sealed: &[u8]) -> anyhow::Result<Vec<u8>> {
+        if sealed.len() < NONCE_LEN {
+            anyhow::bail!("Sealed payload too short");
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        XChaCha20Poly1305::new(&self.key)
+            .decrypt(XNonce::from_slice(nonce), ciphertext)
+            .map_err(|_| anyhow::anyhow!("Wrong passphrase"))
+    }
+}
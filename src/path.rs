@@ -0,0 +1,215 @@
+//! Paths within a filesystem, shared by the CLI, [`crate::fs`] and the servers.
+//!
+//! Paths are always relative to the root of the filesystem, with or without a leading slash.
+//! When parsing, empty segments and `.` are skipped, and `..` goes up one directory (staying
+//! at the root, like `/..` on the host). A [`WnfsPath`] dereferences to its segments, so it
+//! can be passed wherever a `&[String]` is expected.
+
+use std::borrow::Borrow;
+use std::fmt;
+use std::ops::Deref;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::FsError;
+
+/// Longest name of a file or directory in bytes, the limit of most host filesystems.
+pub const MAX_NAME_LEN: usize = 255;
+
+/// A normalized path within a filesystem, displayed as `/a/b`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct WnfsPath(Vec<String>);
+
+impl WnfsPath {
+    /// The root directory.
+    pub fn root() -> Self {
+        Self::default()
+    }
+
+    /// A path of names that are already known to be valid, e.g. names read from a directory.
+    pub fn from_segments(segments: Vec<String>) -> Self {
+        Self(segments)
+    }
+
+    /// Parse a path from the root.
+    pub fn parse(path: &str) -> anyhow::Result<Self> {
+        Self::root().resolve(path)
+    }
+
+    /// Parse a path relative to this one, or from the root if it starts with a slash.
+    pub fn resolve(&self, path: &str) -> anyhow::Result<Self> {
+        let mut segments = if path.starts_with('/') {
+            vec![]
+        } else {
+            self.0.clone()
+        };
+        for segment in path.split('/') {
+            match segment {
+                "" | "." => {}
+                ".." => {
+                    segments.pop();
+                }
+                name => {
+                    check_name(name).map_err(|err| {
+                        let kind = FsError::find(&err).unwrap_or(FsError::InvalidName);
+                        anyhow::Error::new(kind).context(format!("Invalid path {path}: {err}"))
+                    })?;
+                    segments.push(name.to_string());
+                }
+            }
+        }
+        Ok(Self(segments))
+    }
+
+    /// The path of an entry in this directory.
+    ///
+    /// `name` is not checked, use [`check_name`] for names that do not come from a directory.
+    pub fn join(&self, name: impl Into<String>) -> Self {
+        let mut segments = self.0.clone();
+        segments.push(name.into());
+        Self(segments)
+    }
+
+    /// The directory that contains this path, `None` for the root.
+    pub fn parent(&self) -> Option<Self> {
+        let (_name, parent) = self.0.split_last()?;
+        Some(Self(parent.to_vec()))
+    }
+
+    /// The last segment, `None` for the root.
+    pub fn file_name(&self) -> Option<&str> {
+        self.0.last().map(String::as_str)
+    }
+
+    pub fn is_root(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn segments(&self) -> &[String] {
+        &self.0
+    }
+
+    pub fn into_segments(self) -> Vec<String> {
+        self.0
+    }
+}
+
+impl Deref for WnfsPath {
+    type Target = [String];
+
+    fn deref(&self) -> &[String] {
+        &self.0
+    }
+}
+
+/// To look up paths in maps by segments.
+impl Borrow<[String]> for WnfsPath {
+    fn borrow(&self) -> &[String] {
+        &self.0
+    }
+}
+
+impl From<&[String]> for WnfsPath {
+    fn from(segments: &[String]) -> Self {
+        Self(segments.to_vec())
+    }
+}
+
+impl FromStr for WnfsPath {
+    type Err = anyhow::Error;
+
+    fn from_str(path: &str) -> anyhow::Result<Self> {
+        Self::parse(path)
+    }
+}
+
+impl fmt::Display for WnfsPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.0.is_empty() {
+            return f.write_str("/");
+        }
+        for segment in &self.0 {
+            write!(f, "/{segment}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Check that a name can be stored in a directory.
+///
+/// Fails with [`FsError::NameTooLong`] or [`FsError::InvalidName`].
+pub fn check_name(name: &str) -> anyhow::Result<()> {
+    let invalid = |message: String| Err(anyhow::Error::new(FsError::InvalidName).context(message));
+    if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+        return invalid(format!("{name:?} is not a valid name"));
+    }
+    if name.contains('\0') {
+        return invalid(format!("{name:?} contains a NUL character"));
+    }
+    if name.len() > MAX_NAME_LEN {
+        return Err(anyhow::Error::new(FsError::NameTooLong)
+            .context(format!("{name:?} is longer than {MAX_NAME_LEN} bytes")));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(path: &str) -> Vec<String> {
+        WnfsPath::parse(path).unwrap().into_segments()
+    }
+
+    fn kind(result: anyhow::Result<impl fmt::Debug>) -> Option<FsError> {
+        FsError::find(&result.unwrap_err())
+    }
+
+    #[test]
+    fn skips_empty_and_dot_segments() {
+        assert_eq!(parse("/a//./b/"), ["a", "b"]);
+        assert_eq!(parse("a/b"), ["a", "b"]);
+        assert!(WnfsPath::parse("/./").unwrap().is_root());
+    }
+
+    #[test]
+    fn parent_segments_stay_at_the_root() {
+        assert_eq!(parse("a/b/../c"), ["a", "c"]);
+        assert_eq!(parse("/../../a"), ["a"]);
+        assert!(WnfsPath::parse("a/../..").unwrap().is_root());
+    }
+
+    #[test]
+    fn resolves_relative_and_absolute_paths() {
+        let cwd = WnfsPath::parse("/a/b").unwrap();
+        assert_eq!(cwd.resolve("c").unwrap().to_string(), "/a/b/c");
+        assert_eq!(cwd.resolve("../c").unwrap().to_string(), "/a/c");
+        assert_eq!(cwd.resolve("/c").unwrap().to_string(), "/c");
+    }
+
+    #[test]
+    fn rejects_invalid_names() {
+        assert_eq!(kind(WnfsPath::parse("a/b\0c")), Some(FsError::InvalidName));
+        assert_eq!(kind(check_name("")), Some(FsError::InvalidName));
+        assert_eq!(kind(check_name("..")), Some(FsError::InvalidName));
+        assert_eq!(kind(check_name("a/b")), Some(FsError::InvalidName));
+    }
+
+    #[test]
+    fn rejects_long_names() {
+        let longest = "x".repeat(MAX_NAME_LEN);
+        assert!(check_name(&longest).is_ok());
+        let too_long = "x".repeat(MAX_NAME_LEN + 1);
+        assert_eq!(kind(check_name(&too_long)), Some(FsError::NameTooLong));
+        let path = format!("/a/{too_long}");
+        assert_eq!(kind(WnfsPath::parse(&path)), Some(FsError::NameTooLong));
+    }
+
+    #[test]
+    fn displays_from_the_root() {
+        assert_eq!(WnfsPath::root().to_string(), "/");
+        assert_eq!(WnfsPath::root().join("a").join("b").to_string(), "/a/b");
+    }
+}
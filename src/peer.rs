@@ -0,0 +1,242 @@
+//! Direct block transfer between devices over iroh.
+//!
+//! [`advertise`] serves the blocks of a filesystem at its current root and returns a
+//! [`Ticket`] that names the node and the root. [`PeerRemote`] connects to such a node and
+//! acts as a read-only [`RemoteStore`], so [`crate::sync::pull`] can replicate the filesystem
+//! without any server in between. iroh takes care of hole punching and relaying.
+//!
+//! Each request is a bidirectional QUIC stream carrying a JSON [`Request`], answered with the
+//! raw response bytes.
+
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use iroh_net::defaults::default_derp_map;
+use iroh_net::key::{PublicKey, SecretKey};
+use iroh_net::magic_endpoint::accept_conn;
+use iroh_net::MagicEndpoint;
+use libipld::cid::multibase::{self, Base};
+use libipld::Cid;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+
+use crate::fs::private_root_alias;
+use crate::remote::RemoteStore;
+use crate::SqliteBlockStore;
+
+const ALPN: &[u8] = b"wnfs-fuse/blocks/0";
+/// Largest request a node accepts.
+const MAX_REQUEST_SIZE: usize = 1024;
+/// Largest response a peer accepts.
+const MAX_RESPONSE_SIZE: usize = 64 * 1024 * 1024;
+
+/// Everything needed to connect to an advertising node.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Ticket {
+    pub node: PublicKey,
+    pub derp_region: Option<u16>,
+    pub addrs: Vec<SocketAddr>,
+    /// CID of the root record that is advertised.
+    pub root: Cid,
+}
+
+impl Ticket {
+    pub fn encode(&self) -> anyhow::Result<String> {
+        let bytes = serde_json::to_vec(self)?;
+        Ok(multibase::encode(Base::Base32Lower, bytes))
+    }
+
+    pub fn decode(ticket: &str) -> anyhow::Result<Self> {
+        let (_base, bytes) = multibase::decode(ticket.trim())?;
+        Ok(serde_json::from_slice(&bytes)?)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+enum Request {
+    Root,
+    Has(Cid),
+    Get(Cid),
+}
+
+/// A node that serves the blocks of a filesystem.
+pub struct Advertisement {
+    endpoint: MagicEndpoint,
+    store: SqliteBlockStore,
+    root: Cid,
+    cids: Arc<HashSet<Cid>>,
+}
+
+/// Start serving the blocks reachable from the current root of a named filesystem.
+///
+/// Later changes to the filesystem are not advertised. Call [`Advertisement::run`] to answer
+/// requests.
+pub async fn advertise(store: SqliteBlockStore, name: &str) -> anyhow::Result<Advertisement> {
+    let root = store
+        .resolve_alias(&private_root_alias(name))
+        .await?
+        .ok_or_else(|| anyhow::anyhow!("Filesystem {name} does not exist"))?;
+    let cids = store.dag_cids(&root).await?.into_iter().collect();
+    let endpoint = MagicEndpoint::builder()
+        .secret_key(SecretKey::generate())
+        .alpns(vec![ALPN.to_vec()])
+        .derp_map(Some(default_derp_map()))
+        .bind(0)
+        .await?;
+    Ok(Advertisement {
+        endpoint,
+        store,
+        root,
+        cids: Arc::new(cids),
+    })
+}
+
+impl Advertisement {
+    /// The ticket that peers pass to [`PeerRemote::connect`].
+    pub async fn ticket(&self) -> anyhow::Result<Ticket> {
+        let addrs = self
+            .endpoint
+            .local_endpoints()
+            .await?
+            .into_iter()
+            .map(|endpoint| endpoint.addr)
+            .collect();
+        Ok(Ticket {
+            node: self.endpoint.peer_id(),
+            derp_region: self.endpoint.my_derp().await,
+            addrs,
+            root: self.root,
+        })
+    }
+
+    /// Answer requests until the endpoint is closed.
+    pub async fn run(self) -> anyhow::Result<()> {
+        while let Some(connecting) = self.endpoint.accept().await {
+            let store = self.store.clone();
+            let cids = self.cids.clone();
+            let root = self.root;
+            tokio::spawn(async move {
+                let (peer, _alpn, connection) = match accept_conn(connecting).await {
+                    Ok(conn) => conn,
+                    Err(err) => {
+                        warn!("failed to accept connection: {err}");
+                        return;
+                    }
+                };
+                debug!("peer {peer} connected");
+                while let Ok((send, recv)) = connection.accept_bi().await {
+                    let store = store.clone();
+                    let cids = cids.clone();
+                    tokio::spawn(async move {
+                        if let Err(err) = answer(&store, root, &cids, send, recv).await {
+                            debug!("request from {peer} failed: {err}");
+                        }
+                    });
+                }
+                debug!("peer {peer} disconnected");
+            });
+        }
+        Ok(())
+    }
+}
+
+async fn answer(
+    store: &SqliteBlockStore,
+    root: Cid,
+    cids: &HashSet<Cid>,
+    mut send: quinn::SendStream,
+    mut recv: quinn::RecvStream,
+) -> anyhow::Result<()> {
+    let request = recv.read_to_end(MAX_REQUEST_SIZE).await?;
+    let response = match respond(store, root, cids, &request).await {
+        Ok(response) => response,
+        Err(err) => {
+            // Resetting the stream makes the request fail on the other side.
+            let _ = send.reset(1u32.into());
+            return Err(err);
+        }
+    };
+    send.write_all(&response).await?;
+    send.finish().await?;
+    Ok(())
+}
+
+async fn respond(
+    store: &SqliteBlockStore,
+    root: Cid,
+    cids: &HashSet<Cid>,
+    request: &[u8],
+) -> anyhow::Result<Vec<u8>> {
+    let response = match serde_json::from_slice(request)? {
+        Request::Root => root.to_bytes(),
+        Request::Has(cid) => vec![cids.contains(&cid) as u8],
+        // Only blocks of the advertised filesystem are served, not the whole store.
+        Request::Get(cid) if cids.contains(&cid) => store
+            .get_block_if_exists(&cid)
+            .await?
+            .ok_or_else(|| anyhow::anyhow!("Block {cid} not found"))?,
+        Request::Get(cid) => anyhow::bail!("Block {cid} is not advertised"),
+    };
+    Ok(response)
+}
+
+/// A read-only remote store backed by an advertising node.
+///
+/// All filesystem names resolve to the root from the ticket.
+pub struct PeerRemote {
+    _endpoint: MagicEndpoint,
+    connection: quinn::Connection,
+}
+
+impl PeerRemote {
+    pub async fn connect(ticket: &Ticket) -> anyhow::Result<Self> {
+        let endpoint = MagicEndpoint::builder()
+            .secret_key(SecretKey::generate())
+            .derp_map(Some(default_derp_map()))
+            .bind(0)
+            .await?;
+        let connection = endpoint
+            .connect(ticket.node, ALPN, ticket.derp_region, &ticket.addrs)
+            .await?;
+        Ok(Self {
+            _endpoint: endpoint,
+            connection,
+        })
+    }
+
+    async fn request(&self, request: &Request) -> anyhow::Result<Vec<u8>> {
+        let (mut send, mut recv) = self.connection.open_bi().await?;
+        send.write_all(&serde_json::to_vec(request)?).await?;
+        send.finish().await?;
+        let response = recv.read_to_end(MAX_RESPONSE_SIZE).await?;
+        Ok(response)
+    }
+}
+
+#[async_trait]
+impl RemoteStore for PeerRemote {
+    async fn has_block(&self, cid: &Cid) -> anyhow::Result<bool> {
+        Ok(self.request(&Request::Has(*cid)).await? == [1])
+    }
+
+    async fn get_block(&self, cid: &Cid) -> anyhow::Result<Vec<u8>> {
+        self.request(&Request::Get(*cid))
+            .await
+            .map_err(|err| anyhow::anyhow!("Failed to get block {cid} from peer: {err}"))
+    }
+
+    async fn put_block(&self, _cid: &Cid, _bytes: &[u8]) -> anyhow::Result<()> {
+        anyhow::bail!("Peers are read-only")
+    }
+
+    async fn get_root(&self, _name: &str) -> anyhow::Result<Option<Cid>> {
+        let bytes = self.request(&Request::Root).await?;
+        Ok(Some(Cid::try_from(bytes)?))
+    }
+
+    async fn put_root(&self, _name: &str, _cid: &Cid) -> anyhow::Result<()> {
+        anyhow::bail!("Peers are read-only")
+    }
+}
@@ -0,0 +1,118 @@
+//! Offsite durability through IPFS pinning services.
+//!
+//! [`pin`] exports the current root of a filesystem as a CAR file (see [`crate::car`]) and
+//! uploads it to a service that pins the DAG of the first root of uploaded CAR files, such as
+//! web3.storage or nft.storage. The pinned root is recorded in the store under an alias, which
+//! also keeps its blocks from being garbage collected.
+//!
+//! Services are configured in the config file:
+//!
+//! ```toml
+//! [pinning.web3]
+//! service = "web3.storage"
+//! token = "eyJhbGciOi..."
+//! ```
+
+use libipld::Cid;
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+use crate::car::{self, CarStats};
+use crate::fs::private_root_alias;
+use crate::SqliteBlockStore;
+
+const PIN_PREFIX: &str = "pin:";
+
+/// Settings for a pinning service.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PinConfig {
+    /// `web3.storage` or `nft.storage`. Other services that accept CAR uploads can be used
+    /// by setting `url` instead.
+    pub service: Option<String>,
+    /// Upload endpoint, overriding the default of the service.
+    pub url: Option<String>,
+    /// API token, sent as bearer token.
+    pub token: String,
+}
+
+impl PinConfig {
+    fn url(&self) -> anyhow::Result<&str> {
+        if let Some(url) = &self.url {
+            return Ok(url);
+        }
+        match self.service.as_deref() {
+            Some("web3.storage") => Ok("https://api.web3.storage/car"),
+            Some("nft.storage") => Ok("https://api.nft.storage/upload"),
+            Some(service) => anyhow::bail!("Unknown pinning service {service}, set a url"),
+            None => anyhow::bail!("Pinning service needs a service or url"),
+        }
+    }
+}
+
+/// A root that was pinned with [`pin`].
+#[derive(Debug, Clone, Serialize)]
+pub struct Pin {
+    pub service: String,
+    pub root: Cid,
+}
+
+#[derive(Debug, Deserialize)]
+struct UploadResponse {
+    cid: String,
+}
+
+fn pin_prefix(name: &str) -> String {
+    format!("{PIN_PREFIX}{name}:")
+}
+
+/// Upload the current root of a named filesystem to a pinning service.
+///
+/// `service` is the name under which the pin is recorded.
+pub async fn pin(
+    store: &SqliteBlockStore,
+    name: &str,
+    service: &str,
+    config: &PinConfig,
+) -> anyhow::Result<(Cid, CarStats)> {
+    let url = config.url()?;
+    let root = store
+        .resolve_alias(&private_root_alias(name))
+        .await?
+        .ok_or_else(|| anyhow::anyhow!("Filesystem {name} does not exist"))?;
+    let mut car = vec![];
+    let stats = car::export(store, name, None, &mut car).await?;
+    debug!("upload {root} ({} bytes) to {url}", car.len());
+    let response = reqwest::Client::new()
+        .post(url)
+        .bearer_auth(&config.token)
+        .header("Content-Type", "application/vnd.ipld.car")
+        .body(car)
+        .send()
+        .await?;
+    let status = response.status();
+    if !status.is_success() {
+        let message = response.text().await.unwrap_or_default();
+        anyhow::bail!("Pinning service returned {status}: {message}");
+    }
+    let response: UploadResponse = response.json().await?;
+    let pinned = Cid::try_from(response.cid.as_str())?;
+    // Services may report the CID in another version or base, so only compare the hashes.
+    if pinned.hash() != root.hash() {
+        anyhow::bail!("Pinning service pinned {pinned} instead of {root}");
+    }
+    let alias = format!("{}{service}", pin_prefix(name));
+    store.alias(&alias, Some(&root)).await?;
+    Ok((root, stats))
+}
+
+/// List the last pinned root of a named filesystem per service.
+pub async fn list(store: &SqliteBlockStore, name: &str) -> anyhow::Result<Vec<Pin>> {
+    let pins = store
+        .aliases_with_prefix(&pin_prefix(name))
+        .await?
+        .into_iter()
+        .map(|(service, root)| Pin { service, root })
+        .collect();
+    Ok(pins)
+}
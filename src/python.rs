@@ -0,0 +1,174 @@
+//! Python bindings, built with `maturin develop` (see pyproject.toml).
+//!
+//! ```python
+//! import wnfs_experiments
+//! fs = wnfs_experiments.Wnfs.open("blocks.db", "demo")
+//! fs.write("notes/hello.txt", b"hello")
+//! print(fs.ls("notes"))
+//! ```
+//!
+//! All methods block until the operation is done, but release the GIL while waiting, so they
+//! can be run from a thread pool (e.g. `asyncio.to_thread`).
+
+use std::path::PathBuf;
+
+use futures::future::LocalBoxFuture;
+use futures::FutureExt;
+use pyo3::exceptions::{PyFileNotFoundError, PyIOError};
+use pyo3::prelude::*;
+use pyo3::types::{PyBytes, PyDict};
+
+use crate::error::FsError;
+use crate::fs::{EntryKind, Wnfs};
+use crate::handle::WnfsHandle;
+use crate::mirror;
+
+/// An open filesystem.
+#[pyclass(name = "Wnfs")]
+pub struct PyWnfs {
+    runtime: tokio::runtime::Runtime,
+    fs: WnfsHandle,
+}
+
+#[pymethods]
+impl PyWnfs {
+    /// Open an existing filesystem. `passphrase` is required for protected filesystems.
+    #[staticmethod]
+    #[pyo3(signature = (db_path, name, passphrase=None))]
+    fn open(
+        py: Python<'_>,
+        db_path: String,
+        name: String,
+        passphrase: Option<String>,
+    ) -> PyResult<Self> {
+        let runtime = tokio::runtime::Runtime::new()?;
+        let fs = py.allow_threads(|| {
+            runtime.block_on(WnfsHandle::spawn(move || async move {
+                Wnfs::open_with_passphrase(db_path, name, passphrase.as_deref()).await
+            }))
+        });
+        Ok(Self {
+            runtime,
+            fs: fs.map_err(to_py_err)?,
+        })
+    }
+
+    /// List a directory as dicts with `name`, `kind`, `size` and `modified` (a Unix timestamp
+    /// or `None`).
+    #[pyo3(signature = (path=String::new()))]
+    fn ls(&self, py: Python<'_>, path: String) -> PyResult<Vec<PyObject>> {
+        let path_segments = into_segments(&path);
+        let entries = self.call(py, move |fs| {
+            async move {
+                if fs.get_node_or_root(&path_segments).await?.is_none() {
+                    anyhow::bail!(FsError::NotFound);
+                }
+                fs.ls_entries(&path_segments).await
+            }
+            .boxed_local()
+        })?;
+        entries
+            .into_iter()
+            .map(|entry| {
+                let dict = PyDict::new(py);
+                dict.set_item("name", entry.name)?;
+                let kind = match entry.kind {
+                    EntryKind::File => "file",
+                    EntryKind::Dir => "dir",
+                };
+                dict.set_item("kind", kind)?;
+                dict.set_item("size", entry.size)?;
+                dict.set_item("modified", entry.modified.map(|time| time.timestamp()))?;
+                Ok(dict.into())
+            })
+            .collect()
+    }
+
+    /// Read a file, or `length` bytes of it starting at `offset`.
+    #[pyo3(signature = (path, offset=0, length=None))]
+    fn read(
+        &self,
+        py: Python<'_>,
+        path: String,
+        offset: usize,
+        length: Option<usize>,
+    ) -> PyResult<Py<PyBytes>> {
+        let path_segments = into_segments(&path);
+        let data = self.call(py, move |fs| {
+            async move {
+                match length {
+                    Some(length) => fs.read_file_at(&path_segments, offset, length).await,
+                    None if offset == 0 => fs.read_file(&path_segments).await,
+                    None => fs.read_file_at(&path_segments, offset, usize::MAX).await,
+                }
+            }
+            .boxed_local()
+        })?;
+        Ok(PyBytes::new(py, &data).into())
+    }
+
+    /// Replace the content of a file, creating it and its parent directories if needed.
+    fn write(&self, py: Python<'_>, path: String, data: Vec<u8>) -> PyResult<()> {
+        let path_segments = into_segments(&path);
+        self.call(py, move |fs| {
+            async move { fs.write_file(&path_segments, data).await }.boxed_local()
+        })
+    }
+
+    /// Recursively copy a host directory into the filesystem as a single revision.
+    ///
+    /// Returns the number of imported files.
+    #[pyo3(signature = (host_dir, path=String::new()))]
+    fn import_dir(&self, py: Python<'_>, host_dir: PathBuf, path: String) -> PyResult<u64> {
+        let path_segments = into_segments(&path);
+        self.call(py, move |fs| {
+            async move {
+                let stats = mirror::import_dir(fs, &host_dir, &path_segments, &|_| {}).await?;
+                fs.flush().await?;
+                Ok(stats.files)
+            }
+            .boxed_local()
+        })
+    }
+
+    /// Record the current state under a snapshot name.
+    fn snapshot(&self, py: Python<'_>, name: String) -> PyResult<()> {
+        self.call(py, move |fs| {
+            async move { fs.create_snapshot(&name).await }.boxed_local()
+        })
+    }
+}
+
+impl PyWnfs {
+    /// Run an operation on the filesystem without holding the GIL.
+    fn call<T, F>(&self, py: Python<'_>, f: F) -> PyResult<T>
+    where
+        F: for<'a> FnOnce(&'a mut Wnfs) -> LocalBoxFuture<'a, anyhow::Result<T>> + Send + 'static,
+        T: Send + 'static,
+    {
+        py.allow_threads(|| self.runtime.block_on(self.fs.call(f)))
+            .map_err(to_py_err)
+    }
+}
+
+fn to_py_err(err: anyhow::Error) -> PyErr {
+    let message = format!("{err:#}");
+    if FsError::find(&err) == Some(FsError::NotFound) {
+        PyFileNotFoundError::new_err(message)
+    } else {
+        PyIOError::new_err(message)
+    }
+}
+
+fn into_segments(path: &str) -> Vec<String> {
+    path.split('/')
+        .filter(|segment| !segment.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+#[pymodule]
+fn wnfs_experiments(_py: Python<'_>, module: &PyModule) -> PyResult<()> {
+    module.add_class::<PyWnfs>()?;
+    Ok(())
+}
@@ -0,0 +1,384 @@
+//! Sync between a user's own devices over QUIC.
+//!
+//! `daemon --quic <port>` runs a [`QuicServer`] that answers the [`QuicRemote`] of `sync quic`,
+//! so that a laptop and a desktop can run [`crate::sync::sync_with`] against each other's
+//! store. Both sides identify with a persistent iroh node key stored in the block store. The
+//! client dials the node id it was given, which the TLS handshake verifies, and the server
+//! only accepts the node ids in `authorized_nodes` of the config file.
+//!
+//! Each request is a bidirectional stream with a JSON [`Request`] frame (see
+//! [`crate::ssh`] for the framing). Syncs negotiate in batches: the roots are announced,
+//! [`Request::Missing`] asks which blocks the other side lacks, and only those are
+//! transferred, many per request. A sync without changes is a single round trip.
+//!
+//! A [`QuicServer::read_only`] server, as run by `seed`, only answers requests that read.
+
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use iroh_net::defaults::default_derp_map;
+use iroh_net::key::{PublicKey, SecretKey};
+use iroh_net::magic_endpoint::accept_conn;
+use iroh_net::MagicEndpoint;
+use libipld::{Cid, IpldCodec};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info, warn};
+
+use crate::fs::private_root_alias;
+use crate::remote::RemoteStore;
+use crate::ssh::{read_frame, write_frame, STATUS_ERR, STATUS_OK};
+use crate::{SqliteBlockStore, Store};
+
+const ALPN: &[u8] = b"wnfs-fuse/sync/0";
+const NODE_KEY_ALIAS: &str = "quic-key";
+/// Most blocks in one request.
+const MAX_BATCH: usize = 1024;
+
+#[derive(Debug, Serialize, Deserialize)]
+enum Request {
+    /// The root of a filesystem, empty if there is none.
+    Root(String),
+    PutRoot(String, Cid),
+    /// Which of the blocks the node does not have, as a JSON list.
+    Missing(Vec<Cid>),
+    /// Answered with a frame per block after the status.
+    Get(Vec<Cid>),
+    /// Followed by a frame per block.
+    Put(Vec<Cid>),
+}
+
+/// Load the node key of a store, or create and persist a new one.
+pub async fn node_key(store: &mut SqliteBlockStore) -> anyhow::Result<SecretKey> {
+    if let Some(bytes) = store.get_from_alias(NODE_KEY_ALIAS).await? {
+        let bytes: [u8; 32] = bytes
+            .try_into()
+            .map_err(|_| anyhow::anyhow!("Invalid node key in store"))?;
+        return Ok(SecretKey::from_bytes(&bytes));
+    }
+    let key = SecretKey::generate();
+    store
+        .put_with_alias(NODE_KEY_ALIAS, key.to_bytes().to_vec(), IpldCodec::Raw)
+        .await?;
+    debug!("created node key");
+    Ok(key)
+}
+
+/// A node that answers sync requests of authorized nodes.
+pub struct QuicServer {
+    endpoint: MagicEndpoint,
+    store: SqliteBlockStore,
+    authorized: Arc<HashSet<PublicKey>>,
+    read_only: bool,
+}
+
+impl QuicServer {
+    /// Listen on a UDP port (0 for any) with the node key of the store.
+    ///
+    /// `authorized` are the node ids, as printed by `sync node-id`, that may connect.
+    pub async fn bind(
+        mut store: SqliteBlockStore,
+        port: u16,
+        authorized: &[String],
+    ) -> anyhow::Result<Self> {
+        if authorized.is_empty() {
+            anyhow::bail!("No authorized_nodes in the config file");
+        }
+        let authorized = authorized
+            .iter()
+            .map(|node| {
+                node.parse()
+                    .map_err(|err| anyhow::anyhow!("Invalid node id {node}: {err}"))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        let endpoint = MagicEndpoint::builder()
+            .secret_key(node_key(&mut store).await?)
+            .alpns(vec![ALPN.to_vec()])
+            .derp_map(Some(default_derp_map()))
+            .bind(port)
+            .await?;
+        Ok(Self {
+            endpoint,
+            store,
+            authorized: Arc::new(authorized),
+            read_only: false,
+        })
+    }
+
+    /// Reject requests that write blocks or roots.
+    pub fn read_only(mut self) -> Self {
+        self.read_only = true;
+        self
+    }
+
+    pub fn node_id(&self) -> PublicKey {
+        self.endpoint.peer_id()
+    }
+
+    /// Addresses that other devices on the network can dial.
+    pub async fn local_addrs(&self) -> anyhow::Result<Vec<SocketAddr>> {
+        let addrs = self
+            .endpoint
+            .local_endpoints()
+            .await?
+            .into_iter()
+            .map(|endpoint| endpoint.addr)
+            .collect();
+        Ok(addrs)
+    }
+
+    /// Answer requests until the endpoint is closed.
+    pub async fn run(self) -> anyhow::Result<()> {
+        while let Some(connecting) = self.endpoint.accept().await {
+            let store = self.store.clone();
+            let authorized = self.authorized.clone();
+            let read_only = self.read_only;
+            tokio::spawn(async move {
+                let (node, _alpn, connection) = match accept_conn(connecting).await {
+                    Ok(conn) => conn,
+                    Err(err) => {
+                        warn!("failed to accept connection: {err}");
+                        return;
+                    }
+                };
+                if !authorized.contains(&node) {
+                    warn!("rejected unauthorized node {node}");
+                    connection.close(1u32.into(), b"unauthorized");
+                    return;
+                }
+                info!("node {node} connected");
+                while let Ok((send, recv)) = connection.accept_bi().await {
+                    let store = store.clone();
+                    tokio::spawn(async move {
+                        if let Err(err) = answer(&store, read_only, send, recv).await {
+                            debug!("request from {node} failed: {err}");
+                        }
+                    });
+                }
+                debug!("node {node} disconnected");
+            });
+        }
+        Ok(())
+    }
+}
+
+async fn answer(
+    store: &SqliteBlockStore,
+    read_only: bool,
+    mut send: quinn::SendStream,
+    mut recv: quinn::RecvStream,
+) -> anyhow::Result<()> {
+    let request = read_frame(&mut recv)
+        .await?
+        .ok_or_else(|| anyhow::anyhow!("Empty request"))?;
+    let request: Request = serde_json::from_slice(&request)?;
+    let mut blocks = vec![];
+    if let Request::Put(cids) = &request {
+        for _ in 0..cids.len().min(MAX_BATCH) {
+            let block = read_frame(&mut recv)
+                .await?
+                .ok_or_else(|| anyhow::anyhow!("Missing block"))?;
+            blocks.push(block);
+        }
+    }
+    match respond(store, read_only, request, blocks).await {
+        Ok((mut response, blocks)) => {
+            response.insert(0, STATUS_OK);
+            write_frame(&mut send, &response).await?;
+            for block in blocks {
+                write_frame(&mut send, &block).await?;
+            }
+        }
+        Err(err) => {
+            let mut response = vec![STATUS_ERR];
+            response.extend_from_slice(err.to_string().as_bytes());
+            write_frame(&mut send, &response).await?;
+        }
+    }
+    send.finish().await?;
+    Ok(())
+}
+
+async fn respond(
+    store: &SqliteBlockStore,
+    read_only: bool,
+    request: Request,
+    blocks: Vec<Vec<u8>>,
+) -> anyhow::Result<(Vec<u8>, Vec<Vec<u8>>)> {
+    if read_only && matches!(request, Request::PutRoot(..) | Request::Put(_)) {
+        anyhow::bail!("This node is read-only");
+    }
+    if let Request::Missing(cids) | Request::Get(cids) | Request::Put(cids) = &request {
+        if cids.len() > MAX_BATCH {
+            anyhow::bail!("Batch of {} blocks is too large", cids.len());
+        }
+    }
+    let response = match request {
+        Request::Root(name) => match store.resolve_alias(&private_root_alias(&name)).await? {
+            Some(cid) => cid.to_bytes(),
+            None => vec![],
+        },
+        Request::PutRoot(name, cid) => {
+            store.alias(&private_root_alias(&name), Some(&cid)).await?;
+            vec![]
+        }
+        Request::Missing(cids) => {
+            let mut missing = vec![];
+            for cid in cids {
+                if store.get_block_if_exists(&cid).await?.is_none() {
+                    missing.push(cid);
+                }
+            }
+            serde_json::to_vec(&missing)?
+        }
+        Request::Get(cids) => {
+            let mut blocks = vec![];
+            for cid in cids {
+                let block = store
+                    .get_block_if_exists(&cid)
+                    .await?
+                    .ok_or_else(|| anyhow::anyhow!("Block {cid} not found"))?;
+                blocks.push(block);
+            }
+            return Ok((vec![], blocks));
+        }
+        Request::Put(cids) => {
+            for (cid, block) in cids.iter().zip(blocks) {
+                store.put_block_with_cid(cid, block).await?;
+            }
+            vec![]
+        }
+    };
+    Ok((response, vec![]))
+}
+
+/// The store of another device running `daemon --quic`.
+pub struct QuicRemote {
+    _endpoint: MagicEndpoint,
+    connection: quinn::Connection,
+}
+
+impl QuicRemote {
+    /// Connect to a node given as `<node id>@<host>:<port>`, authenticating with the node key
+    /// of the store.
+    pub async fn connect(store: &mut SqliteBlockStore, addr: &str) -> anyhow::Result<Self> {
+        let (node, host) = addr
+            .split_once('@')
+            .ok_or_else(|| anyhow::anyhow!("Expected <node id>@<host>:<port>"))?;
+        let node: PublicKey = node
+            .parse()
+            .map_err(|err| anyhow::anyhow!("Invalid node id {node}: {err}"))?;
+        let addrs: Vec<_> = tokio::net::lookup_host(host).await?.collect();
+        let endpoint = MagicEndpoint::builder()
+            .secret_key(node_key(store).await?)
+            .derp_map(Some(default_derp_map()))
+            .bind(0)
+            .await?;
+        debug!("connecting to {node} at {addrs:?}");
+        let connection = endpoint.connect(node, ALPN, None, &addrs).await?;
+        Ok(Self {
+            _endpoint: endpoint,
+            connection,
+        })
+    }
+
+    /// Send a request with blocks, returning the response and `expect_blocks` blocks.
+    async fn request(
+        &self,
+        request: &Request,
+        blocks: &[&[u8]],
+        expect_blocks: usize,
+    ) -> anyhow::Result<(Vec<u8>, Vec<Vec<u8>>)> {
+        let (mut send, mut recv) = self.connection.open_bi().await?;
+        write_frame(&mut send, &serde_json::to_vec(request)?).await?;
+        for block in blocks {
+            write_frame(&mut send, block).await?;
+        }
+        send.finish().await?;
+        let mut response = read_frame(&mut recv)
+            .await?
+            .ok_or_else(|| anyhow::anyhow!("Connection closed by the remote"))?;
+        match response.first() {
+            Some(&STATUS_OK) => {
+                response.remove(0);
+            }
+            Some(&STATUS_ERR) => {
+                anyhow::bail!("Remote error: {}", String::from_utf8_lossy(&response[1..]))
+            }
+            _ => anyhow::bail!("Invalid response from the remote"),
+        }
+        let mut blocks = vec![];
+        for _ in 0..expect_blocks {
+            let block = read_frame(&mut recv)
+                .await?
+                .ok_or_else(|| anyhow::anyhow!("Connection closed by the remote"))?;
+            blocks.push(block);
+        }
+        Ok((response, blocks))
+    }
+}
+
+#[async_trait]
+impl RemoteStore for QuicRemote {
+    async fn has_block(&self, cid: &Cid) -> anyhow::Result<bool> {
+        Ok(self.missing_blocks(&[*cid]).await?.is_empty())
+    }
+
+    async fn get_block(&self, cid: &Cid) -> anyhow::Result<Vec<u8>> {
+        let mut blocks = self.get_blocks(&[*cid]).await?;
+        Ok(blocks.remove(0))
+    }
+
+    async fn put_block(&self, cid: &Cid, bytes: &[u8]) -> anyhow::Result<()> {
+        self.request(&Request::Put(vec![*cid]), &[bytes], 0).await?;
+        Ok(())
+    }
+
+    async fn get_root(&self, name: &str) -> anyhow::Result<Option<Cid>> {
+        let (bytes, _) = self
+            .request(&Request::Root(name.to_string()), &[], 0)
+            .await?;
+        if bytes.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(Cid::try_from(bytes)?))
+    }
+
+    async fn put_root(&self, name: &str, cid: &Cid) -> anyhow::Result<()> {
+        self.request(&Request::PutRoot(name.to_string(), *cid), &[], 0)
+            .await?;
+        Ok(())
+    }
+
+    async fn missing_blocks(&self, cids: &[Cid]) -> anyhow::Result<Vec<Cid>> {
+        let mut missing = vec![];
+        for batch in cids.chunks(MAX_BATCH) {
+            let (response, _) = self
+                .request(&Request::Missing(batch.to_vec()), &[], 0)
+                .await?;
+            missing.extend(serde_json::from_slice::<Vec<Cid>>(&response)?);
+        }
+        Ok(missing)
+    }
+
+    async fn get_blocks(&self, cids: &[Cid]) -> anyhow::Result<Vec<Vec<u8>>> {
+        let mut blocks = vec![];
+        for batch in cids.chunks(MAX_BATCH) {
+            let (_, batch) = self
+                .request(&Request::Get(batch.to_vec()), &[], batch.len())
+                .await?;
+            blocks.extend(batch);
+        }
+        Ok(blocks)
+    }
+
+    async fn put_blocks(&self, blocks: &[(Cid, Vec<u8>)]) -> anyhow::Result<()> {
+        for batch in blocks.chunks(MAX_BATCH) {
+            let cids = batch.iter().map(|(cid, _)| *cid).collect();
+            let bytes: Vec<_> = batch.iter().map(|(_, bytes)| bytes.as_slice()).collect();
+            self.request(&Request::Put(cids), &bytes, 0).await?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,208 @@
+//! Remote stores for backup and sync.
+//!
+//! A remote store holds blocks by CID and named root records, laid out as
+//! `<prefix>/blocks/<cid>` and `<prefix>/roots/<name>` (the latter containing the CID of the
+//! root record block).
+
+use std::path::PathBuf;
+
+use async_trait::async_trait;
+use libipld::Cid;
+use s3::creds::Credentials;
+use s3::{Bucket, Region};
+
+use crate::http_store::HttpBlockStore;
+
+#[async_trait]
+pub trait RemoteStore: Send + Sync {
+    async fn has_block(&self, cid: &Cid) -> anyhow::Result<bool>;
+    async fn get_block(&self, cid: &Cid) -> anyhow::Result<Vec<u8>>;
+    async fn put_block(&self, cid: &Cid, bytes: &[u8]) -> anyhow::Result<()>;
+    /// Get the CID of the root record of a named filesystem.
+    async fn get_root(&self, name: &str) -> anyhow::Result<Option<Cid>>;
+    async fn put_root(&self, name: &str, cid: &Cid) -> anyhow::Result<()>;
+
+    /// The CIDs of a batch that the remote does not have.
+    ///
+    /// Remotes with a round trip per request should answer this in one.
+    async fn missing_blocks(&self, cids: &[Cid]) -> anyhow::Result<Vec<Cid>> {
+        let mut missing = vec![];
+        for cid in cids {
+            if !self.has_block(cid).await? {
+                missing.push(*cid);
+            }
+        }
+        Ok(missing)
+    }
+
+    /// Get a batch of blocks, in the order of `cids`.
+    async fn get_blocks(&self, cids: &[Cid]) -> anyhow::Result<Vec<Vec<u8>>> {
+        let mut blocks = vec![];
+        for cid in cids {
+            blocks.push(self.get_block(cid).await?);
+        }
+        Ok(blocks)
+    }
+
+    async fn put_blocks(&self, blocks: &[(Cid, Vec<u8>)]) -> anyhow::Result<()> {
+        for (cid, bytes) in blocks {
+            self.put_block(cid, bytes).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Open a remote store from a URL.
+///
+/// Supported are `s3://bucket/prefix` (configured through the usual `AWS_*` environment
+/// variables, plus `AWS_ENDPOINT` for S3-compatible services), `http(s)://host:port` for
+/// `serve blocks` (with the token in `WNFS_BLOCKS_TOKEN`) and `file:///path` or plain paths
+/// for a directory.
+pub fn open_remote(url: &str) -> anyhow::Result<Box<dyn RemoteStore>> {
+    if url.starts_with("http://") || url.starts_with("https://") {
+        Ok(Box::new(HttpBlockStore::from_env(url)?))
+    } else if let Some(rest) = url.strip_prefix("s3://") {
+        let (bucket, prefix) = rest.split_once('/').unwrap_or((rest, ""));
+        Ok(Box::new(S3Remote::new(bucket, prefix)?))
+    } else {
+        let path = url.strip_prefix("file://").unwrap_or(url);
+        Ok(Box::new(DirRemote::new(path)))
+    }
+}
+
+/// A remote store in a local (or network mounted) directory.
+pub struct DirRemote {
+    path: PathBuf,
+}
+
+impl DirRemote {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    fn block_path(&self, cid: &Cid) -> PathBuf {
+        self.path.join("blocks").join(cid.to_string())
+    }
+
+    fn root_path(&self, name: &str) -> PathBuf {
+        self.path.join("roots").join(name)
+    }
+}
+
+#[async_trait]
+impl RemoteStore for DirRemote {
+    async fn has_block(&self, cid: &Cid) -> anyhow::Result<bool> {
+        Ok(tokio::fs::try_exists(self.block_path(cid)).await?)
+    }
+
+    async fn get_block(&self, cid: &Cid) -> anyhow::Result<Vec<u8>> {
+        Ok(tokio::fs::read(self.block_path(cid)).await?)
+    }
+
+    async fn put_block(&self, cid: &Cid, bytes: &[u8]) -> anyhow::Result<()> {
+        write_atomic(self.block_path(cid), bytes).await
+    }
+
+    async fn get_root(&self, name: &str) -> anyhow::Result<Option<Cid>> {
+        match tokio::fs::read_to_string(self.root_path(name)).await {
+            Ok(cid) => Ok(Some(Cid::try_from(cid.trim())?)),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    async fn put_root(&self, name: &str, cid: &Cid) -> anyhow::Result<()> {
+        write_atomic(self.root_path(name), cid.to_string().as_bytes()).await
+    }
+}
+
+async fn write_atomic(path: PathBuf, bytes: &[u8]) -> anyhow::Result<()> {
+    let dir = path.parent().expect("path has a parent");
+    tokio::fs::create_dir_all(dir).await?;
+    let tmp_path = path.with_extension("tmp");
+    tokio::fs::write(&tmp_path, bytes).await?;
+    tokio::fs::rename(&tmp_path, &path).await?;
+    Ok(())
+}
+
+/// A remote store in an S3 bucket.
+pub struct S3Remote {
+    bucket: Bucket,
+    prefix: String,
+}
+
+impl S3Remote {
+    pub fn new(bucket: &str, prefix: &str) -> anyhow::Result<Self> {
+        let region = match std::env::var("AWS_ENDPOINT") {
+            Ok(endpoint) => Region::Custom {
+                region: std::env::var("AWS_REGION").unwrap_or_else(|_| "us-east-1".into()),
+                endpoint,
+            },
+            Err(_) => Region::from_default_env()?,
+        };
+        let credentials = Credentials::default()?;
+        let bucket = Bucket::new(bucket, region, credentials)?.with_path_style();
+        Ok(Self {
+            bucket,
+            prefix: prefix.trim_matches('/').to_string(),
+        })
+    }
+
+    fn key(&self, kind: &str, name: &str) -> String {
+        if self.prefix.is_empty() {
+            format!("{kind}/{name}")
+        } else {
+            format!("{}/{kind}/{name}", self.prefix)
+        }
+    }
+
+    async fn get(&self, key: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        let response = self.bucket.get_object(key).await?;
+        match response.status_code() {
+            200 => Ok(Some(response.bytes().to_vec())),
+            404 => Ok(None),
+            status => anyhow::bail!("Failed to get {key}: HTTP {status}"),
+        }
+    }
+
+    async fn put(&self, key: &str, bytes: &[u8]) -> anyhow::Result<()> {
+        let response = self.bucket.put_object(key, bytes).await?;
+        match response.status_code() {
+            200..=299 => Ok(()),
+            status => anyhow::bail!("Failed to put {key}: HTTP {status}"),
+        }
+    }
+}
+
+#[async_trait]
+impl RemoteStore for S3Remote {
+    async fn has_block(&self, cid: &Cid) -> anyhow::Result<bool> {
+        let (_head, status) = self
+            .bucket
+            .head_object(self.key("blocks", &cid.to_string()))
+            .await?;
+        Ok(status == 200)
+    }
+
+    async fn get_block(&self, cid: &Cid) -> anyhow::Result<Vec<u8>> {
+        self.get(&self.key("blocks", &cid.to_string()))
+            .await?
+            .ok_or_else(|| anyhow::anyhow!("Block not found on remote: {cid}"))
+    }
+
+    async fn put_block(&self, cid: &Cid, bytes: &[u8]) -> anyhow::Result<()> {
+        self.put(&self.key("blocks", &cid.to_string()), bytes).await
+    }
+
+    async fn get_root(&self, name: &str) -> anyhow::Result<Option<Cid>> {
+        match self.get(&self.key("roots", name)).await? {
+            Some(bytes) => Ok(Some(Cid::try_from(String::from_utf8(bytes)?.trim())?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn put_root(&self, name: &str, cid: &Cid) -> anyhow::Result<()> {
+        self.put(&self.key("roots", name), cid.to_string().as_bytes())
+            .await
+    }
+}
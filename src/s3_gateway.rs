@@ -0,0 +1,1330 @@
+//! S3-compatible gateway, so that S3 tools (rclone, restic, duplicity, the AWS CLI) can store
+//! their data in a filesystem.
+//!
+//! Top-level directories are buckets, and keys are the paths below them, split at `/`. Only
+//! path-style requests (`http://host/bucket/key`) are supported, e.g. with
+//! `force_path_style = true` for rclone.
+//!
+//! * ListBuckets, CreateBucket, DeleteBucket, HeadBucket and GetBucketLocation
+//! * ListObjects and ListObjectsV2, with prefixes, delimiters and pagination
+//! * GetObject (with a single `Range`), HeadObject, PutObject, CopyObject, DeleteObject and
+//!   DeleteObjects
+//! * Multipart uploads: create, upload part, complete and abort. Parts are kept below the
+//!   hidden top-level directory `.s3-multipart` until the upload is completed, so abandoned
+//!   uploads take space until they are aborted.
+//!
+//! Requests are authenticated with AWS signature version 4 in the `Authorization` header.
+//! Presigned URLs are not supported. The signature covers the body if the client sends its
+//! hash or signed `aws-chunked` uploads, but not with `UNSIGNED-PAYLOAD`, so serve with TLS
+//! for clients that do that.
+//!
+//! ETags change with the size and modification time of objects but are not MD5 sums, and
+//! `Content-MD5` headers are not checked. ETags have the form of multipart ETags, which
+//! clients do not compare with their own sums.
+
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+use axum::body::{Body, Bytes};
+use axum::extract::State;
+use axum::http::header::{
+    ACCEPT_RANGES, AUTHORIZATION, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, ETAG, LAST_MODIFIED,
+    LOCATION, RANGE, TRANSFER_ENCODING,
+};
+use axum::http::{HeaderMap, HeaderValue, Method, Request, StatusCode, Uri};
+use axum::response::{IntoResponse, Response};
+use axum::Router;
+use axum_server::tls_rustls::RustlsConfig;
+use chrono::{DateTime, Utc};
+use futures::FutureExt;
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use tracing::debug;
+
+use crate::fs::{DirEntry, EntryKind, Wnfs};
+use crate::handle::WnfsHandle;
+
+/// Top-level directory with the parts of unfinished multipart uploads, hidden from clients.
+pub const MULTIPART_DIR: &str = ".s3-multipart";
+/// File in the directory of a multipart upload with the path of the object.
+const UPLOAD_TARGET: &str = "target";
+/// Largest request body. Bodies are held in memory.
+const MAX_BODY_SIZE: u64 = 256 * 1024 * 1024;
+/// Largest difference in seconds between the signing time and the clock of the server.
+const MAX_CLOCK_SKEW: i64 = 15 * 60;
+/// More than the content of a block, to find the exact size of a file from its last block.
+const SIZE_PROBE: u64 = 256 * 1024;
+const MAX_KEYS: usize = 1000;
+const MAX_PARTS: u32 = 10_000;
+const XMLNS: &str = "http://s3.amazonaws.com/doc/2006-03-01/";
+const EMPTY_SHA256: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+/// Subresources of buckets and objects that are not supported.
+const UNSUPPORTED: &[&str] = &[
+    "acl",
+    "cors",
+    "encryption",
+    "lifecycle",
+    "logging",
+    "object-lock",
+    "policy",
+    "replication",
+    "retention",
+    "tagging",
+    "torrent",
+    "versioning",
+    "versions",
+    "website",
+];
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Options for the S3 gateway.
+#[derive(Debug, Clone)]
+pub struct S3Config {
+    /// Access key id that clients have to sign requests with.
+    pub access_key: String,
+    /// Secret access key that clients have to sign requests with.
+    pub secret_key: String,
+    /// Certificate and private key files (PEM) to serve HTTPS instead of HTTP.
+    pub tls: Option<(PathBuf, PathBuf)>,
+}
+
+#[derive(Clone)]
+struct S3State {
+    fs: WnfsHandle,
+    access_key: String,
+    secret_key: String,
+}
+
+/// Serve the gateway until the server fails.
+pub async fn serve(fs: WnfsHandle, addr: SocketAddr, config: S3Config) -> anyhow::Result<()> {
+    let app = router(fs, &config.access_key, &config.secret_key);
+    debug!("serve S3 on {addr}");
+    match config.tls {
+        Some((cert, key)) => {
+            let tls = RustlsConfig::from_pem_file(cert, key).await?;
+            axum_server::bind_rustls(addr, tls)
+                .serve(app.into_make_service())
+                .await?;
+        }
+        None => {
+            axum::Server::bind(&addr)
+                .serve(app.into_make_service())
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+/// Create the gateway routes, to be served by an application's own server.
+///
+/// S3 dispatches on query parameters and headers as much as on paths, so all requests go
+/// through a single handler.
+pub fn router(fs: WnfsHandle, access_key: &str, secret_key: &str) -> Router {
+    let state = S3State {
+        fs,
+        access_key: access_key.to_string(),
+        secret_key: secret_key.to_string(),
+    };
+    Router::new().fallback(handle).with_state(state)
+}
+
+async fn handle(State(state): State<S3State>, request: Request<Body>) -> Result<Response, Error> {
+    let (parts, body) = request.into_parts();
+    let query = Query::parse(parts.uri.query().unwrap_or_default())?;
+    let signature = authenticate(&state, &parts.method, &parts.uri, &parts.headers, &query)?;
+    let body = read_body(&parts.headers, body, &signature).await?;
+    if let Some(name) = UNSUPPORTED.iter().find(|name| query.has(name)) {
+        return Err(not_implemented(&format!("?{name}")));
+    }
+
+    let path = percent_decode(parts.uri.path())?;
+    let path = path.trim_start_matches('/');
+    let (bucket, key) = match path.split_once('/') {
+        Some((bucket, key)) => (bucket, Some(key).filter(|key| !key.is_empty())),
+        None => (path, None),
+    };
+    debug!("{} bucket {bucket:?} key {key:?}", parts.method);
+    let fs = &state.fs;
+    let headers = &parts.headers;
+    let method = parts.method;
+    match (bucket, key) {
+        ("", _) if method == Method::GET => list_buckets(fs).await,
+        ("", _) => Err(method_not_allowed()),
+        (bucket, None) => match method {
+            Method::GET if query.has("location") => get_bucket_location(fs, bucket).await,
+            Method::GET if query.has("uploads") => Err(not_implemented("ListMultipartUploads")),
+            Method::GET => list_objects(fs, bucket, &query).await,
+            Method::HEAD => {
+                check_bucket(fs, bucket).await?;
+                Ok(StatusCode::OK.into_response())
+            }
+            Method::PUT => create_bucket(fs, bucket).await,
+            Method::DELETE => delete_bucket(fs, bucket).await,
+            Method::POST if query.has("delete") => delete_objects(fs, bucket, &body).await,
+            _ => Err(method_not_allowed()),
+        },
+        (bucket, Some(key)) => {
+            let path = object_path(bucket, key)?;
+            match method {
+                Method::GET if query.has("uploadId") => Err(not_implemented("ListParts")),
+                Method::GET => get_object(fs, path, headers, false).await,
+                Method::HEAD => get_object(fs, path, headers, true).await,
+                Method::PUT if query.has("partNumber") => {
+                    if headers.contains_key("x-amz-copy-source") {
+                        return Err(not_implemented("UploadPartCopy"));
+                    }
+                    upload_part(fs, path, &query, body).await
+                }
+                Method::PUT if headers.contains_key("x-amz-copy-source") => {
+                    copy_object(fs, path, headers).await
+                }
+                Method::PUT => put_object(fs, path, key.ends_with('/'), body).await,
+                Method::POST if query.has("uploads") => create_upload(fs, path).await,
+                Method::POST if query.has("uploadId") => {
+                    complete_upload(fs, path, &query, &body).await
+                }
+                Method::DELETE if query.has("uploadId") => abort_upload(fs, path, &query).await,
+                Method::DELETE => {
+                    check_bucket(fs, bucket).await?;
+                    remove_object(fs, path, key.ends_with('/')).await?;
+                    Ok(StatusCode::NO_CONTENT.into_response())
+                }
+                _ => Err(method_not_allowed()),
+            }
+        }
+    }
+}
+
+async fn list_buckets(fs: &WnfsHandle) -> Result<Response, Error> {
+    let entries = fs
+        .call(|fs| async move { fs.ls_entries(&[]).await }.boxed_local())
+        .await?;
+    let mut xml = format!(
+        "<ListAllMyBucketsResult xmlns=\"{XMLNS}\"><Owner><ID>wnfs</ID>\
+         <DisplayName>wnfs</DisplayName></Owner><Buckets>"
+    );
+    for entry in entries {
+        if entry.kind != EntryKind::Dir || entry.name == MULTIPART_DIR {
+            continue;
+        }
+        let created = entry.created.or(entry.modified).unwrap_or_else(Utc::now);
+        xml.push_str(&format!(
+            "<Bucket><Name>{}</Name><CreationDate>{}</CreationDate></Bucket>",
+            escape(&entry.name),
+            iso_time(created)
+        ));
+    }
+    xml.push_str("</Buckets></ListAllMyBucketsResult>");
+    Ok(xml_response(StatusCode::OK, xml))
+}
+
+async fn get_bucket_location(fs: &WnfsHandle, bucket: &str) -> Result<Response, Error> {
+    check_bucket(fs, bucket).await?;
+    // Empty for the default region, us-east-1.
+    let xml = format!("<LocationConstraint xmlns=\"{XMLNS}\"></LocationConstraint>");
+    Ok(xml_response(StatusCode::OK, xml))
+}
+
+async fn create_bucket(fs: &WnfsHandle, bucket: &str) -> Result<Response, Error> {
+    if !is_valid_bucket_name(bucket) {
+        return Err(Error::new(
+            StatusCode::BAD_REQUEST,
+            "InvalidBucketName",
+            "The specified bucket is not valid",
+        ));
+    }
+    if stat(fs, vec![bucket.to_string()]).await?.is_some() {
+        return Err(Error::new(
+            StatusCode::CONFLICT,
+            "BucketAlreadyOwnedByYou",
+            "The bucket already exists",
+        ));
+    }
+    let path = vec![bucket.to_string()];
+    fs.call(move |fs| async move { fs.mkdir(&path).await }.boxed_local())
+        .await?;
+    Ok((StatusCode::OK, [(LOCATION, format!("/{bucket}"))]).into_response())
+}
+
+async fn delete_bucket(fs: &WnfsHandle, bucket: &str) -> Result<Response, Error> {
+    check_bucket(fs, bucket).await?;
+    let path = vec![bucket.to_string()];
+    let removed = fs
+        .call(move |fs| {
+            async move {
+                if !fs.ls_entries(&path).await?.is_empty() {
+                    return Ok(false);
+                }
+                fs.rm(&path).await?;
+                Ok(true)
+            }
+            .boxed_local()
+        })
+        .await?;
+    if !removed {
+        return Err(Error::new(
+            StatusCode::CONFLICT,
+            "BucketNotEmpty",
+            "The bucket you tried to delete is not empty",
+        ));
+    }
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+/// ListObjects and ListObjectsV2 (with `list-type=2`).
+///
+/// Keys are listed from the deepest directory that contains all keys with the prefix, and
+/// with `/` as delimiter only that directory is read.
+async fn list_objects(fs: &WnfsHandle, bucket: &str, query: &Query) -> Result<Response, Error> {
+    check_bucket(fs, bucket).await?;
+    let v2 = query.get("list-type") == Some("2");
+    let prefix = query.get("prefix").unwrap_or_default();
+    let delimiter = query
+        .get("delimiter")
+        .filter(|delimiter| !delimiter.is_empty());
+    let max_keys = match query.get("max-keys") {
+        Some(max_keys) => max_keys
+            .parse::<usize>()
+            .map_err(|_| invalid_argument("Invalid max-keys"))?
+            .min(MAX_KEYS),
+        None => MAX_KEYS,
+    };
+    let marker = if v2 {
+        query
+            .get("continuation-token")
+            .or_else(|| query.get("start-after"))
+    } else {
+        query.get("marker")
+    };
+    let marker = marker.unwrap_or_default();
+    let url_encoded = query.get("encoding-type") == Some("url");
+
+    let mut base = vec![bucket.to_string()];
+    if let Some((dir, _)) = prefix.rsplit_once('/') {
+        base.extend(into_segments(dir));
+    }
+    let shallow = delimiter == Some("/");
+    let found: Vec<(Vec<String>, DirEntry)> = fs
+        .call(move |fs| {
+            async move {
+                if !matches!(fs.stat(&base).await?, Some(entry) if entry.kind == EntryKind::Dir) {
+                    return Ok(vec![]);
+                }
+                let mut found = vec![];
+                if shallow {
+                    for entry in fs.ls_entries(&base).await? {
+                        let mut path = base.clone();
+                        path.push(entry.name.clone());
+                        found.push((path, entry));
+                    }
+                } else {
+                    fs.walk(&base, &mut |path, entry| {
+                        found.push((path.to_vec(), entry.clone()));
+                        Ok(())
+                    })
+                    .await?;
+                }
+                Ok(found)
+            }
+            .boxed_local()
+        })
+        .await?;
+
+    // Objects and common prefixes (without an entry) by key.
+    let mut listed = BTreeMap::new();
+    for (path, entry) in found {
+        let mut key = path[1..].join("/");
+        if entry.kind == EntryKind::Dir {
+            // Only listed as common prefixes.
+            if !shallow {
+                continue;
+            }
+            key.push('/');
+        }
+        if !key.starts_with(prefix) {
+            continue;
+        }
+        let common_prefix = delimiter.and_then(|delimiter| {
+            let end = key[prefix.len()..].find(delimiter)? + prefix.len() + delimiter.len();
+            Some(key[..end].to_string())
+        });
+        match common_prefix {
+            Some(common_prefix) => listed.insert(common_prefix, None),
+            None => listed.insert(key, Some((path, entry))),
+        };
+    }
+
+    let mut page = vec![];
+    let mut truncated = false;
+    for (key, object) in listed {
+        if key.as_str() <= marker || (object.is_none() && marker.starts_with(&key)) {
+            continue;
+        }
+        if page.len() == max_keys {
+            truncated = true;
+            break;
+        }
+        page.push((key, object));
+    }
+    let objects: Vec<_> = page
+        .iter()
+        .filter_map(|(_, object)| object.as_ref())
+        .map(|(path, entry)| (path.clone(), entry.size))
+        .collect();
+    let sizes = fs
+        .call(move |fs| {
+            async move {
+                let mut sizes = vec![];
+                for (path, upper_bound) in objects {
+                    sizes.push(file_size(fs, &path, upper_bound).await?);
+                }
+                Ok(sizes)
+            }
+            .boxed_local()
+        })
+        .await?;
+
+    let encode = |value: &str| {
+        if url_encoded {
+            escape(&uri_encode(value, false))
+        } else {
+            escape(value)
+        }
+    };
+    let mut xml = format!(
+        "<ListBucketResult xmlns=\"{XMLNS}\"><Name>{}</Name><Prefix>{}</Prefix>\
+         <MaxKeys>{max_keys}</MaxKeys><IsTruncated>{truncated}</IsTruncated>",
+        escape(bucket),
+        encode(prefix)
+    );
+    if let Some(delimiter) = delimiter {
+        xml.push_str(&format!("<Delimiter>{}</Delimiter>", encode(delimiter)));
+    }
+    if url_encoded {
+        xml.push_str("<EncodingType>url</EncodingType>");
+    }
+    let next_marker = page
+        .last()
+        .filter(|_| truncated)
+        .map(|(key, _)| key.as_str());
+    if v2 {
+        xml.push_str(&format!("<KeyCount>{}</KeyCount>", page.len()));
+        if let Some(token) = query.get("continuation-token") {
+            xml.push_str(&format!(
+                "<ContinuationToken>{}</ContinuationToken>",
+                escape(token)
+            ));
+        }
+        if let Some(start_after) = query.get("start-after") {
+            xml.push_str(&format!("<StartAfter>{}</StartAfter>", encode(start_after)));
+        }
+        if let Some(next) = next_marker {
+            xml.push_str(&format!(
+                "<NextContinuationToken>{}</NextContinuationToken>",
+                escape(next)
+            ));
+        }
+    } else {
+        xml.push_str(&format!("<Marker>{}</Marker>", encode(marker)));
+        if let Some(next) = next_marker {
+            xml.push_str(&format!("<NextMarker>{}</NextMarker>", encode(next)));
+        }
+    }
+    let mut sizes = sizes.into_iter();
+    let mut common_prefixes = String::new();
+    for (key, object) in &page {
+        match object {
+            Some((path, entry)) => {
+                let size = sizes.next().unwrap_or_default();
+                let modified = entry.modified.unwrap_or_else(Utc::now);
+                xml.push_str(&format!(
+                    "<Contents><Key>{}</Key><LastModified>{}</LastModified><ETag>{}</ETag>\
+                     <Size>{size}</Size><StorageClass>STANDARD</StorageClass></Contents>",
+                    encode(key),
+                    iso_time(modified),
+                    escape(&etag(path, size, entry.modified))
+                ));
+            }
+            None => common_prefixes.push_str(&format!(
+                "<CommonPrefixes><Prefix>{}</Prefix></CommonPrefixes>",
+                encode(key)
+            )),
+        }
+    }
+    xml.push_str(&common_prefixes);
+    xml.push_str("</ListBucketResult>");
+    Ok(xml_response(StatusCode::OK, xml))
+}
+
+/// GetObject, or HeadObject without the content.
+async fn get_object(
+    fs: &WnfsHandle,
+    path: Vec<String>,
+    headers: &HeaderMap,
+    head: bool,
+) -> Result<Response, Error> {
+    let (entry, size) = stat_object(fs, &path).await?;
+    let range = headers
+        .get(RANGE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(parse_range);
+    let (status, start, len) = match range {
+        None => (StatusCode::OK, 0, size),
+        Some(range) => {
+            let (start, end) = match range {
+                (Some(start), end) => (start, end.unwrap_or(u64::MAX).min(size.saturating_sub(1))),
+                (None, Some(suffix)) => (size.saturating_sub(suffix), size.saturating_sub(1)),
+                (None, None) => unreachable!("parse_range requires a start or an end"),
+            };
+            if start >= size || start > end {
+                return Err(Error::new(
+                    StatusCode::RANGE_NOT_SATISFIABLE,
+                    "InvalidRange",
+                    "The requested range is not satisfiable",
+                ));
+            }
+            (StatusCode::PARTIAL_CONTENT, start, end - start + 1)
+        }
+    };
+    let body = if head {
+        vec![]
+    } else {
+        let path = path.clone();
+        let ranged = status == StatusCode::PARTIAL_CONTENT;
+        fs.call(move |fs| {
+            async move {
+                if ranged {
+                    fs.read_file_at(&path, start as usize, len as usize).await
+                } else {
+                    fs.read_file(&path).await
+                }
+            }
+            .boxed_local()
+        })
+        .await?
+    };
+    let modified = entry.modified.unwrap_or_else(Utc::now);
+    let mut response = (status, body).into_response();
+    let response_headers = response.headers_mut();
+    response_headers.insert(CONTENT_LENGTH, HeaderValue::from(len));
+    response_headers.insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static("application/octet-stream"),
+    );
+    response_headers.insert(ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    response_headers.insert(
+        ETAG,
+        HeaderValue::from_str(&etag(&path, size, entry.modified))?,
+    );
+    response_headers.insert(LAST_MODIFIED, HeaderValue::from_str(&http_time(modified))?);
+    if status == StatusCode::PARTIAL_CONTENT {
+        let content_range = format!("bytes {start}-{}/{size}", start + len - 1);
+        response_headers.insert(CONTENT_RANGE, HeaderValue::from_str(&content_range)?);
+    }
+    Ok(response)
+}
+
+/// PutObject. Keys ending in `/` with an empty body create directories.
+async fn put_object(
+    fs: &WnfsHandle,
+    path: Vec<String>,
+    dir: bool,
+    body: Bytes,
+) -> Result<Response, Error> {
+    check_bucket(fs, &path[0]).await?;
+    if dir && body.is_empty() {
+        let dir_path = path.clone();
+        fs.call(move |fs| async move { fs.mkdir(&dir_path).await }.boxed_local())
+            .await?;
+        return Ok((StatusCode::OK, [(ETAG, etag(&path, 0, None))]).into_response());
+    }
+    let (etag, _modified) = fs
+        .call(move |fs| async move { write_object(fs, &path, body.to_vec()).await }.boxed_local())
+        .await?;
+    Ok((StatusCode::OK, [(ETAG, etag)]).into_response())
+}
+
+/// CopyObject, with the source in the `x-amz-copy-source` header as `[/]bucket/key`.
+async fn copy_object(
+    fs: &WnfsHandle,
+    path: Vec<String>,
+    headers: &HeaderMap,
+) -> Result<Response, Error> {
+    let source = header(headers, "x-amz-copy-source").unwrap_or_default();
+    // Versions are not supported, so ?versionId is ignored.
+    let source = source.split('?').next().unwrap_or_default();
+    let source = percent_decode(source)?;
+    let (source_bucket, source_key) = source
+        .trim_start_matches('/')
+        .split_once('/')
+        .ok_or_else(|| invalid_argument("Invalid x-amz-copy-source"))?;
+    let source_path = object_path(source_bucket, source_key)?;
+    stat_object(fs, &source_path).await?;
+    check_bucket(fs, &path[0]).await?;
+    let (etag, modified) = fs
+        .call(move |fs| {
+            async move {
+                let content = fs.read_file(&source_path).await?;
+                write_object(fs, &path, content).await
+            }
+            .boxed_local()
+        })
+        .await?;
+    let xml = format!(
+        "<CopyObjectResult><LastModified>{}</LastModified><ETag>{}</ETag></CopyObjectResult>",
+        iso_time(modified),
+        escape(&etag)
+    );
+    Ok(xml_response(StatusCode::OK, xml))
+}
+
+/// DeleteObjects with a `<Delete>` list of keys.
+async fn delete_objects(fs: &WnfsHandle, bucket: &str, body: &[u8]) -> Result<Response, Error> {
+    check_bucket(fs, bucket).await?;
+    let keys = xml_values(body, "Key")?;
+    if keys.len() > MAX_KEYS {
+        return Err(malformed_xml());
+    }
+    let quiet = xml_values(body, "Quiet")?.first().map(String::as_str) == Some("true");
+    let mut xml = format!("<DeleteResult xmlns=\"{XMLNS}\">");
+    for key in keys {
+        let result = match object_path(bucket, &key) {
+            Ok(path) => remove_object(fs, path, key.ends_with('/'))
+                .await
+                .map_err(Error::from),
+            Err(err) => Err(err),
+        };
+        match result {
+            Ok(()) if quiet => {}
+            Ok(()) => xml.push_str(&format!("<Deleted><Key>{}</Key></Deleted>", escape(&key))),
+            Err(err) => xml.push_str(&format!(
+                "<Error><Key>{}</Key><Code>{}</Code><Message>{}</Message></Error>",
+                escape(&key),
+                err.code,
+                escape(&err.message)
+            )),
+        }
+    }
+    xml.push_str("</DeleteResult>");
+    Ok(xml_response(StatusCode::OK, xml))
+}
+
+/// Remove a file, or an empty directory for keys ending in `/`. Missing objects are not an
+/// error.
+async fn remove_object(fs: &WnfsHandle, path: Vec<String>, dir: bool) -> anyhow::Result<()> {
+    fs.call(move |fs| {
+        async move {
+            let remove = match fs.stat(&path).await? {
+                Some(entry) if entry.kind == EntryKind::File => true,
+                Some(_) if dir => fs.ls_entries(&path).await?.is_empty(),
+                _ => false,
+            };
+            if remove {
+                fs.rm(&path).await?;
+            }
+            Ok(())
+        }
+        .boxed_local()
+    })
+    .await
+}
+
+/// CreateMultipartUpload.
+async fn create_upload(fs: &WnfsHandle, path: Vec<String>) -> Result<Response, Error> {
+    check_bucket(fs, &path[0]).await?;
+    let upload_id: String = rand::random::<[u8; 16]>()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect();
+    let target = vec![
+        MULTIPART_DIR.to_string(),
+        upload_id.clone(),
+        UPLOAD_TARGET.to_string(),
+    ];
+    let xml = format!(
+        "<InitiateMultipartUploadResult xmlns=\"{XMLNS}\"><Bucket>{}</Bucket><Key>{}</Key>\
+         <UploadId>{upload_id}</UploadId></InitiateMultipartUploadResult>",
+        escape(&path[0]),
+        escape(&path[1..].join("/"))
+    );
+    let content = path.join("/").into_bytes();
+    fs.call(move |fs| async move { fs.write_file(&target, content).await }.boxed_local())
+        .await?;
+    Ok(xml_response(StatusCode::OK, xml))
+}
+
+/// UploadPart, which stores the part next to the others of the upload.
+async fn upload_part(
+    fs: &WnfsHandle,
+    path: Vec<String>,
+    query: &Query,
+    body: Bytes,
+) -> Result<Response, Error> {
+    let part_number = query
+        .get("partNumber")
+        .and_then(|number| number.parse::<u32>().ok())
+        .filter(|number| (1..=MAX_PARTS).contains(number))
+        .ok_or_else(|| invalid_argument("Part number must be between 1 and 10000"))?;
+    let mut part_path = upload_dir(fs, &path, query).await?;
+    part_path.push(part_number.to_string());
+    let etag = format!("\"{}\"", &blake3::hash(&body).to_hex()[..32]);
+    fs.call(move |fs| async move { fs.write_file(&part_path, body.to_vec()).await }.boxed_local())
+        .await?;
+    Ok((StatusCode::OK, [(ETAG, etag)]).into_response())
+}
+
+/// CompleteMultipartUpload, which writes the listed parts as the object.
+async fn complete_upload(
+    fs: &WnfsHandle,
+    path: Vec<String>,
+    query: &Query,
+    body: &[u8],
+) -> Result<Response, Error> {
+    let upload = upload_dir(fs, &path, query).await?;
+    let part_numbers = xml_values(body, "PartNumber")?
+        .iter()
+        .map(|number| number.parse::<u32>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| malformed_xml())?;
+    if part_numbers.is_empty() {
+        return Err(malformed_xml());
+    }
+    if part_numbers.windows(2).any(|pair| pair[0] >= pair[1]) {
+        return Err(Error::new(
+            StatusCode::BAD_REQUEST,
+            "InvalidPartOrder",
+            "The list of parts was not in ascending order",
+        ));
+    }
+    let xml = format!(
+        "<CompleteMultipartUploadResult xmlns=\"{XMLNS}\"><Location>/{}</Location>\
+         <Bucket>{}</Bucket><Key>{}</Key>",
+        escape(&path.join("/")),
+        escape(&path[0]),
+        escape(&path[1..].join("/"))
+    );
+    let result = fs
+        .call(move |fs| {
+            async move {
+                let mut content = vec![];
+                for part_number in part_numbers {
+                    let mut part_path = upload.clone();
+                    part_path.push(part_number.to_string());
+                    if fs.stat(&part_path).await?.is_none() {
+                        return Ok(Err(part_number));
+                    }
+                    content.extend(fs.read_file(&part_path).await?);
+                }
+                let written = write_object(fs, &path, content).await?;
+                fs.rm(&upload).await?;
+                Ok(Ok(written))
+            }
+            .boxed_local()
+        })
+        .await?;
+    let (etag, _modified) = result.map_err(|part_number| {
+        Error::new(
+            StatusCode::BAD_REQUEST,
+            "InvalidPart",
+            format!("Part {part_number} was not uploaded"),
+        )
+    })?;
+    let xml = format!(
+        "{xml}<ETag>{}</ETag></CompleteMultipartUploadResult>",
+        escape(&etag)
+    );
+    Ok(xml_response(StatusCode::OK, xml))
+}
+
+/// AbortMultipartUpload, which deletes the uploaded parts.
+async fn abort_upload(
+    fs: &WnfsHandle,
+    path: Vec<String>,
+    query: &Query,
+) -> Result<Response, Error> {
+    let upload = upload_dir(fs, &path, query).await?;
+    fs.call(move |fs| async move { fs.rm(&upload).await }.boxed_local())
+        .await?;
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+/// Directory of the multipart upload in the `uploadId` query parameter, which has to be for
+/// the object at `path`.
+async fn upload_dir(fs: &WnfsHandle, path: &[String], query: &Query) -> Result<Vec<String>, Error> {
+    let upload_id = query.get("uploadId").unwrap_or_default();
+    if upload_id.is_empty() || !upload_id.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        return Err(no_such_upload());
+    }
+    let dir = vec![MULTIPART_DIR.to_string(), upload_id.to_string()];
+    let mut target_path = dir.clone();
+    target_path.push(UPLOAD_TARGET.to_string());
+    let target = fs
+        .call(move |fs| {
+            async move {
+                match fs.stat(&target_path).await? {
+                    Some(_) => Ok(Some(fs.read_file(&target_path).await?)),
+                    None => Ok(None),
+                }
+            }
+            .boxed_local()
+        })
+        .await?;
+    match target {
+        Some(target) if target == path.join("/").as_bytes() => Ok(dir),
+        _ => Err(no_such_upload()),
+    }
+}
+
+/// Write a file and return its ETag and modification time.
+async fn write_object(
+    fs: &mut Wnfs,
+    path: &[String],
+    content: Vec<u8>,
+) -> anyhow::Result<(String, DateTime<Utc>)> {
+    let size = content.len() as u64;
+    fs.write_file(path, content).await?;
+    let modified = fs.stat(path).await?.and_then(|entry| entry.modified);
+    Ok((
+        etag(path, size, modified),
+        modified.unwrap_or_else(Utc::now),
+    ))
+}
+
+/// Exact size of a file. [`DirEntry::size`] is an upper bound, so this reads the last block.
+async fn file_size(fs: &Wnfs, path: &[String], upper_bound: u64) -> anyhow::Result<u64> {
+    let start = upper_bound.saturating_sub(SIZE_PROBE);
+    let tail = fs
+        .read_file_at(path, start as usize, SIZE_PROBE as usize)
+        .await?;
+    Ok(start + tail.len() as u64)
+}
+
+/// The entry and exact size of an object.
+async fn stat_object(fs: &WnfsHandle, path: &[String]) -> Result<(DirEntry, u64), Error> {
+    let file_path = path.to_vec();
+    let found = fs
+        .call(move |fs| {
+            async move {
+                match fs.stat(&file_path).await? {
+                    Some(entry) if entry.kind == EntryKind::File => {
+                        let size = file_size(fs, &file_path, entry.size).await?;
+                        Ok(Some((entry, size)))
+                    }
+                    _ => Ok(None),
+                }
+            }
+            .boxed_local()
+        })
+        .await?;
+    match found {
+        Some(found) => Ok(found),
+        None => {
+            check_bucket(fs, &path[0]).await?;
+            Err(Error::new(
+                StatusCode::NOT_FOUND,
+                "NoSuchKey",
+                "The specified key does not exist",
+            ))
+        }
+    }
+}
+
+async fn check_bucket(fs: &WnfsHandle, bucket: &str) -> Result<(), Error> {
+    if bucket != MULTIPART_DIR {
+        if let Some(entry) = stat(fs, vec![bucket.to_string()]).await? {
+            if entry.kind == EntryKind::Dir {
+                return Ok(());
+            }
+        }
+    }
+    Err(Error::new(
+        StatusCode::NOT_FOUND,
+        "NoSuchBucket",
+        "The specified bucket does not exist",
+    ))
+}
+
+async fn stat(fs: &WnfsHandle, path: Vec<String>) -> anyhow::Result<Option<DirEntry>> {
+    fs.call(move |fs| async move { fs.stat(&path).await }.boxed_local())
+        .await
+}
+
+/// Path of an object in the filesystem.
+fn object_path(bucket: &str, key: &str) -> Result<Vec<String>, Error> {
+    let mut path = vec![bucket.to_string()];
+    path.extend(into_segments(key));
+    if path.len() == 1 || path.iter().any(|segment| segment == "." || segment == "..") {
+        return Err(invalid_argument("Invalid key"));
+    }
+    Ok(path)
+}
+
+fn into_segments(path: &str) -> Vec<String> {
+    path.split('/')
+        .filter(|segment| !segment.is_empty())
+        .map(|segment| segment.to_owned())
+        .collect()
+}
+
+/// The naming rules of S3, which also keep buckets out of hidden directories.
+fn is_valid_bucket_name(name: &str) -> bool {
+    let bytes = name.as_bytes();
+    (3..=63).contains(&bytes.len())
+        && bytes.iter().all(|byte| {
+            byte.is_ascii_lowercase() || byte.is_ascii_digit() || *byte == b'.' || *byte == b'-'
+        })
+        && bytes[0].is_ascii_alphanumeric()
+        && bytes[bytes.len() - 1].is_ascii_alphanumeric()
+}
+
+/// Changes with the size and modification time of an object. The `-1` suffix of multipart
+/// ETags keeps clients from taking it for an MD5 sum of the content.
+fn etag(path: &[String], size: u64, modified: Option<DateTime<Utc>>) -> String {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(path.join("/").as_bytes());
+    hasher.update(&size.to_le_bytes());
+    if let Some(modified) = modified {
+        hasher.update(&modified.timestamp_millis().to_le_bytes());
+    }
+    format!("\"{}-1\"", &hasher.finalize().to_hex()[..32])
+}
+
+/// Parse a single `bytes=start-end` or `bytes=-suffix` range. Invalid ranges are ignored, as
+/// S3 does.
+fn parse_range(value: &str) -> Option<(Option<u64>, Option<u64>)> {
+    let (start, end) = value.strip_prefix("bytes=")?.split_once('-')?;
+    let parse = |value: &str| match value.trim() {
+        "" => Ok(None),
+        value => value.parse().map(Some),
+    };
+    match (parse(start).ok()?, parse(end).ok()?) {
+        (None, None) => None,
+        range => Some(range),
+    }
+}
+
+/// A signature that was verified, to verify the chunks of `aws-chunked` bodies with.
+struct Signature {
+    key: Vec<u8>,
+    date: String,
+    scope: String,
+    signature: String,
+    /// The `x-amz-content-sha256` header.
+    payload: String,
+}
+
+/// Verify the AWS signature version 4 in the `Authorization` header.
+fn authenticate(
+    state: &S3State,
+    method: &Method,
+    uri: &Uri,
+    headers: &HeaderMap,
+    query: &Query,
+) -> Result<Signature, Error> {
+    let authorization = header(headers, AUTHORIZATION.as_str())
+        .ok_or_else(|| access_denied("Missing Authorization header"))?;
+    let fields = authorization
+        .strip_prefix("AWS4-HMAC-SHA256 ")
+        .ok_or_else(|| access_denied("Only AWS signature version 4 is supported"))?;
+    let (mut credential, mut signed_headers, mut signature) = (None, None, None);
+    for field in fields.split(',') {
+        match field.trim().split_once('=') {
+            Some(("Credential", value)) => credential = Some(value),
+            Some(("SignedHeaders", value)) => signed_headers = Some(value),
+            Some(("Signature", value)) => signature = Some(value),
+            _ => {}
+        }
+    }
+    let (Some(credential), Some(signed_headers), Some(signature)) =
+        (credential, signed_headers, signature)
+    else {
+        return Err(access_denied("Malformed Authorization header"));
+    };
+    let (access_key, scope) = credential
+        .split_once('/')
+        .ok_or_else(|| access_denied("Malformed credential"))?;
+    if access_key != state.access_key {
+        return Err(Error::new(
+            StatusCode::FORBIDDEN,
+            "InvalidAccessKeyId",
+            "The access key id does not exist",
+        ));
+    }
+    let date = header(headers, "x-amz-date").ok_or_else(|| access_denied("Missing x-amz-date"))?;
+    let time = DateTime::parse_from_str(&format!("{date} +0000"), "%Y%m%dT%H%M%SZ %z")
+        .map_err(|_| access_denied("Invalid x-amz-date"))?;
+    if (Utc::now().timestamp() - time.timestamp()).abs() > MAX_CLOCK_SKEW {
+        return Err(Error::new(
+            StatusCode::FORBIDDEN,
+            "RequestTimeTooSkewed",
+            "The difference between the request time and the server's time is too large",
+        ));
+    }
+    let [day, region, "s3", "aws4_request"] = scope.split('/').collect::<Vec<_>>()[..] else {
+        return Err(access_denied("Malformed credential scope"));
+    };
+    if !date.starts_with(day) {
+        return Err(access_denied("Credential scope does not match x-amz-date"));
+    }
+    let payload = header(headers, "x-amz-content-sha256")
+        .ok_or_else(|| invalid_argument("Missing x-amz-content-sha256"))?;
+
+    let mut canonical_headers = String::new();
+    for name in signed_headers.split(';') {
+        let values: Vec<String> = headers
+            .get_all(name)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .map(|value| value.split_whitespace().collect::<Vec<_>>().join(" "))
+            .collect();
+        // HTTP/2 requests have the host in the URI.
+        let value = match (values.is_empty(), name, uri.authority()) {
+            (true, "host", Some(authority)) => authority.to_string(),
+            _ => values.join(","),
+        };
+        canonical_headers.push_str(&format!("{name}:{value}\n"));
+    }
+    let canonical_uri = uri
+        .path()
+        .split('/')
+        .map(|segment| percent_decode(segment).map(|segment| uri_encode(&segment, true)))
+        .collect::<Result<Vec<_>, _>>()?
+        .join("/");
+    let canonical_request = format!(
+        "{method}\n{canonical_uri}\n{}\n{canonical_headers}\n{signed_headers}\n{payload}",
+        query.canonical()
+    );
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{date}\n{scope}\n{}",
+        hex(&Sha256::digest(canonical_request.as_bytes()))
+    );
+    let key = hmac(
+        format!("AWS4{}", state.secret_key).as_bytes(),
+        day.as_bytes(),
+    );
+    let key = hmac(&key, region.as_bytes());
+    let key = hmac(&key, b"s3");
+    let key = hmac(&key, b"aws4_request");
+    let expected = hex(&hmac(&key, string_to_sign.as_bytes()));
+    // blake3::Hash compares in constant time.
+    if blake3::hash(expected.as_bytes()) != blake3::hash(signature.as_bytes()) {
+        return Err(signature_mismatch());
+    }
+    Ok(Signature {
+        key,
+        date: date.to_string(),
+        scope: scope.to_string(),
+        signature: expected,
+        payload: payload.to_string(),
+    })
+}
+
+/// Read the request body and check it against the signed payload hash, or decode it if it
+/// is sent as `aws-chunked`.
+async fn read_body(headers: &HeaderMap, body: Body, signature: &Signature) -> Result<Bytes, Error> {
+    let length = header(headers, CONTENT_LENGTH.as_str())
+        .map(|length| length.parse::<u64>())
+        .transpose()
+        .map_err(|_| invalid_argument("Invalid Content-Length"))?;
+    match length {
+        Some(length) if length > MAX_BODY_SIZE => {
+            return Err(Error::new(
+                StatusCode::BAD_REQUEST,
+                "EntityTooLarge",
+                "Your proposed upload exceeds the maximum allowed size, use multipart uploads",
+            ))
+        }
+        None if headers.contains_key(TRANSFER_ENCODING) => {
+            return Err(Error::new(
+                StatusCode::LENGTH_REQUIRED,
+                "MissingContentLength",
+                "You must provide the Content-Length HTTP header",
+            ))
+        }
+        _ => {}
+    }
+    let body = hyper::body::to_bytes(body).await?;
+    match signature.payload.as_str() {
+        "UNSIGNED-PAYLOAD" => Ok(body),
+        "STREAMING-AWS4-HMAC-SHA256-PAYLOAD" | "STREAMING-AWS4-HMAC-SHA256-PAYLOAD-TRAILER" => {
+            decode_chunks(&body, Some(signature))
+        }
+        "STREAMING-UNSIGNED-PAYLOAD-TRAILER" => decode_chunks(&body, None),
+        hash if hash.eq_ignore_ascii_case(&hex(&Sha256::digest(&body))) => Ok(body),
+        _ => Err(Error::new(
+            StatusCode::BAD_REQUEST,
+            "XAmzContentSHA256Mismatch",
+            "The x-amz-content-sha256 header does not match the body",
+        )),
+    }
+}
+
+/// Decode an `aws-chunked` body, verifying the signature of every chunk if it is signed.
+/// Trailing checksums are not checked.
+fn decode_chunks(body: &[u8], signature: Option<&Signature>) -> Result<Bytes, Error> {
+    let malformed = || invalid_argument("Malformed aws-chunked body");
+    let mut decoded = Vec::with_capacity(body.len());
+    let mut previous = signature.map(|signature| signature.signature.clone());
+    let mut rest = body;
+    loop {
+        let line_end = rest
+            .windows(2)
+            .position(|window| window == b"\r\n")
+            .ok_or_else(malformed)?;
+        let line = std::str::from_utf8(&rest[..line_end]).map_err(|_| malformed())?;
+        let (size, chunk_signature) = match line.split_once(';') {
+            Some((size, extension)) => (size, extension.strip_prefix("chunk-signature=")),
+            None => (line, None),
+        };
+        let size = usize::from_str_radix(size.trim(), 16).map_err(|_| malformed())?;
+        rest = &rest[line_end + 2..];
+        if rest.len() < size {
+            return Err(malformed());
+        }
+        let (chunk, tail) = rest.split_at(size);
+        if let (Some(signature), Some(previous)) = (signature, previous.as_mut()) {
+            let chunk_signature = chunk_signature.ok_or_else(signature_mismatch)?;
+            let string_to_sign = format!(
+                "AWS4-HMAC-SHA256-PAYLOAD\n{}\n{}\n{previous}\n{EMPTY_SHA256}\n{}",
+                signature.date,
+                signature.scope,
+                hex(&Sha256::digest(chunk))
+            );
+            let expected = hex(&hmac(&signature.key, string_to_sign.as_bytes()));
+            if blake3::hash(expected.as_bytes()) != blake3::hash(chunk_signature.as_bytes()) {
+                return Err(signature_mismatch());
+            }
+            *previous = expected;
+        }
+        if size == 0 {
+            return Ok(decoded.into());
+        }
+        decoded.extend_from_slice(chunk);
+        rest = tail.strip_prefix(b"\r\n").ok_or_else(malformed)?;
+    }
+}
+
+/// Decoded query parameters in request order.
+struct Query(Vec<(String, String)>);
+
+impl Query {
+    fn parse(query: &str) -> Result<Self, Error> {
+        let mut params = vec![];
+        for param in query.split('&').filter(|param| !param.is_empty()) {
+            let (name, value) = param.split_once('=').unwrap_or((param, ""));
+            params.push((percent_decode(name)?, percent_decode(value)?));
+        }
+        Ok(Self(params))
+    }
+
+    fn get(&self, name: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(param, _)| param == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn has(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    /// The canonical query string of signature version 4.
+    fn canonical(&self) -> String {
+        let mut params: Vec<_> = self
+            .0
+            .iter()
+            .map(|(name, value)| (uri_encode(name, true), uri_encode(value, true)))
+            .collect();
+        params.sort();
+        params
+            .iter()
+            .map(|(name, value)| format!("{name}={value}"))
+            .collect::<Vec<_>>()
+            .join("&")
+    }
+}
+
+fn header<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers.get(name).and_then(|value| value.to_str().ok())
+}
+
+fn percent_decode(value: &str) -> Result<String, Error> {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let byte = value
+                .get(i + 1..i + 3)
+                .filter(|hex| hex.bytes().all(|byte| byte.is_ascii_hexdigit()))
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                .ok_or_else(|| invalid_argument("Invalid percent-encoding"))?;
+            decoded.push(byte);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).map_err(|_| invalid_argument("Invalid UTF-8 in URL"))
+}
+
+/// Percent-encode everything but unreserved characters, as signature version 4 does.
+fn uri_encode(value: &str, encode_slash: bool) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                encoded.push(byte as char)
+            }
+            b'/' if !encode_slash => encoded.push('/'),
+            _ => encoded.push_str(&format!("%{byte:02X}")),
+        }
+    }
+    encoded
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC takes keys of any size");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn iso_time(time: DateTime<Utc>) -> String {
+    time.format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string()
+}
+
+fn http_time(time: DateTime<Utc>) -> String {
+    time.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+fn unescape(value: &str) -> String {
+    value
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+/// The text of all elements with a name, which is enough for the few request bodies of S3.
+fn xml_values(body: &[u8], name: &str) -> Result<Vec<String>, Error> {
+    let body = std::str::from_utf8(body).map_err(|_| malformed_xml())?;
+    let (open, close) = (format!("<{name}>"), format!("</{name}>"));
+    let mut values = vec![];
+    let mut rest = body;
+    while let Some(start) = rest.find(&open) {
+        rest = &rest[start + open.len()..];
+        let end = rest.find(&close).ok_or_else(malformed_xml)?;
+        values.push(unescape(&rest[..end]));
+        rest = &rest[end + close.len()..];
+    }
+    Ok(values)
+}
+
+fn xml_response(status: StatusCode, xml: String) -> Response {
+    let xml = format!("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n{xml}");
+    (status, [(CONTENT_TYPE, "application/xml")], xml).into_response()
+}
+
+fn access_denied(message: &str) -> Error {
+    Error::new(StatusCode::FORBIDDEN, "AccessDenied", message)
+}
+
+fn signature_mismatch() -> Error {
+    Error::new(
+        StatusCode::FORBIDDEN,
+        "SignatureDoesNotMatch",
+        "The request signature does not match the signature calculated by the server",
+    )
+}
+
+fn invalid_argument(message: &str) -> Error {
+    Error::new(StatusCode::BAD_REQUEST, "InvalidArgument", message)
+}
+
+fn malformed_xml() -> Error {
+    Error::new(
+        StatusCode::BAD_REQUEST,
+        "MalformedXML",
+        "The XML you provided was not well-formed",
+    )
+}
+
+fn no_such_upload() -> Error {
+    Error::new(
+        StatusCode::NOT_FOUND,
+        "NoSuchUpload",
+        "The specified multipart upload does not exist",
+    )
+}
+
+fn method_not_allowed() -> Error {
+    Error::new(
+        StatusCode::METHOD_NOT_ALLOWED,
+        "MethodNotAllowed",
+        "The specified method is not allowed against this resource",
+    )
+}
+
+fn not_implemented(what: &str) -> Error {
+    Error::new(
+        StatusCode::NOT_IMPLEMENTED,
+        "NotImplemented",
+        format!("{what} is not implemented"),
+    )
+}
+
+/// An S3 error, returned as `<Error>` XML.
+struct Error {
+    status: StatusCode,
+    code: &'static str,
+    message: String,
+}
+
+impl Error {
+    fn new(status: StatusCode, code: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+impl<E: Into<anyhow::Error>> From<E> for Error {
+    fn from(err: E) -> Self {
+        Self::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "InternalError",
+            err.into().to_string(),
+        )
+    }
+}
+
+impl IntoResponse for Error {
+    fn into_response(self) -> Response {
+        debug!("S3 error {}: {}", self.code, self.message);
+        let xml = format!(
+            "<Error><Code>{}</Code><Message>{}</Message></Error>",
+            self.code,
+            escape(&self.message)
+        );
+        xml_response(self.status, xml)
+    }
+}
@@ -0,0 +1,150 @@
+//! Automatic snapshots with retention, taken by `mount`.
+//!
+//! [`run`] snapshots the last flushed state of a filesystem every hour, day or week (the
+//! shortest period with a non-zero retention) and then prunes automatic snapshots like
+//! `restic forget`: for each period, the newest snapshot of each of the last N hours, days or
+//! weeks that have one is kept. Snapshots created by hand are never pruned.
+//!
+//! Retention is configured in the config file, globally or per mount:
+//!
+//! ```toml
+//! [snapshots]
+//! hourly = 24
+//! daily = 7
+//! weekly = 4
+//! ```
+
+use std::collections::BTreeSet;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use libipld::Cid;
+use serde::Deserialize;
+use tracing::{debug, info, warn};
+
+use crate::fs::{private_root_alias, snapshot_prefix};
+use crate::SqliteBlockStore;
+
+/// Name prefix of automatic snapshots, followed by the time of the snapshot.
+pub const AUTO_PREFIX: &str = "auto-";
+
+/// How many automatic snapshots to keep per period. Zero disables a period.
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SnapshotSchedule {
+    #[serde(default)]
+    pub hourly: usize,
+    #[serde(default)]
+    pub daily: usize,
+    #[serde(default)]
+    pub weekly: usize,
+}
+
+impl SnapshotSchedule {
+    /// Time between snapshots, or `None` if all periods are disabled.
+    pub fn interval(&self) -> Option<Duration> {
+        const HOUR: u64 = 60 * 60;
+        if self.hourly > 0 {
+            Some(Duration::from_secs(HOUR))
+        } else if self.daily > 0 {
+            Some(Duration::from_secs(24 * HOUR))
+        } else if self.weekly > 0 {
+            Some(Duration::from_secs(7 * 24 * HOUR))
+        } else {
+            None
+        }
+    }
+}
+
+/// Take and prune snapshots of a named filesystem until the task is dropped.
+///
+/// Errors are logged and retried at the next interval.
+pub async fn run(store: SqliteBlockStore, name: String, schedule: SnapshotSchedule) {
+    let Some(period) = schedule.interval() else {
+        return;
+    };
+    let mut interval = tokio::time::interval(period);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        if let Err(err) = tick(&store, &name, &schedule, Utc::now()).await {
+            warn!("automatic snapshot of {name} failed: {err}");
+        }
+    }
+}
+
+async fn tick(
+    store: &SqliteBlockStore,
+    name: &str,
+    schedule: &SnapshotSchedule,
+    now: DateTime<Utc>,
+) -> anyhow::Result<()> {
+    let prefix = snapshot_prefix(name);
+    let Some(root) = store.resolve_alias(&private_root_alias(name)).await? else {
+        return Ok(());
+    };
+    let mut snapshots = auto_snapshots(store, name).await?;
+    // Unchanged filesystems are not snapshotted again.
+    if snapshots.first().map(|(_, _, cid)| *cid) != Some(root) {
+        let snapshot = format!("{AUTO_PREFIX}{}", now.format("%Y-%m-%dT%H:%M:%SZ"));
+        store
+            .alias(&format!("{prefix}{snapshot}"), Some(&root))
+            .await?;
+        info!("created snapshot {snapshot} of {name}");
+        snapshots.insert(0, (snapshot, now, root));
+    }
+    let times: Vec<_> = snapshots.iter().map(|(_, time, _)| *time).collect();
+    let keep = retained(&times, schedule);
+    for (i, (snapshot, _, _)) in snapshots.iter().enumerate() {
+        if !keep.contains(&i) {
+            debug!("prune snapshot {snapshot} of {name}");
+            store.alias(&format!("{prefix}{snapshot}"), None).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Automatic snapshots with their time and root, newest first.
+async fn auto_snapshots(
+    store: &SqliteBlockStore,
+    name: &str,
+) -> anyhow::Result<Vec<(String, DateTime<Utc>, Cid)>> {
+    let mut snapshots = vec![];
+    for (snapshot, cid) in store.aliases_with_prefix(&snapshot_prefix(name)).await? {
+        let Some(time) = snapshot.strip_prefix(AUTO_PREFIX) else {
+            continue;
+        };
+        match DateTime::parse_from_rfc3339(time) {
+            Ok(time) => snapshots.push((snapshot, time.with_timezone(&Utc), cid)),
+            Err(_) => debug!("skip snapshot {snapshot}: not an automatic snapshot"),
+        }
+    }
+    snapshots.sort_by(|a, b| b.1.cmp(&a.1));
+    Ok(snapshots)
+}
+
+/// Indices of the snapshots to keep, given their times newest first.
+fn retained(times: &[DateTime<Utc>], schedule: &SnapshotSchedule) -> BTreeSet<usize> {
+    let periods = [
+        (schedule.hourly, "%Y-%m-%d %H"),
+        (schedule.daily, "%Y-%m-%d"),
+        (schedule.weekly, "%G-%V"),
+    ];
+    let mut keep = BTreeSet::new();
+    for (count, bucket_format) in periods {
+        let mut last_bucket = None;
+        let mut kept = 0;
+        for (i, time) in times.iter().enumerate() {
+            if kept == count {
+                break;
+            }
+            let bucket = time.format(bucket_format).to_string();
+            if last_bucket.as_ref() != Some(&bucket) {
+                keep.insert(i);
+                kept += 1;
+                last_bucket = Some(bucket);
+            }
+        }
+    }
+    keep
+}
@@ -0,0 +1,192 @@
+//! Full-text search over file names and text contents.
+//!
+//! A [`SearchIndex`] attached with [`crate::fs::Wnfs::set_search_index`] is updated with the
+//! changed paths on every flush, so searching does not need to decrypt the whole filesystem.
+//! Files are indexed by name, and by content if it is UTF-8 text of at most
+//! [`MAX_INDEXED_SIZE`] bytes.
+//!
+//! The index is a tantivy index in a directory next to the block store (see [`index_dir`]).
+//! It is not encrypted: anyone who can read it learns the names and words of the indexed
+//! files.
+
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+use tantivy::collector::TopDocs;
+use tantivy::directory::MmapDirectory;
+use tantivy::query::QueryParser;
+use tantivy::schema::{Field, Schema, STORED, STRING, TEXT};
+use tantivy::{doc, Index, IndexReader, IndexWriter, ReloadPolicy, Term};
+
+use crate::journal::JournalOp;
+
+/// Larger files are only indexed by name.
+pub const MAX_INDEXED_SIZE: u64 = 1024 * 1024;
+const WRITER_MEMORY: usize = 32 * 1024 * 1024;
+
+/// A file that matched a search.
+#[derive(Debug, Clone, Serialize)]
+pub struct SearchHit {
+    pub path: Vec<String>,
+    pub score: f32,
+}
+
+/// Paths to update in the index at the next flush.
+#[derive(Debug)]
+pub(crate) enum IndexChange {
+    /// Index the file or directory at a path again.
+    Update(Vec<String>),
+    Remove(Vec<String>),
+    /// Index the whole filesystem again, e.g. after restoring another revision.
+    Rebuild,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Fields {
+    /// Full path, to find a file's document.
+    path: Field,
+    /// All parent directories, to find the documents below a directory.
+    ancestor: Field,
+    name: Field,
+    body: Field,
+}
+
+/// The search index of a filesystem.
+pub struct SearchIndex {
+    index: Index,
+    writer: IndexWriter,
+    reader: IndexReader,
+    fields: Fields,
+    pending: Vec<IndexChange>,
+}
+
+/// Directory of the search index of a named filesystem.
+pub fn index_dir(db_path: impl AsRef<Path>, name: &str) -> PathBuf {
+    let mut dir = db_path.as_ref().as_os_str().to_owned();
+    dir.push(".search");
+    PathBuf::from(dir).join(name)
+}
+
+impl SearchIndex {
+    /// Open the index in a directory, creating an empty one if there is none.
+    ///
+    /// A new index must be filled with [`crate::fs::Wnfs::rebuild_search_index`].
+    pub fn open(dir: &Path) -> anyhow::Result<Self> {
+        let mut builder = Schema::builder();
+        let fields = Fields {
+            path: builder.add_text_field("path", STRING | STORED),
+            ancestor: builder.add_text_field("ancestor", STRING),
+            name: builder.add_text_field("name", TEXT),
+            body: builder.add_text_field("body", TEXT),
+        };
+        std::fs::create_dir_all(dir)?;
+        let index = Index::open_or_create(MmapDirectory::open(dir)?, builder.build())?;
+        // A single writer thread is plenty for the few files of a flush.
+        let writer = index.writer_with_num_threads(1, WRITER_MEMORY)?;
+        let reader = index
+            .reader_builder()
+            .reload_policy(ReloadPolicy::Manual)
+            .try_into()?;
+        Ok(Self {
+            index,
+            writer,
+            reader,
+            fields,
+            pending: vec![],
+        })
+    }
+
+    /// Note an operation of the filesystem, to be indexed at the next flush.
+    pub(crate) fn record(&mut self, op: JournalOp, path: &[String], to: Option<&[String]>) {
+        match op {
+            JournalOp::Write => self.pending.push(IndexChange::Update(path.to_vec())),
+            JournalOp::Remove => self.pending.push(IndexChange::Remove(path.to_vec())),
+            JournalOp::Move => {
+                self.pending.push(IndexChange::Remove(path.to_vec()));
+                if let Some(to) = to {
+                    self.pending.push(IndexChange::Update(to.to_vec()));
+                }
+            }
+            // The root may have been replaced by another head.
+            JournalOp::Resolve => self.rebuild_later(),
+            JournalOp::Start | JournalOp::Mkdir | JournalOp::SetMetadata => {}
+        }
+    }
+
+    pub(crate) fn rebuild_later(&mut self) {
+        self.pending.clear();
+        self.pending.push(IndexChange::Rebuild);
+    }
+
+    pub(crate) fn take_pending(&mut self) -> Vec<IndexChange> {
+        std::mem::take(&mut self.pending)
+    }
+
+    /// Add a file, with its content if it is small enough.
+    pub(crate) fn add_file(
+        &mut self,
+        path: &[String],
+        content: Option<&[u8]>,
+    ) -> anyhow::Result<()> {
+        let Some(name) = path.last() else {
+            return Ok(());
+        };
+        let mut document = doc!(
+            self.fields.path => path.join("/"),
+            self.fields.name => name.as_str(),
+        );
+        for len in 1..path.len() {
+            document.add_text(self.fields.ancestor, path[..len].join("/"));
+        }
+        if let Some(text) = content.and_then(|content| std::str::from_utf8(content).ok()) {
+            document.add_text(self.fields.body, text);
+        }
+        self.writer.add_document(document)?;
+        Ok(())
+    }
+
+    /// Remove a file, or all files below a directory.
+    pub(crate) fn remove(&mut self, path: &[String]) {
+        let path = path.join("/");
+        self.writer
+            .delete_term(Term::from_field_text(self.fields.path, &path));
+        self.writer
+            .delete_term(Term::from_field_text(self.fields.ancestor, &path));
+    }
+
+    pub(crate) fn remove_all(&mut self) -> anyhow::Result<()> {
+        self.writer.delete_all_documents()?;
+        Ok(())
+    }
+
+    /// Make the changes since the last commit durable and searchable.
+    pub(crate) fn commit(&mut self) -> anyhow::Result<()> {
+        self.writer.commit()?;
+        self.reader.reload()?;
+        Ok(())
+    }
+
+    /// Find files by a query in tantivy's query syntax, best matches first.
+    ///
+    /// Terms without a field match the name or the content.
+    pub fn search(&self, query: &str, limit: usize) -> anyhow::Result<Vec<SearchHit>> {
+        let parser = QueryParser::for_index(&self.index, vec![self.fields.name, self.fields.body]);
+        let query = parser.parse_query(query)?;
+        let searcher = self.reader.searcher();
+        let mut hits = vec![];
+        for (score, address) in searcher.search(&query, &TopDocs::with_limit(limit))? {
+            let document = searcher.doc(address)?;
+            let Some(path) = document
+                .get_first(self.fields.path)
+                .and_then(|value| value.as_text())
+            else {
+                continue;
+            };
+            hits.push(SearchHit {
+                path: path.split('/').map(str::to_string).collect(),
+                score,
+            });
+        }
+        Ok(hits)
+    }
+}
@@ -0,0 +1,548 @@
+//! SFTP server exposing a filesystem.
+//!
+//! Any SFTP client (`sftp`, FileZilla, rclone, ...) can browse and transfer files. Clients
+//! authenticate with a public key listed in an `authorized_keys` file. Only the `sftp`
+//! subsystem is offered, there is no shell access.
+//!
+//! Files opened for writing are buffered in memory and written when the handle is closed.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use futures::FutureExt;
+use russh::server::{Auth, Msg, Session};
+use russh::{Channel, ChannelId};
+use russh_keys::key::{KeyPair, PublicKey};
+use russh_sftp::protocol::{
+    Attrs, Data, File, FileAttributes, Handle, Name, OpenFlags, Status, StatusCode, Version,
+};
+use tracing::{debug, trace, warn};
+
+use crate::fs::{DirEntry, EntryKind};
+use crate::handle::WnfsHandle;
+
+/// Options for the SFTP server.
+#[derive(Debug, Clone)]
+pub struct SftpConfig {
+    /// File with the host key. A new key is generated and saved if it does not exist.
+    pub host_key: PathBuf,
+    /// File with the public keys that may connect, in OpenSSH `authorized_keys` format.
+    pub authorized_keys: PathBuf,
+}
+
+/// Serve a filesystem over SFTP until the server fails.
+pub async fn serve(fs: WnfsHandle, addr: SocketAddr, config: SftpConfig) -> anyhow::Result<()> {
+    let host_key = load_or_create_host_key(&config.host_key)?;
+    let authorized_keys = Arc::new(load_authorized_keys(&config.authorized_keys)?);
+    let ssh_config = russh::server::Config {
+        auth_rejection_time: Duration::from_secs(1),
+        keys: vec![host_key],
+        ..Default::default()
+    };
+    let server = SftpServer {
+        fs,
+        authorized_keys,
+    };
+    debug!("serve SFTP on {addr}");
+    russh::server::run(Arc::new(ssh_config), addr, server).await?;
+    Ok(())
+}
+
+fn load_or_create_host_key(path: &Path) -> anyhow::Result<KeyPair> {
+    if path.exists() {
+        return Ok(russh_keys::load_secret_key(path, None)?);
+    }
+    let key =
+        KeyPair::generate_ed25519().ok_or_else(|| anyhow::anyhow!("Key generation failed"))?;
+    let mut file = std::fs::File::create(path)?;
+    russh_keys::encode_pkcs8_pem(&key, &mut file)?;
+    debug!("created host key {path:?}");
+    Ok(key)
+}
+
+fn load_authorized_keys(path: &Path) -> anyhow::Result<Vec<PublicKey>> {
+    let content = std::fs::read_to_string(path)
+        .map_err(|err| anyhow::anyhow!("Cannot read authorized keys {path:?}: {err}"))?;
+    let mut keys = vec![];
+    for line in content.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        // <type> <base64> [comment]
+        let Some(base64) = line.split_whitespace().nth(1) else {
+            continue;
+        };
+        match russh_keys::parse_public_key_base64(base64) {
+            Ok(key) => keys.push(key),
+            Err(err) => warn!("skip authorized key: {err}"),
+        }
+    }
+    Ok(keys)
+}
+
+#[derive(Clone)]
+struct SftpServer {
+    fs: WnfsHandle,
+    authorized_keys: Arc<Vec<PublicKey>>,
+}
+
+impl russh::server::Server for SftpServer {
+    type Handler = SshSession;
+
+    fn new_client(&mut self, peer: Option<SocketAddr>) -> SshSession {
+        debug!("new SFTP client {peer:?}");
+        SshSession {
+            server: self.clone(),
+            channels: HashMap::new(),
+        }
+    }
+}
+
+struct SshSession {
+    server: SftpServer,
+    channels: HashMap<ChannelId, Channel<Msg>>,
+}
+
+#[async_trait]
+impl russh::server::Handler for SshSession {
+    type Error = anyhow::Error;
+
+    async fn auth_publickey(
+        self,
+        user: &str,
+        public_key: &PublicKey,
+    ) -> Result<(Self, Auth), Self::Error> {
+        let fingerprint = public_key.fingerprint();
+        let authorized = self
+            .server
+            .authorized_keys
+            .iter()
+            .any(|key| key.fingerprint() == fingerprint);
+        debug!("auth {user} with {fingerprint}: {authorized}");
+        let auth = if authorized {
+            Auth::Accept
+        } else {
+            Auth::Reject {
+                proceed_with_methods: None,
+            }
+        };
+        Ok((self, auth))
+    }
+
+    async fn channel_open_session(
+        mut self,
+        channel: Channel<Msg>,
+        session: Session,
+    ) -> Result<(Self, bool, Session), Self::Error> {
+        self.channels.insert(channel.id(), channel);
+        Ok((self, true, session))
+    }
+
+    async fn subsystem_request(
+        mut self,
+        channel_id: ChannelId,
+        name: &str,
+        mut session: Session,
+    ) -> Result<(Self, Session), Self::Error> {
+        match self.channels.remove(&channel_id) {
+            Some(channel) if name == "sftp" => {
+                session.channel_success(channel_id);
+                let sftp = SftpSession {
+                    fs: self.server.fs.clone(),
+                    handles: HashMap::new(),
+                    next_handle: 0,
+                };
+                russh_sftp::server::run(channel.into_stream(), sftp).await;
+            }
+            _ => session.channel_failure(channel_id),
+        }
+        Ok((self, session))
+    }
+}
+
+/// State of an open handle.
+enum OpenHandle {
+    Dir {
+        path_segments: Vec<String>,
+        /// Whether the listing has been sent. The next readdir signals the end.
+        listed: bool,
+    },
+    File {
+        path_segments: Vec<String>,
+        /// Full content of the file, loaded when opened for writing.
+        content: Option<Vec<u8>>,
+        dirty: bool,
+    },
+}
+
+struct SftpSession {
+    fs: WnfsHandle,
+    handles: HashMap<String, OpenHandle>,
+    next_handle: u64,
+}
+
+impl SftpSession {
+    fn insert_handle(&mut self, handle: OpenHandle) -> String {
+        self.next_handle += 1;
+        let name = self.next_handle.to_string();
+        self.handles.insert(name.clone(), handle);
+        name
+    }
+
+    async fn lookup(&self, path_segments: Vec<String>) -> Result<DirEntry, StatusCode> {
+        self.fs
+            .call(move |fs| async move { fs.stat(&path_segments).await }.boxed_local())
+            .await
+            .map_err(failure)?
+            .ok_or(StatusCode::NoSuchFile)
+    }
+
+    async fn write_file(
+        &self,
+        path_segments: Vec<String>,
+        content: Vec<u8>,
+    ) -> Result<(), StatusCode> {
+        self.fs
+            .call(move |fs| {
+                async move { fs.write_file(&path_segments, content).await }.boxed_local()
+            })
+            .await
+            .map_err(failure)
+    }
+}
+
+#[async_trait]
+impl russh_sftp::server::Handler for SftpSession {
+    type Error = StatusCode;
+
+    fn unimplemented(&self) -> Self::Error {
+        StatusCode::OpUnsupported
+    }
+
+    async fn init(
+        &mut self,
+        _version: u32,
+        _extensions: HashMap<String, String>,
+    ) -> Result<Version, Self::Error> {
+        Ok(Version::new())
+    }
+
+    async fn realpath(&mut self, id: u32, path: String) -> Result<Name, Self::Error> {
+        let path = format!("/{}", into_segments(&path).join("/"));
+        Ok(Name {
+            id,
+            files: vec![File::new(path, FileAttributes::default())],
+        })
+    }
+
+    async fn stat(&mut self, id: u32, path: String) -> Result<Attrs, Self::Error> {
+        trace!("stat {path}");
+        let entry = self.lookup(into_segments(&path)).await?;
+        Ok(Attrs {
+            id,
+            attrs: to_attrs(&entry),
+        })
+    }
+
+    async fn lstat(&mut self, id: u32, path: String) -> Result<Attrs, Self::Error> {
+        // There are no symlinks.
+        self.stat(id, path).await
+    }
+
+    async fn fstat(&mut self, id: u32, handle: String) -> Result<Attrs, Self::Error> {
+        let (path_segments, buffered_size) = match self.handles.get(&handle) {
+            Some(OpenHandle::File {
+                path_segments,
+                content,
+                ..
+            }) => (
+                path_segments.clone(),
+                content.as_ref().map(|content| content.len() as u64),
+            ),
+            Some(OpenHandle::Dir { path_segments, .. }) => (path_segments.clone(), None),
+            None => return Err(StatusCode::Failure),
+        };
+        let mut entry = self.lookup(path_segments).await?;
+        if let Some(size) = buffered_size {
+            entry.size = size;
+        }
+        Ok(Attrs {
+            id,
+            attrs: to_attrs(&entry),
+        })
+    }
+
+    async fn opendir(&mut self, id: u32, path: String) -> Result<Handle, Self::Error> {
+        trace!("opendir {path}");
+        let path_segments = into_segments(&path);
+        if self.lookup(path_segments.clone()).await?.kind != EntryKind::Dir {
+            return Err(StatusCode::NoSuchFile);
+        }
+        let handle = self.insert_handle(OpenHandle::Dir {
+            path_segments,
+            listed: false,
+        });
+        Ok(Handle { id, handle })
+    }
+
+    async fn readdir(&mut self, id: u32, handle: String) -> Result<Name, Self::Error> {
+        let path_segments = match self.handles.get_mut(&handle) {
+            Some(OpenHandle::Dir { listed: true, .. }) => return Err(StatusCode::Eof),
+            Some(OpenHandle::Dir {
+                path_segments,
+                listed,
+            }) => {
+                *listed = true;
+                path_segments.clone()
+            }
+            _ => return Err(StatusCode::Failure),
+        };
+        let entries = self
+            .fs
+            .call(move |fs| async move { fs.ls_entries(&path_segments).await }.boxed_local())
+            .await
+            .map_err(failure)?;
+        let files = entries
+            .iter()
+            .map(|entry| File::new(entry.name.clone(), to_attrs(entry)))
+            .collect();
+        Ok(Name { id, files })
+    }
+
+    async fn open(
+        &mut self,
+        id: u32,
+        filename: String,
+        pflags: OpenFlags,
+        _attrs: FileAttributes,
+    ) -> Result<Handle, Self::Error> {
+        trace!("open {filename} {pflags:?}");
+        let path_segments = into_segments(&filename);
+        let exists = match self.lookup(path_segments.clone()).await {
+            Ok(entry) if entry.kind == EntryKind::Dir => return Err(StatusCode::Failure),
+            Ok(_) if pflags.contains(OpenFlags::CREATE | OpenFlags::EXCLUDE) => {
+                return Err(StatusCode::Failure)
+            }
+            Ok(_) => true,
+            Err(StatusCode::NoSuchFile) if pflags.contains(OpenFlags::CREATE) => false,
+            Err(err) => return Err(err),
+        };
+        let content = if !pflags.contains(OpenFlags::WRITE) {
+            None
+        } else if pflags.contains(OpenFlags::TRUNCATE) || !exists {
+            Some(vec![])
+        } else {
+            let read_path = path_segments.clone();
+            let content = self
+                .fs
+                .call(move |fs| async move { fs.read_file(&read_path).await }.boxed_local())
+                .await
+                .map_err(failure)?;
+            Some(content)
+        };
+        // Create or truncate the file right away, so that this is visible before the handle
+        // is closed.
+        if content.as_ref().map_or(false, Vec::is_empty) {
+            self.write_file(path_segments.clone(), vec![]).await?;
+        }
+        let handle = self.insert_handle(OpenHandle::File {
+            path_segments,
+            content,
+            dirty: false,
+        });
+        Ok(Handle { id, handle })
+    }
+
+    async fn read(
+        &mut self,
+        id: u32,
+        handle: String,
+        offset: u64,
+        len: u32,
+    ) -> Result<Data, Self::Error> {
+        let data = match self.handles.get(&handle) {
+            Some(OpenHandle::File {
+                content: Some(content),
+                ..
+            }) => {
+                let start = (offset as usize).min(content.len());
+                let end = (start + len as usize).min(content.len());
+                content[start..end].to_vec()
+            }
+            Some(OpenHandle::File { path_segments, .. }) => {
+                let path_segments = path_segments.clone();
+                self.fs
+                    .call(move |fs| {
+                        async move {
+                            fs.read_file_at(&path_segments, offset as usize, len as usize)
+                                .await
+                        }
+                        .boxed_local()
+                    })
+                    .await
+                    .map_err(failure)?
+            }
+            _ => return Err(StatusCode::Failure),
+        };
+        if data.is_empty() && len > 0 {
+            return Err(StatusCode::Eof);
+        }
+        Ok(Data { id, data })
+    }
+
+    async fn write(
+        &mut self,
+        id: u32,
+        handle: String,
+        offset: u64,
+        data: Vec<u8>,
+    ) -> Result<Status, Self::Error> {
+        let Some(OpenHandle::File {
+            content: Some(content),
+            dirty,
+            ..
+        }) = self.handles.get_mut(&handle)
+        else {
+            return Err(StatusCode::PermissionDenied);
+        };
+        let offset = offset as usize;
+        let end = offset + data.len();
+        if content.len() < end {
+            content.resize(end, 0);
+        }
+        content[offset..end].copy_from_slice(&data);
+        *dirty = true;
+        Ok(ok(id))
+    }
+
+    async fn close(&mut self, id: u32, handle: String) -> Result<Status, Self::Error> {
+        if let Some(OpenHandle::File {
+            path_segments,
+            content: Some(content),
+            dirty: true,
+        }) = self.handles.remove(&handle)
+        {
+            trace!("write {path_segments:?} on close");
+            self.write_file(path_segments, content).await?;
+        }
+        Ok(ok(id))
+    }
+
+    async fn mkdir(
+        &mut self,
+        id: u32,
+        path: String,
+        _attrs: FileAttributes,
+    ) -> Result<Status, Self::Error> {
+        trace!("mkdir {path}");
+        let path_segments = into_segments(&path);
+        if self.lookup(path_segments.clone()).await.is_ok() {
+            return Err(StatusCode::Failure);
+        }
+        self.fs
+            .call(move |fs| async move { fs.mkdir(&path_segments).await }.boxed_local())
+            .await
+            .map_err(failure)?;
+        Ok(ok(id))
+    }
+
+    async fn rmdir(&mut self, id: u32, path: String) -> Result<Status, Self::Error> {
+        trace!("rmdir {path}");
+        let path_segments = into_segments(&path);
+        let entry = self.lookup(path_segments.clone()).await?;
+        if entry.kind != EntryKind::Dir {
+            return Err(StatusCode::Failure);
+        }
+        let list_path = path_segments.clone();
+        let children = self
+            .fs
+            .call(move |fs| async move { fs.ls_entries(&list_path).await }.boxed_local())
+            .await
+            .map_err(failure)?;
+        // Like rmdir(2), only remove empty directories.
+        if !children.is_empty() {
+            return Err(StatusCode::Failure);
+        }
+        self.remove(id, path).await
+    }
+
+    async fn remove(&mut self, id: u32, filename: String) -> Result<Status, Self::Error> {
+        trace!("remove {filename}");
+        let path_segments = into_segments(&filename);
+        self.lookup(path_segments.clone()).await?;
+        self.fs
+            .call(move |fs| async move { fs.rm(&path_segments).await }.boxed_local())
+            .await
+            .map_err(failure)?;
+        Ok(ok(id))
+    }
+
+    async fn setstat(
+        &mut self,
+        id: u32,
+        _path: String,
+        _attrs: FileAttributes,
+    ) -> Result<Status, Self::Error> {
+        // Attributes are not persisted here, so pretend the change was applied. Clients set
+        // them after uploads and fail the transfer otherwise.
+        Ok(ok(id))
+    }
+
+    async fn fsetstat(
+        &mut self,
+        id: u32,
+        _handle: String,
+        _attrs: FileAttributes,
+    ) -> Result<Status, Self::Error> {
+        Ok(ok(id))
+    }
+}
+
+fn to_attrs(entry: &DirEntry) -> FileAttributes {
+    let (file_type, perm) = match entry.kind {
+        EntryKind::File => (libc::S_IFREG, 0o644),
+        EntryKind::Dir => (libc::S_IFDIR, 0o755),
+    };
+    let mtime = entry.modified.map(|modified| modified.timestamp() as u32);
+    FileAttributes {
+        size: Some(entry.size),
+        permissions: Some(file_type as u32 | entry.mode.unwrap_or(perm)),
+        atime: mtime,
+        mtime,
+        ..Default::default()
+    }
+}
+
+fn ok(id: u32) -> Status {
+    Status {
+        id,
+        status_code: StatusCode::Ok,
+        error_message: "Ok".to_string(),
+        language_tag: "en-US".to_string(),
+    }
+}
+
+/// Resolve an absolute or relative path against the root, handling `.` and `..`.
+fn into_segments(path: &str) -> Vec<String> {
+    let mut path_segments = vec![];
+    for segment in path.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                path_segments.pop();
+            }
+            name => path_segments.push(name.to_string()),
+        }
+    }
+    path_segments
+}
+
+fn failure(err: anyhow::Error) -> StatusCode {
+    debug!("sftp error: {err}");
+    StatusCode::Failure
+}
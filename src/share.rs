@@ -0,0 +1,99 @@
+//! Sharing of private directories between stores.
+//!
+//! A share is the root record of a directory (forest CID and revision ref), encrypted to
+//! the recipient's X25519 exchange key and stored as a raw block. The CID of that block is
+//! the share label that is handed to the recipient. Accepting a share requires access to
+//! the blocks of the sharer's store, e.g. through sync.
+
+use chacha20poly1305::aead::Aead;
+use chacha20poly1305::{ChaCha20Poly1305, KeyInit, Nonce};
+use libipld::cid::multibase::{self, Base};
+use libipld::IpldCodec;
+use x25519_dalek::{EphemeralSecret, PublicKey, StaticSecret};
+
+use crate::store::Store;
+
+const EXCHANGE_KEY_ALIAS: &str = "exchange-key";
+const KDF_CONTEXT: &str = "wnfs-fuse 2023-04 share encryption key";
+
+/// The X25519 key pair of a store, used to receive shares.
+pub struct ExchangeKey(StaticSecret);
+
+impl ExchangeKey {
+    /// Load the exchange key of a store, or create and persist a new one.
+    pub async fn load_or_create(store: &mut impl Store) -> anyhow::Result<Self> {
+        if let Some(bytes) = store.get_from_alias(EXCHANGE_KEY_ALIAS).await? {
+            let bytes: [u8; 32] = bytes
+                .try_into()
+                .map_err(|_| anyhow::anyhow!("Invalid exchange key in store"))?;
+            return Ok(Self(StaticSecret::from(bytes)));
+        }
+        let secret = StaticSecret::random_from_rng(rand::rngs::OsRng);
+        store
+            .put_with_alias(
+                EXCHANGE_KEY_ALIAS,
+                secret.to_bytes().to_vec(),
+                IpldCodec::Raw,
+            )
+            .await?;
+        tracing::debug!("created exchange key");
+        Ok(Self(secret))
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        PublicKey::from(&self.0)
+    }
+
+    /// Decrypt a payload that was sealed to this key with [`seal`].
+    pub fn open(&self, sealed: &[u8]) -> anyhow::Result<Vec<u8>> {
+        if sealed.len() < 32 {
+            anyhow::bail!("Sealed payload too short");
+        }
+        let (ephemeral, ciphertext) = sealed.split_at(32);
+        let ephemeral: [u8; 32] = ephemeral.try_into()?;
+        let ephemeral = PublicKey::from(ephemeral);
+        let shared = self.0.diffie_hellman(&ephemeral);
+        let cipher = cipher(shared.as_bytes(), &ephemeral, &self.public_key());
+        cipher
+            .decrypt(&Nonce::default(), ciphertext)
+            .map_err(|_| anyhow::anyhow!("Failed to decrypt share: not addressed to this key"))
+    }
+}
+
+/// Encrypt a payload to a recipient's exchange key.
+///
+/// The output is the ephemeral public key followed by the ciphertext.
+pub fn seal(recipient: &PublicKey, plaintext: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let secret = EphemeralSecret::random_from_rng(rand::rngs::OsRng);
+    let ephemeral = PublicKey::from(&secret);
+    let shared = secret.diffie_hellman(recipient);
+    // The key is unique per ephemeral secret, so a fixed nonce is fine.
+    let ciphertext = cipher(shared.as_bytes(), &ephemeral, recipient)
+        .encrypt(&Nonce::default(), plaintext)
+        .map_err(|_| anyhow::anyhow!("Failed to encrypt share"))?;
+    let mut sealed = ephemeral.as_bytes().to_vec();
+    sealed.extend(ciphertext);
+    Ok(sealed)
+}
+
+fn cipher(shared: &[u8; 32], ephemeral: &PublicKey, recipient: &PublicKey) -> ChaCha20Poly1305 {
+    let mut key_material = shared.to_vec();
+    key_material.extend_from_slice(ephemeral.as_bytes());
+    key_material.extend_from_slice(recipient.as_bytes());
+    let key = blake3::derive_key(KDF_CONTEXT, &key_material);
+    ChaCha20Poly1305::new(&key.into())
+}
+
+/// Encode an exchange public key as a multibase string.
+pub fn encode_public_key(key: &PublicKey) -> String {
+    multibase::encode(Base::Base58Btc, key.as_bytes())
+}
+
+/// Decode an exchange public key from its multibase string.
+pub fn decode_public_key(key: &str) -> anyhow::Result<PublicKey> {
+    let (_base, bytes) = multibase::decode(key.trim())?;
+    let bytes: [u8; 32] = bytes
+        .try_into()
+        .map_err(|_| anyhow::anyhow!("Invalid exchange key length"))?;
+    Ok(PublicKey::from(bytes))
+}
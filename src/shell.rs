@@ -0,0 +1,255 @@
+//! Interactive shell on an open filesystem.
+//!
+//! The store is opened once for the whole session, which makes exploring a filesystem much
+//! faster than running one CLI command per operation. Line editing runs on a blocking thread,
+//! so filesystem operations (including those for tab-completion) go through a [`WnfsHandle`].
+
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+
+use futures::FutureExt;
+use rustyline::completion::{Completer, Pair};
+use rustyline::error::ReadlineError;
+use rustyline::history::DefaultHistory;
+use rustyline::{Context, Editor, Helper, Highlighter, Hinter, Validator};
+use tokio::runtime::Handle;
+
+use crate::fs::{DirEntry, EntryKind};
+use crate::handle::WnfsHandle;
+use crate::path::WnfsPath;
+
+/// Size of the ranges in which `cat` and `get` read files.
+const READ_CHUNK_SIZE: usize = 1024 * 1024;
+
+const HELP: &str = "\
+cd [path]             change the current directory
+ls [path]             list a directory
+cat <path>            print a file
+put <host> [path]     copy a host file into the filesystem
+get <path> [host]     copy a file to the host
+mkdir <path>          create a directory
+rm <path>             remove a file or directory
+pwd                   print the current directory
+help                  show this help
+exit                  leave the shell";
+
+/// Run the shell until the user exits or closes STDIN.
+///
+/// This blocks the current thread, so call it from [`tokio::task::spawn_blocking`].
+pub fn run(fs: WnfsHandle, rt: Handle) -> anyhow::Result<()> {
+    let cwd = Arc::new(Mutex::new(WnfsPath::root()));
+    let mut editor = Editor::<ShellHelper, DefaultHistory>::new()?;
+    editor.set_helper(Some(ShellHelper {
+        fs: fs.clone(),
+        rt: rt.clone(),
+        cwd: cwd.clone(),
+    }));
+    let shell = Shell { fs, rt, cwd };
+    loop {
+        let prompt = format!("{}> ", shell.cwd());
+        let line = match editor.readline(&prompt) {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(err) => return Err(err.into()),
+        };
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        editor.add_history_entry(line)?;
+        let args = line.split_whitespace().collect::<Vec<_>>();
+        match shell.exec(&args) {
+            Ok(true) => {}
+            Ok(false) => break,
+            Err(err) => eprintln!("error: {err}"),
+        }
+    }
+    Ok(())
+}
+
+struct Shell {
+    fs: WnfsHandle,
+    rt: Handle,
+    cwd: Arc<Mutex<WnfsPath>>,
+}
+
+impl Shell {
+    fn cwd(&self) -> WnfsPath {
+        self.cwd.lock().unwrap().clone()
+    }
+
+    /// Resolve a path relative to the current directory, handling `/`, `.` and `..`.
+    fn resolve(&self, path: Option<&str>) -> anyhow::Result<WnfsPath> {
+        self.cwd().resolve(path.unwrap_or(""))
+    }
+
+    fn stat(&self, path_segments: WnfsPath) -> anyhow::Result<DirEntry> {
+        self.rt
+            .block_on(
+                self.fs
+                    .call(move |fs| async move { fs.stat(&path_segments).await }.boxed_local()),
+            )?
+            .ok_or_else(|| anyhow::anyhow!("Not found"))
+    }
+
+    /// Copy a file to `writer`, in ranges so that large files do not have to fit in memory.
+    fn copy_file(&self, path_segments: WnfsPath, writer: &mut impl Write) -> anyhow::Result<()> {
+        let mut offset = 0;
+        loop {
+            let path_segments = path_segments.clone();
+            let chunk = self.rt.block_on(self.fs.call(move |fs| {
+                async move {
+                    fs.read_file_at(&path_segments, offset, READ_CHUNK_SIZE)
+                        .await
+                }
+                .boxed_local()
+            }))?;
+            writer.write_all(&chunk)?;
+            if chunk.len() < READ_CHUNK_SIZE {
+                return Ok(());
+            }
+            offset += chunk.len();
+        }
+    }
+
+    /// Run a command line. Returns `false` if the shell should exit.
+    fn exec(&self, args: &[&str]) -> anyhow::Result<bool> {
+        let arg = args.get(1).copied();
+        let path_segments = self.resolve(arg)?;
+        match args[0] {
+            "cd" => {
+                let entry = self.stat(path_segments.clone())?;
+                if entry.kind != EntryKind::Dir {
+                    anyhow::bail!("Not a directory");
+                }
+                *self.cwd.lock().unwrap() = path_segments;
+            }
+            "ls" => {
+                let entries = self.rt.block_on(self.fs.call(move |fs| {
+                    async move { fs.ls_entries(&path_segments).await }.boxed_local()
+                }))?;
+                for entry in entries {
+                    match entry.kind {
+                        EntryKind::Dir => println!("{}/", entry.name),
+                        EntryKind::File => println!("{}", entry.name),
+                    }
+                }
+            }
+            "cat" => {
+                require(arg)?;
+                self.copy_file(path_segments, &mut std::io::stdout().lock())?;
+            }
+            "put" => {
+                let host_path = require(arg)?;
+                let content = std::fs::read(host_path)?;
+                // Put into the current directory under the host file name by default.
+                let path_segments = match args.get(2) {
+                    Some(path) => self.resolve(Some(path))?,
+                    None => {
+                        let name = std::path::Path::new(host_path)
+                            .file_name()
+                            .ok_or_else(|| anyhow::anyhow!("Invalid file name"))?;
+                        self.cwd().join(name.to_string_lossy())
+                    }
+                };
+                self.rt.block_on(self.fs.call(move |fs| {
+                    async move { fs.write_file(&path_segments, content).await }.boxed_local()
+                }))?;
+            }
+            "get" => {
+                require(arg)?;
+                let host_path = match args.get(2) {
+                    Some(host_path) => host_path.to_string(),
+                    None => path_segments
+                        .file_name()
+                        .ok_or_else(|| anyhow::anyhow!("Not a file"))?
+                        .to_string(),
+                };
+                let mut file = std::fs::File::create(host_path)?;
+                self.copy_file(path_segments, &mut file)?;
+            }
+            "mkdir" => {
+                require(arg)?;
+                self.rt.block_on(
+                    self.fs.call(move |fs| {
+                        async move { fs.mkdir(&path_segments).await }.boxed_local()
+                    }),
+                )?;
+            }
+            "rm" => {
+                require(arg)?;
+                self.stat(path_segments.clone())?;
+                self.rt
+                    .block_on(self.fs.call(move |fs| {
+                        async move { fs.rm(&path_segments).await }.boxed_local()
+                    }))?;
+            }
+            "pwd" => println!("{}", self.cwd()),
+            "help" => println!("{HELP}"),
+            "exit" | "quit" => return Ok(false),
+            command => anyhow::bail!("Unknown command {command}, try help"),
+        }
+        Ok(true)
+    }
+}
+
+fn require(arg: Option<&str>) -> anyhow::Result<&str> {
+    arg.ok_or_else(|| anyhow::anyhow!("Missing path argument"))
+}
+
+#[derive(Helper, Hinter, Highlighter, Validator)]
+struct ShellHelper {
+    fs: WnfsHandle,
+    rt: Handle,
+    cwd: Arc<Mutex<WnfsPath>>,
+}
+
+impl Completer for ShellHelper {
+    type Candidate = Pair;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<Pair>)> {
+        // Only complete arguments, not the command itself.
+        let line = &line[..pos];
+        let Some(start) = line.rfind(' ').map(|i| i + 1) else {
+            return Ok((pos, vec![]));
+        };
+        let word = &line[start..];
+        let (dir, prefix) = match word.rfind('/') {
+            Some(i) => (&word[..=i], &word[i + 1..]),
+            None => ("", word),
+        };
+        let Ok(dir_segments) = self.cwd.lock().unwrap().resolve(dir) else {
+            return Ok((pos, vec![]));
+        };
+        let entries = self.rt.block_on(
+            self.fs
+                .call(move |fs| async move { fs.ls_entries(&dir_segments).await }.boxed_local()),
+        );
+        // Completion failures (e.g. a non-existing directory) just offer nothing.
+        let Ok(entries) = entries else {
+            return Ok((pos, vec![]));
+        };
+        let candidates = entries
+            .into_iter()
+            .filter(|entry| entry.name.starts_with(prefix))
+            .map(|entry| {
+                let suffix = if entry.kind == EntryKind::Dir {
+                    "/"
+                } else {
+                    ""
+                };
+                Pair {
+                    display: format!("{}{suffix}", entry.name),
+                    replacement: format!("{dir}{}{suffix}", entry.name),
+                }
+            })
+            .collect();
+        Ok((start, candidates))
+    }
+}
@@ -0,0 +1,221 @@
+//! Replication to a store on another machine over SSH.
+//!
+//! [`SshRemote`] runs `wnfs-experiments sync serve-stdio` on the other machine through the
+//! `ssh` command, so the usual SSH configuration (keys, agents, jump hosts) applies, and acts
+//! as a [`RemoteStore`] for [`crate::sync::push`] and [`crate::sync::pull`]. The other side
+//! answers with [`serve`].
+//!
+//! Requests and responses are frames of a big-endian `u32` length followed by the payload.
+//! Requests are a JSON [`Request`], followed by a frame with the block for [`Request::Put`].
+//! Responses start with a status byte, followed by the result or an error message.
+
+use async_trait::async_trait;
+use libipld::Cid;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::process::{Child, ChildStdin, ChildStdout, Command};
+use tokio::sync::Mutex;
+use tracing::debug;
+
+use crate::fs::private_root_alias;
+use crate::remote::RemoteStore;
+use crate::SqliteBlockStore;
+
+/// Largest frame either side accepts.
+const MAX_FRAME_SIZE: usize = 64 * 1024 * 1024;
+pub(crate) const STATUS_OK: u8 = 0;
+pub(crate) const STATUS_ERR: u8 = 1;
+
+#[derive(Debug, Serialize, Deserialize)]
+enum Request {
+    Has(Cid),
+    Get(Cid),
+    Put(Cid),
+    GetRoot(String),
+    PutRoot(String, Cid),
+}
+
+/// A remote store on another machine, reached by running a command over `ssh`.
+pub struct SshRemote {
+    _child: Child,
+    io: Mutex<(ChildStdin, ChildStdout)>,
+}
+
+impl SshRemote {
+    /// Connect to a store given as `[user@]host:/path/blocks.db`.
+    ///
+    /// `program` is the name of this binary on the other machine.
+    pub fn connect(target: &str, program: &str) -> anyhow::Result<Self> {
+        let (host, db_path) = target
+            .split_once(':')
+            .ok_or_else(|| anyhow::anyhow!("Expected [user@]host:/path/blocks.db"))?;
+        debug!("connecting to {host}");
+        let mut child = Command::new("ssh")
+            .arg(host)
+            .arg("--")
+            .arg(program)
+            .arg("--db-path")
+            .arg(shell_quote(db_path))
+            .arg("sync")
+            .arg("serve-stdio")
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|err| anyhow::anyhow!("Failed to run ssh: {err}"))?;
+        let stdin = child.stdin.take().expect("piped stdin");
+        let stdout = child.stdout.take().expect("piped stdout");
+        Ok(Self {
+            _child: child,
+            io: Mutex::new((stdin, stdout)),
+        })
+    }
+
+    async fn request(&self, request: &Request, block: Option<&[u8]>) -> anyhow::Result<Vec<u8>> {
+        let mut io = self.io.lock().await;
+        let (stdin, stdout) = &mut *io;
+        write_frame(stdin, &serde_json::to_vec(request)?).await?;
+        if let Some(block) = block {
+            write_frame(stdin, block).await?;
+        }
+        stdin.flush().await?;
+        let mut response = read_frame(stdout)
+            .await?
+            .ok_or_else(|| anyhow::anyhow!("Connection closed by the remote"))?;
+        match response.first() {
+            Some(&STATUS_OK) => {
+                response.remove(0);
+                Ok(response)
+            }
+            Some(&STATUS_ERR) => {
+                anyhow::bail!("Remote error: {}", String::from_utf8_lossy(&response[1..]))
+            }
+            _ => anyhow::bail!("Invalid response from the remote"),
+        }
+    }
+}
+
+#[async_trait]
+impl RemoteStore for SshRemote {
+    async fn has_block(&self, cid: &Cid) -> anyhow::Result<bool> {
+        Ok(self.request(&Request::Has(*cid), None).await? == [1])
+    }
+
+    async fn get_block(&self, cid: &Cid) -> anyhow::Result<Vec<u8>> {
+        self.request(&Request::Get(*cid), None).await
+    }
+
+    async fn put_block(&self, cid: &Cid, bytes: &[u8]) -> anyhow::Result<()> {
+        self.request(&Request::Put(*cid), Some(bytes)).await?;
+        Ok(())
+    }
+
+    async fn get_root(&self, name: &str) -> anyhow::Result<Option<Cid>> {
+        let bytes = self
+            .request(&Request::GetRoot(name.to_string()), None)
+            .await?;
+        if bytes.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(Cid::try_from(bytes)?))
+    }
+
+    async fn put_root(&self, name: &str, cid: &Cid) -> anyhow::Result<()> {
+        self.request(&Request::PutRoot(name.to_string(), *cid), None)
+            .await?;
+        Ok(())
+    }
+}
+
+/// Answer requests of an [`SshRemote`] until the input is closed.
+pub async fn serve(
+    store: &SqliteBlockStore,
+    mut reader: impl AsyncRead + Unpin,
+    mut writer: impl AsyncWrite + Unpin,
+) -> anyhow::Result<()> {
+    while let Some(request) = read_frame(&mut reader).await? {
+        let request: Request = serde_json::from_slice(&request)?;
+        // Blocks are read before answering, so that the stream stays in sync on errors.
+        let block = match request {
+            Request::Put(_) => Some(
+                read_frame(&mut reader)
+                    .await?
+                    .ok_or_else(|| anyhow::anyhow!("Missing block"))?,
+            ),
+            _ => None,
+        };
+        let response = match respond(store, request, block).await {
+            Ok(mut response) => {
+                response.insert(0, STATUS_OK);
+                response
+            }
+            Err(err) => {
+                let mut response = vec![STATUS_ERR];
+                response.extend_from_slice(err.to_string().as_bytes());
+                response
+            }
+        };
+        write_frame(&mut writer, &response).await?;
+        writer.flush().await?;
+    }
+    Ok(())
+}
+
+async fn respond(
+    store: &SqliteBlockStore,
+    request: Request,
+    block: Option<Vec<u8>>,
+) -> anyhow::Result<Vec<u8>> {
+    let response = match request {
+        Request::Has(cid) => vec![store.get_block_if_exists(&cid).await?.is_some() as u8],
+        Request::Get(cid) => store
+            .get_block_if_exists(&cid)
+            .await?
+            .ok_or_else(|| anyhow::anyhow!("Block {cid} not found"))?,
+        Request::Put(cid) => {
+            store
+                .put_block_with_cid(&cid, block.unwrap_or_default())
+                .await?;
+            vec![]
+        }
+        Request::GetRoot(name) => match store.resolve_alias(&private_root_alias(&name)).await? {
+            Some(cid) => cid.to_bytes(),
+            None => vec![],
+        },
+        Request::PutRoot(name, cid) => {
+            store.alias(&private_root_alias(&name), Some(&cid)).await?;
+            vec![]
+        }
+    };
+    Ok(response)
+}
+
+pub(crate) async fn write_frame(
+    writer: &mut (impl AsyncWrite + Unpin),
+    bytes: &[u8],
+) -> anyhow::Result<()> {
+    writer.write_u32(bytes.len() as u32).await?;
+    writer.write_all(bytes).await?;
+    Ok(())
+}
+
+pub(crate) async fn read_frame(
+    reader: &mut (impl AsyncRead + Unpin),
+) -> anyhow::Result<Option<Vec<u8>>> {
+    let len = match reader.read_u32().await {
+        Ok(len) => len as usize,
+        Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+    if len > MAX_FRAME_SIZE {
+        anyhow::bail!("Frame of {len} bytes is too large");
+    }
+    let mut bytes = vec![0; len];
+    reader.read_exact(&mut bytes).await?;
+    Ok(Some(bytes))
+}
+
+/// Quote an argument for the remote shell, which `ssh` passes the command line to.
+fn shell_quote(arg: &str) -> String {
+    format!("'{}'", arg.replace('\'', r"'\''"))
+}
@@ -0,0 +1,178 @@
+//! Storage usage of a block store, for `stats`.
+//!
+//! [`store_stats`] splits the blocks of a store into those that are reachable from an alias
+//! and the garbage that `gc` deletes, and lists for every alias the blocks that no other alias
+//! reaches, i.e. what deleting the filesystem or snapshot would reclaim.
+//!
+//! [`dedup_report`] compares the content of a filesystem with what it takes in the store.
+//! Every file is encrypted with its own key, so files with the same content are stored once
+//! per copy, and the forest keeps all earlier revisions until the filesystem is rewritten.
+
+use std::collections::{HashMap, HashSet};
+
+use libipld::Cid;
+use serde::Serialize;
+
+use crate::fs::{EntryKind, OnProgress, Progress, Wnfs};
+use crate::SqliteBlockStore;
+
+/// Number and total size of a set of blocks.
+#[derive(Debug, Default, Clone, Copy, Serialize)]
+pub struct Usage {
+    pub blocks: u64,
+    pub bytes: u64,
+}
+
+impl Usage {
+    fn add(&mut self, bytes: u64) {
+        self.blocks += 1;
+        self.bytes += bytes;
+    }
+}
+
+/// Usage of the whole store, as returned by [`store_stats`].
+#[derive(Debug, Clone, Serialize)]
+pub struct StoreStats {
+    pub total: Usage,
+    /// Blocks reachable from at least one alias.
+    pub live: Usage,
+    /// Blocks that garbage collection would delete.
+    pub garbage: Usage,
+    pub roots: Vec<RootStats>,
+}
+
+/// Usage of the blocks below an alias.
+#[derive(Debug, Clone, Serialize)]
+pub struct RootStats {
+    pub alias: String,
+    pub reachable: Usage,
+    /// Blocks that no other alias reaches, deleted by `gc` once the alias is removed.
+    pub exclusive: Usage,
+}
+
+/// Content of a filesystem compared with its size in the store, as returned by
+/// [`dedup_report`].
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct DedupReport {
+    pub files: u64,
+    /// Sum of the content sizes of all files.
+    pub logical_bytes: u64,
+    /// Files with the same content as another file, which are stored again.
+    pub duplicate_files: u64,
+    pub duplicate_bytes: u64,
+    /// Size of all blocks reachable from the root, including earlier revisions.
+    pub stored_bytes: u64,
+    /// Size of a copy of the current state without earlier revisions.
+    pub current_bytes: u64,
+    /// Stored bytes that only earlier revisions need.
+    pub history_bytes: u64,
+}
+
+/// Analyze which blocks of a store are reachable from which aliases.
+pub async fn store_stats(store: &SqliteBlockStore) -> anyhow::Result<StoreStats> {
+    let mut sizes = HashMap::new();
+    let mut total = Usage::default();
+    for cid in store.block_cids().await? {
+        if let Some(block) = store.get_block_if_exists(&cid).await? {
+            total.add(block.len() as u64);
+            sizes.insert(cid, block.len() as u64);
+        }
+    }
+
+    let mut reachable: Vec<(String, Vec<Cid>)> = vec![];
+    let mut references: HashMap<Cid, usize> = HashMap::new();
+    for (alias, root) in store.aliases_with_prefix("").await? {
+        let cids = store.dag_cids(&root).await?;
+        for cid in &cids {
+            *references.entry(*cid).or_default() += 1;
+        }
+        reachable.push((alias, cids));
+    }
+
+    let mut roots = vec![];
+    for (alias, cids) in reachable {
+        let mut stats = RootStats {
+            alias,
+            reachable: Usage::default(),
+            exclusive: Usage::default(),
+        };
+        for cid in cids {
+            let Some(size) = sizes.get(&cid) else {
+                continue;
+            };
+            stats.reachable.add(*size);
+            if references[&cid] == 1 {
+                stats.exclusive.add(*size);
+            }
+        }
+        roots.push(stats);
+    }
+    roots.sort_by(|a, b| b.reachable.bytes.cmp(&a.reachable.bytes));
+
+    let mut live = Usage::default();
+    let mut garbage = Usage::default();
+    for (cid, size) in sizes {
+        if references.contains_key(&cid) {
+            live.add(size);
+        } else {
+            garbage.add(size);
+        }
+    }
+    Ok(StoreStats {
+        total,
+        live,
+        garbage,
+        roots,
+    })
+}
+
+/// Read every file of a filesystem to find duplicate content and the size of its history.
+///
+/// The current state is copied into an in-memory store to measure what it takes without
+/// earlier revisions, so this reads the whole filesystem once.
+pub async fn dedup_report(fs: &Wnfs, on_progress: OnProgress<'_>) -> anyhow::Result<DedupReport> {
+    let mut paths = vec![];
+    fs.walk(&[], &mut |path, entry| {
+        paths.push((path.to_vec(), entry.kind));
+        Ok(())
+    })
+    .await?;
+
+    let mut copy =
+        Wnfs::init_in_store(SqliteBlockStore::memory()?, fs.name().to_string(), None).await?;
+    let mut report = DedupReport::default();
+    let mut seen = HashSet::new();
+    let mut progress = Progress {
+        total: Some(paths.len() as u64),
+        ..Default::default()
+    };
+    for (path, kind) in paths {
+        progress.items += 1;
+        progress.path = Some(path.join("/"));
+        on_progress(&progress);
+        if kind == EntryKind::Dir {
+            copy.mkdir(&path).await?;
+            continue;
+        }
+        let content = fs.read_file(&path).await?;
+        let size = content.len() as u64;
+        report.files += 1;
+        report.logical_bytes += size;
+        progress.bytes += size;
+        if size > 0 && !seen.insert(blake3::hash(&content)) {
+            report.duplicate_files += 1;
+            report.duplicate_bytes += size;
+        }
+        copy.write_file(&path, content).await?;
+    }
+    copy.flush().await?;
+
+    if let Some(root) = fs.root_cid().await? {
+        report.stored_bytes = fs.store().dag_size(&root).await?;
+    }
+    if let Some(root) = copy.root_cid().await? {
+        report.current_bytes = copy.store().dag_size(&root).await?;
+    }
+    report.history_bytes = report.stored_bytes.saturating_sub(report.current_bytes);
+    Ok(report)
+}
@@ -0,0 +1,184 @@
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::rc::Rc;
+
+use async_trait::async_trait;
+use libipld::cid::Version;
+use libipld::store::StoreParams;
+use libipld::{Block, Cid, IpldCodec};
+use multihash::{Code, MultihashDigest};
+use serde::Serialize;
+use wnfs_common::BlockStore;
+
+/// Default store parameters.
+#[derive(Clone, Debug, Default)]
+pub struct DefaultParams;
+
+impl StoreParams for DefaultParams {
+    const MAX_BLOCK_SIZE: usize = usize::MAX;
+    type Codecs = libipld::IpldCodec;
+    type Hashes = libipld::multihash::Code;
+}
+
+/// The store of [`crate::fs::Wnfs`] when no store is named: SQLite where available, memory
+/// otherwise.
+#[cfg(feature = "native")]
+pub type DefaultStore = crate::SqliteBlockStore;
+#[cfg(not(feature = "native"))]
+pub type DefaultStore = MemoryStore;
+
+/// A block store with aliases, which are named pointers to root blocks.
+///
+/// Blocks that are not reachable from an alias may be garbage collected.
+#[async_trait(?Send)]
+pub trait Store: BlockStore + Clone {
+    async fn resolve_alias(&self, name: &str) -> anyhow::Result<Option<Cid>>;
+
+    /// Point an alias to a CID, or remove it if `cid` is `None`.
+    async fn alias(&self, name: &str, cid: Option<&Cid>) -> anyhow::Result<()>;
+
+    /// List all aliases starting with `prefix`, with the prefix stripped.
+    async fn aliases_with_prefix(&self, prefix: &str) -> anyhow::Result<Vec<(String, Cid)>>;
+
+    /// Wait until all blocks that were put before are durable.
+    ///
+    /// Does nothing by default, for stores that persist every block before `put_block`
+    /// returns, in order (like SQLite, which commits a transaction per block).
+    async fn sync(&self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    async fn get_from_alias(&self, name: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        match self.resolve_alias(name).await? {
+            None => Ok(None),
+            Some(cid) => Ok(Some(self.get_block(&cid).await?.into_owned())),
+        }
+    }
+
+    async fn put_with_alias(
+        &mut self,
+        name: &str,
+        blob: Vec<u8>,
+        codec: IpldCodec,
+    ) -> anyhow::Result<Cid> {
+        let cid = self.put_block(blob, codec).await?;
+        self.alias(name, Some(&cid)).await?;
+        Ok(cid)
+    }
+
+    async fn put_serializable_with_alias<V: Serialize>(
+        &mut self,
+        name: &str,
+        value: &V,
+    ) -> anyhow::Result<Cid> {
+        let bytes = serde_ipld_dagcbor::to_vec(value)?;
+        self.put_with_alias(name, bytes, IpldCodec::DagCbor).await
+    }
+
+    /// Sum of the sizes of all blocks in the store that are reachable from `root`.
+    async fn dag_size(&self, root: &Cid) -> anyhow::Result<u64> {
+        let mut size = 0;
+        walk_dag(self, root, &mut |_cid, block| {
+            size += block.map_or(0, |block| block.len() as u64);
+        })
+        .await?;
+        Ok(size)
+    }
+
+    /// List blocks that are referenced from the DAG below `root` but not present in the store.
+    async fn missing_blocks(&self, root: &Cid) -> anyhow::Result<Vec<Cid>> {
+        let mut missing = vec![];
+        walk_dag(self, root, &mut |cid, block| {
+            if block.is_none() {
+                missing.push(*cid);
+            }
+        })
+        .await?;
+        Ok(missing)
+    }
+}
+
+/// Visit all blocks reachable from `root`, with `None` for blocks that are not in the store.
+async fn walk_dag<S: BlockStore + ?Sized>(
+    store: &S,
+    root: &Cid,
+    visit: &mut dyn FnMut(&Cid, Option<&[u8]>),
+) -> anyhow::Result<()> {
+    let mut seen = HashSet::new();
+    let mut queue = vec![*root];
+    while let Some(cid) = queue.pop() {
+        if !seen.insert(cid) {
+            continue;
+        }
+        let Ok(bytes) = store.get_block(&cid).await else {
+            visit(&cid, None);
+            continue;
+        };
+        visit(&cid, Some(&bytes));
+        let block = Block::<DefaultParams>::new_unchecked(cid, bytes.into_owned());
+        block.references(&mut queue)?;
+    }
+    Ok(())
+}
+
+/// A store that keeps blocks and aliases in memory, e.g. for tests.
+///
+/// Clones share the same blocks.
+#[derive(Clone, Debug, Default)]
+pub struct MemoryStore {
+    blocks: Rc<RefCell<HashMap<Cid, Vec<u8>>>>,
+    aliases: Rc<RefCell<BTreeMap<String, Cid>>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait(?Send)]
+impl BlockStore for MemoryStore {
+    async fn get_block<'a>(&'a self, cid: &Cid) -> anyhow::Result<Cow<'a, Vec<u8>>> {
+        let block = self
+            .blocks
+            .borrow()
+            .get(cid)
+            .cloned()
+            .ok_or_else(|| anyhow::anyhow!("Block not found"))?;
+        Ok(Cow::Owned(block))
+    }
+
+    async fn put_block(&mut self, bytes: Vec<u8>, codec: IpldCodec) -> anyhow::Result<Cid> {
+        let hash = Code::Blake3_256.digest(&bytes);
+        let cid = Cid::new(Version::V1, codec.into(), hash)?;
+        self.blocks.borrow_mut().insert(cid, bytes);
+        Ok(cid)
+    }
+}
+
+#[async_trait(?Send)]
+impl Store for MemoryStore {
+    async fn resolve_alias(&self, name: &str) -> anyhow::Result<Option<Cid>> {
+        Ok(self.aliases.borrow().get(name).copied())
+    }
+
+    async fn alias(&self, name: &str, cid: Option<&Cid>) -> anyhow::Result<()> {
+        let mut aliases = self.aliases.borrow_mut();
+        match cid {
+            Some(cid) => aliases.insert(name.to_string(), *cid),
+            None => aliases.remove(name),
+        };
+        Ok(())
+    }
+
+    async fn aliases_with_prefix(&self, prefix: &str) -> anyhow::Result<Vec<(String, Cid)>> {
+        let aliases = self
+            .aliases
+            .borrow()
+            .iter()
+            .filter_map(|(name, cid)| Some((name.strip_prefix(prefix)?.to_string(), *cid)))
+            .collect();
+        Ok(aliases)
+    }
+}
@@ -0,0 +1,361 @@
+//! Transfer of filesystems between a local store and a remote store.
+//!
+//! Only blocks that are missing on the receiving side are transferred. The root record is
+//! updated last, so that an interrupted transfer never leaves a root pointing to missing
+//! blocks.
+//!
+//! [`push`] and [`pull`] replace one side with the other, while [`sync_with`] merges the
+//! changes of both sides since they were last synced.
+
+use std::collections::HashSet;
+
+use libipld::Cid;
+use serde::Serialize;
+use wnfs_common::BlockStore;
+
+use crate::fs::{private_root_alias, ChangeKind, EntryKind, OnProgress, Progress, Wnfs};
+use crate::path::WnfsPath;
+use crate::remote::RemoteStore;
+use crate::ucan::WriteAuth;
+use crate::SqliteBlockStore;
+
+const SYNC_BASE_PREFIX: &str = "sync-base:";
+/// Blocks per request to the remote.
+const BATCH_SIZE: usize = 256;
+
+/// Blocks and bytes transferred by [`push`] or [`pull`].
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SyncStats {
+    pub blocks: u64,
+    pub bytes: u64,
+}
+
+/// Upload the blocks of a named filesystem that are missing on the remote, then its root.
+pub async fn push(
+    store: &SqliteBlockStore,
+    remote: &dyn RemoteStore,
+    name: &str,
+) -> anyhow::Result<SyncStats> {
+    push_with_progress(store, remote, name, &|_| {}).await
+}
+
+/// Like [`push`], reporting each checked block.
+pub async fn push_with_progress(
+    store: &SqliteBlockStore,
+    remote: &dyn RemoteStore,
+    name: &str,
+    on_progress: OnProgress<'_>,
+) -> anyhow::Result<SyncStats> {
+    let root = store
+        .resolve_alias(&private_root_alias(name))
+        .await?
+        .ok_or_else(|| anyhow::anyhow!("Filesystem {name} does not exist"))?;
+    let mut stats = SyncStats::default();
+    let cids = store.dag_cids(&root).await?;
+    let mut progress = Progress {
+        total: Some(cids.len() as u64),
+        ..Default::default()
+    };
+    for batch in cids.chunks(BATCH_SIZE) {
+        let mut blocks = vec![];
+        for cid in remote.missing_blocks(batch).await? {
+            let bytes = store.get_block(&cid).await?.into_owned();
+            stats.blocks += 1;
+            stats.bytes += bytes.len() as u64;
+            blocks.push((cid, bytes));
+        }
+        remote.put_blocks(&blocks).await?;
+        progress.items += batch.len() as u64;
+        progress.bytes = stats.bytes;
+        on_progress(&progress);
+    }
+    remote.put_root(name, &root).await?;
+    Ok(stats)
+}
+
+/// Download the blocks of a named filesystem that are missing locally, then set its root.
+///
+/// Fails if the filesystem exists locally with a different root, unless `force` is set.
+pub async fn pull(
+    store: &SqliteBlockStore,
+    remote: &dyn RemoteStore,
+    name: &str,
+    force: bool,
+) -> anyhow::Result<SyncStats> {
+    pull_with_progress(store, remote, name, force, &|_| {}).await
+}
+
+/// Like [`pull`], reporting each downloaded block.
+///
+/// The total is not known in advance, as the DAG is discovered while downloading.
+pub async fn pull_with_progress(
+    store: &SqliteBlockStore,
+    remote: &dyn RemoteStore,
+    name: &str,
+    force: bool,
+    on_progress: OnProgress<'_>,
+) -> anyhow::Result<SyncStats> {
+    let root = remote
+        .get_root(name)
+        .await?
+        .ok_or_else(|| anyhow::anyhow!("Filesystem {name} does not exist on the remote"))?;
+    let alias = private_root_alias(name);
+    match store.resolve_alias(&alias).await? {
+        Some(local) if local == root => return Ok(SyncStats::default()),
+        Some(_) if !force => anyhow::bail!(
+            "Local filesystem {name} differs from the remote, use --force to replace it"
+        ),
+        _ => {}
+    }
+    let stats = fetch(store, remote, &root, on_progress).await?;
+    store.alias(&alias, Some(&root)).await?;
+    Ok(stats)
+}
+
+/// Download the blocks below a root record that are missing locally, without setting any
+/// root.
+pub async fn fetch(
+    store: &SqliteBlockStore,
+    remote: &dyn RemoteStore,
+    root: &Cid,
+    on_progress: OnProgress<'_>,
+) -> anyhow::Result<SyncStats> {
+    let mut stats = SyncStats::default();
+    let mut missing = store.missing_blocks(root).await?;
+    while !missing.is_empty() {
+        for batch in missing.chunks(BATCH_SIZE) {
+            let blocks = remote.get_blocks(batch).await?;
+            for (cid, bytes) in batch.iter().zip(blocks) {
+                stats.blocks += 1;
+                stats.bytes += bytes.len() as u64;
+                store.put_block_with_cid(cid, bytes).await?;
+            }
+            on_progress(&Progress {
+                items: stats.blocks,
+                bytes: stats.bytes,
+                ..Default::default()
+            });
+        }
+        missing = store.missing_blocks(root).await?;
+    }
+    Ok(stats)
+}
+
+/// How [`sync_with`] resolves files that were changed on both sides.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ConflictPolicy {
+    /// Keep the version that was modified last.
+    NewestWins,
+    /// Keep the local version and store the remote one next to it as a conflict copy.
+    KeepBoth,
+}
+
+/// How a conflict was resolved by [`sync_with`].
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "snake_case", tag = "resolution")]
+pub enum Resolution {
+    KeptLocal,
+    TookRemote,
+    /// The remote version was written to `copy`.
+    KeptBoth {
+        copy: WnfsPath,
+    },
+}
+
+/// A path that was changed on both sides since the last sync.
+#[derive(Debug, Clone, Serialize)]
+pub struct Conflict {
+    pub path: WnfsPath,
+    #[serde(flatten)]
+    pub resolution: Resolution,
+}
+
+/// Result of [`sync_with`].
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct SyncReport {
+    #[serde(skip)]
+    pub pulled: SyncStats,
+    #[serde(skip)]
+    pub pushed: SyncStats,
+    /// Number of remote changes applied to the local filesystem.
+    pub applied: usize,
+    pub conflicts: Vec<Conflict>,
+}
+
+fn sync_base_alias(name: &str, remote_id: &str) -> String {
+    format!("{SYNC_BASE_PREFIX}{name}:{remote_id}")
+}
+
+/// Synchronize a filesystem with its copy on a remote in both directions.
+///
+/// Changes since the last sync with the same `remote_id` (e.g. the remote URL) are merged:
+/// changes made on only one side are applied to the other side, and paths changed on both
+/// sides are resolved with `policy`. Modifications win over removals. Before the first sync,
+/// nothing is removed on either side.
+///
+/// The merged state is pushed to the remote. Other devices that synced with the remote in the
+/// meantime are not detected, so the last push wins for them.
+///
+/// Pushed revisions are signed with `auth`, and remote revisions are only applied if `auth`
+/// accepts them (see [`crate::ucan`]).
+pub async fn sync_with(
+    fs: &mut Wnfs<SqliteBlockStore>,
+    remote: &dyn RemoteStore,
+    remote_id: &str,
+    policy: ConflictPolicy,
+    auth: &WriteAuth,
+) -> anyhow::Result<SyncReport> {
+    let name = fs.name().to_string();
+    let store = fs.store().clone();
+    fs.flush().await?;
+    let local = fs
+        .root_cid()
+        .await?
+        .ok_or_else(|| anyhow::anyhow!("Filesystem {name} does not exist"))?;
+    let base_alias = sync_base_alias(&name, remote_id);
+    let base = store.resolve_alias(&base_alias).await?;
+    let mut report = SyncReport::default();
+    let remote_root = remote.get_root(&name).await?;
+    match remote_root {
+        Some(remote_root) if remote_root == local => {}
+        // Only changed locally, or the remote is new.
+        None => report.pushed = push_signed(&store, remote, &name, auth).await?,
+        Some(remote_root) if Some(remote_root) == base => {
+            report.pushed = push_signed(&store, remote, &name, auth).await?;
+        }
+        // Only changed on the remote.
+        Some(remote_root) if Some(local) == base => {
+            report.pulled = fetch(&store, remote, &remote_root, &|_| {}).await?;
+            let changes = fs.diff("current", &remote_root.to_string()).await?;
+            let changed: Vec<_> = changes
+                .into_iter()
+                .map(|change| change.path.into_segments())
+                .collect();
+            auth.check(remote, &name, &remote_root, &changed).await?;
+            report.applied = changed.len();
+            fs.restore_root(&remote_root).await?;
+        }
+        Some(remote_root) => {
+            report.pulled = fetch(&store, remote, &remote_root, &|_| {}).await?;
+            let base_rev = base.map_or("current".to_string(), |base| base.to_string());
+            let changed: Vec<_> = fs
+                .diff(&base_rev, &remote_root.to_string())
+                .await?
+                .into_iter()
+                // Without a base, paths that only exist locally are kept.
+                .filter(|change| base.is_some() || change.kind != ChangeKind::Removed)
+                .map(|change| change.path.into_segments())
+                .collect();
+            auth.check(remote, &name, &remote_root, &changed).await?;
+            merge(fs, base.as_ref(), &remote_root, policy, &mut report).await?;
+            fs.flush().await?;
+            report.pushed = push_signed(&store, remote, &name, auth).await?;
+        }
+    }
+    let root = fs.root_cid().await?;
+    store.alias(&base_alias, root.as_ref()).await?;
+    Ok(report)
+}
+
+async fn push_signed(
+    store: &SqliteBlockStore,
+    remote: &dyn RemoteStore,
+    name: &str,
+    auth: &WriteAuth,
+) -> anyhow::Result<SyncStats> {
+    let stats = push(store, remote, name).await?;
+    if let Some(root) = store.resolve_alias(&private_root_alias(name)).await? {
+        auth.sign(store, remote, name, &root).await?;
+    }
+    Ok(stats)
+}
+
+/// Apply the remote changes since `base` to the current state.
+async fn merge(
+    fs: &mut Wnfs<SqliteBlockStore>,
+    base: Option<&Cid>,
+    remote_root: &Cid,
+    policy: ConflictPolicy,
+    report: &mut SyncReport,
+) -> anyhow::Result<()> {
+    let remote_rev = remote_root.to_string();
+    let (remote_changes, local_changes) = match base {
+        Some(base) => {
+            let base = base.to_string();
+            let remote_changes = fs.diff(&base, &remote_rev).await?;
+            let local_changes = fs.diff(&base, "current").await?;
+            let local_paths = local_changes.into_iter().map(|change| change.path);
+            (remote_changes, local_paths.collect::<HashSet<_>>())
+        }
+        // Without a common base, paths that only exist locally are kept and paths that exist
+        // on both sides but differ are conflicts.
+        None => {
+            let changes = fs.diff("current", &remote_rev).await?;
+            let local_paths = changes
+                .iter()
+                .filter(|change| change.kind == ChangeKind::Modified)
+                .map(|change| change.path.clone())
+                .collect();
+            let remote_changes = changes
+                .into_iter()
+                .filter(|change| change.kind != ChangeKind::Removed)
+                .collect();
+            (remote_changes, local_paths)
+        }
+    };
+
+    for change in remote_changes {
+        let path = change.path;
+        if change.kind == ChangeKind::Removed {
+            // Keep directories in which something changed locally.
+            if local_changes.iter().any(|local| local.starts_with(&path)) {
+                if local_changes.contains(&path) {
+                    report.conflicts.push(Conflict {
+                        path,
+                        resolution: Resolution::KeptLocal,
+                    });
+                }
+            } else if fs.get_node(&path).await?.is_some() {
+                fs.rm(&path).await?;
+                report.applied += 1;
+            }
+            continue;
+        }
+        if change.entry_kind == EntryKind::Dir {
+            if !matches!(fs.stat(&path).await?, Some(entry) if entry.kind == EntryKind::Dir) {
+                fs.mkdir(&path).await?;
+                report.applied += 1;
+            }
+            continue;
+        }
+        let content = fs.read_file_in_revision(&remote_rev, &path).await?;
+        let local = match fs.stat(&path).await? {
+            // A directory is never replaced by a file, even if it did not change.
+            Some(local) if local_changes.contains(&path) || local.kind == EntryKind::Dir => local,
+            _ => {
+                fs.write_file(&path, content).await?;
+                report.applied += 1;
+                continue;
+            }
+        };
+        if local.kind == EntryKind::File && fs.read_file(&path).await? == content {
+            continue;
+        }
+        let remote = fs.stat_in_revision(&remote_rev, &path).await?;
+        let remote_is_newer = remote.and_then(|entry| entry.modified) > local.modified;
+        let resolution = match policy {
+            ConflictPolicy::NewestWins if local.kind == EntryKind::File && remote_is_newer => {
+                fs.write_file(&path, content).await?;
+                Resolution::TookRemote
+            }
+            ConflictPolicy::NewestWins if local.kind == EntryKind::File => Resolution::KeptLocal,
+            _ => {
+                let copy = fs.conflict_copy_path(&path).await?;
+                fs.write_file(&copy, content).await?;
+                Resolution::KeptBoth { copy }
+            }
+        };
+        report.conflicts.push(Conflict { path, resolution });
+    }
+    Ok(())
+}
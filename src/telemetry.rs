@@ -0,0 +1,134 @@
+//! Metrics and traces for monitoring long-running mounts and servers.
+//!
+//! Metrics are recorded through the `metrics` facade everywhere in the crate and are only
+//! collected once [`serve_metrics`] installed the Prometheus exporter.
+//!
+//! With the `otlp` feature, [`otlp_layer`] exports the spans of FUSE operations, filesystem
+//! operations and store I/O to an OpenTelemetry collector (e.g. Jaeger or Tempo).
+
+use std::net::SocketAddr;
+use std::time::Instant;
+
+use metrics::{describe_counter, describe_gauge, describe_histogram, histogram, Unit};
+use metrics_exporter_prometheus::PrometheusBuilder;
+
+/// Serve metrics for Prometheus at `http://<addr>/metrics`.
+///
+/// Must be called from within a tokio runtime.
+pub fn serve_metrics(addr: SocketAddr) -> anyhow::Result<()> {
+    PrometheusBuilder::new()
+        .with_http_listener(addr)
+        .install()?;
+    describe_histogram!(
+        "wnfs_fuse_op_duration_seconds",
+        Unit::Seconds,
+        "Duration of FUSE operations, by op"
+    );
+    describe_counter!(
+        "wnfs_fuse_node_lookups_total",
+        "Nodes resolved by FUSE operations, by whether the node cache had them"
+    );
+    describe_gauge!(
+        "wnfs_fuse_cache_bytes",
+        Unit::Bytes,
+        "Estimated memory of the caches of a mount, by cache"
+    );
+    describe_counter!("wnfs_store_reads_total", "Blocks read from the local store");
+    describe_counter!(
+        "wnfs_store_read_bytes_total",
+        Unit::Bytes,
+        "Bytes of blocks read from the local store"
+    );
+    describe_counter!(
+        "wnfs_store_writes_total",
+        "Blocks written to the local store"
+    );
+    describe_counter!(
+        "wnfs_store_written_bytes_total",
+        Unit::Bytes,
+        "Bytes of blocks written to the local store"
+    );
+    describe_counter!(
+        "wnfs_store_misses_total",
+        "Blocks that were not in the local store, by whether a resolver fetched them"
+    );
+    describe_counter!(
+        "wnfs_store_cache_lookups_total",
+        "Blocks read from the store, by whether the block cache had them"
+    );
+    describe_gauge!(
+        "wnfs_store_cache_bytes",
+        Unit::Bytes,
+        "Bytes of blocks in the block cache of the store"
+    );
+    describe_counter!("wnfs_flushes_total", "Revisions committed to the store");
+    describe_counter!(
+        "wnfs_read_bytes_total",
+        Unit::Bytes,
+        "File content bytes read"
+    );
+    describe_counter!(
+        "wnfs_written_bytes_total",
+        Unit::Bytes,
+        "File content bytes written"
+    );
+    Ok(())
+}
+
+/// A tracing layer that exports spans over OTLP, if `OTEL_EXPORTER_OTLP_ENDPOINT` is set.
+///
+/// Spans are filtered by `WNFS_TRACE` (like `RUST_LOG`), which defaults to debug spans of
+/// this crate. Call [`shutdown_otlp`] before exiting to send the remaining spans.
+#[cfg(feature = "otlp")]
+pub fn otlp_layer<S>() -> anyhow::Result<Option<impl tracing_subscriber::Layer<S>>>
+where
+    S: tracing::Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>,
+{
+    use opentelemetry::sdk::{trace, Resource};
+    use opentelemetry::KeyValue;
+    use tracing_subscriber::{EnvFilter, Layer};
+
+    if std::env::var_os("OTEL_EXPORTER_OTLP_ENDPOINT").is_none() {
+        return Ok(None);
+    }
+    // The endpoint and headers are read from the standard OTEL_EXPORTER_OTLP_* variables.
+    let resource = Resource::new([KeyValue::new("service.name", "wnfs-fuse")]);
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(opentelemetry_otlp::new_exporter().tonic())
+        .with_trace_config(trace::config().with_resource(resource))
+        .install_batch(opentelemetry::runtime::Tokio)?;
+    let filter = EnvFilter::try_from_env("WNFS_TRACE")
+        .unwrap_or_else(|_| EnvFilter::new("wnfs_experiments=debug"));
+    let layer = tracing_opentelemetry::layer()
+        .with_tracer(tracer)
+        .with_filter(filter);
+    Ok(Some(layer))
+}
+
+/// Send the spans that are not exported yet.
+#[cfg(feature = "otlp")]
+pub fn shutdown_otlp() {
+    opentelemetry::global::shutdown_tracer_provider();
+}
+
+/// Records the duration of a FUSE operation when dropped.
+pub(crate) struct OpTimer {
+    op: &'static str,
+    start: Instant,
+}
+
+impl OpTimer {
+    pub fn new(op: &'static str) -> Self {
+        Self {
+            op,
+            start: Instant::now(),
+        }
+    }
+}
+
+impl Drop for OpTimer {
+    fn drop(&mut self) {
+        histogram!("wnfs_fuse_op_duration_seconds", self.start.elapsed(), "op" => self.op);
+    }
+}
@@ -0,0 +1,234 @@
+//! Real FUSE mounts for end-to-end tests.
+//!
+//! [`TestMount`] mounts a filesystem in an in-memory store on a temporary directory, so tests
+//! can exercise the FUSE handlers through `std::fs` and check the result:
+//!
+//! ```no_run
+//! use wnfs_experiments::testing::TestMount;
+//!
+//! let mount = TestMount::builder()
+//!     .file("docs/hello.txt", b"hello")
+//!     .mount()
+//!     .unwrap();
+//! mount.assert_dir("docs", &["hello.txt"]);
+//! mount.assert_file("docs/hello.txt", b"hello");
+//! ```
+//!
+//! The mount is unmounted when the guard is dropped. Mounting needs `/dev/fuse` and
+//! `fusermount`, which are often missing in containers.
+
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use futures::executor::block_on;
+
+use crate::fs::Wnfs;
+use crate::fuse::{self, MountOptions};
+use crate::SqliteBlockStore;
+
+/// Name of the mounted filesystem in the store.
+pub const FS_NAME: &str = "test";
+/// How long to wait for the mount to appear.
+const MOUNT_TIMEOUT: Duration = Duration::from_secs(5);
+
+static MOUNT_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Content and options of a [`TestMount`].
+#[derive(Debug, Default)]
+pub struct TestMountBuilder {
+    /// Paths with the content of files, or `None` for directories.
+    entries: Vec<(Vec<String>, Option<Vec<u8>>)>,
+    options: MountOptions,
+}
+
+impl TestMountBuilder {
+    /// Add a file, creating its parent directories.
+    pub fn file(mut self, path: &str, content: &[u8]) -> Self {
+        self.entries.push((segments(path), Some(content.to_vec())));
+        self
+    }
+
+    /// Add a directory, creating its parent directories.
+    pub fn dir(mut self, path: &str) -> Self {
+        self.entries.push((segments(path), None));
+        self
+    }
+
+    pub fn options(mut self, options: MountOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Create the filesystem and mount it, waiting until the mount is ready.
+    pub fn mount(self) -> anyhow::Result<TestMount> {
+        let id = MOUNT_COUNT.fetch_add(1, Ordering::Relaxed);
+        let mountpoint =
+            std::env::temp_dir().join(format!("wnfs-fuse-test-{}-{id}", std::process::id()));
+        std::fs::create_dir_all(&mountpoint)?;
+        let store = SqliteBlockStore::memory()?;
+
+        // Wnfs is not Send, so it is created on the thread that serves the mount.
+        let (setup_tx, setup_rx) = std::sync::mpsc::channel();
+        let thread = {
+            let store = store.clone();
+            let mountpoint = mountpoint.clone();
+            std::thread::spawn(move || {
+                let fs = match block_on(create(store, self.entries)) {
+                    Ok(fs) => fs,
+                    Err(err) => {
+                        let _ = setup_tx.send(Err(err));
+                        return Ok(());
+                    }
+                };
+                let _ = setup_tx.send(Ok(()));
+                fuse::mount_with_options(fs, mountpoint, &self.options)
+            })
+        };
+        let mut mount = TestMount {
+            mountpoint,
+            store,
+            thread: Some(thread),
+        };
+        setup_rx.recv()??;
+        mount.wait_until_mounted()?;
+        Ok(mount)
+    }
+}
+
+async fn create(
+    store: SqliteBlockStore,
+    entries: Vec<(Vec<String>, Option<Vec<u8>>)>,
+) -> anyhow::Result<Wnfs> {
+    let mut fs = Wnfs::init_in_store(store, FS_NAME.to_string(), None).await?;
+    for (path, content) in entries {
+        match content {
+            Some(content) => fs.write_file(&path, content).await?,
+            None => fs.mkdir(&path).await?,
+        }
+    }
+    fs.flush().await?;
+    Ok(fs)
+}
+
+/// A mounted filesystem, unmounted on drop.
+pub struct TestMount {
+    mountpoint: PathBuf,
+    store: SqliteBlockStore,
+    thread: Option<JoinHandle<anyhow::Result<()>>>,
+}
+
+impl TestMount {
+    pub fn builder() -> TestMountBuilder {
+        TestMountBuilder::default()
+    }
+
+    /// Mount an empty filesystem.
+    pub fn new() -> anyhow::Result<Self> {
+        Self::builder().mount()
+    }
+
+    pub fn mountpoint(&self) -> &Path {
+        &self.mountpoint
+    }
+
+    /// The host path of a path in the filesystem.
+    pub fn path(&self, path: &str) -> PathBuf {
+        self.mountpoint.join(path.trim_start_matches('/'))
+    }
+
+    /// Unmount and return the store, to check what was flushed.
+    pub fn unmount(mut self) -> anyhow::Result<SqliteBlockStore> {
+        self.stop()?;
+        Ok(self.store.clone())
+    }
+
+    /// Unmount, then open the filesystem from the store.
+    pub fn reopen(self) -> anyhow::Result<Wnfs> {
+        let store = self.unmount()?;
+        block_on(Wnfs::open_in_store(store, FS_NAME.to_string(), None))
+    }
+
+    #[track_caller]
+    pub fn assert_file(&self, path: &str, content: &[u8]) {
+        let actual = std::fs::read(self.path(path))
+            .unwrap_or_else(|err| panic!("failed to read {path}: {err}"));
+        assert_eq!(actual, content, "content of {path}");
+    }
+
+    /// Assert the names in a directory, in any order.
+    #[track_caller]
+    pub fn assert_dir(&self, path: &str, names: &[&str]) {
+        let mut actual: Vec<String> = std::fs::read_dir(self.path(path))
+            .unwrap_or_else(|err| panic!("failed to list {path}: {err}"))
+            .map(|entry| {
+                let entry = entry.unwrap_or_else(|err| panic!("failed to list {path}: {err}"));
+                entry.file_name().to_string_lossy().into_owned()
+            })
+            .collect();
+        actual.sort();
+        let mut expected: Vec<_> = names.iter().map(|name| name.to_string()).collect();
+        expected.sort();
+        assert_eq!(actual, expected, "entries of {path}");
+    }
+
+    #[track_caller]
+    pub fn assert_missing(&self, path: &str) {
+        match std::fs::symlink_metadata(self.path(path)) {
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+            Err(err) => panic!("failed to stat {path}: {err}"),
+            Ok(_) => panic!("{path} exists"),
+        }
+    }
+
+    fn wait_until_mounted(&mut self) -> anyhow::Result<()> {
+        let parent = self.mountpoint.parent().unwrap_or(Path::new("/"));
+        let parent_dev = std::fs::metadata(parent)?.dev();
+        let start = Instant::now();
+        loop {
+            // The mount has its own device, unlike the empty directory beneath it.
+            if std::fs::metadata(&self.mountpoint)?.dev() != parent_dev {
+                return Ok(());
+            }
+            if self.thread.as_ref().map_or(true, JoinHandle::is_finished) {
+                self.stop()?;
+                anyhow::bail!("Mount at {:?} exited", self.mountpoint);
+            }
+            if start.elapsed() > MOUNT_TIMEOUT {
+                anyhow::bail!("Timed out mounting at {:?}", self.mountpoint);
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    fn stop(&mut self) -> anyhow::Result<()> {
+        let Some(thread) = self.thread.take() else {
+            return Ok(());
+        };
+        if !thread.is_finished() {
+            fuse::unmount(&self.mountpoint)?;
+        }
+        let result = thread
+            .join()
+            .map_err(|_| anyhow::anyhow!("Mount thread panicked"))?;
+        let _ = std::fs::remove_dir(&self.mountpoint);
+        result
+    }
+}
+
+impl Drop for TestMount {
+    fn drop(&mut self) {
+        if let Err(err) = self.stop() {
+            tracing::warn!("failed to unmount {:?}: {err}", self.mountpoint);
+        }
+    }
+}
+
+fn segments(path: &str) -> Vec<String> {
+    path.split('/')
+        .filter(|segment| !segment.is_empty())
+        .map(str::to_string)
+        .collect()
+}
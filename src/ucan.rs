@@ -0,0 +1,380 @@
+//! Write authorization for shared filesystems with UCANs.
+//!
+//! Every store has an Ed25519 device key, identified by a `did:key`. A device delegates write
+//! access to a subtree of a filesystem to another device by issuing a [UCAN] for its DID,
+//! optionally with an expiry. The other device can delegate the same or a narrower subtree
+//! further by including its token as proof.
+//!
+//! [`crate::sync::sync_with`] signs the root of each pushed revision with the device key and
+//! uploads the signature with the token of the device as `<name>.auth`. If owners are
+//! configured for a filesystem, remote revisions are only merged if they are signed by a
+//! device with a valid token, issued by an owner or through a chain of delegations from one,
+//! that allows writing all paths changed since the last sync. A revision that includes the
+//! changes of several writers thus needs a token covering all of them, like an owner's.
+//!
+//! [UCAN]: https://github.com/ucan-wg/spec
+
+use chrono::Utc;
+use ed25519_dalek::{Signature, Signer as _, SigningKey, Verifier as _, VerifyingKey};
+use libipld::cid::multibase::{self, Base};
+use libipld::{Cid, IpldCodec};
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+use wnfs_common::BlockStore;
+
+use crate::remote::RemoteStore;
+use crate::store::Store;
+use crate::SqliteBlockStore;
+
+const DEVICE_KEY_ALIAS: &str = "device-key";
+const TOKEN_PREFIX: &str = "ucan:";
+const AUTH_SUFFIX: &str = ".auth";
+/// Multicodec prefix of Ed25519 public keys in `did:key`.
+const ED25519_PUB: [u8; 2] = [0xed, 0x01];
+/// The ability that allows writing to a subtree.
+pub const WRITE: &str = "wnfs/write";
+
+/// The Ed25519 key pair of a store, used to sign revisions and issue UCANs.
+pub struct DeviceKey(SigningKey);
+
+impl DeviceKey {
+    /// Load the device key of a store, or create and persist a new one.
+    pub async fn load_or_create(store: &mut impl Store) -> anyhow::Result<Self> {
+        if let Some(bytes) = store.get_from_alias(DEVICE_KEY_ALIAS).await? {
+            let bytes: [u8; 32] = bytes
+                .try_into()
+                .map_err(|_| anyhow::anyhow!("Invalid device key in store"))?;
+            return Ok(Self(SigningKey::from_bytes(&bytes)));
+        }
+        let key = SigningKey::generate(&mut rand::rngs::OsRng);
+        store
+            .put_with_alias(DEVICE_KEY_ALIAS, key.to_bytes().to_vec(), IpldCodec::Raw)
+            .await?;
+        debug!("created device key");
+        Ok(Self(key))
+    }
+
+    /// The `did:key` of this device.
+    pub fn did(&self) -> String {
+        let mut bytes = ED25519_PUB.to_vec();
+        bytes.extend_from_slice(self.0.verifying_key().as_bytes());
+        format!("did:key:{}", multibase::encode(Base::Base58Btc, bytes))
+    }
+}
+
+/// A resource and what may be done with it, e.g. writing to `wnfs://photos/2023`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Capability {
+    pub with: String,
+    pub can: String,
+}
+
+impl Capability {
+    /// Write access to a path of a filesystem.
+    pub fn write(name: &str, path_segments: &[String]) -> Self {
+        let mut with = format!("wnfs://{name}");
+        for segment in path_segments {
+            with.push('/');
+            with.push_str(segment);
+        }
+        Self {
+            with,
+            can: WRITE.to_string(),
+        }
+    }
+
+    /// Whether this capability includes `other`, i.e. is for the same ability on the same or
+    /// a parent path.
+    pub fn covers(&self, other: &Capability) -> bool {
+        if self.can != other.can {
+            return false;
+        }
+        let (Some(this), Some(other)) = (parse_resource(&self.with), parse_resource(&other.with))
+        else {
+            return false;
+        };
+        this.0 == other.0 && other.1.starts_with(&this.1)
+    }
+}
+
+fn parse_resource(with: &str) -> Option<(String, Vec<String>)> {
+    let rest = with.strip_prefix("wnfs://")?;
+    let mut segments = rest.split('/').filter(|segment| !segment.is_empty());
+    let name = segments.next()?.to_string();
+    Some((name, segments.map(str::to_string).collect()))
+}
+
+/// The claims of a UCAN.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Ucan {
+    #[serde(rename = "iss")]
+    pub issuer: String,
+    #[serde(rename = "aud")]
+    pub audience: String,
+    /// Unix time from which the token is valid.
+    #[serde(rename = "nbf", default, skip_serializing_if = "Option::is_none")]
+    pub not_before: Option<i64>,
+    /// Unix time until which the token is valid, or `None` if it does not expire.
+    #[serde(rename = "exp")]
+    pub expires: Option<i64>,
+    #[serde(rename = "att")]
+    pub capabilities: Vec<Capability>,
+    /// Encoded tokens that delegated the capabilities to the issuer.
+    #[serde(rename = "prf", default)]
+    pub proofs: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Header {
+    alg: String,
+    typ: String,
+    ucv: String,
+}
+
+impl Ucan {
+    /// Sign the claims with the key of the issuer and encode them as JWT.
+    pub fn encode(&self, key: &DeviceKey) -> anyhow::Result<String> {
+        if self.issuer != key.did() {
+            anyhow::bail!("Token must be issued by the signing device");
+        }
+        let header = Header {
+            alg: "EdDSA".to_string(),
+            typ: "JWT".to_string(),
+            ucv: "0.9.0".to_string(),
+        };
+        let signed = format!(
+            "{}.{}",
+            Base::Base64Url.encode(serde_json::to_vec(&header)?),
+            Base::Base64Url.encode(serde_json::to_vec(self)?)
+        );
+        let signature = key.0.sign(signed.as_bytes());
+        Ok(format!(
+            "{signed}.{}",
+            Base::Base64Url.encode(signature.to_bytes())
+        ))
+    }
+
+    /// Decode a token and check its signature, without checking times or proofs.
+    pub fn decode(token: &str) -> anyhow::Result<Self> {
+        let invalid = || anyhow::anyhow!("Invalid UCAN");
+        let (signed, signature) = token.trim().rsplit_once('.').ok_or_else(invalid)?;
+        let (header, payload) = signed.split_once('.').ok_or_else(invalid)?;
+        let header: Header = serde_json::from_slice(&Base::Base64Url.decode(header)?)?;
+        if header.alg != "EdDSA" {
+            anyhow::bail!("Unsupported UCAN algorithm {}", header.alg);
+        }
+        let ucan: Ucan = serde_json::from_slice(&Base::Base64Url.decode(payload)?)?;
+        let signature = Signature::from_slice(&Base::Base64Url.decode(signature)?)?;
+        verifying_key(&ucan.issuer)?
+            .verify(signed.as_bytes(), &signature)
+            .map_err(|_| anyhow::anyhow!("Invalid UCAN signature"))?;
+        Ok(ucan)
+    }
+
+    /// Whether the token is valid at a Unix time.
+    fn is_valid_at(&self, now: i64) -> bool {
+        self.not_before.map_or(true, |nbf| nbf <= now) && self.expires.map_or(true, |exp| now < exp)
+    }
+
+    /// Whether the issuer holds a capability: as one of the owners, or through a valid proof
+    /// that was delegated to it and does not expire before this token.
+    fn issuer_holds(&self, capability: &Capability, owners: &[String], now: i64) -> bool {
+        if owners.contains(&self.issuer) {
+            return true;
+        }
+        self.proofs.iter().any(|proof| {
+            let Ok(proof) = Ucan::decode(proof) else {
+                return false;
+            };
+            let outlives = match (proof.expires, self.expires) {
+                (None, _) => true,
+                (Some(_), None) => false,
+                (Some(proof), Some(this)) => proof >= this,
+            };
+            proof.audience == self.issuer
+                && outlives
+                && proof.is_valid_at(now)
+                && proof.allows(capability, owners, now)
+        })
+    }
+
+    /// Whether the token grants a capability to its audience, through a chain of valid
+    /// delegations from one of the owners.
+    pub fn allows(&self, capability: &Capability, owners: &[String], now: i64) -> bool {
+        self.is_valid_at(now)
+            && self
+                .capabilities
+                .iter()
+                .any(|own| own.covers(capability) && self.issuer_holds(capability, owners, now))
+    }
+}
+
+fn verifying_key(did: &str) -> anyhow::Result<VerifyingKey> {
+    let key = did
+        .strip_prefix("did:key:")
+        .ok_or_else(|| anyhow::anyhow!("Unsupported DID {did}"))?;
+    let (_base, bytes) = multibase::decode(key)?;
+    let key = bytes
+        .strip_prefix(&ED25519_PUB[..])
+        .and_then(|key| <[u8; 32]>::try_from(key).ok())
+        .ok_or_else(|| anyhow::anyhow!("Unsupported key type in {did}"))?;
+    Ok(VerifyingKey::from_bytes(&key)?)
+}
+
+/// Delegate write access to a path of a filesystem to another device and return the token.
+///
+/// The token of this device for the filesystem, if it has one, is included as proof. `expires`
+/// is a Unix time.
+pub async fn delegate(
+    store: &mut SqliteBlockStore,
+    name: &str,
+    audience: &str,
+    path_segments: &[String],
+    expires: Option<i64>,
+) -> anyhow::Result<String> {
+    verifying_key(audience)?;
+    let key = DeviceKey::load_or_create(store).await?;
+    let proofs = load_token(store, name).await?.into_iter().collect();
+    let ucan = Ucan {
+        issuer: key.did(),
+        audience: audience.to_string(),
+        not_before: None,
+        expires,
+        capabilities: vec![Capability::write(name, path_segments)],
+        proofs,
+    };
+    ucan.encode(&key)
+}
+
+/// Store a token that was delegated to this device, to sign revisions of a filesystem with.
+pub async fn accept(store: &mut SqliteBlockStore, name: &str, token: &str) -> anyhow::Result<()> {
+    let ucan = Ucan::decode(token)?;
+    let key = DeviceKey::load_or_create(store).await?;
+    if ucan.audience != key.did() {
+        anyhow::bail!("Token is not addressed to this device");
+    }
+    let alias = format!("{TOKEN_PREFIX}{name}");
+    store
+        .put_with_alias(&alias, token.trim().as_bytes().to_vec(), IpldCodec::Raw)
+        .await?;
+    Ok(())
+}
+
+async fn load_token(store: &SqliteBlockStore, name: &str) -> anyhow::Result<Option<String>> {
+    let alias = format!("{TOKEN_PREFIX}{name}");
+    match store.get_from_alias(&alias).await? {
+        Some(bytes) => Ok(Some(String::from_utf8(bytes)?)),
+        None => Ok(None),
+    }
+}
+
+/// A revision root, signed by the device that pushed it.
+#[derive(Debug, Serialize, Deserialize)]
+struct SignedRevision {
+    root: Cid,
+    /// Token of the signing device, which is its audience.
+    ucan: String,
+    signature: Vec<u8>,
+}
+
+/// Write authorization for [`crate::sync::sync_with`].
+pub struct WriteAuth {
+    key: DeviceKey,
+    token: String,
+    /// DIDs whose delegations are accepted, or `None` to accept all revisions.
+    owners: Option<Vec<String>>,
+}
+
+impl WriteAuth {
+    /// Load the device key and token of a filesystem. Without a delegated token, this device
+    /// signs with a token issued to itself, which is only accepted if it is an owner.
+    ///
+    /// With `owners`, remote revisions are checked, and this device counts as owner as well.
+    pub async fn load(
+        store: &mut SqliteBlockStore,
+        name: &str,
+        owners: Option<Vec<String>>,
+    ) -> anyhow::Result<Self> {
+        let key = DeviceKey::load_or_create(store).await?;
+        let token = match load_token(store, name).await? {
+            Some(token) => token,
+            None => Ucan {
+                issuer: key.did(),
+                audience: key.did(),
+                not_before: None,
+                expires: None,
+                capabilities: vec![Capability::write(name, &[])],
+                proofs: vec![],
+            }
+            .encode(&key)?,
+        };
+        let owners = owners.map(|mut owners| {
+            owners.push(key.did());
+            owners
+        });
+        Ok(Self { key, token, owners })
+    }
+
+    /// Sign a pushed root and upload the signature.
+    pub async fn sign(
+        &self,
+        store: &SqliteBlockStore,
+        remote: &dyn RemoteStore,
+        name: &str,
+        root: &Cid,
+    ) -> anyhow::Result<()> {
+        let revision = SignedRevision {
+            root: *root,
+            ucan: self.token.clone(),
+            signature: self.key.0.sign(&root.to_bytes()).to_bytes().to_vec(),
+        };
+        let bytes = serde_ipld_dagcbor::to_vec(&revision)?;
+        let cid = store
+            .clone()
+            .put_block(bytes.clone(), IpldCodec::DagCbor)
+            .await?;
+        remote.put_block(&cid, &bytes).await?;
+        remote
+            .put_root(&format!("{name}{AUTH_SUFFIX}"), &cid)
+            .await?;
+        Ok(())
+    }
+
+    /// Check that a remote root is signed by a device that may write all changed paths.
+    pub async fn check(
+        &self,
+        remote: &dyn RemoteStore,
+        name: &str,
+        root: &Cid,
+        changed: &[Vec<String>],
+    ) -> anyhow::Result<()> {
+        let Some(owners) = &self.owners else {
+            return Ok(());
+        };
+        let cid = remote
+            .get_root(&format!("{name}{AUTH_SUFFIX}"))
+            .await?
+            .ok_or_else(|| anyhow::anyhow!("Remote revision of {name} is not signed"))?;
+        let revision: SignedRevision =
+            serde_ipld_dagcbor::from_slice(&remote.get_block(&cid).await?)?;
+        if revision.root != *root {
+            anyhow::bail!("Remote revision of {name} is not signed");
+        }
+        let ucan = Ucan::decode(&revision.ucan)?;
+        let signature = Signature::from_slice(&revision.signature)?;
+        verifying_key(&ucan.audience)?
+            .verify(&root.to_bytes(), &signature)
+            .map_err(|_| anyhow::anyhow!("Invalid signature of remote revision of {name}"))?;
+        let now = Utc::now().timestamp();
+        for path in changed {
+            if !ucan.allows(&Capability::write(name, path), owners, now) {
+                anyhow::bail!(
+                    "{} is not authorized to write {}",
+                    ucan.audience,
+                    path.join("/")
+                );
+            }
+        }
+        Ok(())
+    }
+}
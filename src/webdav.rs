@@ -0,0 +1,461 @@
+//! WebDAV server exposing a filesystem.
+//!
+//! This allows mounting the filesystem with the native WebDAV clients of Windows Explorer,
+//! macOS Finder and GNOME Files, without FUSE. Applications that embed a [`Wnfs`] can use
+//! [`serve`] directly, or mount the [`handler`] into their own HTTP server.
+//!
+//! PROPFIND reports the metadata of each node as properties in the [`METADATA_NS`] namespace.
+//! Ranged GETs read only the requested part of a file. Writes are buffered in memory until the
+//! file is flushed, because files can only be written as a whole.
+//!
+//! [`Wnfs`]: crate::fs::Wnfs
+
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::future::Future;
+use std::io::SeekFrom;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::time::SystemTime;
+
+use bytes::{Buf, Bytes};
+use dav_server::davpath::DavPath;
+use dav_server::fakels::FakeLs;
+use dav_server::fs::{
+    DavDirEntry, DavFile, DavFileSystem, DavMetaData, DavProp, FsError, FsFuture, FsResult,
+    FsStream, OpenOptions, ReadDirMeta,
+};
+use dav_server::ls::DavLockSystem;
+use dav_server::memls::MemLs;
+use dav_server::{DavHandler, DavMethodSet};
+use futures::FutureExt;
+use hyper::service::{make_service_fn, service_fn};
+use tracing::{debug, trace};
+
+use crate::fs::{DirEntry, EntryKind};
+use crate::handle::WnfsHandle;
+
+/// XML namespace of the properties that expose node metadata.
+pub const METADATA_NS: &str = "https://github.com/Frando/wnfs-fuse/ns/metadata";
+
+/// Options for the WebDAV server.
+#[derive(Debug, Clone, Default)]
+pub struct WebdavConfig {
+    /// URL path under which the filesystem is served, e.g. `/dav`.
+    pub prefix: Option<String>,
+    /// Reject all requests that would modify the filesystem.
+    pub read_only: bool,
+    /// Keep track of locks in memory. Otherwise every lock request is granted, which is
+    /// enough for clients that refuse to write without locking support.
+    pub memory_locks: bool,
+}
+
+/// Serve a filesystem over WebDAV until the server fails.
+pub async fn serve(fs: WnfsHandle, addr: SocketAddr, config: WebdavConfig) -> anyhow::Result<()> {
+    let dav = handler(fs, &config);
+    let make_service = make_service_fn(move |_conn| {
+        let dav = dav.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                let dav = dav.clone();
+                async move { Ok::<_, Infallible>(dav.handle(req).await) }
+            }))
+        }
+    });
+    debug!("serve WebDAV on {addr}");
+    hyper::Server::bind(&addr).serve(make_service).await?;
+    Ok(())
+}
+
+/// Create a WebDAV request handler for a filesystem.
+pub fn handler(fs: WnfsHandle, config: &WebdavConfig) -> DavHandler {
+    // Finder and Explorer refuse to write without locking support.
+    let locksystem: Box<dyn DavLockSystem> = if config.memory_locks {
+        MemLs::new()
+    } else {
+        FakeLs::new()
+    };
+    let mut builder = DavHandler::builder()
+        .filesystem(Box::new(WnfsDav { fs }))
+        .locksystem(locksystem);
+    if let Some(prefix) = &config.prefix {
+        builder = builder.strip_prefix(prefix.clone());
+    }
+    if config.read_only {
+        builder = builder.methods(DavMethodSet::WEBDAV_RO);
+    }
+    builder.build_handler()
+}
+
+#[derive(Clone)]
+struct WnfsDav {
+    fs: WnfsHandle,
+}
+
+impl WnfsDav {
+    async fn stat(&self, path_segments: Vec<String>) -> FsResult<DirEntry> {
+        self.fs
+            .call(move |fs| async move { fs.stat(&path_segments).await }.boxed_local())
+            .await
+            .map_err(general_failure)?
+            .ok_or(FsError::NotFound)
+    }
+
+    async fn metadata_map(&self, path: &DavPath) -> FsResult<BTreeMap<String, serde_json::Value>> {
+        let path_segments = into_segments(path);
+        self.fs
+            .call(move |fs| async move { fs.get_metadata(&path_segments).await }.boxed_local())
+            .await
+            .map_err(general_failure)
+    }
+}
+
+impl DavFileSystem for WnfsDav {
+    fn open<'a>(
+        &'a self,
+        path: &'a DavPath,
+        options: OpenOptions,
+    ) -> FsFuture<'a, Box<dyn DavFile>> {
+        async move {
+            trace!("open {path} {options:?}");
+            let path_segments = into_segments(path);
+            let entry = match self.stat(path_segments.clone()).await {
+                Ok(_) if options.create_new => return Err(FsError::Exists),
+                Ok(entry) if entry.kind == EntryKind::Dir => return Err(FsError::Forbidden),
+                Ok(entry) => Some(entry),
+                Err(FsError::NotFound) if options.create || options.create_new => None,
+                Err(err) => return Err(err),
+            };
+            let mut file = WnfsDavFile {
+                fs: self.fs.clone(),
+                path_segments,
+                pos: 0,
+                content: None,
+                dirty: false,
+                size: entry.as_ref().map(|entry| entry.size).unwrap_or(0),
+            };
+            // Writes are buffered in memory and persisted on flush.
+            if options.write {
+                if options.truncate || entry.is_none() {
+                    file.content = Some(vec![]);
+                    file.dirty = true;
+                } else {
+                    file.load().await?;
+                }
+                if options.append {
+                    file.pos = file.size;
+                }
+            }
+            Ok(Box::new(file) as Box<dyn DavFile>)
+        }
+        .boxed()
+    }
+
+    fn read_dir<'a>(
+        &'a self,
+        path: &'a DavPath,
+        _meta: ReadDirMeta,
+    ) -> FsFuture<'a, FsStream<Box<dyn DavDirEntry>>> {
+        async move {
+            trace!("read_dir {path}");
+            let path_segments = into_segments(path);
+            let entries = self
+                .fs
+                .call(move |fs| async move { fs.ls_entries(&path_segments).await }.boxed_local())
+                .await
+                .map_err(general_failure)?;
+            let entries = entries
+                .into_iter()
+                .map(|entry| Box::new(DavEntry(entry)) as Box<dyn DavDirEntry>);
+            Ok(Box::pin(futures::stream::iter(entries)) as FsStream<Box<dyn DavDirEntry>>)
+        }
+        .boxed()
+    }
+
+    fn metadata<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, Box<dyn DavMetaData>> {
+        async move {
+            trace!("metadata {path}");
+            let entry = self.stat(into_segments(path)).await?;
+            Ok(Box::new(DavEntry(entry)) as Box<dyn DavMetaData>)
+        }
+        .boxed()
+    }
+
+    fn create_dir<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        async move {
+            trace!("create_dir {path}");
+            let path_segments = into_segments(path);
+            if self.stat(path_segments.clone()).await.is_ok() {
+                return Err(FsError::Exists);
+            }
+            self.fs
+                .call(move |fs| async move { fs.mkdir(&path_segments).await }.boxed_local())
+                .await
+                .map_err(general_failure)
+        }
+        .boxed()
+    }
+
+    fn have_props<'a>(
+        &'a self,
+        _path: &'a DavPath,
+    ) -> Pin<Box<dyn Future<Output = bool> + Send + 'a>> {
+        Box::pin(futures::future::ready(true))
+    }
+
+    fn get_props<'a>(&'a self, path: &'a DavPath, do_content: bool) -> FsFuture<'a, Vec<DavProp>> {
+        async move {
+            trace!("get_props {path}");
+            let props = self
+                .metadata_map(path)
+                .await?
+                .into_iter()
+                .filter(|(key, _)| is_xml_name(key))
+                .map(|(key, value)| DavProp {
+                    xml: do_content.then(|| prop_xml(&key, &value)),
+                    name: key,
+                    prefix: Some("M".to_string()),
+                    namespace: Some(METADATA_NS.to_string()),
+                })
+                .collect();
+            Ok(props)
+        }
+        .boxed()
+    }
+
+    fn get_prop<'a>(&'a self, path: &'a DavPath, prop: DavProp) -> FsFuture<'a, Vec<u8>> {
+        async move {
+            trace!("get_prop {path} {}", prop.name);
+            if prop.namespace.as_deref() != Some(METADATA_NS) {
+                return Err(FsError::NotFound);
+            }
+            let metadata = self.metadata_map(path).await?;
+            let value = metadata.get(&prop.name).ok_or(FsError::NotFound)?;
+            Ok(prop_xml(&prop.name, value))
+        }
+        .boxed()
+    }
+
+    fn remove_dir<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        self.remove_file(path)
+    }
+
+    fn remove_file<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        async move {
+            trace!("remove {path}");
+            let path_segments = into_segments(path);
+            self.stat(path_segments.clone()).await?;
+            self.fs
+                .call(move |fs| async move { fs.rm(&path_segments).await }.boxed_local())
+                .await
+                .map_err(general_failure)
+        }
+        .boxed()
+    }
+}
+
+#[derive(Debug)]
+struct WnfsDavFile {
+    fs: WnfsHandle,
+    path_segments: Vec<String>,
+    pos: u64,
+    size: u64,
+    /// Full content of the file, loaded when opened for writing.
+    content: Option<Vec<u8>>,
+    dirty: bool,
+}
+
+impl WnfsDavFile {
+    async fn load(&mut self) -> FsResult<()> {
+        let path_segments = self.path_segments.clone();
+        let content = self
+            .fs
+            .call(move |fs| async move { fs.read_file(&path_segments).await }.boxed_local())
+            .await
+            .map_err(general_failure)?;
+        self.size = content.len() as u64;
+        self.content = Some(content);
+        Ok(())
+    }
+}
+
+impl DavFile for WnfsDavFile {
+    fn metadata(&mut self) -> FsFuture<Box<dyn DavMetaData>> {
+        async move {
+            let entry = DirEntry {
+                name: self.path_segments.last().cloned().unwrap_or_default(),
+                kind: EntryKind::File,
+                size: self.size,
+                modified: None,
+                created: None,
+                mode: None,
+            };
+            Ok(Box::new(DavEntry(entry)) as Box<dyn DavMetaData>)
+        }
+        .boxed()
+    }
+
+    fn write_buf(&mut self, mut buf: Box<dyn Buf + Send>) -> FsFuture<()> {
+        let bytes = buf.copy_to_bytes(buf.remaining());
+        self.write_bytes(bytes)
+    }
+
+    fn write_bytes(&mut self, buf: Bytes) -> FsFuture<()> {
+        async move {
+            let content = self.content.as_mut().ok_or(FsError::Forbidden)?;
+            let pos = self.pos as usize;
+            let end = pos + buf.len();
+            if content.len() < end {
+                content.resize(end, 0);
+            }
+            content[pos..end].copy_from_slice(&buf);
+            self.pos = end as u64;
+            self.size = content.len() as u64;
+            self.dirty = true;
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn read_bytes(&mut self, count: usize) -> FsFuture<Bytes> {
+        async move {
+            let data = match &self.content {
+                Some(content) => {
+                    let start = (self.pos as usize).min(content.len());
+                    let end = (start + count).min(content.len());
+                    content[start..end].to_vec()
+                }
+                None => {
+                    let path_segments = self.path_segments.clone();
+                    let offset = self.pos as usize;
+                    self.fs
+                        .call(move |fs| {
+                            async move { fs.read_file_at(&path_segments, offset, count).await }
+                                .boxed_local()
+                        })
+                        .await
+                        .map_err(general_failure)?
+                }
+            };
+            self.pos += data.len() as u64;
+            Ok(Bytes::from(data))
+        }
+        .boxed()
+    }
+
+    fn seek(&mut self, pos: SeekFrom) -> FsFuture<u64> {
+        async move {
+            let pos = match pos {
+                SeekFrom::Start(pos) => pos as i64,
+                SeekFrom::End(offset) => self.size as i64 + offset,
+                SeekFrom::Current(offset) => self.pos as i64 + offset,
+            };
+            if pos < 0 {
+                return Err(FsError::GeneralFailure);
+            }
+            self.pos = pos as u64;
+            Ok(self.pos)
+        }
+        .boxed()
+    }
+
+    fn flush(&mut self) -> FsFuture<()> {
+        async move {
+            if !self.dirty {
+                return Ok(());
+            }
+            let Some(content) = self.content.clone() else {
+                return Ok(());
+            };
+            let path_segments = self.path_segments.clone();
+            self.fs
+                .call(move |fs| {
+                    async move { fs.write_file(&path_segments, content).await }.boxed_local()
+                })
+                .await
+                .map_err(general_failure)?;
+            self.dirty = false;
+            Ok(())
+        }
+        .boxed()
+    }
+}
+
+#[derive(Debug, Clone)]
+struct DavEntry(DirEntry);
+
+impl DavDirEntry for DavEntry {
+    fn name(&self) -> Vec<u8> {
+        self.0.name.as_bytes().to_vec()
+    }
+
+    fn metadata(&self) -> FsFuture<Box<dyn DavMetaData>> {
+        let meta = self.clone();
+        async move { Ok(Box::new(meta) as Box<dyn DavMetaData>) }.boxed()
+    }
+}
+
+impl DavMetaData for DavEntry {
+    fn len(&self) -> u64 {
+        self.0.size
+    }
+
+    fn modified(&self) -> FsResult<SystemTime> {
+        self.0
+            .modified
+            .map(SystemTime::from)
+            .ok_or(FsError::GeneralFailure)
+    }
+
+    fn created(&self) -> FsResult<SystemTime> {
+        self.0
+            .created
+            .map(SystemTime::from)
+            .ok_or(FsError::GeneralFailure)
+    }
+
+    fn is_dir(&self) -> bool {
+        self.0.kind == EntryKind::Dir
+    }
+
+    fn executable(&self) -> FsResult<bool> {
+        Ok(self.0.mode.map_or(false, |mode| mode & 0o111 != 0))
+    }
+}
+
+/// The XML element of a metadata property. Strings are used as is, other values as JSON.
+fn prop_xml(key: &str, value: &serde_json::Value) -> Vec<u8> {
+    let value = match value {
+        serde_json::Value::String(value) => value.clone(),
+        value => value.to_string(),
+    };
+    let value = value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;");
+    format!("<M:{key} xmlns:M=\"{METADATA_NS}\">{value}</M:{key}>").into_bytes()
+}
+
+/// Metadata keys that cannot be used as XML element names are not exposed.
+fn is_xml_name(key: &str) -> bool {
+    let mut chars = key.chars();
+    chars
+        .next()
+        .map_or(false, |c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'))
+}
+
+fn into_segments(path: &DavPath) -> Vec<String> {
+    path.as_pathbuf()
+        .components()
+        .filter_map(|component| match component {
+            std::path::Component::Normal(name) => Some(name.to_string_lossy().into_owned()),
+            _ => None,
+        })
+        .collect()
+}
+
+fn general_failure(err: anyhow::Error) -> FsError {
+    debug!("webdav error: {err}");
+    FsError::GeneralFailure
+}
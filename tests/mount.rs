@@ -0,0 +1,168 @@
+//! End-to-end tests of the FUSE handlers through a real mount.
+//!
+//! They are skipped where `/dev/fuse` or `fusermount` is missing, as in most containers.
+#![cfg(feature = "native")]
+
+use std::fs::{OpenOptions, Permissions};
+use std::io::Write;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::Path;
+
+use futures::executor::block_on;
+use wnfs_experiments::testing::{TestMount, TestMountBuilder};
+
+fn mount(builder: TestMountBuilder) -> Option<TestMount> {
+    let fusermount = std::env::var_os("PATH").map_or(false, |paths| {
+        std::env::split_paths(&paths).any(|dir| dir.join("fusermount").exists())
+    });
+    if !Path::new("/dev/fuse").exists() || !fusermount {
+        eprintln!("skipped: /dev/fuse or fusermount is missing");
+        return None;
+    }
+    Some(builder.mount().expect("failed to mount"))
+}
+
+fn segments(path: &str) -> Vec<String> {
+    path.split('/').map(str::to_string).collect()
+}
+
+#[test]
+fn reads_existing_files() {
+    let Some(mount) = mount(
+        TestMount::builder()
+            .file("docs/hello.txt", b"hello")
+            .dir("empty"),
+    ) else {
+        return;
+    };
+    mount.assert_dir("", &["docs", "empty"]);
+    mount.assert_dir("docs", &["hello.txt"]);
+    mount.assert_dir("empty", &[]);
+    mount.assert_file("docs/hello.txt", b"hello");
+    mount.assert_missing("docs/other.txt");
+}
+
+#[test]
+fn creates_and_writes_files() {
+    let Some(mount) = mount(TestMount::builder()) else {
+        return;
+    };
+    std::fs::create_dir(mount.path("docs")).unwrap();
+    std::fs::write(mount.path("docs/new.txt"), b"new content").unwrap();
+    mount.assert_file("docs/new.txt", b"new content");
+    // Overwriting truncates.
+    std::fs::write(mount.path("docs/new.txt"), b"short").unwrap();
+    mount.assert_file("docs/new.txt", b"short");
+    assert_eq!(
+        std::fs::create_dir(mount.path("docs")).unwrap_err().kind(),
+        std::io::ErrorKind::AlreadyExists
+    );
+
+    let fs = mount.reopen().unwrap();
+    let content = block_on(fs.read_file(&segments("docs/new.txt"))).unwrap();
+    assert_eq!(content, b"short");
+}
+
+#[test]
+fn renames_within_and_across_directories() {
+    let Some(mount) = mount(
+        TestMount::builder()
+            .file("a.txt", b"a")
+            .file("b.txt", b"b")
+            .dir("dir"),
+    ) else {
+        return;
+    };
+    std::fs::rename(mount.path("a.txt"), mount.path("renamed.txt")).unwrap();
+    mount.assert_missing("a.txt");
+    mount.assert_file("renamed.txt", b"a");
+
+    std::fs::rename(mount.path("renamed.txt"), mount.path("dir/moved.txt")).unwrap();
+    mount.assert_dir("dir", &["moved.txt"]);
+    mount.assert_file("dir/moved.txt", b"a");
+
+    // Like rename(2), an existing file is replaced.
+    std::fs::rename(mount.path("b.txt"), mount.path("dir/moved.txt")).unwrap();
+    mount.assert_dir("", &["dir"]);
+    mount.assert_file("dir/moved.txt", b"b");
+}
+
+#[test]
+fn removes_files_and_empty_directories() {
+    let Some(mount) = mount(
+        TestMount::builder()
+            .file("dir/file.txt", b"content")
+            .dir("empty"),
+    ) else {
+        return;
+    };
+    assert_eq!(
+        std::fs::remove_dir(mount.path("dir"))
+            .unwrap_err()
+            .raw_os_error(),
+        Some(libc::ENOTEMPTY)
+    );
+    std::fs::remove_file(mount.path("dir/file.txt")).unwrap();
+    mount.assert_missing("dir/file.txt");
+    std::fs::remove_dir(mount.path("dir")).unwrap();
+    std::fs::remove_dir(mount.path("empty")).unwrap();
+    mount.assert_dir("", &[]);
+
+    let fs = mount.reopen().unwrap();
+    assert!(block_on(fs.ls_entries(&[])).unwrap().is_empty());
+}
+
+/// The steps of file managers that move a file to the trash of the mount, following the
+/// freedesktop.org Trash specification, and restore it.
+#[test]
+fn moves_files_to_the_trash() {
+    let Some(mount) = mount(TestMount::builder().file("docs/report.txt", b"report")) else {
+        return;
+    };
+    let uid = unsafe { libc::getuid() };
+    let trash = format!(".Trash-{uid}");
+    std::fs::create_dir(mount.path(&trash)).unwrap();
+    let metadata = std::fs::symlink_metadata(mount.path(&trash)).unwrap();
+    assert!(metadata.is_dir());
+    assert_eq!(metadata.uid(), uid);
+    assert_eq!(metadata.mode() & 0o777, 0o700);
+    std::fs::set_permissions(mount.path(&trash), Permissions::from_mode(0o700)).unwrap();
+    std::fs::create_dir(mount.path(&format!("{trash}/files"))).unwrap();
+    std::fs::create_dir(mount.path(&format!("{trash}/info"))).unwrap();
+
+    let info_path = format!("{trash}/info/report.txt.trashinfo");
+    let info = "[Trash Info]\nPath=docs/report.txt\nDeletionDate=2024-01-01T12:00:00\n";
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(mount.path(&info_path))
+        .unwrap();
+    file.write_all(info.as_bytes()).unwrap();
+    drop(file);
+    let trashed = format!("{trash}/files/report.txt");
+    std::fs::rename(mount.path("docs/report.txt"), mount.path(&trashed)).unwrap();
+    mount.assert_dir("docs", &[]);
+    mount.assert_file(&trashed, b"report");
+    mount.assert_file(&info_path, info.as_bytes());
+
+    std::fs::rename(mount.path(&trashed), mount.path("docs/report.txt")).unwrap();
+    std::fs::remove_file(mount.path(&info_path)).unwrap();
+    mount.assert_file("docs/report.txt", b"report");
+    mount.assert_dir(&format!("{trash}/files"), &[]);
+}
+
+#[test]
+fn refuses_to_extend_files_beyond_the_maximum_read_size() {
+    let Some(mount) = mount(TestMount::builder().file("small.txt", b"small")) else {
+        return;
+    };
+    let file = OpenOptions::new()
+        .write(true)
+        .open(mount.path("small.txt"))
+        .unwrap();
+    // Far beyond the default of 1 GiB, which would have to be allocated.
+    let err = file.set_len(1 << 40).unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::EFBIG));
+    drop(file);
+    mount.assert_file("small.txt", b"small");
+}